@@ -0,0 +1,4 @@
+-- Record where each session was signed in from
+ALTER TABLE sessions ADD COLUMN device_label TEXT;
+ALTER TABLE sessions ADD COLUMN ip_address TEXT;
+ALTER TABLE sessions ADD COLUMN user_agent TEXT;
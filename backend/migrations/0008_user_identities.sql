@@ -0,0 +1,11 @@
+-- Create user_identities table, linking accounts to external (OIDC) logins
+CREATE TABLE IF NOT EXISTS user_identities (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    email TEXT,
+    created_at DATETIME NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id),
+    UNIQUE(issuer, subject)
+);
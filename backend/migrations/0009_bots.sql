@@ -0,0 +1,19 @@
+-- Bot accounts are users owned by another user; they cannot log in with a
+-- password and authenticate with API tokens instead
+ALTER TABLE users ADD COLUMN is_bot BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN bot_owner_id TEXT REFERENCES users (id);
+
+-- Create api_tokens table; scopes is a JSON array of scope strings
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT UNIQUE NOT NULL,
+    scopes TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    last_used_at DATETIME,
+    revoked_at DATETIME,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_api_tokens_user_id ON api_tokens (user_id);
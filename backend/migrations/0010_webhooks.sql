@@ -0,0 +1,42 @@
+-- Channels joined over the WebSocket are backed by a chat_rooms row of the
+-- same name
+CREATE UNIQUE INDEX IF NOT EXISTS idx_chat_rooms_channel_name
+    ON chat_rooms (name) WHERE room_type = 'channel';
+
+-- Deleted messages keep their row so replies to them still resolve
+ALTER TABLE messages ADD COLUMN deleted_at DATETIME;
+CREATE INDEX IF NOT EXISTS idx_messages_room_created ON messages (room_id, created_at);
+
+-- Create room_webhooks table; events is a JSON array of event names
+CREATE TABLE IF NOT EXISTS room_webhooks (
+    id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    events TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    FOREIGN KEY (room_id) REFERENCES chat_rooms (id),
+    FOREIGN KEY (created_by) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_room_webhooks_room_id ON room_webhooks (room_id);
+
+-- Create webhook_deliveries table, the persistent delivery queue and log
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id TEXT PRIMARY KEY,
+    webhook_id TEXT NOT NULL,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at DATETIME,
+    response_status INTEGER,
+    last_error TEXT,
+    created_at DATETIME NOT NULL,
+    delivered_at DATETIME,
+    FOREIGN KEY (webhook_id) REFERENCES room_webhooks (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id);
+CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_due ON webhook_deliveries (status, next_attempt_at);
@@ -0,0 +1,21 @@
+-- Create incoming_webhooks table; only a hash of each URL token is stored
+CREATE TABLE IF NOT EXISTS incoming_webhooks (
+    id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_by TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    last_used_at DATETIME,
+    revoked_at DATETIME,
+    FOREIGN KEY (room_id) REFERENCES chat_rooms (id),
+    FOREIGN KEY (created_by) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_incoming_webhooks_room_id ON incoming_webhooks (room_id);
+
+-- Messages posted by an integration keep its id, the name they were posted
+-- under and their attachments (a JSON array)
+ALTER TABLE messages ADD COLUMN integration_id TEXT REFERENCES incoming_webhooks (id);
+ALTER TABLE messages ADD COLUMN sender_name TEXT;
+ALTER TABLE messages ADD COLUMN attachments TEXT;
@@ -0,0 +1,15 @@
+-- Create bot_commands table; each slash command a bot registers is answered
+-- by POSTing to its callback URL, signed with its secret
+CREATE TABLE IF NOT EXISTS bot_commands (
+    id TEXT PRIMARY KEY,
+    bot_id TEXT NOT NULL,
+    name TEXT NOT NULL UNIQUE,
+    usage TEXT NOT NULL,
+    description TEXT NOT NULL,
+    callback_url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    FOREIGN KEY (bot_id) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_bot_commands_bot_id ON bot_commands (bot_id);
@@ -0,0 +1,20 @@
+-- Create room_sanctions table: kicks, bans, mutes and timeouts, kept as a
+-- log; a sanction is active until it expires or is lifted
+CREATE TABLE IF NOT EXISTS room_sanctions (
+    id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    reason TEXT,
+    actor_id TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    expires_at DATETIME,
+    lifted_at DATETIME,
+    lifted_by TEXT,
+    FOREIGN KEY (room_id) REFERENCES chat_rooms (id),
+    FOREIGN KEY (user_id) REFERENCES users (id),
+    FOREIGN KEY (actor_id) REFERENCES users (id),
+    FOREIGN KEY (lifted_by) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_room_sanctions_room_user ON room_sanctions (room_id, user_id);
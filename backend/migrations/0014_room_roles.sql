@@ -0,0 +1,12 @@
+-- Create room_roles table for custom roles; permissions is a JSON array of
+-- permission names. Built-in roles (owner, admin, moderator, member,
+-- viewer) are defined in code.
+CREATE TABLE IF NOT EXISTS room_roles (
+    id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    permissions TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    FOREIGN KEY (room_id) REFERENCES chat_rooms (id),
+    UNIQUE (room_id, name)
+);
@@ -0,0 +1,19 @@
+-- Server administrators, and accounts an administrator has disabled
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN disabled_at DATETIME;
+
+-- Create admin_actions table; every call to /api/admin is recorded here.
+-- details is a JSON object
+CREATE TABLE IF NOT EXISTS admin_actions (
+    id TEXT PRIMARY KEY,
+    admin_id TEXT NOT NULL,
+    action TEXT NOT NULL,
+    target_type TEXT,
+    target_id TEXT,
+    details TEXT,
+    ip_address TEXT,
+    created_at DATETIME NOT NULL,
+    FOREIGN KEY (admin_id) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_admin_actions_created_at ON admin_actions (created_at);
@@ -0,0 +1,37 @@
+-- Create audit_log table for security, moderation and admin events. It is
+-- append-only: entries cannot be changed or removed, and outlive the users
+-- they mention. details is a JSON object
+CREATE TABLE IF NOT EXISTS audit_log (
+    id TEXT PRIMARY KEY,
+    actor_id TEXT,
+    action TEXT NOT NULL,
+    target_type TEXT,
+    target_id TEXT,
+    details TEXT,
+    ip_address TEXT,
+    created_at DATETIME NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_log_created_at ON audit_log (created_at);
+CREATE INDEX IF NOT EXISTS idx_audit_log_actor_id ON audit_log (actor_id);
+CREATE INDEX IF NOT EXISTS idx_audit_log_target ON audit_log (target_type, target_id);
+
+CREATE TRIGGER IF NOT EXISTS audit_log_no_update
+BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_log_no_delete
+BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+
+-- The admin audit trail moves into the audit log
+INSERT INTO audit_log (id, actor_id, action, target_type, target_id, details, ip_address,
+                       created_at)
+SELECT id, admin_id, 'admin.' || action, target_type, target_id, details, ip_address, created_at
+FROM admin_actions;
+
+DROP TABLE admin_actions;
@@ -0,0 +1,30 @@
+-- Create message_reports table: members flag messages for the room's
+-- moderators. The reported text is copied so edits and deletions do not
+-- change what was reported
+CREATE TABLE IF NOT EXISTS message_reports (
+    id TEXT PRIMARY KEY,
+    message_id TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    reporter_id TEXT NOT NULL,
+    sender_id TEXT NOT NULL,
+    content TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    comment TEXT,
+    status TEXT NOT NULL DEFAULT 'open',
+    resolution TEXT,
+    resolution_note TEXT,
+    sanction_id TEXT,
+    resolved_by TEXT,
+    resolved_at DATETIME,
+    created_at DATETIME NOT NULL,
+    FOREIGN KEY (message_id) REFERENCES messages (id),
+    FOREIGN KEY (room_id) REFERENCES chat_rooms (id),
+    FOREIGN KEY (reporter_id) REFERENCES users (id),
+    FOREIGN KEY (sanction_id) REFERENCES room_sanctions (id),
+    FOREIGN KEY (resolved_by) REFERENCES users (id),
+    UNIQUE(message_id, reporter_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_reports_room_status ON message_reports (room_id, status);
+CREATE INDEX IF NOT EXISTS idx_message_reports_status ON message_reports (status, created_at);
+CREATE INDEX IF NOT EXISTS idx_message_reports_reporter ON message_reports (reporter_id);
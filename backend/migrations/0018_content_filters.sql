@@ -0,0 +1,38 @@
+-- Create room_word_filters table: per-room additions to the server's
+-- banned words (mode 'ban') and exemptions from it (mode 'allow')
+CREATE TABLE IF NOT EXISTS room_word_filters (
+    id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    word TEXT NOT NULL,
+    mode TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    FOREIGN KEY (room_id) REFERENCES chat_rooms (id),
+    FOREIGN KEY (created_by) REFERENCES users (id),
+    UNIQUE(room_id, word)
+);
+
+-- Create held_messages table: messages a content filter kept back until a
+-- moderator approves or rejects them. attachments is a JSON array
+CREATE TABLE IF NOT EXISTS held_messages (
+    id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    sender_id TEXT NOT NULL,
+    username TEXT NOT NULL,
+    content TEXT NOT NULL,
+    message_type TEXT,
+    is_bot BOOLEAN NOT NULL DEFAULT 0,
+    integration_id TEXT,
+    attachments TEXT,
+    filter TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'held',
+    reviewed_by TEXT,
+    reviewed_at DATETIME,
+    created_at DATETIME NOT NULL,
+    FOREIGN KEY (room_id) REFERENCES chat_rooms (id),
+    FOREIGN KEY (sender_id) REFERENCES users (id),
+    FOREIGN KEY (reviewed_by) REFERENCES users (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_held_messages_room_status ON held_messages (room_id, status);
@@ -0,0 +1,5 @@
+-- Per-room posting restrictions: the minimum seconds between one sender's
+-- messages (0 is off), posting by admins only, and posting by members only
+ALTER TABLE chat_rooms ADD COLUMN slow_mode_seconds INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE chat_rooms ADD COLUMN read_only BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE chat_rooms ADD COLUMN members_only BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,20 @@
+-- Create files table: uploaded blobs, kept by the storage backend under
+-- storage_key. A file belongs to the room it was uploaded to and is linked
+-- to its message once that is sent
+CREATE TABLE IF NOT EXISTS files (
+    id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    uploader_id TEXT NOT NULL,
+    message_id TEXT,
+    storage_key TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    created_at DATETIME NOT NULL,
+    FOREIGN KEY (room_id) REFERENCES chat_rooms (id),
+    FOREIGN KEY (uploader_id) REFERENCES users (id),
+    FOREIGN KEY (message_id) REFERENCES messages (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_files_room_id ON files (room_id);
+CREATE INDEX IF NOT EXISTS idx_files_message_id ON files (message_id);
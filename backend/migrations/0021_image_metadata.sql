@@ -0,0 +1,7 @@
+-- Uploaded images: their dimensions, blurhash placeholder and the type of
+-- their thumbnails, which are stored next to the original. All NULL for
+-- other files
+ALTER TABLE files ADD COLUMN width INTEGER;
+ALTER TABLE files ADD COLUMN height INTEGER;
+ALTER TABLE files ADD COLUMN blurhash TEXT;
+ALTER TABLE files ADD COLUMN thumbnail_type TEXT;
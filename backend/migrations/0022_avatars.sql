@@ -0,0 +1,5 @@
+-- Uploaded avatars: a hash of the upload, which changes the avatar's URL
+-- and ETag when a new one is uploaded, and the type of its stored sizes.
+-- NULL while the user has no uploaded avatar
+ALTER TABLE users ADD COLUMN avatar_hash TEXT;
+ALTER TABLE users ADD COLUMN avatar_type TEXT;
@@ -0,0 +1,12 @@
+-- Link previews: Open Graph / Twitter card metadata fetched for links in
+-- messages, cached by URL, and the previews shown under each message
+CREATE TABLE IF NOT EXISTS link_previews (
+    url TEXT PRIMARY KEY,
+    title TEXT,
+    description TEXT,
+    image_url TEXT,
+    site_name TEXT,
+    fetched_at DATETIME NOT NULL
+);
+
+ALTER TABLE messages ADD COLUMN link_previews TEXT;
@@ -0,0 +1,464 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{request::Parts, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::{
+    api_error, audit,
+    auth::{
+        generate_secret,
+        password::{hash_password, send_reset_link},
+        session::{self, ClientInfo},
+        AuthUser,
+    },
+    db, files, internal_error, rooms, ApiError, AppState, StatusResponse,
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+const MAX_REASON_LEN: usize = 500;
+
+/// A signed-in server administrator. Every handler taking one records what
+/// it did with [`Admin::record`].
+pub struct Admin {
+    pub user: db::User,
+    pub client: ClientInfo,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Admin {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let auth = AuthUser::from_request_parts(parts, state).await?;
+        let Ok(client) = ClientInfo::from_request_parts(parts, state).await;
+        let user = state
+            .db
+            .get_user_by_id(&auth.id)
+            .await
+            .map_err(internal_error)?
+            .filter(|user| user.is_admin && user.disabled_at.is_none())
+            .ok_or_else(|| api_error(StatusCode::FORBIDDEN, "Admin access required"))?;
+
+        Ok(Admin { user, client })
+    }
+}
+
+impl Admin {
+    /// Writes an `admin.<action>` entry to the audit log.
+    pub async fn record(
+        &self,
+        state: &AppState,
+        action: &str,
+        target: Option<(&str, &str)>,
+        details: Value,
+    ) {
+        audit::record(
+            state,
+            audit::Event {
+                action: &format!("admin.{action}"),
+                actor_id: Some(&self.user.id),
+                target,
+                ip_address: self.client.ip_address.as_deref(),
+                details,
+            },
+        )
+        .await;
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsersQuery {
+    /// Matches usernames, display names and emails.
+    q: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableUserRequest {
+    reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    /// Without one the old password stops working and the user is emailed
+    /// a reset link.
+    new_password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetAdminRequest {
+    is_admin: bool,
+}
+
+/// A user as administrators see them.
+#[derive(Debug, Serialize)]
+pub struct AdminUserResponse {
+    id: String,
+    username: String,
+    email: String,
+    email_verified: bool,
+    display_name: Option<String>,
+    status: String,
+    is_bot: bool,
+    bot_owner_id: Option<String>,
+    is_admin: bool,
+    two_factor_enabled: bool,
+    created_at: DateTime<Utc>,
+    last_seen: Option<DateTime<Utc>>,
+    disabled_at: Option<DateTime<Utc>>,
+}
+
+impl AdminUserResponse {
+    fn from_row(user: db::User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            email_verified: user.email_verified,
+            display_name: user.display_name,
+            status: user.status,
+            is_bot: user.is_bot,
+            bot_owner_id: user.bot_owner_id,
+            is_admin: user.is_admin,
+            two_factor_enabled: user.totp_enabled,
+            created_at: user.created_at,
+            last_seen: user.last_seen,
+            disabled_at: user.disabled_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatsResponse {
+    #[serde(flatten)]
+    counts: db::ServerCounts,
+    /// WebSocket channels with at least one connection.
+    open_channels: usize,
+    connected_users: usize,
+    uptime_seconds: u64,
+}
+
+fn page(limit: Option<i64>, offset: Option<i64>) -> (i64, i64) {
+    (
+        limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+        offset.unwrap_or(0).max(0),
+    )
+}
+
+async fn load_user(state: &AppState, user_id: &str) -> Result<db::User, ApiError> {
+    state
+        .db
+        .get_user_by_id(user_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "User not found"))
+}
+
+/// Closes a disabled account's WebSockets: its sessions, and the API
+/// tokens of the account or of any bot it owns.
+async fn disconnect_user(state: &AppState, user: &db::User) -> anyhow::Result<()> {
+    session::revoke_user_sessions(state, &user.id, None).await?;
+
+    let mut bots = state.db.get_bots_by_owner(&user.id).await?;
+    if user.is_bot {
+        bots.push(user.clone());
+    }
+    for bot in bots {
+        for token in state.db.get_user_api_tokens(&bot.id).await? {
+            session::disconnect_session(state, &token.id);
+        }
+    }
+    Ok(())
+}
+
+// 列出或搜索用户
+pub async fn list_users_handler(
+    State(state): State<Arc<AppState>>,
+    admin: Admin,
+    Query(query): Query<UsersQuery>,
+) -> Result<Json<Vec<AdminUserResponse>>, ApiError> {
+    let (limit, offset) = page(query.limit, query.offset);
+    let search = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let users = state
+        .db
+        .search_users(search, limit, offset)
+        .await
+        .map_err(internal_error)?;
+
+    admin
+        .record(&state, "users.list", None, json!({ "q": search }))
+        .await;
+    Ok(Json(
+        users.into_iter().map(AdminUserResponse::from_row).collect(),
+    ))
+}
+
+// 查看单个用户
+pub async fn get_user_handler(
+    State(state): State<Arc<AppState>>,
+    admin: Admin,
+    Path(user_id): Path<String>,
+) -> Result<Json<AdminUserResponse>, ApiError> {
+    let user = load_user(&state, &user_id).await?;
+
+    admin
+        .record(&state, "user.view", Some(("user", &user.id)), Value::Null)
+        .await;
+    Ok(Json(AdminUserResponse::from_row(user)))
+}
+
+// 停用账号并结束其所有会话
+pub async fn disable_user_handler(
+    State(state): State<Arc<AppState>>,
+    admin: Admin,
+    Path(user_id): Path<String>,
+    Json(req): Json<DisableUserRequest>,
+) -> Result<Json<AdminUserResponse>, ApiError> {
+    let mut user = load_user(&state, &user_id).await?;
+    if user.id == admin.user.id {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "You cannot disable your own account",
+        ));
+    }
+    if user.is_admin {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Remove the user's admin role before disabling them",
+        ));
+    }
+    let reason = req
+        .reason
+        .as_deref()
+        .map(str::trim)
+        .filter(|r| !r.is_empty());
+    if reason.is_some_and(|reason| reason.chars().count() > MAX_REASON_LEN) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Reason can be at most {MAX_REASON_LEN} characters"),
+        ));
+    }
+
+    if user.disabled_at.is_none() {
+        let now = Utc::now();
+        state
+            .db
+            .set_user_disabled(&user.id, Some(now))
+            .await
+            .map_err(internal_error)?;
+        user.disabled_at = Some(now);
+    }
+    disconnect_user(&state, &user)
+        .await
+        .map_err(internal_error)?;
+
+    admin
+        .record(
+            &state,
+            "user.disable",
+            Some(("user", &user.id)),
+            json!({ "username": user.username, "reason": reason }),
+        )
+        .await;
+    Ok(Json(AdminUserResponse::from_row(user)))
+}
+
+// 重新启用账号
+pub async fn enable_user_handler(
+    State(state): State<Arc<AppState>>,
+    admin: Admin,
+    Path(user_id): Path<String>,
+) -> Result<Json<AdminUserResponse>, ApiError> {
+    let mut user = load_user(&state, &user_id).await?;
+    state
+        .db
+        .set_user_disabled(&user.id, None)
+        .await
+        .map_err(internal_error)?;
+    user.disabled_at = None;
+
+    admin
+        .record(
+            &state,
+            "user.enable",
+            Some(("user", &user.id)),
+            json!({ "username": user.username }),
+        )
+        .await;
+    Ok(Json(AdminUserResponse::from_row(user)))
+}
+
+// 重置用户密码
+pub async fn reset_password_handler(
+    State(state): State<Arc<AppState>>,
+    admin: Admin,
+    Path(user_id): Path<String>,
+    Json(req): Json<ResetPasswordRequest>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let user = load_user(&state, &user_id).await?;
+    if user.is_bot {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Bots do not have passwords",
+        ));
+    }
+
+    // 未指定新密码时换成无人知晓的随机密码，再发重置链接
+    let emailed = req.new_password.is_none();
+    let password_hash = match &req.new_password {
+        Some(password) => hash_password(password)?,
+        None => hash_password(&generate_secret())?,
+    };
+    state
+        .db
+        .update_user_password(&user.id, &password_hash)
+        .await
+        .map_err(internal_error)?;
+    state
+        .db
+        .delete_password_reset_tokens(&user.id)
+        .await
+        .map_err(internal_error)?;
+    session::revoke_user_sessions(&state, &user.id, None)
+        .await
+        .map_err(internal_error)?;
+    if emailed {
+        send_reset_link(&state, &user)
+            .await
+            .map_err(internal_error)?;
+    }
+
+    admin
+        .record(
+            &state,
+            "user.reset_password",
+            Some(("user", &user.id)),
+            json!({ "username": user.username, "emailed": emailed }),
+        )
+        .await;
+    Ok(Json(StatusResponse::new(if emailed {
+        "Password reset; a reset link was emailed to the user"
+    } else {
+        "Password reset"
+    })))
+}
+
+// 授予或收回管理员角色
+pub async fn set_admin_handler(
+    State(state): State<Arc<AppState>>,
+    admin: Admin,
+    Path(user_id): Path<String>,
+    Json(req): Json<SetAdminRequest>,
+) -> Result<Json<AdminUserResponse>, ApiError> {
+    let mut user = load_user(&state, &user_id).await?;
+    if user.id == admin.user.id {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "You cannot change your own admin role",
+        ));
+    }
+    if req.is_admin && (user.is_bot || user.disabled_at.is_some()) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Bots and disabled accounts cannot be admins",
+        ));
+    }
+
+    state
+        .db
+        .set_user_admin(&user.id, req.is_admin)
+        .await
+        .map_err(internal_error)?;
+    user.is_admin = req.is_admin;
+
+    admin
+        .record(
+            &state,
+            if req.is_admin {
+                "user.grant_admin"
+            } else {
+                "user.revoke_admin"
+            },
+            Some(("user", &user.id)),
+            json!({ "username": user.username }),
+        )
+        .await;
+    Ok(Json(AdminUserResponse::from_row(user)))
+}
+
+// 删除房间及其消息
+pub async fn delete_room_handler(
+    State(state): State<Arc<AppState>>,
+    admin: Admin,
+    Path(room_id): Path<String>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let room = state
+        .db
+        .get_chat_room(&room_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Room not found"))?;
+
+    let room_files = state
+        .db
+        .get_room_files(&room.id)
+        .await
+        .map_err(internal_error)?;
+    state
+        .db
+        .delete_chat_room(&room.id)
+        .await
+        .map_err(internal_error)?;
+    files::delete_stored(&state, &room_files).await;
+    rooms::announce(
+        &state,
+        &room.name,
+        format!("{} was deleted by an administrator", room.name),
+    );
+    rooms::disconnect_all(&state, &room.name);
+
+    admin
+        .record(
+            &state,
+            "room.delete",
+            Some(("room", &room.id)),
+            json!({ "name": room.name }),
+        )
+        .await;
+    Ok(Json(StatusResponse::new("Room deleted")))
+}
+
+// 服务器统计
+pub async fn stats_handler(
+    State(state): State<Arc<AppState>>,
+    admin: Admin,
+) -> Result<Json<StatsResponse>, ApiError> {
+    let counts = state.db.server_counts().await.map_err(internal_error)?;
+    let (open_channels, connected_users) = state
+        .channels
+        .iter()
+        .map(|channel| channel.users.len())
+        .filter(|users| *users > 0)
+        .fold((0, 0), |(channels, total), users| {
+            (channels + 1, total + users)
+        });
+
+    admin.record(&state, "stats.view", None, Value::Null).await;
+    Ok(Json(StatsResponse {
+        counts,
+        open_channels,
+        connected_users,
+        uptime_seconds: state.started_at.elapsed().as_secs(),
+    }))
+}
@@ -0,0 +1,241 @@
+use axum::{
+    extract::{Query, State},
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{admin::Admin, auth::session::ClientInfo, db, internal_error, ApiError, AppState};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+/// Exports are read from the database this many entries at a time.
+const EXPORT_BATCH_SIZE: i64 = 1000;
+
+/// Something that happened, on its way into the audit log.
+#[derive(Default)]
+pub struct Event<'a> {
+    /// Dotted name such as `auth.login` or `moderation.ban`.
+    pub action: &'a str,
+    pub actor_id: Option<&'a str>,
+    /// The kind and id of what was acted on.
+    pub target: Option<(&'a str, &'a str)>,
+    pub ip_address: Option<&'a str>,
+    pub details: Value,
+}
+
+impl<'a> Event<'a> {
+    /// Something a user did to their own account.
+    pub fn account(action: &'a str, user_id: &'a str, client: &'a ClientInfo) -> Self {
+        Self {
+            action,
+            actor_id: Some(user_id),
+            target: Some(("user", user_id)),
+            ip_address: client.ip_address.as_deref(),
+            details: Value::Null,
+        }
+    }
+}
+
+/// A signed-in user acting on something, and where they acted from.
+pub struct Actor {
+    pub user: db::User,
+    pub ip_address: Option<String>,
+}
+
+impl Actor {
+    pub async fn record(
+        &self,
+        state: &AppState,
+        action: &str,
+        target: Option<(&str, &str)>,
+        details: Value,
+    ) {
+        record(
+            state,
+            Event {
+                action,
+                actor_id: Some(&self.user.id),
+                target,
+                ip_address: self.ip_address.as_deref(),
+                details,
+            },
+        )
+        .await;
+    }
+}
+
+/// Appends an event to the audit log. What was audited has already
+/// happened, so a failure to record it is only logged.
+pub async fn record(state: &AppState, event: Event<'_>) {
+    let entry = db::AuditEntry {
+        id: Uuid::new_v4().to_string(),
+        actor_id: event.actor_id.map(str::to_string),
+        action: event.action.to_string(),
+        target_type: event.target.map(|(kind, _)| kind.to_string()),
+        target_id: event.target.map(|(_, id)| id.to_string()),
+        details: (!event.details.is_null()).then(|| event.details.to_string()),
+        ip_address: event.ip_address.map(str::to_string),
+        created_at: Utc::now(),
+    };
+    if let Err(err) = state.db.create_audit_entry(&entry).await {
+        eprintln!(
+            "failed to record {} in the audit log: {err:#}",
+            entry.action
+        );
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    actor_id: Option<String>,
+    /// An action, or a family of them such as `auth.*`.
+    action: Option<String>,
+    target_type: Option<String>,
+    target_id: Option<String>,
+    ip: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+impl AuditQuery {
+    fn filter(&self) -> db::AuditFilter {
+        let set = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        db::AuditFilter {
+            actor_id: set(&self.actor_id),
+            action: set(&self.action),
+            target_type: set(&self.target_type),
+            target_id: set(&self.target_id),
+            ip_address: set(&self.ip),
+            since: self.since,
+            until: self.until,
+        }
+    }
+
+    /// The filters that were set, for recording the query itself.
+    fn details(&self) -> Value {
+        let filter = self.filter();
+        serde_json::json!({
+            "actor_id": filter.actor_id,
+            "action": filter.action,
+            "target_type": filter.target_type,
+            "target_id": filter.target_id,
+            "ip": filter.ip_address,
+            "since": filter.since,
+            "until": filter.until,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntryResponse {
+    id: String,
+    actor_id: Option<String>,
+    action: String,
+    target_type: Option<String>,
+    target_id: Option<String>,
+    details: Option<Value>,
+    ip_address: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl AuditEntryResponse {
+    fn from_row(entry: db::AuditEntry) -> Self {
+        Self {
+            id: entry.id,
+            actor_id: entry.actor_id,
+            action: entry.action,
+            target_type: entry.target_type,
+            target_id: entry.target_id,
+            details: entry
+                .details
+                .and_then(|details| serde_json::from_str(&details).ok()),
+            ip_address: entry.ip_address,
+            created_at: entry.created_at,
+        }
+    }
+}
+
+// 按条件分页查询审计日志
+pub async fn list_audit_log_handler(
+    State(state): State<Arc<AppState>>,
+    admin: Admin,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEntryResponse>>, ApiError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let entries = state
+        .db
+        .query_audit_log(&query.filter(), false, limit, offset)
+        .await
+        .map_err(internal_error)?;
+
+    admin
+        .record(&state, "audit.view", None, query.details())
+        .await;
+    Ok(Json(
+        entries
+            .into_iter()
+            .map(AuditEntryResponse::from_row)
+            .collect(),
+    ))
+}
+
+// 以 JSON Lines 导出审计日志，按时间先后排列
+pub async fn export_audit_log_handler(
+    State(state): State<Arc<AppState>>,
+    admin: Admin,
+    Query(query): Query<AuditQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let filter = query.filter();
+    let mut body = String::new();
+    let mut offset = 0;
+    loop {
+        let batch = state
+            .db
+            .query_audit_log(&filter, true, EXPORT_BATCH_SIZE, offset)
+            .await
+            .map_err(internal_error)?;
+        let done = (batch.len() as i64) < EXPORT_BATCH_SIZE;
+        offset += batch.len() as i64;
+        for entry in batch {
+            let line = serde_json::to_string(&AuditEntryResponse::from_row(entry))
+                .map_err(|err| internal_error(err.into()))?;
+            body.push_str(&line);
+            body.push('\n');
+        }
+        if done {
+            break;
+        }
+    }
+
+    admin
+        .record(&state, "audit.export", None, query.details())
+        .await;
+    Ok((
+        [
+            (CONTENT_TYPE, "application/x-ndjson"),
+            (
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"audit-log.jsonl\"",
+            ),
+        ],
+        body,
+    ))
+}
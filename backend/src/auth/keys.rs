@@ -0,0 +1,433 @@
+use anyhow::{bail, Context, Result};
+use axum::{extract::State, Json};
+use data_encoding::BASE64URL_NOPAD;
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use rsa::{
+    pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, traits::PublicKeyParts, RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::AppState;
+
+/// The secret `main()` used to fall back to; only accepted with `CHATX_DEV`.
+const DEV_SECRET: &str = "your-secret-key-change-this-in-production";
+/// DER prefix of an Ed25519 SubjectPublicKeyInfo; the raw key follows it.
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// A public key in the `GET /.well-known/jwks.json` document (RFC 7517).
+#[derive(Debug, Clone, Serialize)]
+pub struct Jwk {
+    kty: &'static str,
+    #[serde(rename = "use")]
+    use_: &'static str,
+    alg: &'static str,
+    kid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crv: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    x: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+struct VerificationKey {
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// The keys tokens are signed and verified with. One key signs; every
+/// configured verification key is accepted, so a new signing key can be
+/// rolled out while tokens from the previous one are still in circulation.
+pub struct JwtKeys {
+    kid: String,
+    algorithm: Algorithm,
+    signing: EncodingKey,
+    verification: HashMap<String, VerificationKey>,
+    jwks: Vec<Jwk>,
+}
+
+impl JwtKeys {
+    /// Loads keys from the environment:
+    ///
+    /// - `JWT_SIGNING_KEY_FILE`, `JWT_SIGNING_KEY_ID` and `JWT_VERIFICATION_KEYS`
+    ///   (`kid=path.pem,...`, including the signing key's public half) select
+    ///   RS256/EdDSA signing with PEM keys.
+    /// - Otherwise tokens are signed with HS256 using `JWT_SECRET`. The old
+    ///   built-in secret is refused unless `CHATX_DEV` is set.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("JWT_SIGNING_KEY_FILE") {
+            Ok(path) => Self::asymmetric_from_env(&path),
+            Err(_) => Self::hmac_from_env(),
+        }
+    }
+
+    fn hmac_from_env() -> Result<Self> {
+        let dev = std::env::var("CHATX_DEV")
+            .map(|value| matches!(value.as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        let secret = hmac_secret(std::env::var("JWT_SECRET").ok(), dev)?;
+        let kid = std::env::var("JWT_KEY_ID").unwrap_or_else(|_| "default".to_string());
+
+        Ok(Self::hmac(kid, &secret))
+    }
+
+    /// Signs and verifies with HS256 using a shared secret.
+    pub fn hmac(kid: String, secret: &str) -> Self {
+        let mut verification = HashMap::new();
+        verification.insert(
+            kid.clone(),
+            VerificationKey {
+                algorithm: Algorithm::HS256,
+                key: DecodingKey::from_secret(secret.as_bytes()),
+            },
+        );
+
+        // 对称密钥不能公开，JWKS 为空
+        Self {
+            kid,
+            algorithm: Algorithm::HS256,
+            signing: EncodingKey::from_secret(secret.as_bytes()),
+            verification,
+            jwks: Vec::new(),
+        }
+    }
+
+    fn asymmetric_from_env(signing_path: &str) -> Result<Self> {
+        let kid = std::env::var("JWT_SIGNING_KEY_ID")
+            .context("JWT_SIGNING_KEY_ID is required with JWT_SIGNING_KEY_FILE")?;
+        let spec = std::env::var("JWT_VERIFICATION_KEYS")
+            .context("JWT_VERIFICATION_KEYS is required with JWT_SIGNING_KEY_FILE")?;
+
+        let mut public_keys = Vec::new();
+        for entry in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (key_id, path) = entry.split_once('=').with_context(|| {
+                format!("JWT_VERIFICATION_KEYS entry {entry:?} is not kid=path")
+            })?;
+            let pem = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read verification key {path}"))?;
+            public_keys.push(
+                parse_public_key(key_id, &pem)
+                    .with_context(|| format!("invalid verification key {path}"))?,
+            );
+        }
+        let pem = std::fs::read(signing_path)
+            .with_context(|| format!("failed to read signing key {signing_path}"))?;
+
+        Self::asymmetric(kid, &pem, public_keys)
+    }
+
+    /// Signs with the PEM private key `kid` and accepts tokens from any of
+    /// `public_keys`, which must include the signing key's public half.
+    fn asymmetric(
+        kid: String,
+        signing_pem: &[u8],
+        public_keys: Vec<(VerificationKey, Jwk)>,
+    ) -> Result<Self> {
+        let mut verification = HashMap::new();
+        let mut jwks = Vec::new();
+        for (key, jwk) in public_keys {
+            verification.insert(jwk.kid.clone(), key);
+            jwks.push(jwk);
+        }
+
+        let algorithm = verification
+            .get(&kid)
+            .map(|key| key.algorithm)
+            .with_context(|| format!("JWT_VERIFICATION_KEYS has no public key for {kid}"))?;
+        let signing = match algorithm {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(signing_pem),
+            _ => EncodingKey::from_rsa_pem(signing_pem),
+        }
+        .context("invalid signing key in JWT_SIGNING_KEY_FILE")?;
+
+        let keys = Self {
+            kid,
+            algorithm,
+            signing,
+            verification,
+            jwks,
+        };
+        keys.self_test()?;
+        Ok(keys)
+    }
+
+    /// Makes sure the signing key matches its configured public key, so a
+    /// mismatch fails at startup rather than on every login.
+    fn self_test(&self) -> Result<()> {
+        #[derive(Serialize, Deserialize)]
+        struct Probe {
+            exp: usize,
+        }
+
+        let token = self.sign(&Probe { exp: usize::MAX })?;
+        self.verify::<Probe>(&token)
+            .context("signing key does not match its public key in JWT_VERIFICATION_KEYS")?;
+        Ok(())
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+        Ok(encode(&header, claims, &self.signing)?)
+    }
+
+    /// Checks a token's signature and expiry against the key named by its
+    /// `kid`. Tokens without a `kid` are tried against the signing key.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T> {
+        let header = decode_header(token)?;
+        let kid = header.kid.as_deref().unwrap_or(&self.kid);
+        let key = self
+            .verification
+            .get(kid)
+            .with_context(|| format!("unknown key id {kid}"))?;
+        if header.alg != key.algorithm {
+            bail!("token algorithm does not match key {kid}");
+        }
+
+        Ok(decode::<T>(token, &key.key, &Validation::new(key.algorithm))?.claims)
+    }
+}
+
+/// The HS256 secret from `JWT_SECRET`. The built-in development secret,
+/// used when none is configured, is only accepted with `dev`.
+fn hmac_secret(configured: Option<String>, dev: bool) -> Result<String> {
+    match configured {
+        Some(secret) if !secret.is_empty() && (secret != DEV_SECRET || dev) => Ok(secret),
+        _ if dev => Ok(DEV_SECRET.to_string()),
+        _ => bail!(
+            "JWT_SECRET is missing or uses the development default; set a real secret, \
+             configure JWT_SIGNING_KEY_FILE, or set CHATX_DEV=1 for local development"
+        ),
+    }
+}
+
+/// Reads an RSA or Ed25519 public key from PEM.
+fn parse_public_key(kid: &str, pem: &str) -> Result<(VerificationKey, Jwk)> {
+    let block = pem::parse(pem)?;
+    let der = block.contents();
+
+    if block.tag() == "PUBLIC KEY"
+        && der.len() == ED25519_SPKI_PREFIX.len() + 32
+        && der.starts_with(&ED25519_SPKI_PREFIX)
+    {
+        let x = BASE64URL_NOPAD.encode(&der[ED25519_SPKI_PREFIX.len()..]);
+        let key = VerificationKey {
+            algorithm: Algorithm::EdDSA,
+            key: DecodingKey::from_ed_components(&x)?,
+        };
+        let jwk = Jwk {
+            kty: "OKP",
+            use_: "sig",
+            alg: "EdDSA",
+            kid: kid.to_string(),
+            n: None,
+            e: None,
+            crv: Some("Ed25519"),
+            x: Some(x),
+        };
+        return Ok((key, jwk));
+    }
+
+    let rsa = match block.tag() {
+        "PUBLIC KEY" => RsaPublicKey::from_public_key_der(der)?,
+        "RSA PUBLIC KEY" => RsaPublicKey::from_pkcs1_der(der)?,
+        other => bail!("expected an RSA or Ed25519 public key, found {other}"),
+    };
+    let n = BASE64URL_NOPAD.encode(&rsa.n().to_bytes_be());
+    let e = BASE64URL_NOPAD.encode(&rsa.e().to_bytes_be());
+    let key = VerificationKey {
+        algorithm: Algorithm::RS256,
+        key: DecodingKey::from_rsa_components(&n, &e)?,
+    };
+    let jwk = Jwk {
+        kty: "RSA",
+        use_: "sig",
+        alg: "RS256",
+        kid: kid.to_string(),
+        n: Some(n),
+        e: Some(e),
+        crv: None,
+        x: None,
+    };
+    Ok((key, jwk))
+}
+
+// 公开验证 token 所需的公钥
+pub async fn jwks_handler(State(state): State<Arc<AppState>>) -> Json<JwkSet> {
+    Json(JwkSet {
+        keys: state.jwt_keys.jwks.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSA_PRIVATE: &str = include_str!("../../testdata/jwt/rsa.pem");
+    const RSA_PUBLIC: &str = include_str!("../../testdata/jwt/rsa.pub.pem");
+    const ED25519_A_PRIVATE: &str = include_str!("../../testdata/jwt/ed25519-a.pem");
+    const ED25519_A_PUBLIC: &str = include_str!("../../testdata/jwt/ed25519-a.pub.pem");
+    const ED25519_B_PRIVATE: &str = include_str!("../../testdata/jwt/ed25519-b.pem");
+    const ED25519_B_PUBLIC: &str = include_str!("../../testdata/jwt/ed25519-b.pub.pem");
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Claims {
+        sub: String,
+        exp: usize,
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            sub: "alice".to_string(),
+            exp: usize::MAX,
+        }
+    }
+
+    /// Keys signing with `kid` and accepting every test public key.
+    fn keys(kid: &str, signing_pem: &str) -> Result<JwtKeys> {
+        let public_keys = [
+            ("rsa", RSA_PUBLIC),
+            ("a", ED25519_A_PUBLIC),
+            ("b", ED25519_B_PUBLIC),
+        ]
+        .into_iter()
+        .map(|(kid, pem)| parse_public_key(kid, pem).unwrap())
+        .collect();
+        JwtKeys::asymmetric(kid.to_string(), signing_pem.as_bytes(), public_keys)
+    }
+
+    #[test]
+    fn tokens_survive_key_rotation() {
+        let old = keys("a", ED25519_A_PRIVATE).unwrap();
+        let new = keys("b", ED25519_B_PRIVATE).unwrap();
+
+        let token = old.sign(&claims()).unwrap();
+        assert_eq!(decode_header(&token).unwrap().kid.as_deref(), Some("a"));
+        assert_eq!(new.verify::<Claims>(&token).unwrap(), claims());
+        let token = new.sign(&claims()).unwrap();
+        assert_eq!(old.verify::<Claims>(&token).unwrap(), claims());
+
+        let rsa = keys("rsa", RSA_PRIVATE).unwrap();
+        let token = rsa.sign(&claims()).unwrap();
+        assert_eq!(decode_header(&token).unwrap().alg, Algorithm::RS256);
+        assert_eq!(new.verify::<Claims>(&token).unwrap(), claims());
+    }
+
+    #[test]
+    fn rejects_unknown_kids() {
+        let keys = keys("a", ED25519_A_PRIVATE).unwrap();
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some("retired".to_string());
+        let signing = EncodingKey::from_ed_pem(ED25519_A_PRIVATE.as_bytes()).unwrap();
+        let token = encode(&header, &claims(), &signing).unwrap();
+
+        let err = keys.verify::<Claims>(&token).unwrap_err();
+        assert!(err.to_string().contains("unknown key id retired"), "{err}");
+    }
+
+    #[test]
+    fn rejects_algorithms_other_than_the_keys() {
+        let keys = keys("a", ED25519_A_PRIVATE).unwrap();
+
+        // 用公钥当作 HS256 密钥签名的经典混淆攻击
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("a".to_string());
+        let token = encode(
+            &header,
+            &claims(),
+            &EncodingKey::from_secret(ED25519_A_PUBLIC.as_bytes()),
+        )
+        .unwrap();
+        let err = keys.verify::<Claims>(&token).unwrap_err();
+        assert!(err.to_string().contains("does not match key a"), "{err}");
+
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("a".to_string());
+        let signing = EncodingKey::from_rsa_pem(RSA_PRIVATE.as_bytes()).unwrap();
+        let token = encode(&header, &claims(), &signing).unwrap();
+        assert!(keys.verify::<Claims>(&token).is_err());
+    }
+
+    #[test]
+    fn refuses_a_signing_key_that_does_not_match() {
+        let err = keys("a", ED25519_B_PRIVATE).err().unwrap();
+        assert!(
+            format!("{err:#}").contains("does not match its public key"),
+            "{err:#}"
+        );
+        assert!(keys("missing", ED25519_A_PRIVATE).is_err());
+    }
+
+    #[test]
+    fn publishes_jwks() {
+        let keys = keys("a", ED25519_A_PRIVATE).unwrap();
+        let jwks = serde_json::to_value(&keys.jwks).unwrap();
+
+        assert_eq!(
+            jwks[0],
+            serde_json::json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": "rsa",
+                "n": "nJ8zcHOyNYDHQH-EepvG7X_8g7dKq16Ehi2ESPMbKnn45nwuR4oOHf04mq8hTZtXqixp9m5ksGTfZkD7v9G3Ql5FXqz2paQl2bB-kQ09Bbxv9R8HJANblJ793Vbs8YrPlrqlZja9Je_-4xoyhB7Ppsa-mZuGzeE3UWgdRNXpPczv5UW-SdKlp-4TOuphIjWmVRH8Uu3doP7Cs5UPJ_KyiTHfalHpYa9v1vUBytlK8fKgn8fXlTMwI60wWz4SpndT-gBBsgTZMcSvJsuVjsNSl2pFEXbmU54msrtLObrTRnRhkvDP5Bbr5QsbIFs4Nxuw9lFR1GX2_ezFCc5MMjffAQ",
+                "e": "AQAB",
+            })
+        );
+        assert_eq!(
+            jwks[1],
+            serde_json::json!({
+                "kty": "OKP",
+                "use": "sig",
+                "alg": "EdDSA",
+                "kid": "a",
+                "crv": "Ed25519",
+                "x": "OT351Dmd-Aj3C33-bdTrTj9W4c1IM5u8ZDZrzb2QWmo",
+            })
+        );
+        assert!(JwtKeys::hmac("default".to_string(), "secret")
+            .jwks
+            .is_empty());
+    }
+
+    #[test]
+    fn refuses_the_development_secret_outside_dev() {
+        for configured in [None, Some(String::new()), Some(DEV_SECRET.to_string())] {
+            assert!(
+                hmac_secret(configured.clone(), false).is_err(),
+                "{configured:?}"
+            );
+            assert_eq!(hmac_secret(configured, true).unwrap(), DEV_SECRET);
+        }
+        assert_eq!(
+            hmac_secret(Some("a real secret".to_string()), false).unwrap(),
+            "a real secret"
+        );
+    }
+
+    #[test]
+    fn hmac_keys_round_trip() {
+        let keys = JwtKeys::hmac("default".to_string(), "secret");
+        let token = keys.sign(&claims()).unwrap();
+        assert_eq!(keys.verify::<Claims>(&token).unwrap(), claims());
+        assert!(JwtKeys::hmac("default".to_string(), "other")
+            .verify::<Claims>(&token)
+            .is_err());
+    }
+}
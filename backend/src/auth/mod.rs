@@ -0,0 +1,226 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+};
+use chrono::{Duration, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::{
+    api_error, audit,
+    bots::{self, BotCaller, Scope},
+    db, internal_error, ApiError, AppState,
+};
+
+pub mod keys;
+pub mod oidc;
+pub mod password;
+pub mod session;
+pub mod two_factor;
+pub mod verification;
+
+pub const MIN_PASSWORD_LEN: usize = 6;
+
+/// Access tokens are short-lived; clients renew them with a refresh token.
+const ACCESS_TOKEN_TTL_MINUTES: i64 = 15;
+
+/// How stale a session's `last_used_at` may get before a request refreshes it.
+const SESSION_TOUCH_INTERVAL_MINUTES: i64 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String, // user id
+    pub username: String,
+    pub sid: String, // session id
+    pub exp: usize,
+}
+
+// 为用户签发JWT token
+pub fn issue_token(
+    state: &AppState,
+    user: &db::User,
+    session_id: &str,
+) -> Result<String, ApiError> {
+    let claims = Claims {
+        sub: user.id.clone(),
+        username: user.username.clone(),
+        sid: session_id.to_string(),
+        exp: (Utc::now() + Duration::minutes(ACCESS_TOKEN_TTL_MINUTES)).timestamp() as usize,
+    };
+
+    state.jwt_keys.sign(&claims).map_err(|_| {
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to generate token",
+        )
+    })
+}
+
+pub fn decode_token(state: &AppState, token: &str) -> Result<Claims, ApiError> {
+    state
+        .jwt_keys
+        .verify::<Claims>(token)
+        .map_err(|_| api_error(StatusCode::UNAUTHORIZED, "Invalid token"))
+}
+
+/// Decodes an access token and checks that the session it was issued for
+/// has not been signed out or revoked since.
+pub async fn authenticate(state: &AppState, token: &str) -> Result<Claims, ApiError> {
+    let claims = decode_token(state, token)?;
+
+    let session = state
+        .db
+        .get_session(&claims.sid)
+        .await
+        .map_err(internal_error)?
+        .filter(|session| session.user_id == claims.sub && session.is_active())
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "Session has ended"))?;
+
+    // 记录会话最近活动时间，避免每个请求都写库
+    if Utc::now() - session.last_used_at > Duration::minutes(SESSION_TOUCH_INTERVAL_MINUTES) {
+        state
+            .db
+            .touch_session(&session.id)
+            .await
+            .map_err(internal_error)?;
+    }
+
+    Ok(claims)
+}
+
+/// Generates a random, URL-safe secret for single-use links and similar
+/// bearer credentials. Only its [`hash_secret`] digest should be stored.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Records a successful sign-in in the audit log.
+pub async fn record_login(
+    state: &AppState,
+    user: &db::User,
+    method: &str,
+    ip_address: Option<&str>,
+) {
+    audit::record(
+        state,
+        audit::Event {
+            action: "auth.login",
+            actor_id: Some(&user.id),
+            target: Some(("user", &user.id)),
+            ip_address,
+            details: serde_json::json!({ "method": method }),
+        },
+    )
+    .await;
+}
+
+/// The caller of an authenticated route, taken from an
+/// `Authorization: Bearer <token>` header.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: String,
+    pub session_id: String,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let token = bearer_token(parts)?;
+        if token.starts_with(bots::API_TOKEN_PREFIX) {
+            return Err(api_error(
+                StatusCode::FORBIDDEN,
+                "API tokens cannot be used for this endpoint",
+            ));
+        }
+
+        let claims = authenticate(state, token).await?;
+
+        Ok(AuthUser {
+            id: claims.sub,
+            session_id: claims.sid,
+        })
+    }
+}
+
+/// The caller of a route that bots may use too: either a signed-in user or
+/// a bot presenting an API token.
+#[derive(Debug, Clone)]
+pub enum Caller {
+    User(AuthUser),
+    Bot(BotCaller),
+}
+
+impl Caller {
+    pub fn id(&self) -> &str {
+        match self {
+            Caller::User(user) => &user.id,
+            Caller::Bot(bot) => &bot.id,
+        }
+    }
+
+    /// The user whose permissions apply: the caller, or a bot's owner.
+    pub fn acting_user_id(&self) -> &str {
+        match self {
+            Caller::User(user) => &user.id,
+            Caller::Bot(bot) => &bot.owner_id,
+        }
+    }
+
+    /// Users may do anything their account can; bots need the scope on the
+    /// token they used.
+    pub fn require(&self, scope: &Scope) -> Result<(), ApiError> {
+        match self {
+            Caller::Bot(bot) if !bot.allows(scope) => Err(api_error(
+                StatusCode::FORBIDDEN,
+                format!("API token lacks the {scope} scope"),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Caller {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let token = bearer_token(parts)?;
+        if token.starts_with(bots::API_TOKEN_PREFIX) {
+            return Ok(Caller::Bot(bots::authenticate_token(state, token).await?));
+        }
+
+        let claims = authenticate(state, token).await?;
+
+        Ok(Caller::User(AuthUser {
+            id: claims.sub,
+            session_id: claims.sid,
+        }))
+    }
+}
+
+fn bearer_token(parts: &Parts) -> Result<&str, ApiError> {
+    parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "Missing bearer token"))
+}
@@ -0,0 +1,309 @@
+use axum::{extract::State, http::StatusCode, Json};
+use bcrypt::{hash, verify, DEFAULT_COST};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::{
+    generate_secret, hash_secret,
+    session::{self, ClientInfo},
+    AuthUser, MIN_PASSWORD_LEN,
+};
+use crate::{
+    api_error, audit, db, internal_error, mail::OutgoingMail, ApiError, AppState, StatusResponse,
+};
+
+/// How long a password reset link stays valid.
+const RESET_TOKEN_TTL_MINUTES: i64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetRequest {
+    email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmResetRequest {
+    token: String,
+    new_password: String,
+}
+
+pub fn hash_password(password: &str) -> Result<String, ApiError> {
+    if password.len() < MIN_PASSWORD_LEN {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Password must be at least {MIN_PASSWORD_LEN} characters"),
+        ));
+    }
+
+    hash(password, DEFAULT_COST)
+        .map_err(|_| api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash password"))
+}
+
+// 修改密码
+pub async fn change_password_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Json(req): Json<ChangePasswordRequest>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let user = state
+        .db
+        .get_user_by_id(&auth.id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "User not found"))?;
+
+    if !verify(&req.current_password, &user.password_hash).unwrap_or(false) {
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            "Current password is incorrect",
+        ));
+    }
+
+    let password_hash = hash_password(&req.new_password)?;
+    state
+        .db
+        .update_user_password(&user.id, &password_hash)
+        .await
+        .map_err(internal_error)?;
+    state
+        .db
+        .delete_password_reset_tokens(&user.id)
+        .await
+        .map_err(internal_error)?;
+    // 其他设备需要重新登录
+    session::revoke_user_sessions(&state, &user.id, Some(&auth.session_id))
+        .await
+        .map_err(internal_error)?;
+    audit::record(
+        &state,
+        audit::Event::account("auth.password_change", &user.id, &client),
+    )
+    .await;
+
+    Ok(Json(StatusResponse::new("Password changed")))
+}
+
+// 申请重置密码
+//
+// Always answers the same way so the endpoint can't be used to find out
+// which addresses have accounts.
+pub async fn request_reset_handler(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(req): Json<ResetRequest>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let response = Json(StatusResponse::new(
+        "If an account exists for that email, a reset link has been sent",
+    ));
+
+    let Some(user) = state
+        .db
+        .get_user_by_email(req.email.trim())
+        .await
+        .map_err(internal_error)?
+    else {
+        return Ok(response);
+    };
+
+    send_reset_link(&state, &user)
+        .await
+        .map_err(internal_error)?;
+    audit::record(
+        &state,
+        audit::Event {
+            actor_id: None,
+            ..audit::Event::account("auth.password_reset_request", &user.id, &client)
+        },
+    )
+    .await;
+
+    Ok(response)
+}
+
+/// Emails a user a single-use link for choosing a new password. Only a
+/// failure to store the link is an error; mail failures are logged.
+pub async fn send_reset_link(state: &AppState, user: &db::User) -> anyhow::Result<()> {
+    let secret = generate_secret();
+    let now = Utc::now();
+    state
+        .db
+        .create_password_reset_token(&db::PasswordResetToken {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            token_hash: hash_secret(&secret),
+            created_at: now,
+            expires_at: now + Duration::minutes(RESET_TOKEN_TTL_MINUTES),
+            used_at: None,
+        })
+        .await?;
+
+    let link = format!(
+        "{}/reset-password?token={}",
+        state.public_url.trim_end_matches('/'),
+        secret
+    );
+    let mail = OutgoingMail {
+        to: user.email.clone(),
+        subject: "Reset your ChatX password".to_string(),
+        body: format!(
+            "Hi {},\n\nSomeone asked to reset the password for your ChatX account. \
+             Use the link below within {} minutes to choose a new one:\n\n{}\n\n\
+             If this wasn't you, you can ignore this email.\n",
+            user.username, RESET_TOKEN_TTL_MINUTES, link
+        ),
+    };
+    if let Err(err) = state.mailer.send(mail).await {
+        eprintln!("failed to send password reset mail: {err:#}");
+    }
+
+    Ok(())
+}
+
+// 使用重置链接设置新密码
+pub async fn confirm_reset_handler(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(req): Json<ConfirmResetRequest>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let password_hash = hash_password(&req.new_password)?;
+
+    let user_id = state
+        .db
+        .consume_password_reset_token(&hash_secret(req.token.trim()))
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| {
+            api_error(
+                StatusCode::BAD_REQUEST,
+                "Reset link is invalid or has expired",
+            )
+        })?;
+
+    state
+        .db
+        .update_user_password(&user_id, &password_hash)
+        .await
+        .map_err(internal_error)?;
+    state
+        .db
+        .delete_password_reset_tokens(&user_id)
+        .await
+        .map_err(internal_error)?;
+    session::revoke_user_sessions(&state, &user_id, None)
+        .await
+        .map_err(internal_error)?;
+    audit::record(
+        &state,
+        audit::Event::account("auth.password_reset", &user_id, &client),
+    )
+    .await;
+
+    Ok(Json(StatusResponse::new("Password has been reset")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mail::MemoryMailer, testing};
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            ip_address: None,
+            user_agent: None,
+        }
+    }
+
+    async fn request_reset(state: &Arc<AppState>, email: &str) {
+        let Json(response) = request_reset_handler(
+            State(state.clone()),
+            client(),
+            Json(ResetRequest {
+                email: email.to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        // 无论账号是否存在，回复都一样
+        assert_eq!(
+            response.message,
+            "If an account exists for that email, a reset link has been sent"
+        );
+    }
+
+    async fn confirm_reset(
+        state: &Arc<AppState>,
+        token: &str,
+        new_password: &str,
+    ) -> Result<Json<StatusResponse>, ApiError> {
+        confirm_reset_handler(
+            State(state.clone()),
+            client(),
+            Json(ConfirmResetRequest {
+                token: token.to_string(),
+                new_password: new_password.to_string(),
+            }),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn resets_password_with_the_mailed_link() {
+        let mailer = Arc::new(MemoryMailer::default());
+        let state = Arc::new(testing::state(mailer.clone()).await);
+        let user = testing::user(&state, "alice").await;
+
+        request_reset(&state, " alice@example.com ").await;
+
+        let sent = mailer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "alice@example.com");
+        let token = sent[0]
+            .body
+            .split_whitespace()
+            .find_map(|word| word.strip_prefix("http://chatx.test/reset-password?token="))
+            .expect("mail contains a reset link");
+
+        assert_eq!(
+            confirm_reset(&state, token, "short").await.unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+        let Json(response) = confirm_reset(&state, token, "new-password").await.unwrap();
+        assert_eq!(response.message, "Password has been reset");
+        let hash = state
+            .db
+            .get_user_by_id(&user.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .password_hash;
+        assert!(verify("new-password", &hash).unwrap());
+
+        // 链接只能使用一次
+        assert_eq!(
+            confirm_reset(&state, token, "another-password")
+                .await
+                .unwrap_err()
+                .0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_addresses_get_no_mail() {
+        let mailer = Arc::new(MemoryMailer::default());
+        let state = Arc::new(testing::state(mailer.clone()).await);
+        testing::user(&state, "alice").await;
+
+        request_reset(&state, "nobody@example.com").await;
+
+        assert!(mailer.sent().is_empty());
+    }
+}
@@ -0,0 +1,319 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Path, State},
+    http::{header::USER_AGENT, request::Parts, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use super::{generate_secret, hash_secret, issue_token, AuthUser};
+use crate::{api_error, audit, db, internal_error, ApiError, AppState, StatusResponse};
+
+/// How long a session stays alive without its refresh token being used.
+const REFRESH_TOKEN_TTL_DAYS: i64 = 30;
+const MAX_DEVICE_LABEL_LEN: usize = 64;
+const MAX_USER_AGENT_LEN: usize = 512;
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// An access token together with the refresh token that renews it. Each
+/// refresh token works once; using it hands out a replacement.
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    token: String,
+    refresh_token: String,
+}
+
+/// A signed-in device as shown in `GET /api/me/sessions`.
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    id: String,
+    device_label: Option<String>,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+    /// Whether this is the session making the request.
+    current: bool,
+}
+
+/// Where a request came from, recorded on the session it opens.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(ClientInfo {
+            ip_address: parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string()),
+            user_agent: parts
+                .headers
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.chars().take(MAX_USER_AGENT_LEN).collect()),
+        })
+    }
+}
+
+/// Opens a new session for a user who has just signed in. Without a
+/// client-supplied `device_label` one is guessed from the user agent.
+pub async fn start_session(
+    state: &AppState,
+    user: &db::User,
+    client: ClientInfo,
+    device_label: Option<String>,
+) -> Result<TokenResponse, ApiError> {
+    if user.disabled_at.is_some() {
+        audit::record(
+            state,
+            audit::Event {
+                target: None,
+                details: serde_json::json!({ "username": user.username, "reason": "disabled" }),
+                ..audit::Event::account("auth.login_failed", &user.id, &client)
+            },
+        )
+        .await;
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            "This account has been disabled",
+        ));
+    }
+
+    let device_label = device_label
+        .map(|label| {
+            label
+                .trim()
+                .chars()
+                .take(MAX_DEVICE_LABEL_LEN)
+                .collect::<String>()
+        })
+        .filter(|label| !label.is_empty())
+        .or_else(|| client.user_agent.as_deref().and_then(describe_user_agent));
+
+    let refresh_token = generate_secret();
+    let now = Utc::now();
+    let session = db::Session {
+        id: Uuid::new_v4().to_string(),
+        user_id: user.id.clone(),
+        refresh_token_hash: hash_secret(&refresh_token),
+        created_at: now,
+        last_used_at: now,
+        expires_at: now + Duration::days(REFRESH_TOKEN_TTL_DAYS),
+        revoked_at: None,
+        device_label,
+        ip_address: client.ip_address,
+        user_agent: client.user_agent,
+    };
+    state
+        .db
+        .create_session(&session)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(TokenResponse {
+        token: issue_token(state, user, &session.id)?,
+        refresh_token,
+    })
+}
+
+// 刷新access token
+pub async fn refresh_handler(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<RefreshRequest>,
+) -> Result<Json<TokenResponse>, ApiError> {
+    let refresh_token = generate_secret();
+    let session = state
+        .db
+        .rotate_refresh_token(
+            &hash_secret(req.refresh_token.trim()),
+            &hash_secret(&refresh_token),
+            Utc::now() + Duration::days(REFRESH_TOKEN_TTL_DAYS),
+        )
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| {
+            api_error(
+                StatusCode::UNAUTHORIZED,
+                "Refresh token is invalid or has expired",
+            )
+        })?;
+
+    let user = state
+        .db
+        .get_user_by_id(&session.user_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "User not found"))?;
+
+    Ok(Json(TokenResponse {
+        token: issue_token(&state, &user, &session.id)?,
+        refresh_token,
+    }))
+}
+
+// 退出登录
+pub async fn logout_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+) -> Result<Json<StatusResponse>, ApiError> {
+    state
+        .db
+        .revoke_session(&auth.session_id)
+        .await
+        .map_err(internal_error)?;
+    disconnect_session(&state, &auth.session_id);
+    audit::record(
+        &state,
+        audit::Event::account("auth.logout", &auth.id, &client),
+    )
+    .await;
+
+    Ok(Json(StatusResponse::new("Signed out")))
+}
+
+// 列出当前用户的登录会话
+pub async fn list_sessions_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<Vec<SessionResponse>>, ApiError> {
+    let sessions = state
+        .db
+        .get_user_sessions(&auth.id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| SessionResponse {
+                current: session.id == auth.session_id,
+                id: session.id,
+                device_label: session.device_label,
+                ip_address: session.ip_address,
+                user_agent: session.user_agent,
+                created_at: session.created_at,
+                last_used_at: session.last_used_at,
+            })
+            .collect(),
+    ))
+}
+
+// 注销指定设备的会话
+pub async fn delete_session_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path(session_id): Path<String>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    state
+        .db
+        .get_session(&session_id)
+        .await
+        .map_err(internal_error)?
+        .filter(|session| session.user_id == auth.id && session.is_active())
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Session not found"))?;
+
+    state
+        .db
+        .revoke_session(&session_id)
+        .await
+        .map_err(internal_error)?;
+    disconnect_session(&state, &session_id);
+    audit::record(
+        &state,
+        audit::Event {
+            target: Some(("session", &session_id)),
+            ..audit::Event::account("auth.session_revoke", &auth.id, &client)
+        },
+    )
+    .await;
+
+    Ok(Json(StatusResponse::new("Session ended")))
+}
+
+/// Ends every session of a user except `keep`, closing their WebSockets.
+pub async fn revoke_user_sessions(
+    state: &AppState,
+    user_id: &str,
+    keep: Option<&str>,
+) -> anyhow::Result<()> {
+    for session_id in state.db.revoke_user_sessions(user_id, keep).await? {
+        disconnect_session(state, &session_id);
+    }
+    Ok(())
+}
+
+/// Subscribes a WebSocket connection to be told when its session ends.
+pub fn watch_session(state: &AppState, session_id: &str) -> broadcast::Receiver<()> {
+    state
+        .session_sockets
+        .entry(session_id.to_string())
+        .or_insert_with(|| broadcast::channel(1).0)
+        .subscribe()
+}
+
+/// Drops the session's entry once its last connection has gone away.
+pub fn unwatch_session(state: &AppState, session_id: &str) {
+    state
+        .session_sockets
+        .remove_if(session_id, |_, tx| tx.receiver_count() == 0);
+}
+
+/// Closes the WebSockets watching `session_id`.
+pub fn disconnect_session(state: &AppState, session_id: &str) {
+    if let Some((_, tx)) = state.session_sockets.remove(session_id) {
+        let _ = tx.send(());
+    }
+}
+
+/// Builds a rough "Browser on OS" label from a User-Agent header.
+fn describe_user_agent(user_agent: &str) -> Option<String> {
+    // Order matters: Edge and Opera also claim to be Chrome, Chrome claims Safari.
+    const BROWSERS: [(&str, &str); 5] = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+    ];
+    const SYSTEMS: [(&str, &str); 6] = [
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("Windows", "Windows"),
+        ("Mac OS X", "macOS"),
+        ("Linux", "Linux"),
+    ];
+
+    let find = |names: &[(&str, &'static str)]| {
+        names
+            .iter()
+            .find(|(marker, _)| user_agent.contains(marker))
+            .map(|(_, name)| *name)
+    };
+
+    match (find(&BROWSERS), find(&SYSTEMS)) {
+        (Some(browser), Some(os)) => Some(format!("{browser} on {os}")),
+        (Some(name), None) | (None, Some(name)) => Some(name.to_string()),
+        (None, None) => None,
+    }
+}
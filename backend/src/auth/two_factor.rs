@@ -0,0 +1,518 @@
+use axum::{extract::State, http::StatusCode, Json};
+use bcrypt::verify;
+use chrono::{Duration, Utc};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::{
+    generate_secret, hash_secret,
+    session::{self, ClientInfo},
+    AuthUser,
+};
+use crate::{
+    api_error, audit, db, internal_error, ApiError, AppState, AuthResponse, StatusResponse,
+    UserResponse,
+};
+
+/// Name shown next to the account in authenticator apps.
+const TOTP_ISSUER: &str = "ChatX";
+/// RFC 6238 defaults, which is what authenticator apps assume.
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Accept codes from one step either side to allow for clock drift.
+const TOTP_SKEW_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// How long the second login step may take.
+const CHALLENGE_TTL_MINUTES: i64 = 5;
+const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+
+#[derive(Debug, Deserialize)]
+pub struct EnableTotpRequest {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableTotpRequest {
+    current_password: String,
+    /// A current TOTP code or an unused recovery code.
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyLoginRequest {
+    challenge_token: String,
+    /// A current TOTP code or an unused recovery code.
+    code: String,
+    device_label: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpSetupResponse {
+    secret: String,
+    /// `otpauth://` URI to render as a QR code for authenticator apps.
+    otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>,
+}
+
+/// Starts the second login step for a user with 2FA enabled and returns the
+/// challenge token to present alongside their code.
+pub async fn create_login_challenge(state: &AppState, user: &db::User) -> Result<String, ApiError> {
+    let token = generate_secret();
+    let now = Utc::now();
+    state
+        .db
+        .create_login_challenge(&db::LoginChallenge {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            token_hash: hash_secret(&token),
+            created_at: now,
+            expires_at: now + Duration::minutes(CHALLENGE_TTL_MINUTES),
+            attempts: 0,
+        })
+        .await
+        .map_err(internal_error)?;
+
+    Ok(token)
+}
+
+// 开始绑定两步验证
+pub async fn setup_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<TotpSetupResponse>, ApiError> {
+    let user = current_user(&state, &auth).await?;
+    if user.totp_enabled {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            "Two-factor authentication is already enabled",
+        ));
+    }
+
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = BASE32_NOPAD.encode(&bytes);
+    state
+        .db
+        .set_totp_secret(&user.id, Some(&secret))
+        .await
+        .map_err(internal_error)?;
+
+    let otpauth_uri = format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP_SECONDS}",
+        issuer = urlencoding::encode(TOTP_ISSUER),
+        account = urlencoding::encode(&user.username),
+    );
+
+    Ok(Json(TotpSetupResponse {
+        secret,
+        otpauth_uri,
+    }))
+}
+
+// 确认绑定两步验证
+pub async fn enable_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Json(req): Json<EnableTotpRequest>,
+) -> Result<Json<RecoveryCodesResponse>, ApiError> {
+    let user = current_user(&state, &auth).await?;
+    if user.totp_enabled {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            "Two-factor authentication is already enabled",
+        ));
+    }
+    if user.totp_secret.is_none() {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Start two-factor setup first",
+        ));
+    }
+
+    if !check_totp(&state, &user, &req.code).await? {
+        return Err(api_error(StatusCode::BAD_REQUEST, "Invalid code"));
+    }
+
+    let recovery_codes = generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| hash_secret(&normalize_recovery_code(code)))
+        .collect();
+    state
+        .db
+        .replace_recovery_codes(&user.id, &hashes)
+        .await
+        .map_err(internal_error)?;
+    state
+        .db
+        .enable_totp(&user.id)
+        .await
+        .map_err(internal_error)?;
+    audit::record(
+        &state,
+        audit::Event::account("auth.2fa_enable", &user.id, &client),
+    )
+    .await;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+// 关闭两步验证
+pub async fn disable_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Json(req): Json<DisableTotpRequest>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let user = current_user(&state, &auth).await?;
+    if !user.totp_enabled {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Two-factor authentication is not enabled",
+        ));
+    }
+
+    if !verify(&req.current_password, &user.password_hash).unwrap_or(false) {
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            "Current password is incorrect",
+        ));
+    }
+    if !check_second_factor(&state, &user, &req.code).await? {
+        return Err(api_error(StatusCode::FORBIDDEN, "Invalid code"));
+    }
+
+    state
+        .db
+        .set_totp_secret(&user.id, None)
+        .await
+        .map_err(internal_error)?;
+    state
+        .db
+        .delete_recovery_codes(&user.id)
+        .await
+        .map_err(internal_error)?;
+    audit::record(
+        &state,
+        audit::Event::account("auth.2fa_disable", &user.id, &client),
+    )
+    .await;
+
+    Ok(Json(StatusResponse::new(
+        "Two-factor authentication disabled",
+    )))
+}
+
+// 登录第二步：校验验证码
+pub async fn verify_login_handler(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    Json(req): Json<VerifyLoginRequest>,
+) -> Result<Json<AuthResponse>, ApiError> {
+    let invalid = || {
+        api_error(
+            StatusCode::UNAUTHORIZED,
+            "Login challenge is invalid or has expired",
+        )
+    };
+
+    let challenge = state
+        .db
+        .attempt_login_challenge(
+            &hash_secret(req.challenge_token.trim()),
+            MAX_CHALLENGE_ATTEMPTS,
+        )
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(invalid)?;
+
+    let user = state
+        .db
+        .get_user_by_id(&challenge.user_id)
+        .await
+        .map_err(internal_error)?
+        .filter(|user| user.totp_enabled)
+        .ok_or_else(invalid)?;
+
+    if !check_second_factor(&state, &user, &req.code).await? {
+        state.login_lockout.record_failure(&user.username);
+        audit::record(
+            &state,
+            audit::Event {
+                target: None,
+                details: serde_json::json!({ "username": user.username, "reason": "invalid_code" }),
+                ..audit::Event::account("auth.login_failed", &user.id, &client)
+            },
+        )
+        .await;
+        return Err(api_error(StatusCode::UNAUTHORIZED, "Invalid code"));
+    }
+    state.login_lockout.record_success(&user.username);
+
+    state
+        .db
+        .delete_login_challenge(&challenge.id)
+        .await
+        .map_err(internal_error)?;
+
+    let ip_address = client.ip_address.clone();
+    let tokens = session::start_session(&state, &user, client, req.device_label).await?;
+    super::record_login(&state, &user, "two_factor", ip_address.as_deref()).await;
+
+    Ok(Json(AuthResponse {
+        tokens,
+        user: UserResponse::private(user),
+    }))
+}
+
+async fn current_user(state: &AppState, auth: &AuthUser) -> Result<db::User, ApiError> {
+    state
+        .db
+        .get_user_by_id(&auth.id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "User not found"))
+}
+
+/// Accepts either a TOTP code or one of the user's unused recovery codes.
+async fn check_second_factor(
+    state: &AppState,
+    user: &db::User,
+    code: &str,
+) -> Result<bool, ApiError> {
+    let code = code.trim();
+    if code.len() == TOTP_DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit()) {
+        return check_totp(state, user, code).await;
+    }
+
+    state
+        .db
+        .consume_recovery_code(&user.id, &hash_secret(&normalize_recovery_code(code)))
+        .await
+        .map_err(internal_error)
+}
+
+/// Checks a TOTP code against the user's secret, refusing codes from a time
+/// step that has already been used.
+async fn check_totp(state: &AppState, user: &db::User, code: &str) -> Result<bool, ApiError> {
+    let Some(secret) = user
+        .totp_secret
+        .as_deref()
+        .and_then(|secret| BASE32_NOPAD.decode(secret.as_bytes()).ok())
+    else {
+        return Ok(false);
+    };
+    let Ok(code) = code.trim().parse::<u32>() else {
+        return Ok(false);
+    };
+
+    let current = Utc::now().timestamp() / TOTP_STEP_SECONDS;
+    let Some(step) = (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS)
+        .find(|&step| totp_code(&secret, step as u64) == code)
+    else {
+        return Ok(false);
+    };
+
+    state
+        .db
+        .record_totp_step(&user.id, step)
+        .await
+        .map_err(internal_error)
+}
+
+/// The RFC 4226 HOTP value for a counter, which TOTP uses with the time step.
+fn totp_code(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    value % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Recovery codes are shown as `xxxxx-xxxxx`; only their hashes are stored.
+fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 5];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let code = hex::encode(bytes);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mail::MemoryMailer, testing};
+
+    /// The SHA-1 key of the RFC 6238 test vectors.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        // RFC 6238 附录 B，取后 6 位
+        for (time, expected) in [
+            (59, 287_082),
+            (1_111_111_109, 81_804),
+            (1_111_111_111, 50_471),
+            (1_234_567_890, 5_924),
+            (2_000_000_000, 279_037),
+            (20_000_000_000, 353_130),
+        ] {
+            assert_eq!(
+                totp_code(RFC_SECRET, time / TOTP_STEP_SECONDS as u64),
+                expected,
+                "{time}"
+            );
+        }
+    }
+
+    #[test]
+    fn recovery_codes_are_distinct_and_normalized() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for code in &codes {
+            assert_eq!(code.len(), 11);
+            assert_eq!(&code[5..6], "-");
+        }
+        let unique: std::collections::HashSet<_> = codes.iter().collect();
+        assert_eq!(unique.len(), codes.len());
+        assert_eq!(normalize_recovery_code(" AB12c-DE34f "), "ab12cde34f");
+    }
+
+    /// A user with a TOTP secret, reloaded so it carries the secret.
+    async fn totp_user(state: &AppState, username: &str) -> db::User {
+        let user = testing::user(state, username).await;
+        state
+            .db
+            .set_totp_secret(&user.id, Some(&BASE32_NOPAD.encode(RFC_SECRET)))
+            .await
+            .unwrap();
+        state.db.get_user_by_id(&user.id).await.unwrap().unwrap()
+    }
+
+    fn code_at(offset: i64) -> String {
+        let step = Utc::now().timestamp() / TOTP_STEP_SECONDS + offset;
+        format!("{:06}", totp_code(RFC_SECRET, step as u64))
+    }
+
+    #[tokio::test]
+    async fn accepts_one_step_of_skew() {
+        let state = testing::state(Arc::new(MemoryMailer::default())).await;
+        for (username, offset, accepted) in [
+            ("early", -1, true),
+            ("late", 1, true),
+            ("too_early", -2, false),
+            ("too_late", 2, false),
+        ] {
+            let user = totp_user(&state, username).await;
+            assert_eq!(
+                check_totp(&state, &user, &code_at(offset)).await.unwrap(),
+                accepted,
+                "{offset}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_used_steps() {
+        let state = testing::state(Arc::new(MemoryMailer::default())).await;
+        let user = totp_user(&state, "alice").await;
+
+        assert!(check_totp(&state, &user, &code_at(0)).await.unwrap());
+        assert!(!check_totp(&state, &user, &code_at(0)).await.unwrap());
+        // 更早的时间步也不能再用
+        assert!(!check_totp(&state, &user, &code_at(-1)).await.unwrap());
+        assert!(!check_totp(&state, &user, "not a code").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_work_once() {
+        let state = Arc::new(testing::state(Arc::new(MemoryMailer::default())).await);
+        let user = totp_user(&state, "alice").await;
+        let Json(response) = enable_handler(
+            State(state.clone()),
+            AuthUser {
+                id: user.id.clone(),
+                session_id: Uuid::new_v4().to_string(),
+            },
+            ClientInfo {
+                ip_address: None,
+                user_agent: None,
+            },
+            Json(EnableTotpRequest { code: code_at(0) }),
+        )
+        .await
+        .unwrap();
+        let code = response.recovery_codes[0].to_uppercase();
+
+        assert!(check_second_factor(&state, &user, &code).await.unwrap());
+        assert!(!check_second_factor(&state, &user, &code).await.unwrap());
+        assert!(
+            check_second_factor(&state, &user, &response.recovery_codes[1])
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn challenge_locks_after_too_many_attempts() {
+        let state = Arc::new(testing::state(Arc::new(MemoryMailer::default())).await);
+        let user = totp_user(&state, "alice").await;
+        state.db.enable_totp(&user.id).await.unwrap();
+        let token = create_login_challenge(&state, &user).await.unwrap();
+        let verify = |code: String| {
+            verify_login_handler(
+                State(state.clone()),
+                ClientInfo {
+                    ip_address: None,
+                    user_agent: None,
+                },
+                Json(VerifyLoginRequest {
+                    challenge_token: token.clone(),
+                    code,
+                    device_label: None,
+                }),
+            )
+        };
+
+        let valid = [code_at(-1), code_at(0), code_at(1)];
+        let wrong = (0..)
+            .map(|n| format!("{n:06}"))
+            .find(|code| !valid.contains(code))
+            .unwrap();
+
+        for _ in 0..MAX_CHALLENGE_ATTEMPTS {
+            let err = verify(wrong.clone()).await.err().unwrap();
+            assert_eq!(err.1.error, "Invalid code");
+        }
+        let err = verify(code_at(0)).await.err().unwrap();
+        assert_eq!(err.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(err.1.error, "Login challenge is invalid or has expired");
+    }
+}
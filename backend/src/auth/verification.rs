@@ -0,0 +1,116 @@
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::{Duration, Utc};
+use email_address::{EmailAddress, Options};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::{generate_secret, hash_secret, AuthUser};
+use crate::{
+    api_error, db, internal_error, mail::OutgoingMail, ApiError, AppState, StatusResponse,
+};
+
+/// How long an email verification link stays valid.
+const VERIFICATION_TOKEN_TTL_HOURS: i64 = 48;
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    token: String,
+}
+
+/// Checks that `email` is a plain `local@domain.tld` address, without a
+/// display name or an IP literal domain.
+pub fn is_valid_email(email: &str) -> bool {
+    EmailAddress::parse_with_options(
+        email,
+        Options::default()
+            .with_required_tld()
+            .without_display_text()
+            .without_domain_literal(),
+    )
+    .is_ok()
+}
+
+/// Issues a fresh verification token for the user's current address and
+/// mails them the confirmation link.
+pub async fn send_verification_email(state: &AppState, user: &db::User) -> anyhow::Result<()> {
+    let secret = generate_secret();
+    let now = Utc::now();
+    state
+        .db
+        .create_email_verification_token(&db::EmailVerificationToken {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.id.clone(),
+            email: user.email.clone(),
+            token_hash: hash_secret(&secret),
+            created_at: now,
+            expires_at: now + Duration::hours(VERIFICATION_TOKEN_TTL_HOURS),
+            used_at: None,
+        })
+        .await?;
+
+    let link = format!(
+        "{}/verify-email?token={}",
+        state.public_url.trim_end_matches('/'),
+        secret
+    );
+    state
+        .mailer
+        .send(OutgoingMail {
+            to: user.email.clone(),
+            subject: "Confirm your ChatX email address".to_string(),
+            body: format!(
+                "Hi {},\n\nPlease confirm that {} is your email address by opening \
+                 the link below within {} hours:\n\n{}\n\n\
+                 If you didn't create a ChatX account, you can ignore this email.\n",
+                user.username, user.email, VERIFICATION_TOKEN_TTL_HOURS, link
+            ),
+        })
+        .await
+}
+
+// 验证邮箱
+pub async fn verify_email_handler(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<VerifyEmailRequest>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    state
+        .db
+        .consume_email_verification_token(&hash_secret(req.token.trim()))
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| {
+            api_error(
+                StatusCode::BAD_REQUEST,
+                "Verification link is invalid or has expired",
+            )
+        })?;
+
+    Ok(Json(StatusResponse::new("Email address verified")))
+}
+
+// 重新发送验证邮件
+pub async fn resend_verification_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let user = state
+        .db
+        .get_user_by_id(&auth.id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "User not found"))?;
+
+    if user.email_verified {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            "Email address is already verified",
+        ));
+    }
+
+    send_verification_email(&state, &user)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(StatusResponse::new("Verification email sent")))
+}
@@ -0,0 +1,587 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{fmt, str::FromStr, sync::Arc};
+use uuid::Uuid;
+
+use crate::{
+    api_error, audit,
+    auth::{
+        generate_secret, hash_secret,
+        session::{self, ClientInfo},
+        AuthUser,
+    },
+    db, internal_error, ApiError, AppState, StatusResponse, UserResponse,
+};
+
+/// Marks a bearer token as an API token rather than a session JWT.
+pub const API_TOKEN_PREFIX: &str = "chx_";
+const MAX_TOKEN_NAME_LEN: usize = 64;
+const MAX_TOKENS_PER_BOT: usize = 20;
+/// How stale a token's `last_used_at` may get before a request refreshes it.
+const TOKEN_TOUCH_INTERVAL_MINUTES: i64 = 1;
+
+/// What an API token may do. Written as `rooms:read`, `rooms:post:<room>`,
+/// `webhooks:manage` and `commands:manage`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Scope {
+    /// Join rooms and receive their messages.
+    ReadRooms,
+    /// Post messages to one room.
+    PostRoom(String),
+    ManageWebhooks,
+    /// Register and remove the bot's own slash commands.
+    ManageCommands,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "rooms:read" => Ok(Self::ReadRooms),
+            "webhooks:manage" => Ok(Self::ManageWebhooks),
+            "commands:manage" => Ok(Self::ManageCommands),
+            _ => match value.strip_prefix("rooms:post:") {
+                Some(room) if !room.is_empty() => Ok(Self::PostRoom(room.to_string())),
+                _ => Err(format!("Unknown scope {value:?}")),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for Scope {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReadRooms => f.write_str("rooms:read"),
+            Self::PostRoom(room) => write!(f, "rooms:post:{room}"),
+            Self::ManageWebhooks => f.write_str("webhooks:manage"),
+            Self::ManageCommands => f.write_str("commands:manage"),
+        }
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> Self {
+        scope.to_string()
+    }
+}
+
+/// A bot authenticated with one of its API tokens.
+#[derive(Debug, Clone)]
+pub struct BotCaller {
+    pub id: String,
+    pub owner_id: String,
+    pub username: String,
+    pub token_id: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl BotCaller {
+    pub fn allows(&self, scope: &Scope) -> bool {
+        self.scopes.contains(scope)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateBotRequest {
+    username: String,
+    display_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateTokenRequest {
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+/// An API token as listed to the bot's owner; the secret is never shown again.
+#[derive(Debug, Serialize)]
+pub struct ApiTokenResponse {
+    id: String,
+    name: String,
+    scopes: Vec<Scope>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedTokenResponse {
+    #[serde(flatten)]
+    info: ApiTokenResponse,
+    /// The bearer token; only returned when it is created.
+    token: String,
+}
+
+impl ApiTokenResponse {
+    fn from_row(token: db::ApiToken) -> Self {
+        Self {
+            scopes: parse_scopes(&token.scopes),
+            id: token.id,
+            name: token.name,
+            created_at: token.created_at,
+            last_used_at: token.last_used_at,
+        }
+    }
+}
+
+/// Whether any of a bot's unrevoked tokens grants `scope`; checked for
+/// what the bot does without presenting a token, such as answering its
+/// commands in a channel.
+pub async fn any_token_allows(
+    state: &AppState,
+    bot_id: &str,
+    scope: &Scope,
+) -> anyhow::Result<bool> {
+    Ok(state
+        .db
+        .get_user_api_tokens(bot_id)
+        .await?
+        .iter()
+        .any(|token| parse_scopes(&token.scopes).contains(scope)))
+}
+
+fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    serde_json::from_str(scopes).unwrap_or_default()
+}
+
+/// Checks an API token and returns the bot it belongs to.
+pub async fn authenticate_token(state: &AppState, token: &str) -> Result<BotCaller, ApiError> {
+    let invalid = || api_error(StatusCode::UNAUTHORIZED, "Invalid API token");
+
+    let api_token = state
+        .db
+        .get_api_token_by_hash(&hash_secret(token))
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(invalid)?;
+    let bot = state
+        .db
+        .get_user_by_id(&api_token.user_id)
+        .await
+        .map_err(internal_error)?
+        .filter(|user| user.is_bot && user.disabled_at.is_none())
+        .ok_or_else(invalid)?;
+    let owner_id = bot.bot_owner_id.clone().ok_or_else(invalid)?;
+    // 停用账号的机器人也随之失效
+    state
+        .db
+        .get_user_by_id(&owner_id)
+        .await
+        .map_err(internal_error)?
+        .filter(|owner| owner.disabled_at.is_none())
+        .ok_or_else(invalid)?;
+
+    // 记录最近使用时间，避免每个请求都写库
+    let stale = api_token.last_used_at.is_none_or(|last_used| {
+        Utc::now() - last_used > Duration::minutes(TOKEN_TOUCH_INTERVAL_MINUTES)
+    });
+    if stale {
+        state
+            .db
+            .touch_api_token(&api_token.id)
+            .await
+            .map_err(internal_error)?;
+    }
+
+    Ok(BotCaller {
+        id: bot.id,
+        owner_id,
+        username: bot.username,
+        scopes: parse_scopes(&api_token.scopes),
+        token_id: api_token.id,
+    })
+}
+
+// 列出自己创建的机器人
+pub async fn list_bots_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<Vec<UserResponse>>, ApiError> {
+    let bots = state
+        .db
+        .get_bots_by_owner(&auth.id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(bots.into_iter().map(UserResponse::public).collect()))
+}
+
+// 创建机器人账号
+pub async fn create_bot_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Json(req): Json<CreateBotRequest>,
+) -> Result<Json<UserResponse>, ApiError> {
+    let username = req.username.trim();
+    if username.is_empty() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "Username is required"));
+    }
+    if state
+        .db
+        .get_user_by_username(username)
+        .await
+        .map_err(internal_error)?
+        .is_some()
+    {
+        return Err(api_error(StatusCode::CONFLICT, "Username already exists"));
+    }
+
+    // 机器人没有邮箱和密码，只能用 API token 认证
+    let id = Uuid::new_v4().to_string();
+    let bot = db::User {
+        email: format!("bot-{id}@bots.invalid"),
+        id,
+        username: username.to_string(),
+        password_hash: "!".to_string(),
+        created_at: Utc::now(),
+        last_seen: None,
+        status: "offline".to_string(),
+        display_name: req
+            .display_name
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty()),
+        bio: None,
+        timezone: None,
+        avatar_url: None,
+        email_verified: false,
+        totp_secret: None,
+        totp_enabled: false,
+        totp_last_step: None,
+        is_bot: true,
+        bot_owner_id: Some(auth.id),
+        is_admin: false,
+        disabled_at: None,
+        avatar_hash: None,
+        avatar_type: None,
+    };
+    state.db.create_user(&bot).await.map_err(internal_error)?;
+
+    Ok(Json(UserResponse::public(bot)))
+}
+
+// 删除机器人：吊销 token，账号保留给历史消息
+pub async fn delete_bot_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path(bot_id): Path<String>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let bot = owned_bot(&state, &auth, &bot_id).await?;
+    let tokens = state
+        .db
+        .get_user_api_tokens(&bot.id)
+        .await
+        .map_err(internal_error)?;
+    state.db.delete_bot(&bot.id).await.map_err(internal_error)?;
+    for token in tokens {
+        session::disconnect_session(&state, &token.id);
+    }
+    audit::record(
+        &state,
+        audit::Event {
+            action: "bot.delete",
+            actor_id: Some(&auth.id),
+            target: Some(("user", &bot.id)),
+            ip_address: client.ip_address.as_deref(),
+            details: json!({ "username": bot.username }),
+        },
+    )
+    .await;
+
+    Ok(Json(StatusResponse::new("Bot deleted")))
+}
+
+// 列出机器人的 API token
+pub async fn list_tokens_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Path(bot_id): Path<String>,
+) -> Result<Json<Vec<ApiTokenResponse>>, ApiError> {
+    let bot = owned_bot(&state, &auth, &bot_id).await?;
+    let tokens = state
+        .db
+        .get_user_api_tokens(&bot.id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(
+        tokens.into_iter().map(ApiTokenResponse::from_row).collect(),
+    ))
+}
+
+// 为机器人签发 API token
+pub async fn create_token_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Path(bot_id): Path<String>,
+    Json(req): Json<CreateTokenRequest>,
+) -> Result<Json<CreatedTokenResponse>, ApiError> {
+    let bot = owned_bot(&state, &auth, &bot_id).await?;
+
+    let name = req.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_TOKEN_NAME_LEN {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Token name must be 1 to {MAX_TOKEN_NAME_LEN} characters"),
+        ));
+    }
+    if req.scopes.is_empty() {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "At least one scope is required",
+        ));
+    }
+    if state
+        .db
+        .get_user_api_tokens(&bot.id)
+        .await
+        .map_err(internal_error)?
+        .len()
+        >= MAX_TOKENS_PER_BOT
+    {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("A bot can have at most {MAX_TOKENS_PER_BOT} tokens"),
+        ));
+    }
+
+    let mut scopes: Vec<Scope> = Vec::new();
+    for scope in req.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    let token = format!("{API_TOKEN_PREFIX}{}", generate_secret());
+    let row = db::ApiToken {
+        id: Uuid::new_v4().to_string(),
+        user_id: bot.id,
+        name: name.to_string(),
+        token_hash: hash_secret(&token),
+        scopes: serde_json::to_string(&scopes).map_err(|err| internal_error(err.into()))?,
+        created_at: Utc::now(),
+        last_used_at: None,
+        revoked_at: None,
+    };
+    state
+        .db
+        .create_api_token(&row)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(CreatedTokenResponse {
+        info: ApiTokenResponse::from_row(row),
+        token,
+    }))
+}
+
+// 吊销 API token
+pub async fn revoke_token_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Path((bot_id, token_id)): Path<(String, String)>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let bot = owned_bot(&state, &auth, &bot_id).await?;
+    if !state
+        .db
+        .revoke_api_token(&bot.id, &token_id)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(api_error(StatusCode::NOT_FOUND, "Token not found"));
+    }
+    session::disconnect_session(&state, &token_id);
+
+    Ok(Json(StatusResponse::new("Token revoked")))
+}
+
+/// Loads a bot, treating bots owned by someone else as missing.
+async fn owned_bot(state: &AppState, auth: &AuthUser, bot_id: &str) -> Result<db::User, ApiError> {
+    state
+        .db
+        .get_user_by_id(bot_id)
+        .await
+        .map_err(internal_error)?
+        .filter(|user| user.is_bot && user.bot_owner_id.as_deref() == Some(auth.id.as_str()))
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Bot not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mail::MemoryMailer, testing};
+
+    #[tokio::test]
+    async fn deleting_a_bot_keeps_its_history() {
+        let state = Arc::new(testing::state(Arc::new(MemoryMailer::default())).await);
+        let owner = testing::user(&state, "owner").await;
+        let room = testing::room(&state, "lobby", &owner).await;
+        let auth = AuthUser {
+            id: owner.id.clone(),
+            session_id: Uuid::new_v4().to_string(),
+        };
+        let client = ClientInfo {
+            ip_address: Some("203.0.113.7".to_string()),
+            user_agent: None,
+        };
+
+        let Json(bot) = create_bot_handler(
+            State(state.clone()),
+            auth.clone(),
+            Json(CreateBotRequest {
+                username: "helper".to_string(),
+                display_name: None,
+            }),
+        )
+        .await
+        .unwrap();
+        let Json(token) = create_token_handler(
+            State(state.clone()),
+            auth.clone(),
+            Path(bot.id.clone()),
+            Json(CreateTokenRequest {
+                name: "main".to_string(),
+                scopes: vec![Scope::ReadRooms, Scope::PostRoom("lobby".to_string())],
+            }),
+        )
+        .await
+        .unwrap();
+
+        // 机器人加入房间、发消息、上传文件并创建 webhook
+        let now = Utc::now();
+        state
+            .db
+            .add_room_member(&db::RoomMember {
+                id: Uuid::new_v4().to_string(),
+                room_id: room.id.clone(),
+                user_id: bot.id.clone(),
+                joined_at: now,
+                role: "member".to_string(),
+            })
+            .await
+            .unwrap();
+        let message = db::Message {
+            id: Uuid::new_v4().to_string(),
+            room_id: room.id.clone(),
+            sender_id: bot.id.clone(),
+            content: "beep".to_string(),
+            message_type: "text".to_string(),
+            created_at: now,
+            edited_at: None,
+            reply_to: None,
+            deleted_at: None,
+            integration_id: None,
+            sender_name: None,
+            attachments: None,
+            link_previews: None,
+        };
+        state.db.create_message(&message).await.unwrap();
+        state
+            .db
+            .create_file(&db::File {
+                id: Uuid::new_v4().to_string(),
+                room_id: room.id.clone(),
+                uploader_id: bot.id.clone(),
+                message_id: Some(message.id.clone()),
+                storage_key: "files/test".to_string(),
+                filename: "log.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                size_bytes: 4,
+                created_at: now,
+                width: None,
+                height: None,
+                blurhash: None,
+                thumbnail_type: None,
+            })
+            .await
+            .unwrap();
+        state
+            .db
+            .create_incoming_webhook(&db::IncomingWebhook {
+                id: Uuid::new_v4().to_string(),
+                room_id: room.id.clone(),
+                name: "ci".to_string(),
+                token_hash: hash_secret("hook"),
+                created_by: bot.id.clone(),
+                created_at: now,
+                last_used_at: None,
+                revoked_at: None,
+            })
+            .await
+            .unwrap();
+
+        let Json(response) = delete_bot_handler(
+            State(state.clone()),
+            auth.clone(),
+            client,
+            Path(bot.id.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.message, "Bot deleted");
+
+        let row = state.db.get_user_by_id(&bot.id).await.unwrap().unwrap();
+        assert!(row.disabled_at.is_some());
+        assert_eq!(row.bot_owner_id, None);
+        assert!(state
+            .db
+            .get_room_member(&room.id, &bot.id)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(state.db.get_message(&message.id).await.unwrap().is_some());
+        assert!(authenticate_token(&state, &token.token).await.is_err());
+        assert!(state
+            .db
+            .get_bots_by_owner(&owner.id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let entries = state
+            .db
+            .query_audit_log(
+                &db::AuditFilter {
+                    action: Some("bot.delete".to_string()),
+                    ..Default::default()
+                },
+                false,
+                10,
+                0,
+            )
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].actor_id.as_deref(), Some(owner.id.as_str()));
+        assert_eq!(entries[0].target_id.as_deref(), Some(bot.id.as_str()));
+        assert_eq!(entries[0].ip_address.as_deref(), Some("203.0.113.7"));
+
+        // 已删除的机器人对主人不再可见
+        let err = delete_bot_handler(
+            State(state.clone()),
+            auth,
+            ClientInfo {
+                ip_address: None,
+                user_agent: None,
+            },
+            Path(bot.id.clone()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+    }
+}
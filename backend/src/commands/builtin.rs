@@ -0,0 +1,461 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use super::{split_word, Command, CommandContext, CommandError, CommandRegistry, CommandReply};
+use crate::{
+    moderation::{self, SanctionKind},
+    rooms::{self, permissions::Permission},
+};
+
+const MAX_TOPIC_LEN: usize = 250;
+
+pub fn register(registry: &mut CommandRegistry) {
+    registry.register(Arc::new(Help));
+    registry.register(Arc::new(Me));
+    registry.register(Arc::new(Topic));
+    registry.register(Arc::new(Names));
+    registry.register(Arc::new(Whois));
+    registry.register(Arc::new(Invite));
+    registry.register(Arc::new(Sanction {
+        kind: SanctionKind::Kick,
+        name: "kick",
+        usage: "/kick <user> [reason]",
+        description: "Remove a user from the channel (manage_members)",
+    }));
+    registry.register(Arc::new(Sanction {
+        kind: SanctionKind::Ban,
+        name: "ban",
+        usage: "/ban <user> [duration] [reason]",
+        description: "Keep a user out of the room, e.g. for 7d or for good (manage_members)",
+    }));
+    registry.register(Arc::new(Sanction {
+        kind: SanctionKind::Mute,
+        name: "mute",
+        usage: "/mute <user> [duration] [reason]",
+        description: "Stop a user from posting, e.g. for 1h or for good (manage_members)",
+    }));
+    registry.register(Arc::new(Sanction {
+        kind: SanctionKind::Timeout,
+        name: "timeout",
+        usage: "/timeout <user> <duration> [reason]",
+        description: "Stop a user from posting for a while, e.g. 10m (manage_members)",
+    }));
+    registry.register(Arc::new(Lift {
+        kinds: &[SanctionKind::Ban],
+        name: "unban",
+        usage: "/unban <user>",
+        description: "Lift a user's ban (manage_members)",
+        not_sanctioned: "is not banned",
+    }));
+    registry.register(Arc::new(Lift {
+        kinds: &[SanctionKind::Mute, SanctionKind::Timeout],
+        name: "unmute",
+        usage: "/unmute <user>",
+        description: "Lift a user's mute or timeout (manage_members)",
+        not_sanctioned: "is not muted",
+    }));
+}
+
+fn usage_error(command: &dyn Command) -> CommandError {
+    CommandError::Invalid(format!("Usage: {}", command.usage()))
+}
+
+struct Help;
+
+#[async_trait]
+impl Command for Help {
+    fn name(&self) -> &str {
+        "help"
+    }
+
+    fn usage(&self) -> &str {
+        "/help [command]"
+    }
+
+    fn description(&self) -> &str {
+        "List commands, or explain one"
+    }
+
+    async fn run(&self, ctx: &CommandContext, args: &str) -> Result<CommandReply, CommandError> {
+        let (name, _) = split_word(args);
+        let name = name.trim_start_matches('/').to_lowercase();
+        if !name.is_empty() {
+            return match super::resolve(&ctx.state, &name).await? {
+                Some(command) => Ok(CommandReply::Ephemeral(format!(
+                    "{} - {}",
+                    command.usage(),
+                    command.description()
+                ))),
+                None => Err(CommandError::Invalid(format!("Unknown command /{name}"))),
+            };
+        }
+
+        let mut lines: Vec<String> = ctx
+            .state
+            .commands
+            .all()
+            .iter()
+            .map(|command| format!("{} - {}", command.usage(), command.description()))
+            .collect();
+        for command in ctx.state.db.get_bot_commands(None).await? {
+            lines.push(format!("{} - {}", command.usage, command.description));
+        }
+        Ok(CommandReply::Ephemeral(lines.join("\n")))
+    }
+}
+
+struct Me;
+
+#[async_trait]
+impl Command for Me {
+    fn name(&self) -> &str {
+        "me"
+    }
+
+    fn usage(&self) -> &str {
+        "/me <action>"
+    }
+
+    fn description(&self) -> &str {
+        "Describe what you are doing"
+    }
+
+    async fn run(&self, ctx: &CommandContext, args: &str) -> Result<CommandReply, CommandError> {
+        let action = args.trim();
+        if action.is_empty() {
+            return Err(usage_error(self));
+        }
+
+        Ok(CommandReply::Public(rooms::NewMessage {
+            sender_id: ctx.user_id.clone(),
+            username: ctx.username.clone(),
+            content: action.to_string(),
+            message_type: Some("action".to_string()),
+            is_bot: ctx.is_bot,
+            integration_id: None,
+            attachments: Vec::new(),
+        }))
+    }
+}
+
+struct Topic;
+
+#[async_trait]
+impl Command for Topic {
+    fn name(&self) -> &str {
+        "topic"
+    }
+
+    fn usage(&self) -> &str {
+        "/topic [new topic]"
+    }
+
+    fn description(&self) -> &str {
+        "Show the room's topic, or set it (manage_room)"
+    }
+
+    async fn run(&self, ctx: &CommandContext, args: &str) -> Result<CommandReply, CommandError> {
+        let topic = args.trim();
+        if topic.is_empty() {
+            let current = ctx
+                .room
+                .as_ref()
+                .and_then(|room| room.description.as_deref());
+            return Ok(CommandReply::Ephemeral(match current {
+                Some(topic) => format!("Topic for {}: {topic}", ctx.channel),
+                None => format!("No topic is set for {}", ctx.channel),
+            }));
+        }
+        if topic.chars().count() > MAX_TOPIC_LEN {
+            return Err(CommandError::Invalid(format!(
+                "Topics can be at most {MAX_TOPIC_LEN} characters"
+            )));
+        }
+
+        let (room, _) = ctx.authorize(Permission::ManageRoom).await?;
+        ctx.state
+            .db
+            .update_room_description(&room.id, Some(topic))
+            .await?;
+        Ok(CommandReply::Announce(format!(
+            "{} set the topic to: {topic}",
+            ctx.username
+        )))
+    }
+}
+
+struct Names;
+
+#[async_trait]
+impl Command for Names {
+    fn name(&self) -> &str {
+        "names"
+    }
+
+    fn usage(&self) -> &str {
+        "/names"
+    }
+
+    fn description(&self) -> &str {
+        "List who is in the channel"
+    }
+
+    async fn run(&self, ctx: &CommandContext, _args: &str) -> Result<CommandReply, CommandError> {
+        let mut names: Vec<String> = ctx
+            .state
+            .channels
+            .get(&ctx.channel)
+            .map(|channel| {
+                channel
+                    .users
+                    .iter()
+                    .map(|entry| entry.key().clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        names.sort();
+
+        Ok(CommandReply::Ephemeral(format!(
+            "In {}: {}",
+            ctx.channel,
+            names.join(", ")
+        )))
+    }
+}
+
+struct Whois;
+
+#[async_trait]
+impl Command for Whois {
+    fn name(&self) -> &str {
+        "whois"
+    }
+
+    fn usage(&self) -> &str {
+        "/whois <user>"
+    }
+
+    fn description(&self) -> &str {
+        "Show who a user is"
+    }
+
+    async fn run(&self, ctx: &CommandContext, args: &str) -> Result<CommandReply, CommandError> {
+        let (username, _) = split_word(args);
+        if username.is_empty() {
+            return Err(usage_error(self));
+        }
+        let user = ctx
+            .state
+            .db
+            .get_user_by_username(username)
+            .await?
+            .ok_or_else(|| CommandError::Invalid(format!("No such user {username}")))?;
+
+        let mut line = user.username.clone();
+        if let Some(display_name) = &user.display_name {
+            line.push_str(&format!(" ({display_name})"));
+        }
+        if user.is_bot {
+            line.push_str(" [bot]");
+        }
+        line.push_str(&format!(", {}", user.status));
+        if let Some(room) = &ctx.room {
+            match ctx.state.db.get_room_member(&room.id, &user.id).await? {
+                Some(member) => line.push_str(&format!(", {} of {}", member.role, ctx.channel)),
+                None => line.push_str(&format!(", not in {}", ctx.channel)),
+            }
+        }
+        Ok(CommandReply::Ephemeral(line))
+    }
+}
+
+struct Invite;
+
+#[async_trait]
+impl Command for Invite {
+    fn name(&self) -> &str {
+        "invite"
+    }
+
+    fn usage(&self) -> &str {
+        "/invite <user>"
+    }
+
+    fn description(&self) -> &str {
+        "Add a user to the room (manage_members)"
+    }
+
+    async fn run(&self, ctx: &CommandContext, args: &str) -> Result<CommandReply, CommandError> {
+        let (username, _) = split_word(args);
+        if username.is_empty() {
+            return Err(usage_error(self));
+        }
+        let (room, _) = ctx.authorize(Permission::ManageMembers).await?;
+        let user = ctx
+            .state
+            .db
+            .get_user_by_username(username)
+            .await?
+            .ok_or_else(|| CommandError::Invalid(format!("No such user {username}")))?;
+        if ctx
+            .state
+            .db
+            .get_room_member(&room.id, &user.id)
+            .await?
+            .is_some()
+        {
+            return Ok(CommandReply::Ephemeral(format!(
+                "{} is already in {}",
+                user.username, ctx.channel
+            )));
+        }
+
+        rooms::add_member(&ctx.state, &room.id, &user.id, "member").await?;
+        Ok(CommandReply::Announce(format!(
+            "{} invited {} to {}",
+            ctx.username, user.username, ctx.channel
+        )))
+    }
+}
+
+/// `/kick`, `/ban`, `/mute` and `/timeout`.
+struct Sanction {
+    kind: SanctionKind,
+    name: &'static str,
+    usage: &'static str,
+    description: &'static str,
+}
+
+#[async_trait]
+impl Command for Sanction {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn usage(&self) -> &str {
+        self.usage
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    async fn run(&self, ctx: &CommandContext, args: &str) -> Result<CommandReply, CommandError> {
+        let (username, rest) = split_word(args);
+        if username.is_empty() {
+            return Err(usage_error(self));
+        }
+        // 时长是可选的第二个参数，其余部分是理由
+        let (duration, reason) = match self.kind {
+            SanctionKind::Kick => (None, rest),
+            _ => {
+                let (word, after) = split_word(rest);
+                match moderation::parse_duration(word) {
+                    Some(duration) => (Some(duration), after),
+                    None => (None, rest),
+                }
+            }
+        };
+        if self.kind == SanctionKind::Timeout && duration.is_none() {
+            return Err(usage_error(self));
+        }
+
+        let (room, _) = ctx.member()?;
+        let actor = ctx.caller().await?;
+        match ctx.state.db.get_user_by_username(username).await? {
+            Some(target) => {
+                moderation::apply(
+                    &ctx.state,
+                    room,
+                    &actor,
+                    &target,
+                    self.kind,
+                    duration,
+                    Some(reason),
+                )
+                .await?;
+            }
+            // 访客没有账号，只能按连接上的名字踢出
+            None if self.kind == SanctionKind::Kick => {
+                ctx.authorize(Permission::ManageMembers).await?;
+                let connected = ctx
+                    .state
+                    .channels
+                    .get(&ctx.channel)
+                    .is_some_and(|channel| channel.users.contains_key(username));
+                if !connected {
+                    return Err(CommandError::Invalid(format!("No such user {username}")));
+                }
+                rooms::announce(
+                    &ctx.state,
+                    &ctx.channel,
+                    match reason {
+                        "" => format!("{username} was kicked by {}", actor.user.username),
+                        reason => {
+                            format!("{username} was kicked by {}: {reason}", actor.user.username)
+                        }
+                    },
+                );
+                rooms::kick(&ctx.state, &ctx.channel, username);
+                actor
+                    .record(
+                        &ctx.state,
+                        "moderation.kick",
+                        Some(("room", &room.id)),
+                        serde_json::json!({ "guest": username, "reason": reason }),
+                    )
+                    .await;
+            }
+            None => return Err(CommandError::Invalid(format!("No such user {username}"))),
+        }
+        Ok(CommandReply::None)
+    }
+}
+
+/// `/unban` and `/unmute`.
+struct Lift {
+    kinds: &'static [SanctionKind],
+    name: &'static str,
+    usage: &'static str,
+    description: &'static str,
+    /// Completes "<user> ..." when there was nothing to lift.
+    not_sanctioned: &'static str,
+}
+
+#[async_trait]
+impl Command for Lift {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn usage(&self) -> &str {
+        self.usage
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    async fn run(&self, ctx: &CommandContext, args: &str) -> Result<CommandReply, CommandError> {
+        let (username, _) = split_word(args);
+        if username.is_empty() {
+            return Err(usage_error(self));
+        }
+        let (room, _) = ctx.member()?;
+        let actor = ctx.caller().await?;
+        let target = ctx
+            .state
+            .db
+            .get_user_by_username(username)
+            .await?
+            .ok_or_else(|| CommandError::Invalid(format!("No such user {username}")))?;
+
+        if moderation::lift_for_user(&ctx.state, room, &actor, &target, self.kinds).await? == 0 {
+            return Err(CommandError::Invalid(format!(
+                "{} {}",
+                target.username, self.not_sanctioned
+            )));
+        }
+        Ok(CommandReply::None)
+    }
+}
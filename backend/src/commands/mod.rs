@@ -0,0 +1,604 @@
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    api_error, audit,
+    auth::{generate_secret, Caller},
+    bots::{self, Scope},
+    db, error_event, internal_error, outbound,
+    rooms::{
+        self,
+        permissions::{self, Permission},
+    },
+    ApiError, AppState, ChatMessage, StatusResponse,
+};
+
+mod builtin;
+
+const MAX_COMMAND_NAME_LEN: usize = 32;
+const MAX_DESCRIPTION_LEN: usize = 200;
+const MAX_COMMANDS_PER_BOT: usize = 20;
+/// Larger callback responses are treated as a failed call.
+const MAX_CALLBACK_RESPONSE_BYTES: usize = 64 * 1024;
+
+/// Where a command was run and by whom.
+pub struct CommandContext {
+    pub state: Arc<AppState>,
+    pub channel: String,
+    /// The channel's room; guests can be alone in a channel without one.
+    pub room: Option<db::ChatRoom>,
+    /// `None` for guests.
+    pub user_id: Option<String>,
+    pub username: String,
+    pub is_bot: bool,
+    pub ip_address: Option<String>,
+}
+
+impl CommandContext {
+    /// The room and the caller's account, for commands guests cannot use.
+    fn member(&self) -> Result<(&db::ChatRoom, &str), CommandError> {
+        match (&self.room, &self.user_id) {
+            (Some(room), Some(user_id)) => Ok((room, user_id)),
+            _ => Err(CommandError::Invalid(
+                "Sign in to use this command".to_string(),
+            )),
+        }
+    }
+
+    /// Like [`Self::member`], but the caller must also hold `permission`
+    /// in the room.
+    async fn authorize(
+        &self,
+        permission: Permission,
+    ) -> Result<(&db::ChatRoom, &str), CommandError> {
+        let (room, user_id) = self.member()?;
+        permissions::authorize(&self.state, &room.id, user_id, permission).await?;
+        Ok((room, user_id))
+    }
+
+    /// The caller's account, for commands guests cannot use.
+    async fn caller(&self) -> Result<audit::Actor, CommandError> {
+        let (_, user_id) = self.member()?;
+        let user = self
+            .state
+            .db
+            .get_user_by_id(user_id)
+            .await?
+            .ok_or_else(|| CommandError::Invalid("Sign in to use this command".to_string()))?;
+        Ok(audit::Actor {
+            user,
+            ip_address: self.ip_address.clone(),
+        })
+    }
+}
+
+/// What a command answers with.
+pub enum CommandReply {
+    /// Shown only to whoever ran the command.
+    Ephemeral(String),
+    /// Posted to the channel like any other chat message.
+    Public(rooms::NewMessage),
+    /// Announced to the channel by the server.
+    Announce(String),
+    /// The command has already done all it needed to.
+    None,
+}
+
+pub enum CommandError {
+    /// Shown to the caller as an error event.
+    Invalid(String),
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for CommandError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::Internal(err)
+    }
+}
+
+/// Lets commands reuse the checks of the REST handlers. Server errors have
+/// already been logged by [`internal_error`].
+impl From<ApiError> for CommandError {
+    fn from((status, Json(body)): ApiError) -> Self {
+        if status.is_server_error() {
+            Self::Internal(anyhow::anyhow!(body.error))
+        } else {
+            Self::Invalid(body.error)
+        }
+    }
+}
+
+/// A slash command, run when a chat message starts with `/<name>`.
+#[async_trait]
+pub trait Command: Send + Sync {
+    fn name(&self) -> &str;
+    /// How to call it, e.g. `/kick <user> [reason]`.
+    fn usage(&self) -> &str;
+    fn description(&self) -> &str;
+    /// Runs the command; `args` is the rest of the line after its name.
+    async fn run(&self, ctx: &CommandContext, args: &str) -> Result<CommandReply, CommandError>;
+}
+
+/// Commands handled in-process: the built-ins and any registered at
+/// startup. Bots' commands live in the database and are looked up after
+/// these, so they can never shadow one.
+pub struct CommandRegistry {
+    commands: HashMap<String, Arc<dyn Command>>,
+}
+
+impl CommandRegistry {
+    pub fn with_builtins() -> Self {
+        let mut registry = Self {
+            commands: HashMap::new(),
+        };
+        builtin::register(&mut registry);
+        registry
+    }
+
+    pub fn register(&mut self, command: Arc<dyn Command>) {
+        self.commands.insert(command.name().to_string(), command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Command>> {
+        self.commands.get(name).cloned()
+    }
+
+    /// The registered commands, sorted by name.
+    pub fn all(&self) -> Vec<Arc<dyn Command>> {
+        let mut commands: Vec<_> = self.commands.values().cloned().collect();
+        commands.sort_by(|a, b| a.name().cmp(b.name()));
+        commands
+    }
+}
+
+/// Finds a command by name, in-process ones first.
+async fn resolve(state: &AppState, name: &str) -> anyhow::Result<Option<Arc<dyn Command>>> {
+    if let Some(command) = state.commands.get(name) {
+        return Ok(Some(command));
+    }
+    Ok(state
+        .db
+        .get_bot_command_by_name(name)
+        .await?
+        .map(|command| Arc::new(CallbackCommand(command)) as Arc<dyn Command>))
+}
+
+/// Runs the slash command on `line` (without its leading `/`). Ephemeral
+/// replies and errors go to the caller's own connection through `direct`;
+/// everything else goes to the channel.
+pub async fn dispatch(ctx: CommandContext, line: String, direct: mpsc::Sender<String>) {
+    let (name, args) = split_word(&line);
+    let name = name.to_lowercase();
+
+    let result = match resolve(&ctx.state, &name).await {
+        Ok(Some(command)) => command.run(&ctx, args).await,
+        Ok(None) => Err(CommandError::Invalid(format!(
+            "Unknown command /{name}, try /help"
+        ))),
+        Err(err) => Err(CommandError::Internal(err)),
+    };
+
+    let direct_event = match result {
+        Ok(CommandReply::Ephemeral(message)) => serde_json::to_string(&ChatMessage {
+            username: "System".to_string(),
+            message,
+            channel: ctx.channel.clone(),
+            message_type: Some("ephemeral".to_string()),
+            is_bot: false,
+            id: None,
+            is_integration: false,
+            attachments: Vec::new(),
+            members: Vec::new(),
+            previews: Vec::new(),
+        })
+        .ok(),
+        Ok(CommandReply::Public(new)) => {
+            let slow_mode_key =
+                rooms::slow_mode_key(new.sender_id.as_deref(), ctx.ip_address.as_deref());
+            match rooms::post_message(
+                &ctx.state,
+                &ctx.channel,
+                ctx.room.as_ref(),
+                new,
+                &slow_mode_key,
+            )
+            .await
+            {
+                Ok(posted) => posted
+                    .notice()
+                    .and_then(|notice| error_event(&ctx.channel, &notice)),
+                Err(err) => {
+                    eprintln!("failed to store message: {err:#}");
+                    None
+                }
+            }
+        }
+        Ok(CommandReply::Announce(message)) => {
+            rooms::announce(&ctx.state, &ctx.channel, message);
+            None
+        }
+        Ok(CommandReply::None) => None,
+        Err(CommandError::Invalid(reason)) => error_event(&ctx.channel, &reason),
+        Err(CommandError::Internal(err)) => {
+            eprintln!("command /{name} failed: {err:#}");
+            error_event(&ctx.channel, &format!("/{name} failed, try again later"))
+        }
+    };
+    if let Some(event) = direct_event {
+        let _ = direct.send(event).await;
+    }
+}
+
+/// Splits off the first whitespace-separated word.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (text, ""),
+    }
+}
+
+/// A bot's command, answered by POSTing the invocation to its callback URL.
+struct CallbackCommand(db::BotCommand);
+
+/// What a bot's callback answers with; an empty body means no reply.
+#[derive(Debug, Deserialize)]
+struct CallbackReply {
+    #[serde(default)]
+    text: String,
+    /// `ephemeral` (the default) or `in_channel`.
+    #[serde(default)]
+    response_type: Option<String>,
+    #[serde(default)]
+    attachments: Vec<db::Attachment>,
+}
+
+#[async_trait]
+impl Command for CallbackCommand {
+    fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    fn usage(&self) -> &str {
+        &self.0.usage
+    }
+
+    fn description(&self) -> &str {
+        &self.0.description
+    }
+
+    async fn run(&self, ctx: &CommandContext, args: &str) -> Result<CommandReply, CommandError> {
+        let command = &self.0;
+        let not_responding =
+            || CommandError::Invalid(format!("/{} is not responding", command.name));
+        let bot = ctx
+            .state
+            .db
+            .get_user_by_id(&command.bot_id)
+            .await?
+            .ok_or_else(not_responding)?;
+
+        let body = json!({
+            "command": command.name,
+            "args": args,
+            "channel": ctx.channel,
+            "room_id": ctx.room.as_ref().map(|room| &room.id),
+            "user": {
+                "id": ctx.user_id,
+                "username": ctx.username,
+                "is_bot": ctx.is_bot,
+            },
+        })
+        .to_string();
+        let response = ctx
+            .state
+            .webhooks
+            .send_signed(
+                &command.callback_url,
+                &command.secret,
+                "command",
+                &Uuid::new_v4().to_string(),
+                body,
+            )
+            .await
+            .map_err(|err| {
+                eprintln!("command /{} callback failed: {err:#}", command.name);
+                not_responding()
+            })?;
+        if !response.status().is_success() {
+            eprintln!(
+                "command /{} callback answered {}",
+                command.name,
+                response.status()
+            );
+            return Err(not_responding());
+        }
+        let (bytes, complete) = outbound::read_limited(response, MAX_CALLBACK_RESPONSE_BYTES)
+            .await
+            .map_err(|_| not_responding())?;
+        if !complete {
+            return Err(not_responding());
+        }
+        if bytes.iter().all(u8::is_ascii_whitespace) {
+            return Ok(CommandReply::None);
+        }
+        let mut reply: CallbackReply =
+            serde_json::from_slice(&bytes).map_err(|_| not_responding())?;
+        // 上传的文件只能由服务器附上
+        for attachment in &mut reply.attachments {
+            attachment.file = None;
+        }
+        if reply.text.trim().is_empty() && reply.attachments.is_empty() {
+            return Ok(CommandReply::None);
+        }
+
+        // 机器人回复到频道时以机器人身份发送，须有该房间的发言权限
+        Ok(match reply.response_type.as_deref() {
+            Some("in_channel") => {
+                let scope = Scope::PostRoom(ctx.channel.clone());
+                if !bots::any_token_allows(&ctx.state, &bot.id, &scope).await? {
+                    return Err(CommandError::Invalid(format!(
+                        "{} lacks the {scope} scope",
+                        bot.username
+                    )));
+                }
+                if let Some(room) = &ctx.room {
+                    if let Some(refusal) =
+                        rooms::send_refusal(&ctx.state, room, Some(&bot.id), &bot.id, &reply.text)
+                            .await?
+                    {
+                        return Err(CommandError::Invalid(format!(
+                            "{}: {refusal}",
+                            bot.username
+                        )));
+                    }
+                }
+                CommandReply::Public(rooms::NewMessage {
+                    sender_id: Some(bot.id),
+                    username: bot.username,
+                    content: reply.text,
+                    message_type: None,
+                    is_bot: true,
+                    integration_id: None,
+                    attachments: reply.attachments,
+                })
+            }
+            _ => CommandReply::Ephemeral(reply.text),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateBotCommandRequest {
+    name: String,
+    usage: Option<String>,
+    description: String,
+    callback_url: String,
+}
+
+/// A command as listed to clients.
+#[derive(Debug, Serialize)]
+pub struct CommandInfo {
+    name: String,
+    usage: String,
+    description: String,
+    /// The bot answering the command; `None` for built-ins.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bot_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BotCommandResponse {
+    id: String,
+    name: String,
+    usage: String,
+    description: String,
+    callback_url: String,
+    created_at: DateTime<Utc>,
+    /// Key for verifying `X-Chatx-Signature`; only returned on creation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+impl BotCommandResponse {
+    fn from_row(command: db::BotCommand) -> Self {
+        Self {
+            id: command.id,
+            name: command.name,
+            usage: command.usage,
+            description: command.description,
+            callback_url: command.callback_url,
+            created_at: command.created_at,
+            secret: None,
+        }
+    }
+}
+
+// 列出所有可用的斜杠命令
+pub async fn list_commands_handler(
+    State(state): State<Arc<AppState>>,
+    _caller: Caller,
+) -> Result<Json<Vec<CommandInfo>>, ApiError> {
+    let mut commands: Vec<CommandInfo> = state
+        .commands
+        .all()
+        .into_iter()
+        .map(|command| CommandInfo {
+            name: command.name().to_string(),
+            usage: command.usage().to_string(),
+            description: command.description().to_string(),
+            bot_id: None,
+        })
+        .collect();
+    let bot_commands = state
+        .db
+        .get_bot_commands(None)
+        .await
+        .map_err(internal_error)?;
+    commands.extend(bot_commands.into_iter().map(|command| CommandInfo {
+        name: command.name,
+        usage: command.usage,
+        description: command.description,
+        bot_id: Some(command.bot_id),
+    }));
+
+    Ok(Json(commands))
+}
+
+// 列出机器人注册的命令
+pub async fn list_bot_commands_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path(bot_id): Path<String>,
+) -> Result<Json<Vec<BotCommandResponse>>, ApiError> {
+    let bot = managed_bot(&state, &caller, &bot_id).await?;
+    let commands = state
+        .db
+        .get_bot_commands(Some(&bot.id))
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(
+        commands
+            .into_iter()
+            .map(BotCommandResponse::from_row)
+            .collect(),
+    ))
+}
+
+// 为机器人注册斜杠命令
+pub async fn create_bot_command_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path(bot_id): Path<String>,
+    Json(req): Json<CreateBotCommandRequest>,
+) -> Result<Json<BotCommandResponse>, ApiError> {
+    let bot = managed_bot(&state, &caller, &bot_id).await?;
+
+    let name = req.name.trim().trim_start_matches('/').to_lowercase();
+    if name.is_empty()
+        || name.len() > MAX_COMMAND_NAME_LEN
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Command names must be 1 to {MAX_COMMAND_NAME_LEN} letters, digits, '-' or '_'"
+            ),
+        ));
+    }
+    let description = req.description.trim();
+    if description.is_empty() || description.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Description must be 1 to {MAX_DESCRIPTION_LEN} characters"),
+        ));
+    }
+    let callback_url = state.webhooks.check_url(req.callback_url.trim()).await?;
+    if state.commands.get(&name).is_some()
+        || state
+            .db
+            .get_bot_command_by_name(&name)
+            .await
+            .map_err(internal_error)?
+            .is_some()
+    {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("/{name} is already taken"),
+        ));
+    }
+    if state
+        .db
+        .get_bot_commands(Some(&bot.id))
+        .await
+        .map_err(internal_error)?
+        .len()
+        >= MAX_COMMANDS_PER_BOT
+    {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("A bot can have at most {MAX_COMMANDS_PER_BOT} commands"),
+        ));
+    }
+
+    let usage = req
+        .usage
+        .map(|usage| usage.trim().to_string())
+        .filter(|usage| !usage.is_empty())
+        .unwrap_or_else(|| format!("/{name}"));
+    let command = db::BotCommand {
+        id: Uuid::new_v4().to_string(),
+        bot_id: bot.id,
+        name,
+        usage: usage.chars().take(MAX_DESCRIPTION_LEN).collect(),
+        description: description.to_string(),
+        callback_url,
+        secret: generate_secret(),
+        created_at: Utc::now(),
+    };
+    state
+        .db
+        .create_bot_command(&command)
+        .await
+        .map_err(internal_error)?;
+
+    let secret = command.secret.clone();
+    Ok(Json(BotCommandResponse {
+        secret: Some(secret),
+        ..BotCommandResponse::from_row(command)
+    }))
+}
+
+// 删除机器人的命令
+pub async fn delete_bot_command_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path((bot_id, command_id)): Path<(String, String)>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let bot = managed_bot(&state, &caller, &bot_id).await?;
+    if !state
+        .db
+        .delete_bot_command(&bot.id, &command_id)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(api_error(StatusCode::NOT_FOUND, "Command not found"));
+    }
+
+    Ok(Json(StatusResponse::new("Command deleted")))
+}
+
+/// Loads a bot whose commands the caller may manage: its owner, or the bot
+/// itself with the `commands:manage` scope.
+async fn managed_bot(
+    state: &AppState,
+    caller: &Caller,
+    bot_id: &str,
+) -> Result<db::User, ApiError> {
+    caller.require(&Scope::ManageCommands)?;
+    let allowed = |bot: &db::User| match caller {
+        Caller::Bot(caller) => caller.id == bot.id,
+        Caller::User(user) => bot.bot_owner_id.as_deref() == Some(user.id.as_str()),
+    };
+
+    state
+        .db
+        .get_user_by_id(bot_id)
+        .await
+        .map_err(internal_error)?
+        .filter(|user| user.is_bot && allowed(user))
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Bot not found"))
+}
@@ -1,7 +1,13 @@
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{sqlite::SqlitePoolOptions, FromRow, SqlitePool};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    types::Json,
+    FromRow, SqlitePool,
+};
+use std::str::FromStr;
+use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct User {
@@ -19,6 +25,35 @@ pub struct User {
     pub last_seen: Option<DateTime<Utc>>,
     #[sqlx(rename = "status")]
     pub status: String,
+    #[sqlx(rename = "display_name")]
+    pub display_name: Option<String>,
+    #[sqlx(rename = "bio")]
+    pub bio: Option<String>,
+    #[sqlx(rename = "timezone")]
+    pub timezone: Option<String>,
+    #[sqlx(rename = "avatar_url")]
+    pub avatar_url: Option<String>,
+    #[sqlx(rename = "email_verified")]
+    pub email_verified: bool,
+    #[sqlx(rename = "totp_secret")]
+    pub totp_secret: Option<String>,
+    #[sqlx(rename = "totp_enabled")]
+    pub totp_enabled: bool,
+    #[sqlx(rename = "totp_last_step")]
+    pub totp_last_step: Option<i64>,
+    #[sqlx(rename = "is_bot")]
+    pub is_bot: bool,
+    #[sqlx(rename = "bot_owner_id")]
+    pub bot_owner_id: Option<String>,
+    #[sqlx(rename = "is_admin")]
+    pub is_admin: bool,
+    #[sqlx(rename = "disabled_at")]
+    pub disabled_at: Option<DateTime<Utc>>,
+    /// Set while the user has an uploaded avatar.
+    #[sqlx(rename = "avatar_hash")]
+    pub avatar_hash: Option<String>,
+    #[sqlx(rename = "avatar_type")]
+    pub avatar_type: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -35,6 +70,16 @@ pub struct ChatRoom {
     pub created_at: DateTime<Utc>,
     #[sqlx(rename = "description")]
     pub description: Option<String>,
+    /// Minimum seconds between one sender's messages; 0 is off.
+    #[sqlx(rename = "slow_mode_seconds")]
+    pub slow_mode_seconds: i64,
+    /// Only members with `manage_room` may post.
+    #[sqlx(rename = "read_only")]
+    pub read_only: bool,
+    /// Guests may not post, and users joining the channel do not become
+    /// members.
+    #[sqlx(rename = "members_only")]
+    pub members_only: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -69,6 +114,79 @@ pub struct Message {
     pub edited_at: Option<DateTime<Utc>>,
     #[sqlx(rename = "reply_to")]
     pub reply_to: Option<String>,
+    #[sqlx(rename = "deleted_at")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// The incoming webhook that posted the message, if any.
+    #[sqlx(rename = "integration_id")]
+    pub integration_id: Option<String>,
+    /// Name shown instead of the sender's username.
+    #[sqlx(rename = "sender_name")]
+    pub sender_name: Option<String>,
+    #[sqlx(rename = "attachments")]
+    pub attachments: Option<Json<Vec<Attachment>>>,
+    /// Previews of the links in the message, added after it is sent.
+    #[sqlx(rename = "link_previews")]
+    pub link_previews: Option<Json<Vec<LinkPreview>>>,
+}
+
+/// What a linked page says about itself in its Open Graph or Twitter card
+/// metadata.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct LinkPreview {
+    #[sqlx(rename = "url")]
+    pub url: String,
+    #[sqlx(rename = "title")]
+    pub title: Option<String>,
+    #[sqlx(rename = "description")]
+    pub description: Option<String>,
+    #[sqlx(rename = "image_url")]
+    pub image_url: Option<String>,
+    #[sqlx(rename = "site_name")]
+    pub site_name: Option<String>,
+}
+
+/// Rich content attached to a message by an integration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Attachment {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    /// A file uploaded with the message; only the server sets this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileAttachment>,
+}
+
+/// What clients see of an uploaded file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileAttachment {
+    pub id: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    /// Where members of the room can download it.
+    pub url: String,
+    /// Images' dimensions and a blurhash to show while they load.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    /// Smaller versions of an image, smallest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    pub url: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -87,335 +205,2658 @@ pub struct Friendship {
     pub updated_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct PasswordResetToken {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "user_id")]
+    pub user_id: String,
+    #[sqlx(rename = "token_hash")]
+    pub token_hash: String,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+    #[sqlx(rename = "expires_at")]
+    pub expires_at: DateTime<Utc>,
+    #[sqlx(rename = "used_at")]
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct EmailVerificationToken {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "user_id")]
+    pub user_id: String,
+    #[sqlx(rename = "email")]
+    pub email: String,
+    #[sqlx(rename = "token_hash")]
+    pub token_hash: String,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+    #[sqlx(rename = "expires_at")]
+    pub expires_at: DateTime<Utc>,
+    #[sqlx(rename = "used_at")]
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Session {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "user_id")]
+    pub user_id: String,
+    #[sqlx(rename = "refresh_token_hash")]
+    pub refresh_token_hash: String,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+    #[sqlx(rename = "last_used_at")]
+    pub last_used_at: DateTime<Utc>,
+    #[sqlx(rename = "expires_at")]
+    pub expires_at: DateTime<Utc>,
+    #[sqlx(rename = "revoked_at")]
+    pub revoked_at: Option<DateTime<Utc>>,
+    #[sqlx(rename = "device_label")]
+    pub device_label: Option<String>,
+    #[sqlx(rename = "ip_address")]
+    pub ip_address: Option<String>,
+    #[sqlx(rename = "user_agent")]
+    pub user_agent: Option<String>,
+}
+
+impl Session {
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none() && self.expires_at > Utc::now()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct LoginChallenge {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "user_id")]
+    pub user_id: String,
+    #[sqlx(rename = "token_hash")]
+    pub token_hash: String,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+    #[sqlx(rename = "expires_at")]
+    pub expires_at: DateTime<Utc>,
+    #[sqlx(rename = "attempts")]
+    pub attempts: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct UserIdentity {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "user_id")]
+    pub user_id: String,
+    #[sqlx(rename = "issuer")]
+    pub issuer: String,
+    #[sqlx(rename = "subject")]
+    pub subject: String,
+    #[sqlx(rename = "email")]
+    pub email: Option<String>,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ApiToken {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "user_id")]
+    pub user_id: String,
+    #[sqlx(rename = "name")]
+    pub name: String,
+    #[sqlx(rename = "token_hash")]
+    pub token_hash: String,
+    /// JSON array of scope strings.
+    #[sqlx(rename = "scopes")]
+    pub scopes: String,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+    #[sqlx(rename = "last_used_at")]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[sqlx(rename = "revoked_at")]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Webhook {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "room_id")]
+    pub room_id: String,
+    #[sqlx(rename = "url")]
+    pub url: String,
+    #[sqlx(rename = "secret")]
+    pub secret: String,
+    /// JSON array of event names.
+    #[sqlx(rename = "events")]
+    pub events: String,
+    #[sqlx(rename = "created_by")]
+    pub created_by: String,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct AuditEntry {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    /// `None` when nobody was signed in, e.g. a failed login for an
+    /// unknown username.
+    #[sqlx(rename = "actor_id")]
+    pub actor_id: Option<String>,
+    #[sqlx(rename = "action")]
+    pub action: String,
+    #[sqlx(rename = "target_type")]
+    pub target_type: Option<String>,
+    #[sqlx(rename = "target_id")]
+    pub target_id: Option<String>,
+    /// JSON object with whatever else the action needs recorded.
+    #[sqlx(rename = "details")]
+    pub details: Option<String>,
+    #[sqlx(rename = "ip_address")]
+    pub ip_address: Option<String>,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Which audit log entries to return; unset fields match everything.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub actor_id: Option<String>,
+    /// Matches `action` exactly, or every action under it when it ends in
+    /// `.*`, e.g. `auth.*`.
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub ip_address: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// Row counts for the admin dashboard.
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ServerCounts {
+    pub users: i64,
+    pub bots: i64,
+    pub admins: i64,
+    pub disabled_users: i64,
+    pub rooms: i64,
+    pub messages: i64,
+    pub messages_last_24h: i64,
+    pub active_sessions: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct RoomRole {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "room_id")]
+    pub room_id: String,
+    #[sqlx(rename = "name")]
+    pub name: String,
+    /// JSON array of permission names.
+    #[sqlx(rename = "permissions")]
+    pub permissions: String,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct RoomSanction {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "room_id")]
+    pub room_id: String,
+    #[sqlx(rename = "user_id")]
+    pub user_id: String,
+    /// `kick`, `ban`, `mute` or `timeout`.
+    #[sqlx(rename = "kind")]
+    pub kind: String,
+    #[sqlx(rename = "reason")]
+    pub reason: Option<String>,
+    #[sqlx(rename = "actor_id")]
+    pub actor_id: String,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+    /// `None` for permanent sanctions.
+    #[sqlx(rename = "expires_at")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[sqlx(rename = "lifted_at")]
+    pub lifted_at: Option<DateTime<Utc>>,
+    #[sqlx(rename = "lifted_by")]
+    pub lifted_by: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct MessageReport {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "message_id")]
+    pub message_id: String,
+    #[sqlx(rename = "room_id")]
+    pub room_id: String,
+    #[sqlx(rename = "reporter_id")]
+    pub reporter_id: String,
+    #[sqlx(rename = "sender_id")]
+    pub sender_id: String,
+    /// The message as it read when it was reported.
+    #[sqlx(rename = "content")]
+    pub content: String,
+    #[sqlx(rename = "reason")]
+    pub reason: String,
+    #[sqlx(rename = "comment")]
+    pub comment: Option<String>,
+    /// `open` or `resolved`.
+    #[sqlx(rename = "status")]
+    pub status: String,
+    /// `dismiss`, `delete_message` or `sanction`.
+    #[sqlx(rename = "resolution")]
+    pub resolution: Option<String>,
+    #[sqlx(rename = "resolution_note")]
+    pub resolution_note: Option<String>,
+    #[sqlx(rename = "sanction_id")]
+    pub sanction_id: Option<String>,
+    #[sqlx(rename = "resolved_by")]
+    pub resolved_by: Option<String>,
+    #[sqlx(rename = "resolved_at")]
+    pub resolved_at: Option<DateTime<Utc>>,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct RoomWordFilter {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "room_id")]
+    pub room_id: String,
+    #[sqlx(rename = "word")]
+    pub word: String,
+    /// `ban` or `allow`.
+    #[sqlx(rename = "mode")]
+    pub mode: String,
+    #[sqlx(rename = "created_by")]
+    pub created_by: String,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct HeldMessage {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "room_id")]
+    pub room_id: String,
+    #[sqlx(rename = "sender_id")]
+    pub sender_id: String,
+    /// The name the message will be shown under.
+    #[sqlx(rename = "username")]
+    pub username: String,
+    #[sqlx(rename = "content")]
+    pub content: String,
+    #[sqlx(rename = "message_type")]
+    pub message_type: Option<String>,
+    #[sqlx(rename = "is_bot")]
+    pub is_bot: bool,
+    #[sqlx(rename = "integration_id")]
+    pub integration_id: Option<String>,
+    #[sqlx(rename = "attachments")]
+    pub attachments: Option<Json<Vec<Attachment>>>,
+    /// The filter that held the message, and why.
+    #[sqlx(rename = "filter")]
+    pub filter: String,
+    #[sqlx(rename = "reason")]
+    pub reason: String,
+    /// `held`, `approved` or `rejected`.
+    #[sqlx(rename = "status")]
+    pub status: String,
+    #[sqlx(rename = "reviewed_by")]
+    pub reviewed_by: Option<String>,
+    #[sqlx(rename = "reviewed_at")]
+    pub reviewed_at: Option<DateTime<Utc>>,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct File {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "room_id")]
+    pub room_id: String,
+    #[sqlx(rename = "uploader_id")]
+    pub uploader_id: String,
+    /// Set once the message carrying the file is sent.
+    #[sqlx(rename = "message_id")]
+    pub message_id: Option<String>,
+    #[sqlx(rename = "storage_key")]
+    pub storage_key: String,
+    #[sqlx(rename = "filename")]
+    pub filename: String,
+    #[sqlx(rename = "content_type")]
+    pub content_type: String,
+    #[sqlx(rename = "size_bytes")]
+    pub size_bytes: i64,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+    /// Only set for images.
+    #[sqlx(rename = "width")]
+    pub width: Option<i64>,
+    #[sqlx(rename = "height")]
+    pub height: Option<i64>,
+    #[sqlx(rename = "blurhash")]
+    pub blurhash: Option<String>,
+    /// Set if the image has thumbnails.
+    #[sqlx(rename = "thumbnail_type")]
+    pub thumbnail_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct BotCommand {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "bot_id")]
+    pub bot_id: String,
+    #[sqlx(rename = "name")]
+    pub name: String,
+    #[sqlx(rename = "usage")]
+    pub usage: String,
+    #[sqlx(rename = "description")]
+    pub description: String,
+    #[sqlx(rename = "callback_url")]
+    pub callback_url: String,
+    #[sqlx(rename = "secret")]
+    pub secret: String,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct IncomingWebhook {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "room_id")]
+    pub room_id: String,
+    #[sqlx(rename = "name")]
+    pub name: String,
+    #[sqlx(rename = "token_hash")]
+    pub token_hash: String,
+    #[sqlx(rename = "created_by")]
+    pub created_by: String,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+    #[sqlx(rename = "last_used_at")]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[sqlx(rename = "revoked_at")]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct WebhookDelivery {
+    #[sqlx(rename = "id")]
+    pub id: String,
+    #[sqlx(rename = "webhook_id")]
+    pub webhook_id: String,
+    #[sqlx(rename = "event")]
+    pub event: String,
+    #[sqlx(rename = "payload")]
+    pub payload: String,
+    /// `pending`, `delivered` or `failed`.
+    #[sqlx(rename = "status")]
+    pub status: String,
+    #[sqlx(rename = "attempts")]
+    pub attempts: i64,
+    #[sqlx(rename = "next_attempt_at")]
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    #[sqlx(rename = "response_status")]
+    pub response_status: Option<i64>,
+    #[sqlx(rename = "last_error")]
+    pub last_error: Option<String>,
+    #[sqlx(rename = "created_at")]
+    pub created_at: DateTime<Utc>,
+    #[sqlx(rename = "delivered_at")]
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
 pub struct Database {
     pool: SqlitePool,
 }
 
-impl Database {
-    pub async fn new(database_url: &str) -> Result<Self> {
-        let pool = SqlitePoolOptions::new()
-            .max_connections(5)
-            .connect(database_url)
-            .await?;
+impl Database {
+    pub async fn new(database_url: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(database_url)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await?;
+
+        Ok(Self { pool })
+    }
+
+    /// Brings the schema up to date by applying any pending files from
+    /// `migrations/`, the same ones `sqlx migrate run` uses.
+    pub async fn init(&self) -> Result<()> {
+        sqlx::migrate!("./migrations").run(&self.pool).await?;
+
+        Ok(())
+    }
+
+    // User operations
+    pub async fn create_user(&self, user: &User) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username, email, password_hash, created_at, last_seen, status,
+                               display_name, bio, timezone, avatar_url, email_verified, is_bot,
+                               bot_owner_id)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&user.id)
+        .bind(&user.username)
+        .bind(&user.email)
+        .bind(&user.password_hash)
+        .bind(user.created_at)
+        .bind(user.last_seen)
+        .bind(&user.status)
+        .bind(&user.display_name)
+        .bind(&user.bio)
+        .bind(&user.timezone)
+        .bind(&user.avatar_url)
+        .bind(user.email_verified)
+        .bind(user.is_bot)
+        .bind(&user.bot_owner_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            SELECT * FROM users WHERE username = ?
+            "#,
+        )
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn get_user_by_id(&self, user_id: &str) -> Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            SELECT * FROM users WHERE id = ?
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            SELECT * FROM users WHERE email = ? COLLATE NOCASE
+            "#,
+        )
+        .bind(email)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn update_user_profile(&self, user: &User) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET email = ?, email_verified = ?, display_name = ?, bio = ?, timezone = ?,
+                avatar_url = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(&user.email)
+        .bind(user.email_verified)
+        .bind(&user.display_name)
+        .bind(&user.bio)
+        .bind(&user.timezone)
+        .bind(&user.avatar_url)
+        .bind(&user.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn update_user_password(&self, user_id: &str, password_hash: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE users SET password_hash = ? WHERE id = ?
+            "#,
+        )
+        .bind(password_hash)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn update_user_status(&self, user_id: &str, status: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE users SET status = ?, last_seen = ? WHERE id = ?
+            "#,
+        )
+        .bind(status)
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Finds users whose username, display name or email contains `query`,
+    /// newest first.
+    pub async fn search_users(
+        &self,
+        query: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<User>> {
+        let pattern = query.map(|query| {
+            let escaped = query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{escaped}%")
+        });
+        let users = sqlx::query_as::<_, User>(
+            r#"
+            SELECT * FROM users
+            WHERE ?1 IS NULL
+               OR username LIKE ?1 ESCAPE '\'
+               OR display_name LIKE ?1 ESCAPE '\'
+               OR email LIKE ?1 ESCAPE '\'
+            ORDER BY created_at DESC
+            LIMIT ?2 OFFSET ?3
+            "#,
+        )
+        .bind(pattern)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users)
+    }
+
+    /// Records an uploaded avatar, or with `None` removes it. Either way
+    /// an external `avatar_url` is cleared.
+    pub async fn set_user_avatar(&self, user_id: &str, avatar: Option<(&str, &str)>) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE users SET avatar_url = NULL, avatar_hash = ?, avatar_type = ? WHERE id = ?
+            "#,
+        )
+        .bind(avatar.map(|(hash, _)| hash))
+        .bind(avatar.map(|(_, content_type)| content_type))
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_user_admin(&self, user_id: &str, is_admin: bool) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE users SET is_admin = ? WHERE id = ?
+            "#,
+        )
+        .bind(is_admin)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Makes the named accounts admins; unknown names are ignored.
+    pub async fn promote_admins(&self, usernames: &[String]) -> Result<()> {
+        for username in usernames {
+            sqlx::query(
+                r#"
+                UPDATE users SET is_admin = 1 WHERE username = ? AND is_bot = 0
+                "#,
+            )
+            .bind(username)
+            .execute(&self.pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Disables an account, or re-enables it with `None`.
+    pub async fn set_user_disabled(
+        &self,
+        user_id: &str,
+        disabled_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE users SET disabled_at = ? WHERE id = ?
+            "#,
+        )
+        .bind(disabled_at)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn server_counts(&self) -> Result<ServerCounts> {
+        let counts = sqlx::query_as::<_, ServerCounts>(
+            r#"
+            SELECT
+                (SELECT COUNT(*) FROM users WHERE is_bot = 0) AS users,
+                (SELECT COUNT(*) FROM users WHERE is_bot = 1) AS bots,
+                (SELECT COUNT(*) FROM users WHERE is_admin = 1) AS admins,
+                (SELECT COUNT(*) FROM users WHERE disabled_at IS NOT NULL) AS disabled_users,
+                (SELECT COUNT(*) FROM chat_rooms) AS rooms,
+                (SELECT COUNT(*) FROM messages WHERE deleted_at IS NULL) AS messages,
+                (SELECT COUNT(*) FROM messages
+                 WHERE deleted_at IS NULL AND created_at > ?) AS messages_last_24h,
+                (SELECT COUNT(*) FROM sessions
+                 WHERE revoked_at IS NULL AND expires_at > ?) AS active_sessions
+            "#,
+        )
+        .bind(Utc::now() - chrono::Duration::hours(24))
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(counts)
+    }
+
+    // Audit log operations
+    pub async fn create_audit_entry(&self, entry: &AuditEntry) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO audit_log (id, actor_id, action, target_type, target_id, details,
+                                   ip_address, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&entry.id)
+        .bind(&entry.actor_id)
+        .bind(&entry.action)
+        .bind(&entry.target_type)
+        .bind(&entry.target_id)
+        .bind(&entry.details)
+        .bind(&entry.ip_address)
+        .bind(entry.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Lists matching audit log entries, newest first or, for exports,
+    /// oldest first.
+    pub async fn query_audit_log(
+        &self,
+        filter: &AuditFilter,
+        oldest_first: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<AuditEntry>> {
+        let (action, action_prefix) = match filter.action.as_deref() {
+            Some(action) => match action.strip_suffix(".*") {
+                Some(prefix) => (None, Some(format!("{prefix}."))),
+                None => (Some(action.to_string()), None),
+            },
+            None => (None, None),
+        };
+        let order = if oldest_first { "ASC" } else { "DESC" };
+        let entries = sqlx::query_as::<_, AuditEntry>(&format!(
+            r#"
+            SELECT * FROM audit_log
+            WHERE (?1 IS NULL OR actor_id = ?1)
+              AND (?2 IS NULL OR action = ?2)
+              AND (?3 IS NULL OR substr(action, 1, length(?3)) = ?3)
+              AND (?4 IS NULL OR target_type = ?4)
+              AND (?5 IS NULL OR target_id = ?5)
+              AND (?6 IS NULL OR ip_address = ?6)
+              AND (?7 IS NULL OR created_at >= ?7)
+              AND (?8 IS NULL OR created_at < ?8)
+            ORDER BY created_at {order}, id {order}
+            LIMIT ?9 OFFSET ?10
+            "#
+        ))
+        .bind(&filter.actor_id)
+        .bind(action)
+        .bind(action_prefix)
+        .bind(&filter.target_type)
+        .bind(&filter.target_id)
+        .bind(&filter.ip_address)
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(entries)
+    }
+
+    // External identity operations
+    pub async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            SELECT u.* FROM users u
+            JOIN user_identities ui ON u.id = ui.user_id
+            WHERE ui.issuer = ? AND ui.subject = ?
+            "#,
+        )
+        .bind(issuer)
+        .bind(subject)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn create_user_identity(&self, identity: &UserIdentity) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO user_identities (id, user_id, issuer, subject, email, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&identity.id)
+        .bind(&identity.user_id)
+        .bind(&identity.issuer)
+        .bind(&identity.subject)
+        .bind(&identity.email)
+        .bind(identity.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Password reset operations
+    pub async fn create_password_reset_token(&self, token: &PasswordResetToken) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO password_reset_tokens (id, user_id, token_hash, created_at, expires_at, used_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&token.id)
+        .bind(&token.user_id)
+        .bind(&token.token_hash)
+        .bind(token.created_at)
+        .bind(token.expires_at)
+        .bind(token.used_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks an unused, unexpired reset token as used and returns the user it
+    /// belongs to. Returns `None` if the token is unknown, expired or spent.
+    pub async fn consume_password_reset_token(&self, token_hash: &str) -> Result<Option<String>> {
+        let now = Utc::now();
+        let user_id = sqlx::query_scalar::<_, String>(
+            r#"
+            UPDATE password_reset_tokens SET used_at = ?
+            WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?
+            RETURNING user_id
+            "#,
+        )
+        .bind(now)
+        .bind(token_hash)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user_id)
+    }
+
+    pub async fn delete_password_reset_tokens(&self, user_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            DELETE FROM password_reset_tokens WHERE user_id = ?
+            "#,
+        )
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Email verification operations
+    pub async fn create_email_verification_token(
+        &self,
+        token: &EmailVerificationToken,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO email_verification_tokens (id, user_id, email, token_hash, created_at, expires_at, used_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&token.id)
+        .bind(&token.user_id)
+        .bind(&token.email)
+        .bind(&token.token_hash)
+        .bind(token.created_at)
+        .bind(token.expires_at)
+        .bind(token.used_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Spends a verification token and marks the address it was issued for as
+    /// verified, provided the user still has that address. Returns the user id
+    /// on success.
+    pub async fn consume_email_verification_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<String>> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        let token = sqlx::query_as::<_, EmailVerificationToken>(
+            r#"
+            UPDATE email_verification_tokens SET used_at = ?
+            WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?
+            RETURNING *
+            "#,
+        )
+        .bind(now)
+        .bind(token_hash)
+        .bind(now)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(token) = token else {
+            return Ok(None);
+        };
+
+        let updated = sqlx::query(
+            r#"
+            UPDATE users SET email_verified = 1 WHERE id = ? AND email = ?
+            "#,
+        )
+        .bind(&token.user_id)
+        .bind(&token.email)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok((updated.rows_affected() > 0).then_some(token.user_id))
+    }
+
+    // Two-factor operations
+    /// Stores a new, not yet confirmed TOTP secret, or clears 2FA entirely
+    /// when `secret` is `None`.
+    pub async fn set_totp_secret(&self, user_id: &str, secret: Option<&str>) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE users SET totp_secret = ?, totp_enabled = 0, totp_last_step = NULL WHERE id = ?
+            "#,
+        )
+        .bind(secret)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn enable_totp(&self, user_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE users SET totp_enabled = 1 WHERE id = ? AND totp_secret IS NOT NULL
+            "#,
+        )
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records that the code for `step` was used. Returns `false` if that step
+    /// or a later one has already been accepted, i.e. the code is a replay.
+    pub async fn record_totp_step(&self, user_id: &str, step: i64) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE users SET totp_last_step = ?
+            WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)
+            "#,
+        )
+        .bind(step)
+        .bind(user_id)
+        .bind(step)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Replaces all of a user's recovery codes with a new set.
+    pub async fn replace_recovery_codes(
+        &self,
+        user_id: &str,
+        code_hashes: &[String],
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            r#"
+            DELETE FROM recovery_codes WHERE user_id = ?
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+        let now = Utc::now();
+        for code_hash in code_hashes {
+            sqlx::query(
+                r#"
+                INSERT INTO recovery_codes (id, user_id, code_hash, created_at, used_at)
+                VALUES (?, ?, ?, ?, NULL)
+                "#,
+            )
+            .bind(Uuid::new_v4().to_string())
+            .bind(user_id)
+            .bind(code_hash)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Marks an unused recovery code as used. Returns `false` if the user has
+    /// no such code or it was already spent.
+    pub async fn consume_recovery_code(&self, user_id: &str, code_hash: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE recovery_codes SET used_at = ?
+            WHERE user_id = ? AND code_hash = ? AND used_at IS NULL
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(user_id)
+        .bind(code_hash)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete_recovery_codes(&self, user_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            DELETE FROM recovery_codes WHERE user_id = ?
+            "#,
+        )
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn create_login_challenge(&self, challenge: &LoginChallenge) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO login_challenges (id, user_id, token_hash, created_at, expires_at, attempts)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&challenge.id)
+        .bind(&challenge.user_id)
+        .bind(&challenge.token_hash)
+        .bind(challenge.created_at)
+        .bind(challenge.expires_at)
+        .bind(challenge.attempts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Counts one attempt against an unexpired login challenge. Returns `None`
+    /// once the challenge is unknown, expired or out of attempts.
+    pub async fn attempt_login_challenge(
+        &self,
+        token_hash: &str,
+        max_attempts: i64,
+    ) -> Result<Option<LoginChallenge>> {
+        let challenge = sqlx::query_as::<_, LoginChallenge>(
+            r#"
+            UPDATE login_challenges SET attempts = attempts + 1
+            WHERE token_hash = ? AND expires_at > ? AND attempts < ?
+            RETURNING *
+            "#,
+        )
+        .bind(token_hash)
+        .bind(Utc::now())
+        .bind(max_attempts)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(challenge)
+    }
+
+    pub async fn delete_login_challenge(&self, challenge_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            DELETE FROM login_challenges WHERE id = ?
+            "#,
+        )
+        .bind(challenge_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Session operations
+    pub async fn create_session(&self, session: &Session) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO sessions (id, user_id, refresh_token_hash, created_at, last_used_at, expires_at, revoked_at,
+                                  device_label, ip_address, user_agent)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&session.id)
+        .bind(&session.user_id)
+        .bind(&session.refresh_token_hash)
+        .bind(session.created_at)
+        .bind(session.last_used_at)
+        .bind(session.expires_at)
+        .bind(session.revoked_at)
+        .bind(&session.device_label)
+        .bind(&session.ip_address)
+        .bind(&session.user_agent)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_session(&self, session_id: &str) -> Result<Option<Session>> {
+        let session = sqlx::query_as::<_, Session>(
+            r#"
+            SELECT * FROM sessions WHERE id = ?
+            "#,
+        )
+        .bind(session_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Lists a user's live sessions, most recently used first.
+    pub async fn get_user_sessions(&self, user_id: &str) -> Result<Vec<Session>> {
+        let sessions = sqlx::query_as::<_, Session>(
+            r#"
+            SELECT * FROM sessions
+            WHERE user_id = ? AND revoked_at IS NULL AND expires_at > ?
+            ORDER BY last_used_at DESC
+            "#,
+        )
+        .bind(user_id)
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sessions)
+    }
+
+    pub async fn touch_session(&self, session_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE sessions SET last_used_at = ? WHERE id = ?
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(session_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Swaps the refresh token of a live session for a new one and extends
+    /// its lifetime. Returns `None` if the old token is unknown, already
+    /// rotated, revoked or expired.
+    pub async fn rotate_refresh_token(
+        &self,
+        old_hash: &str,
+        new_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Option<Session>> {
+        let now = Utc::now();
+        let session = sqlx::query_as::<_, Session>(
+            r#"
+            UPDATE sessions SET refresh_token_hash = ?, last_used_at = ?, expires_at = ?
+            WHERE refresh_token_hash = ? AND revoked_at IS NULL AND expires_at > ?
+            RETURNING *
+            "#,
+        )
+        .bind(new_hash)
+        .bind(now)
+        .bind(expires_at)
+        .bind(old_hash)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    pub async fn revoke_session(&self, session_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE sessions SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(session_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revokes every live session of a user apart from `keep`, returning the
+    /// ids of the sessions that were ended.
+    pub async fn revoke_user_sessions(
+        &self,
+        user_id: &str,
+        keep: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let ids = sqlx::query_scalar::<_, String>(
+            r#"
+            UPDATE sessions SET revoked_at = ?
+            WHERE user_id = ? AND revoked_at IS NULL AND id IS NOT ?
+            RETURNING id
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(user_id)
+        .bind(keep)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(ids)
+    }
+
+    // Bot and API token operations
+    pub async fn get_bots_by_owner(&self, owner_id: &str) -> Result<Vec<User>> {
+        let bots = sqlx::query_as::<_, User>(
+            r#"
+            SELECT * FROM users WHERE is_bot = 1 AND bot_owner_id = ? ORDER BY created_at
+            "#,
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(bots)
+    }
+
+    /// Removes a bot account together with its API tokens.
+    /// Retires a bot: its tokens are revoked, its commands and room
+    /// memberships removed, and the account disabled and unlinked from its
+    /// owner. The row stays, since its messages and files still point at it.
+    pub async fn delete_bot(&self, bot_id: &str) -> Result<()> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE api_tokens SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL
+            "#,
+        )
+        .bind(now)
+        .bind(bot_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM bot_commands WHERE bot_id = ?
+            "#,
+        )
+        .bind(bot_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM room_members WHERE user_id = ?
+            "#,
+        )
+        .bind(bot_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE users SET disabled_at = ?, bot_owner_id = NULL WHERE id = ? AND is_bot = 1
+            "#,
+        )
+        .bind(now)
+        .bind(bot_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn create_api_token(&self, token: &ApiToken) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO api_tokens (id, user_id, name, token_hash, scopes, created_at, last_used_at, revoked_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&token.id)
+        .bind(&token.user_id)
+        .bind(&token.name)
+        .bind(&token.token_hash)
+        .bind(&token.scopes)
+        .bind(token.created_at)
+        .bind(token.last_used_at)
+        .bind(token.revoked_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Looks up an unrevoked API token by the hash of its secret.
+    pub async fn get_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        let token = sqlx::query_as::<_, ApiToken>(
+            r#"
+            SELECT * FROM api_tokens WHERE token_hash = ? AND revoked_at IS NULL
+            "#,
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Lists a user's unrevoked API tokens, newest first.
+    pub async fn get_user_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>> {
+        let tokens = sqlx::query_as::<_, ApiToken>(
+            r#"
+            SELECT * FROM api_tokens
+            WHERE user_id = ? AND revoked_at IS NULL
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(tokens)
+    }
+
+    pub async fn touch_api_token(&self, token_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE api_tokens SET last_used_at = ? WHERE id = ?
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(token_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revokes one of a user's API tokens. Returns `false` if there was no
+    /// such live token.
+    pub async fn revoke_api_token(&self, user_id: &str, token_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE api_tokens SET revoked_at = ?
+            WHERE id = ? AND user_id = ? AND revoked_at IS NULL
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(token_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Chat room operations
+    pub async fn create_chat_room(&self, room: &ChatRoom) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO chat_rooms (id, name, room_type, created_by, created_at, description)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&room.id)
+        .bind(&room.name)
+        .bind(&room.room_type)
+        .bind(&room.created_by)
+        .bind(room.created_at)
+        .bind(&room.description)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_chat_room(&self, room_id: &str) -> Result<Option<ChatRoom>> {
+        let room = sqlx::query_as::<_, ChatRoom>(
+            r#"
+            SELECT * FROM chat_rooms WHERE id = ?
+            "#,
+        )
+        .bind(room_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(room)
+    }
+
+    /// Removes a room with its messages, members, webhooks, sanctions,
+    /// custom roles and file records; the stored files are the caller's to
+    /// delete.
+    pub async fn delete_chat_room(&self, room_id: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for statement in [
+            "DELETE FROM files WHERE room_id = ?",
+            "DELETE FROM message_reports WHERE room_id = ?",
+            "DELETE FROM held_messages WHERE room_id = ?",
+            "DELETE FROM room_word_filters WHERE room_id = ?",
+            "DELETE FROM messages WHERE room_id = ?",
+            "DELETE FROM room_members WHERE room_id = ?",
+            "DELETE FROM webhook_deliveries WHERE webhook_id IN \
+             (SELECT id FROM room_webhooks WHERE room_id = ?)",
+            "DELETE FROM room_webhooks WHERE room_id = ?",
+            "DELETE FROM incoming_webhooks WHERE room_id = ?",
+            "DELETE FROM room_sanctions WHERE room_id = ?",
+            "DELETE FROM room_roles WHERE room_id = ?",
+            "DELETE FROM chat_rooms WHERE id = ?",
+        ] {
+            sqlx::query(statement)
+                .bind(room_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn update_room_description(
+        &self,
+        room_id: &str,
+        description: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE chat_rooms SET description = ? WHERE id = ?
+            "#,
+        )
+        .bind(description)
+        .bind(room_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn update_room_settings(&self, room: &ChatRoom) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE chat_rooms SET slow_mode_seconds = ?, read_only = ?, members_only = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(room.slow_mode_seconds)
+        .bind(room.read_only)
+        .bind(room.members_only)
+        .bind(&room.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_channel_room(&self, name: &str) -> Result<Option<ChatRoom>> {
+        let room = sqlx::query_as::<_, ChatRoom>(
+            r#"
+            SELECT * FROM chat_rooms WHERE name = ? AND room_type = 'channel'
+            "#,
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(room)
+    }
+
+    pub async fn get_user_chat_rooms(&self, user_id: &str) -> Result<Vec<ChatRoom>> {
+        let rooms = sqlx::query_as::<_, ChatRoom>(
+            r#"
+            SELECT cr.* FROM chat_rooms cr
+            JOIN room_members rm ON cr.id = rm.room_id
+            WHERE rm.user_id = ?
+            ORDER BY cr.created_at DESC
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rooms)
+    }
+
+    pub async fn get_direct_chat_room(
+        &self,
+        user1_id: &str,
+        user2_id: &str,
+    ) -> Result<Option<ChatRoom>> {
+        let room = sqlx::query_as::<_, ChatRoom>(
+            r#"
+            SELECT cr.* FROM chat_rooms cr
+            JOIN room_members rm1 ON cr.id = rm1.room_id
+            JOIN room_members rm2 ON cr.id = rm2.room_id
+            WHERE cr.room_type = 'direct'
+            AND rm1.user_id = ? AND rm2.user_id = ?
+            "#,
+        )
+        .bind(user1_id)
+        .bind(user2_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(room)
+    }
+
+    // Room member operations
+    /// Adds a member unless they already belong to the room.
+    pub async fn add_room_member(&self, member: &RoomMember) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO room_members (id, room_id, user_id, joined_at, role)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (room_id, user_id) DO NOTHING
+            "#,
+        )
+        .bind(&member.id)
+        .bind(&member.room_id)
+        .bind(&member.user_id)
+        .bind(member.joined_at)
+        .bind(&member.role)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_room_member(&self, room_id: &str, user_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            DELETE FROM room_members WHERE room_id = ? AND user_id = ?
+            "#,
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_room_member(
+        &self,
+        room_id: &str,
+        user_id: &str,
+    ) -> Result<Option<RoomMember>> {
+        let member = sqlx::query_as::<_, RoomMember>(
+            r#"
+            SELECT * FROM room_members WHERE room_id = ? AND user_id = ?
+            "#,
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(member)
+    }
+
+    pub async fn get_room_members(&self, room_id: &str) -> Result<Vec<User>> {
+        let users = sqlx::query_as::<_, User>(
+            r#"
+            SELECT u.* FROM users u
+            JOIN room_members rm ON u.id = rm.user_id
+            WHERE rm.room_id = ?
+            ORDER BY u.username
+            "#,
+        )
+        .bind(room_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users)
+    }
+
+    /// Lists a room's memberships, oldest first.
+    pub async fn get_room_member_entries(&self, room_id: &str) -> Result<Vec<RoomMember>> {
+        let members = sqlx::query_as::<_, RoomMember>(
+            r#"
+            SELECT * FROM room_members WHERE room_id = ? ORDER BY joined_at
+            "#,
+        )
+        .bind(room_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    pub async fn update_member_role(&self, room_id: &str, user_id: &str, role: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE room_members SET role = ? WHERE room_id = ? AND user_id = ?
+            "#,
+        )
+        .bind(role)
+        .bind(room_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Room role operations
+    pub async fn create_room_role(&self, role: &RoomRole) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO room_roles (id, room_id, name, permissions, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&role.id)
+        .bind(&role.room_id)
+        .bind(&role.name)
+        .bind(&role.permissions)
+        .bind(role.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_room_roles(&self, room_id: &str) -> Result<Vec<RoomRole>> {
+        let roles = sqlx::query_as::<_, RoomRole>(
+            r#"
+            SELECT * FROM room_roles WHERE room_id = ? ORDER BY name
+            "#,
+        )
+        .bind(room_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(roles)
+    }
+
+    pub async fn get_room_role_by_name(
+        &self,
+        room_id: &str,
+        name: &str,
+    ) -> Result<Option<RoomRole>> {
+        let role = sqlx::query_as::<_, RoomRole>(
+            r#"
+            SELECT * FROM room_roles WHERE room_id = ? AND name = ?
+            "#,
+        )
+        .bind(room_id)
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(role)
+    }
+
+    pub async fn update_room_role_permissions(
+        &self,
+        role_id: &str,
+        permissions: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE room_roles SET permissions = ? WHERE id = ?
+            "#,
+        )
+        .bind(permissions)
+        .bind(role_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes a custom role; members who had it become plain members.
+    pub async fn delete_room_role(&self, role: &RoomRole) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE room_members SET role = 'member' WHERE room_id = ? AND role = ?
+            "#,
+        )
+        .bind(&role.room_id)
+        .bind(&role.name)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM room_roles WHERE id = ?
+            "#,
+        )
+        .bind(&role.id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Message operations
+    pub async fn create_message(&self, message: &Message) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO messages (id, room_id, sender_id, content, message_type, created_at, edited_at,
+                                  reply_to, integration_id, sender_name, attachments)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&message.id)
+        .bind(&message.room_id)
+        .bind(&message.sender_id)
+        .bind(&message.content)
+        .bind(&message.message_type)
+        .bind(message.created_at)
+        .bind(message.edited_at)
+        .bind(&message.reply_to)
+        .bind(&message.integration_id)
+        .bind(&message.sender_name)
+        .bind(&message.attachments)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_messages(
+        &self,
+        room_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Message>> {
+        let messages = sqlx::query_as::<_, Message>(
+            r#"
+            SELECT m.* FROM messages m
+            WHERE m.room_id = ? AND m.deleted_at IS NULL
+            ORDER BY m.created_at DESC
+            LIMIT ? OFFSET ?
+            "#,
+        )
+        .bind(room_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(messages)
+    }
+
+    pub async fn get_message(&self, message_id: &str) -> Result<Option<Message>> {
+        let message = sqlx::query_as::<_, Message>(
+            r#"
+            SELECT * FROM messages WHERE id = ?
+            "#,
+        )
+        .bind(message_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(message)
+    }
+
+    pub async fn update_message_content(&self, message_id: &str, content: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE messages SET content = ?, edited_at = ? WHERE id = ?
+            "#,
+        )
+        .bind(content)
+        .bind(Utc::now())
+        .bind(message_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Blanks a message and marks it deleted.
+    pub async fn delete_message(&self, message_id: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE messages SET content = '', link_previews = NULL, deleted_at = ? WHERE id = ?
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(message_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Sets a message's link previews, unless its content has changed from
+    /// `content` since they were made. Returns whether it was updated.
+    pub async fn set_message_previews(
+        &self,
+        message_id: &str,
+        content: &str,
+        previews: &[LinkPreview],
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE messages SET link_previews = ?
+            WHERE id = ? AND content = ? AND deleted_at IS NULL
+            "#,
+        )
+        .bind((!previews.is_empty()).then_some(Json(previews)))
+        .bind(message_id)
+        .bind(content)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Link preview operations
+    /// A cached preview of `url` fetched after `fetched_after`.
+    pub async fn get_link_preview(
+        &self,
+        url: &str,
+        fetched_after: DateTime<Utc>,
+    ) -> Result<Option<LinkPreview>> {
+        let preview = sqlx::query_as::<_, LinkPreview>(
+            r#"
+            SELECT * FROM link_previews WHERE url = ? AND fetched_at > ?
+            "#,
+        )
+        .bind(url)
+        .bind(fetched_after)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(preview)
+    }
+
+    pub async fn save_link_preview(&self, preview: &LinkPreview) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO link_previews (url, title, description, image_url, site_name, fetched_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (url) DO UPDATE SET
+                title = excluded.title,
+                description = excluded.description,
+                image_url = excluded.image_url,
+                site_name = excluded.site_name,
+                fetched_at = excluded.fetched_at
+            "#,
+        )
+        .bind(&preview.url)
+        .bind(&preview.title)
+        .bind(&preview.description)
+        .bind(&preview.image_url)
+        .bind(&preview.site_name)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Forgets previews fetched before `cutoff`.
+    pub async fn prune_link_previews(&self, cutoff: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM link_previews WHERE fetched_at < ?
+            "#,
+        )
+        .bind(cutoff)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    // Webhook operations
+    pub async fn create_webhook(&self, webhook: &Webhook) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO room_webhooks (id, room_id, url, secret, events, created_by, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&webhook.id)
+        .bind(&webhook.room_id)
+        .bind(&webhook.url)
+        .bind(&webhook.secret)
+        .bind(&webhook.events)
+        .bind(&webhook.created_by)
+        .bind(webhook.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_webhook(&self, webhook_id: &str) -> Result<Option<Webhook>> {
+        let webhook = sqlx::query_as::<_, Webhook>(
+            r#"
+            SELECT * FROM room_webhooks WHERE id = ?
+            "#,
+        )
+        .bind(webhook_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(webhook)
+    }
+
+    pub async fn get_room_webhooks(&self, room_id: &str) -> Result<Vec<Webhook>> {
+        let webhooks = sqlx::query_as::<_, Webhook>(
+            r#"
+            SELECT * FROM room_webhooks WHERE room_id = ? ORDER BY created_at
+            "#,
+        )
+        .bind(room_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(webhooks)
+    }
+
+    /// Removes a webhook together with its delivery log.
+    pub async fn delete_webhook(&self, webhook_id: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            DELETE FROM webhook_deliveries WHERE webhook_id = ?
+            "#,
+        )
+        .bind(webhook_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM room_webhooks WHERE id = ?
+            "#,
+        )
+        .bind(webhook_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn create_webhook_delivery(&self, delivery: &WebhookDelivery) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO webhook_deliveries (id, webhook_id, event, payload, status, attempts, next_attempt_at,
+                                            response_status, last_error, created_at, delivered_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&delivery.id)
+        .bind(&delivery.webhook_id)
+        .bind(&delivery.event)
+        .bind(&delivery.payload)
+        .bind(&delivery.status)
+        .bind(delivery.attempts)
+        .bind(delivery.next_attempt_at)
+        .bind(delivery.response_status)
+        .bind(&delivery.last_error)
+        .bind(delivery.created_at)
+        .bind(delivery.delivered_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_webhook_delivery(&self, delivery_id: &str) -> Result<Option<WebhookDelivery>> {
+        let delivery = sqlx::query_as::<_, WebhookDelivery>(
+            r#"
+            SELECT * FROM webhook_deliveries WHERE id = ?
+            "#,
+        )
+        .bind(delivery_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(delivery)
+    }
+
+    /// Lists a webhook's most recent deliveries, newest first.
+    pub async fn get_webhook_deliveries(
+        &self,
+        webhook_id: &str,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>> {
+        let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+            r#"
+            SELECT * FROM webhook_deliveries
+            WHERE webhook_id = ?
+            ORDER BY created_at DESC
+            LIMIT ?
+            "#,
+        )
+        .bind(webhook_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(deliveries)
+    }
+
+    /// Pending deliveries whose next attempt is due, oldest first.
+    pub async fn get_due_webhook_deliveries(&self, limit: i64) -> Result<Vec<WebhookDelivery>> {
+        let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+            r#"
+            SELECT * FROM webhook_deliveries
+            WHERE status = 'pending' AND next_attempt_at <= ?
+            ORDER BY next_attempt_at
+            LIMIT ?
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(deliveries)
+    }
+
+    /// Records the outcome of one delivery attempt.
+    pub async fn record_webhook_attempt(&self, delivery: &WebhookDelivery) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = ?, attempts = ?, next_attempt_at = ?, response_status = ?, last_error = ?,
+                delivered_at = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(&delivery.status)
+        .bind(delivery.attempts)
+        .bind(delivery.next_attempt_at)
+        .bind(delivery.response_status)
+        .bind(&delivery.last_error)
+        .bind(delivery.delivered_at)
+        .bind(&delivery.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Room sanction operations
+    pub async fn create_room_sanction(&self, sanction: &RoomSanction) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO room_sanctions (id, room_id, user_id, kind, reason, actor_id, created_at,
+                                        expires_at, lifted_at, lifted_by)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&sanction.id)
+        .bind(&sanction.room_id)
+        .bind(&sanction.user_id)
+        .bind(&sanction.kind)
+        .bind(&sanction.reason)
+        .bind(&sanction.actor_id)
+        .bind(sanction.created_at)
+        .bind(sanction.expires_at)
+        .bind(sanction.lifted_at)
+        .bind(&sanction.lifted_by)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Lists a room's bans, mutes and timeouts that are still in force,
+    /// optionally for one user only. Kicks are never active.
+    pub async fn get_active_room_sanctions(
+        &self,
+        room_id: &str,
+        user_id: Option<&str>,
+    ) -> Result<Vec<RoomSanction>> {
+        let sanctions = sqlx::query_as::<_, RoomSanction>(
+            r#"
+            SELECT * FROM room_sanctions
+            WHERE room_id = ? AND (? IS NULL OR user_id = ?)
+              AND kind != 'kick' AND lifted_at IS NULL
+              AND (expires_at IS NULL OR expires_at > ?)
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .bind(user_id)
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sanctions)
+    }
+
+    /// Lifts an active sanction in a room. Returns `false` if there was no
+    /// such sanction in force.
+    pub async fn lift_room_sanction(
+        &self,
+        room_id: &str,
+        sanction_id: &str,
+        lifted_by: &str,
+    ) -> Result<bool> {
+        let now = Utc::now();
+        let result = sqlx::query(
+            r#"
+            UPDATE room_sanctions SET lifted_at = ?, lifted_by = ?
+            WHERE id = ? AND room_id = ? AND kind != 'kick' AND lifted_at IS NULL
+              AND (expires_at IS NULL OR expires_at > ?)
+            "#,
+        )
+        .bind(now)
+        .bind(lifted_by)
+        .bind(sanction_id)
+        .bind(room_id)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Message report operations
+    /// Stores a report. Returns `false` if the reporter has already
+    /// reported the message.
+    pub async fn create_message_report(&self, report: &MessageReport) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO message_reports (id, message_id, room_id, reporter_id, sender_id, content,
+                                         reason, comment, status, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (message_id, reporter_id) DO NOTHING
+            "#,
+        )
+        .bind(&report.id)
+        .bind(&report.message_id)
+        .bind(&report.room_id)
+        .bind(&report.reporter_id)
+        .bind(&report.sender_id)
+        .bind(&report.content)
+        .bind(&report.reason)
+        .bind(&report.comment)
+        .bind(&report.status)
+        .bind(report.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn get_message_report(&self, report_id: &str) -> Result<Option<MessageReport>> {
+        let report = sqlx::query_as::<_, MessageReport>(
+            r#"
+            SELECT * FROM message_reports WHERE id = ?
+            "#,
+        )
+        .bind(report_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(report)
+    }
+
+    /// Lists reports oldest first, so the queue is worked in order, in one
+    /// room or across the server and optionally with one status only.
+    pub async fn get_message_reports(
+        &self,
+        room_id: Option<&str>,
+        status: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<MessageReport>> {
+        let reports = sqlx::query_as::<_, MessageReport>(
+            r#"
+            SELECT * FROM message_reports
+            WHERE (?1 IS NULL OR room_id = ?1) AND (?2 IS NULL OR status = ?2)
+            ORDER BY created_at ASC, id ASC
+            LIMIT ?3 OFFSET ?4
+            "#,
+        )
+        .bind(room_id)
+        .bind(status)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(reports)
+    }
+
+    pub async fn get_user_message_reports(
+        &self,
+        reporter_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<MessageReport>> {
+        let reports = sqlx::query_as::<_, MessageReport>(
+            r#"
+            SELECT * FROM message_reports WHERE reporter_id = ?
+            ORDER BY created_at DESC, id DESC
+            LIMIT ? OFFSET ?
+            "#,
+        )
+        .bind(reporter_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
 
-        Ok(Self { pool })
+        Ok(reports)
     }
 
-    pub async fn init(&self) -> Result<()> {
-        sqlx::query(
+    /// Resolves every open report of a message in one go and returns them.
+    pub async fn resolve_message_reports(
+        &self,
+        message_id: &str,
+        resolution: &str,
+        note: Option<&str>,
+        sanction_id: Option<&str>,
+        resolved_by: &str,
+    ) -> Result<Vec<MessageReport>> {
+        let reports = sqlx::query_as::<_, MessageReport>(
             r#"
-            CREATE TABLE IF NOT EXISTS users (
-                id TEXT PRIMARY KEY,
-                username TEXT UNIQUE NOT NULL,
-                email TEXT UNIQUE NOT NULL,
-                password_hash TEXT NOT NULL,
-                created_at DATETIME NOT NULL,
-                last_seen DATETIME,
-                status TEXT DEFAULT 'offline'
-            )
+            UPDATE message_reports
+            SET status = 'resolved', resolution = ?, resolution_note = ?, sanction_id = ?,
+                resolved_by = ?, resolved_at = ?
+            WHERE message_id = ? AND status = 'open'
+            RETURNING *
             "#,
         )
-        .execute(&self.pool)
+        .bind(resolution)
+        .bind(note)
+        .bind(sanction_id)
+        .bind(resolved_by)
+        .bind(Utc::now())
+        .bind(message_id)
+        .fetch_all(&self.pool)
         .await?;
 
-        sqlx::query(
+        Ok(reports)
+    }
+
+    // Content filter operations
+    pub async fn get_room_word_filters(&self, room_id: &str) -> Result<Vec<RoomWordFilter>> {
+        let filters = sqlx::query_as::<_, RoomWordFilter>(
             r#"
-            CREATE TABLE IF NOT EXISTS chat_rooms (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL,
-                room_type TEXT NOT NULL,
-                created_by TEXT NOT NULL,
-                created_at DATETIME NOT NULL,
-                description TEXT,
-                FOREIGN KEY (created_by) REFERENCES users (id)
-            )
+            SELECT * FROM room_word_filters WHERE room_id = ? ORDER BY word
             "#,
         )
-        .execute(&self.pool)
+        .bind(room_id)
+        .fetch_all(&self.pool)
         .await?;
 
+        Ok(filters)
+    }
+
+    /// Adds a word to a room's filters, or changes its mode if it is
+    /// already there.
+    pub async fn upsert_room_word_filter(&self, filter: &RoomWordFilter) -> Result<RoomWordFilter> {
         sqlx::query(
             r#"
-            CREATE TABLE IF NOT EXISTS room_members (
-                id TEXT PRIMARY KEY,
-                room_id TEXT NOT NULL,
-                user_id TEXT NOT NULL,
-                joined_at DATETIME NOT NULL,
-                role TEXT DEFAULT 'member',
-                FOREIGN KEY (room_id) REFERENCES chat_rooms (id),
-                FOREIGN KEY (user_id) REFERENCES users (id),
-                UNIQUE(room_id, user_id)
-            )
+            INSERT INTO room_word_filters (id, room_id, word, mode, created_by, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (room_id, word) DO UPDATE
+            SET mode = excluded.mode, created_by = excluded.created_by,
+                created_at = excluded.created_at
             "#,
         )
+        .bind(&filter.id)
+        .bind(&filter.room_id)
+        .bind(&filter.word)
+        .bind(&filter.mode)
+        .bind(&filter.created_by)
+        .bind(filter.created_at)
         .execute(&self.pool)
         .await?;
 
-        sqlx::query(
+        // Not RETURNING: a statement read for one row is left unfinished,
+        // and other connections may not see the write yet.
+        let filter = sqlx::query_as::<_, RoomWordFilter>(
             r#"
-            CREATE TABLE IF NOT EXISTS messages (
-                id TEXT PRIMARY KEY,
-                room_id TEXT NOT NULL,
-                sender_id TEXT NOT NULL,
-                content TEXT NOT NULL,
-                message_type TEXT DEFAULT 'text',
-                created_at DATETIME NOT NULL,
-                edited_at DATETIME,
-                reply_to TEXT,
-                FOREIGN KEY (room_id) REFERENCES chat_rooms (id),
-                FOREIGN KEY (sender_id) REFERENCES users (id),
-                FOREIGN KEY (reply_to) REFERENCES messages (id)
-            )
+            SELECT * FROM room_word_filters WHERE room_id = ? AND word = ?
             "#,
         )
-        .execute(&self.pool)
+        .bind(&filter.room_id)
+        .bind(&filter.word)
+        .fetch_one(&self.pool)
         .await?;
 
-        sqlx::query(
+        Ok(filter)
+    }
+
+    /// Returns `false` if the room has no such filter.
+    pub async fn delete_room_word_filter(&self, room_id: &str, filter_id: &str) -> Result<bool> {
+        let result = sqlx::query(
             r#"
-            CREATE TABLE IF NOT EXISTS friendships (
-                id TEXT PRIMARY KEY,
-                user_id TEXT NOT NULL,
-                friend_id TEXT NOT NULL,
-                status TEXT NOT NULL,
-                created_at DATETIME NOT NULL,
-                updated_at DATETIME NOT NULL,
-                FOREIGN KEY (user_id) REFERENCES users (id),
-                FOREIGN KEY (friend_id) REFERENCES users (id),
-                UNIQUE(user_id, friend_id)
-            )
+            DELETE FROM room_word_filters WHERE id = ? AND room_id = ?
             "#,
         )
+        .bind(filter_id)
+        .bind(room_id)
         .execute(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 
-    // User operations
-    pub async fn create_user(&self, user: &User) -> Result<()> {
+    pub async fn create_held_message(&self, held: &HeldMessage) -> Result<()> {
         sqlx::query(
             r#"
-            INSERT INTO users (id, username, email, password_hash, created_at, last_seen, status)
-            VALUES (?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO held_messages (id, room_id, sender_id, username, content, message_type,
+                                       is_bot, integration_id, attachments, filter, reason,
+                                       status, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             "#,
         )
-        .bind(&user.id)
-        .bind(&user.username)
-        .bind(&user.email)
-        .bind(&user.password_hash)
-        .bind(user.created_at)
-        .bind(user.last_seen)
-        .bind(&user.status)
+        .bind(&held.id)
+        .bind(&held.room_id)
+        .bind(&held.sender_id)
+        .bind(&held.username)
+        .bind(&held.content)
+        .bind(&held.message_type)
+        .bind(held.is_bot)
+        .bind(&held.integration_id)
+        .bind(&held.attachments)
+        .bind(&held.filter)
+        .bind(&held.reason)
+        .bind(&held.status)
+        .bind(held.created_at)
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
-    pub async fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
-        let user = sqlx::query_as::<_, User>(
+    pub async fn get_held_message(&self, held_id: &str) -> Result<Option<HeldMessage>> {
+        let held = sqlx::query_as::<_, HeldMessage>(
             r#"
-            SELECT * FROM users WHERE username = ?
+            SELECT * FROM held_messages WHERE id = ?
             "#,
         )
-        .bind(username)
+        .bind(held_id)
         .fetch_optional(&self.pool)
         .await?;
 
-        Ok(user)
+        Ok(held)
     }
 
-    pub async fn get_user_by_id(&self, user_id: &str) -> Result<Option<User>> {
-        let user = sqlx::query_as::<_, User>(
+    /// Lists the messages of a room still waiting for review, oldest first.
+    pub async fn get_pending_held_messages(
+        &self,
+        room_id: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<HeldMessage>> {
+        let held = sqlx::query_as::<_, HeldMessage>(
             r#"
-            SELECT * FROM users WHERE id = ?
+            SELECT * FROM held_messages WHERE room_id = ? AND status = 'held'
+            ORDER BY created_at ASC, id ASC
+            LIMIT ? OFFSET ?
             "#,
         )
-        .bind(user_id)
-        .fetch_optional(&self.pool)
+        .bind(room_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
         .await?;
 
-        Ok(user)
+        Ok(held)
     }
 
-    pub async fn update_user_status(&self, user_id: &str, status: &str) -> Result<()> {
-        sqlx::query(
+    /// Approves or rejects a held message. Returns `None` if it had
+    /// already been reviewed.
+    pub async fn review_held_message(
+        &self,
+        held_id: &str,
+        status: &str,
+        reviewed_by: &str,
+    ) -> Result<Option<HeldMessage>> {
+        let held = sqlx::query_as::<_, HeldMessage>(
             r#"
-            UPDATE users SET status = ?, last_seen = ? WHERE id = ?
+            UPDATE held_messages SET status = ?, reviewed_by = ?, reviewed_at = ?
+            WHERE id = ? AND status = 'held'
+            RETURNING *
             "#,
         )
         .bind(status)
+        .bind(reviewed_by)
         .bind(Utc::now())
-        .bind(user_id)
-        .execute(&self.pool)
+        .bind(held_id)
+        .fetch_optional(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(held)
     }
 
-    // Chat room operations
-    pub async fn create_chat_room(&self, room: &ChatRoom) -> Result<()> {
+    // File operations
+    pub async fn create_file(&self, file: &File) -> Result<()> {
         sqlx::query(
             r#"
-            INSERT INTO chat_rooms (id, name, room_type, created_by, created_at, description)
-            VALUES (?, ?, ?, ?, ?, ?)
+            INSERT INTO files (id, room_id, uploader_id, message_id, storage_key, filename,
+                               content_type, size_bytes, created_at, width, height, blurhash,
+                               thumbnail_type)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             "#,
         )
-        .bind(&room.id)
-        .bind(&room.name)
-        .bind(&room.room_type)
-        .bind(&room.created_by)
-        .bind(room.created_at)
-        .bind(&room.description)
+        .bind(&file.id)
+        .bind(&file.room_id)
+        .bind(&file.uploader_id)
+        .bind(&file.message_id)
+        .bind(&file.storage_key)
+        .bind(&file.filename)
+        .bind(&file.content_type)
+        .bind(file.size_bytes)
+        .bind(file.created_at)
+        .bind(file.width)
+        .bind(file.height)
+        .bind(&file.blurhash)
+        .bind(&file.thumbnail_type)
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
-    pub async fn get_chat_room(&self, room_id: &str) -> Result<Option<ChatRoom>> {
-        let room = sqlx::query_as::<_, ChatRoom>(
+    pub async fn get_file(&self, file_id: &str) -> Result<Option<File>> {
+        let file = sqlx::query_as::<_, File>(
             r#"
-            SELECT * FROM chat_rooms WHERE id = ?
+            SELECT * FROM files WHERE id = ?
             "#,
         )
-        .bind(room_id)
+        .bind(file_id)
         .fetch_optional(&self.pool)
         .await?;
 
-        Ok(room)
+        Ok(file)
     }
 
-    pub async fn get_user_chat_rooms(&self, user_id: &str) -> Result<Vec<ChatRoom>> {
-        let rooms = sqlx::query_as::<_, ChatRoom>(
+    pub async fn get_room_files(&self, room_id: &str) -> Result<Vec<File>> {
+        let files = sqlx::query_as::<_, File>(
             r#"
-            SELECT cr.* FROM chat_rooms cr
-            JOIN room_members rm ON cr.id = rm.room_id
-            WHERE rm.user_id = ?
-            ORDER BY cr.created_at DESC
+            SELECT * FROM files WHERE room_id = ?
             "#,
         )
-        .bind(user_id)
+        .bind(room_id)
         .fetch_all(&self.pool)
         .await?;
 
-        Ok(rooms)
+        Ok(files)
     }
 
-    pub async fn get_direct_chat_room(
-        &self,
-        user1_id: &str,
-        user2_id: &str,
-    ) -> Result<Option<ChatRoom>> {
-        let room = sqlx::query_as::<_, ChatRoom>(
+    pub async fn get_message_files(&self, message_id: &str) -> Result<Vec<File>> {
+        let files = sqlx::query_as::<_, File>(
             r#"
-            SELECT cr.* FROM chat_rooms cr
-            JOIN room_members rm1 ON cr.id = rm1.room_id
-            JOIN room_members rm2 ON cr.id = rm2.room_id
-            WHERE cr.room_type = 'direct'
-            AND rm1.user_id = ? AND rm2.user_id = ?
+            SELECT * FROM files WHERE message_id = ?
             "#,
         )
-        .bind(user1_id)
-        .bind(user2_id)
-        .fetch_optional(&self.pool)
+        .bind(message_id)
+        .fetch_all(&self.pool)
         .await?;
 
-        Ok(room)
+        Ok(files)
     }
 
-    // Room member operations
-    pub async fn add_room_member(&self, member: &RoomMember) -> Result<()> {
+    /// Links a file to the message that carries it, if the file was
+    /// uploaded to the message's room and is not linked yet.
+    pub async fn link_file(&self, file_id: &str, room_id: &str, message_id: &str) -> Result<()> {
         sqlx::query(
             r#"
-            INSERT INTO room_members (id, room_id, user_id, joined_at, role)
-            VALUES (?, ?, ?, ?, ?)
+            UPDATE files SET message_id = ?
+            WHERE id = ? AND room_id = ? AND message_id IS NULL
             "#,
         )
-        .bind(&member.id)
-        .bind(&member.room_id)
-        .bind(&member.user_id)
-        .bind(member.joined_at)
-        .bind(&member.role)
+        .bind(message_id)
+        .bind(file_id)
+        .bind(room_id)
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
-    pub async fn get_room_members(&self, room_id: &str) -> Result<Vec<User>> {
-        let users = sqlx::query_as::<_, User>(
+    pub async fn delete_file(&self, file_id: &str) -> Result<()> {
+        sqlx::query(
             r#"
-            SELECT u.* FROM users u
-            JOIN room_members rm ON u.id = rm.user_id
-            WHERE rm.room_id = ?
-            ORDER BY u.username
+            DELETE FROM files WHERE id = ?
             "#,
         )
-        .bind(room_id)
+        .bind(file_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Bot command operations
+    pub async fn create_bot_command(&self, command: &BotCommand) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO bot_commands (id, bot_id, name, usage, description, callback_url, secret, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&command.id)
+        .bind(&command.bot_id)
+        .bind(&command.name)
+        .bind(&command.usage)
+        .bind(&command.description)
+        .bind(&command.callback_url)
+        .bind(&command.secret)
+        .bind(command.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_bot_command_by_name(&self, name: &str) -> Result<Option<BotCommand>> {
+        let command = sqlx::query_as::<_, BotCommand>(
+            r#"
+            SELECT * FROM bot_commands WHERE name = ?
+            "#,
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(command)
+    }
+
+    /// Lists the commands of one bot, or of every bot when `bot_id` is `None`.
+    pub async fn get_bot_commands(&self, bot_id: Option<&str>) -> Result<Vec<BotCommand>> {
+        let commands = sqlx::query_as::<_, BotCommand>(
+            r#"
+            SELECT * FROM bot_commands
+            WHERE ? IS NULL OR bot_id = ?
+            ORDER BY name
+            "#,
+        )
+        .bind(bot_id)
+        .bind(bot_id)
         .fetch_all(&self.pool)
         .await?;
 
-        Ok(users)
+        Ok(commands)
     }
 
-    // Message operations
-    pub async fn create_message(&self, message: &Message) -> Result<()> {
+    /// Removes one of a bot's commands. Returns `false` if it had no such
+    /// command.
+    pub async fn delete_bot_command(&self, bot_id: &str, command_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM bot_commands WHERE id = ? AND bot_id = ?
+            "#,
+        )
+        .bind(command_id)
+        .bind(bot_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Incoming webhook operations
+    pub async fn create_incoming_webhook(&self, webhook: &IncomingWebhook) -> Result<()> {
         sqlx::query(
             r#"
-            INSERT INTO messages (id, room_id, sender_id, content, message_type, created_at, edited_at, reply_to)
+            INSERT INTO incoming_webhooks (id, room_id, name, token_hash, created_by, created_at, last_used_at, revoked_at)
             VALUES (?, ?, ?, ?, ?, ?, ?, ?)
             "#,
         )
-        .bind(&message.id)
-        .bind(&message.room_id)
-        .bind(&message.sender_id)
-        .bind(&message.content)
-        .bind(&message.message_type)
-        .bind(message.created_at)
-        .bind(message.edited_at)
-        .bind(&message.reply_to)
+        .bind(&webhook.id)
+        .bind(&webhook.room_id)
+        .bind(&webhook.name)
+        .bind(&webhook.token_hash)
+        .bind(&webhook.created_by)
+        .bind(webhook.created_at)
+        .bind(webhook.last_used_at)
+        .bind(webhook.revoked_at)
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
-    pub async fn get_messages(
+    /// Looks up an unrevoked incoming webhook by the hash of its token.
+    pub async fn get_incoming_webhook_by_hash(
         &self,
-        room_id: &str,
-        limit: i64,
-        offset: i64,
-    ) -> Result<Vec<Message>> {
-        let messages = sqlx::query_as::<_, Message>(
+        token_hash: &str,
+    ) -> Result<Option<IncomingWebhook>> {
+        let webhook = sqlx::query_as::<_, IncomingWebhook>(
             r#"
-            SELECT m.* FROM messages m
-            WHERE m.room_id = ?
-            ORDER BY m.created_at DESC
-            LIMIT ? OFFSET ?
+            SELECT * FROM incoming_webhooks WHERE token_hash = ? AND revoked_at IS NULL
+            "#,
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(webhook)
+    }
+
+    /// Lists a room's unrevoked incoming webhooks.
+    pub async fn get_room_incoming_webhooks(&self, room_id: &str) -> Result<Vec<IncomingWebhook>> {
+        let webhooks = sqlx::query_as::<_, IncomingWebhook>(
+            r#"
+            SELECT * FROM incoming_webhooks
+            WHERE room_id = ? AND revoked_at IS NULL
+            ORDER BY created_at
             "#,
         )
         .bind(room_id)
-        .bind(limit)
-        .bind(offset)
         .fetch_all(&self.pool)
         .await?;
 
-        Ok(messages)
+        Ok(webhooks)
     }
 
-    pub async fn get_message(&self, message_id: &str) -> Result<Option<Message>> {
-        let message = sqlx::query_as::<_, Message>(
+    pub async fn touch_incoming_webhook(&self, webhook_id: &str) -> Result<()> {
+        sqlx::query(
             r#"
-            SELECT * FROM messages WHERE id = ?
+            UPDATE incoming_webhooks SET last_used_at = ? WHERE id = ?
             "#,
         )
-        .bind(message_id)
-        .fetch_optional(&self.pool)
+        .bind(Utc::now())
+        .bind(webhook_id)
+        .execute(&self.pool)
         .await?;
 
-        Ok(message)
+        Ok(())
+    }
+
+    /// Revokes one of a room's incoming webhooks. The row is kept because
+    /// the messages it posted still refer to it. Returns `false` if there
+    /// was no such live webhook.
+    pub async fn revoke_incoming_webhook(&self, room_id: &str, webhook_id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE incoming_webhooks SET revoked_at = ?
+            WHERE id = ? AND room_id = ? AND revoked_at IS NULL
+            "#,
+        )
+        .bind(Utc::now())
+        .bind(webhook_id)
+        .bind(room_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
     }
 
     // Friendship operations
@@ -490,3 +2931,175 @@ impl Database {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use sqlx::migrate::Migrate;
+
+    /// An empty database in a temporary directory, without migrations.
+    async fn database() -> Database {
+        let dir = std::env::temp_dir().join(format!("chatx-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Database::new(&format!("sqlite:{}", dir.join("chatx.db").display()))
+            .await
+            .unwrap()
+    }
+
+    fn entry(action: &str, created_at: DateTime<Utc>) -> AuditEntry {
+        AuditEntry {
+            id: Uuid::new_v4().to_string(),
+            actor_id: None,
+            action: action.to_string(),
+            target_type: None,
+            target_id: None,
+            details: None,
+            ip_address: None,
+            created_at,
+        }
+    }
+
+    fn actions(entries: &[AuditEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.action.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn audit_log_is_append_only() {
+        let db = database().await;
+        db.init().await.unwrap();
+        let entry = entry("auth.login", Utc::now());
+        db.create_audit_entry(&entry).await.unwrap();
+
+        let err = sqlx::query("UPDATE audit_log SET action = 'auth.logout' WHERE id = ?")
+            .bind(&entry.id)
+            .execute(&db.pool)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("append-only"), "{err}");
+        let err = sqlx::query("DELETE FROM audit_log")
+            .execute(&db.pool)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("append-only"), "{err}");
+
+        let entries = db
+            .query_audit_log(&AuditFilter::default(), false, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(actions(&entries), ["auth.login"]);
+    }
+
+    #[tokio::test]
+    async fn filters_and_pages_the_audit_log() {
+        let db = database().await;
+        db.init().await.unwrap();
+        let start = Utc::now() - Duration::hours(1);
+        for (minutes, action) in [
+            (0, "auth.login"),
+            (10, "authz.denied"),
+            (20, "auth.logout"),
+            (30, "admin.user_disable"),
+            (40, "auth.login"),
+        ] {
+            db.create_audit_entry(&entry(action, start + Duration::minutes(minutes)))
+                .await
+                .unwrap();
+        }
+        let query = |filter: AuditFilter, oldest_first, limit, offset| {
+            let db = &db;
+            async move {
+                db.query_audit_log(&filter, oldest_first, limit, offset)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // auth.* does not take in authz.denied
+        let auth = AuditFilter {
+            action: Some("auth.*".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            actions(&query(auth, true, 10, 0).await),
+            ["auth.login", "auth.logout", "auth.login"]
+        );
+        let exact = AuditFilter {
+            action: Some("auth.login".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query(exact, false, 10, 0).await.len(), 2);
+
+        // since is inclusive, until exclusive
+        let window = AuditFilter {
+            since: Some(start + Duration::minutes(10)),
+            until: Some(start + Duration::minutes(30)),
+            ..Default::default()
+        };
+        assert_eq!(
+            actions(&query(window, true, 10, 0).await),
+            ["authz.denied", "auth.logout"]
+        );
+
+        let newest = query(AuditFilter::default(), false, 2, 0).await;
+        assert_eq!(actions(&newest), ["auth.login", "admin.user_disable"]);
+        let next = query(AuditFilter::default(), false, 2, 2).await;
+        assert_eq!(actions(&next), ["auth.logout", "authz.denied"]);
+        let last = query(AuditFilter::default(), false, 2, 4).await;
+        assert_eq!(last.len(), 1);
+        assert!(query(AuditFilter::default(), false, 2, 6).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn admin_actions_move_into_the_audit_log() {
+        let db = database().await;
+        let migrator = sqlx::migrate!("./migrations");
+        let mut conn = db.pool.acquire().await.unwrap();
+        conn.ensure_migrations_table().await.unwrap();
+        for migration in migrator.iter().filter(|migration| migration.version < 16) {
+            conn.apply(migration).await.unwrap();
+        }
+        drop(conn);
+
+        let created_at = Utc::now() - Duration::days(3);
+        sqlx::query(
+            "INSERT INTO users (id, username, email, password_hash, created_at)
+             VALUES ('admin-1', 'root', 'root@example.com', 'x', ?)",
+        )
+        .bind(created_at)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO admin_actions (id, admin_id, action, target_type, target_id, details,
+                                        ip_address, created_at)
+             VALUES ('action-1', 'admin-1', 'user_disable', 'user', 'user-9',
+                     '{\"reason\":\"spam\"}', '203.0.113.7', ?)",
+        )
+        .bind(created_at)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+        db.init().await.unwrap();
+
+        let entries = db
+            .query_audit_log(&AuditFilter::default(), false, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(entry.id, "action-1");
+        assert_eq!(entry.actor_id.as_deref(), Some("admin-1"));
+        assert_eq!(entry.action, "admin.user_disable");
+        assert_eq!(entry.target_type.as_deref(), Some("user"));
+        assert_eq!(entry.target_id.as_deref(), Some("user-9"));
+        assert_eq!(entry.details.as_deref(), Some(r#"{"reason":"spam"}"#));
+        assert_eq!(entry.ip_address.as_deref(), Some("203.0.113.7"));
+        assert_eq!(entry.created_at, created_at);
+        assert!(sqlx::query("SELECT 1 FROM admin_actions")
+            .fetch_optional(&db.pool)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use anyhow::{Context, Result};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    metadata::Orientation,
+    DynamicImage, ImageDecoder, ImageReader, Limits,
+};
+use img_parts::{
+    jpeg::{markers, Jpeg},
+    png::Png,
+    webp::{WebP, CHUNK_XMP},
+    Bytes, ImageEXIF,
+};
+use std::io::Cursor;
+
+/// The longest side of each thumbnail, in pixels. Only thumbnails smaller
+/// than the image are made.
+pub const THUMBNAIL_SIZES: [u32; 3] = [160, 480, 960];
+/// Larger images are refused rather than decoded.
+const MAX_DIMENSION: u32 = 8192;
+const MAX_DECODE_BYTES: u64 = 256 * 1024 * 1024;
+/// Quality of re-encoded originals and of thumbnails.
+const ORIGINAL_QUALITY: u8 = 90;
+const THUMBNAIL_QUALITY: u8 = 80;
+/// Size of the image the blurhash is computed from; it is blurry anyway.
+const BLURHASH_SOURCE_SIZE: u32 = 32;
+/// PNG chunks holding EXIF data, text (often camera or editor details) and
+/// the modification time.
+const PNG_METADATA_CHUNKS: [[u8; 4]; 5] = [*b"eXIf", *b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"];
+
+/// An uploaded image, made ready to store.
+pub struct ProcessedImage {
+    /// The upload without its metadata.
+    pub data: Vec<u8>,
+    /// Differs from the upload's if it had to be re-encoded.
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: String,
+    /// PNG if the image has transparency, otherwise JPEG.
+    pub thumbnail_type: String,
+    /// Smallest first.
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+pub struct Thumbnail {
+    /// One of [`THUMBNAIL_SIZES`].
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// Decodes an uploaded PNG, JPEG, GIF or WebP image, strips its metadata
+/// and makes its thumbnails and blurhash. This is slow; call it from a
+/// blocking task.
+pub fn process(data: Vec<u8>, content_type: &str) -> Result<ProcessedImage> {
+    let (image, turned) = decode(&data)?;
+    let thumbnail_type = encoded_type(&image);
+    // 只去掉元数据即可保留原图画质；需要旋转的照片只能重新编码
+    let (data, content_type) = if !turned {
+        (strip_metadata(data, content_type)?, content_type)
+    } else {
+        (
+            encode(&image, thumbnail_type, ORIGINAL_QUALITY)?,
+            thumbnail_type,
+        )
+    };
+
+    let longest_side = image.width().max(image.height());
+    let mut thumbnails = Vec::new();
+    for size in THUMBNAIL_SIZES
+        .into_iter()
+        .filter(|&size| size < longest_side)
+    {
+        let thumbnail = image.thumbnail(size, size);
+        thumbnails.push(Thumbnail {
+            size,
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+            data: encode(&thumbnail, thumbnail_type, THUMBNAIL_QUALITY)?,
+        });
+    }
+
+    let source = image
+        .thumbnail(BLURHASH_SOURCE_SIZE, BLURHASH_SOURCE_SIZE)
+        .to_rgba8();
+    let (components_x, components_y) = if image.width() >= image.height() {
+        (4, 3)
+    } else {
+        (3, 4)
+    };
+    let blurhash = blurhash::encode(
+        components_x,
+        components_y,
+        source.width(),
+        source.height(),
+        source.as_raw(),
+    )
+    .context("failed to compute blurhash")?;
+
+    Ok(ProcessedImage {
+        data,
+        content_type: content_type.to_string(),
+        width: image.width(),
+        height: image.height(),
+        blurhash,
+        thumbnail_type: thumbnail_type.to_string(),
+        thumbnails,
+    })
+}
+
+/// Decodes an image within the size limits and turns it upright by its
+/// EXIF orientation, telling whether it had to be turned.
+pub fn decode(data: &[u8]) -> Result<(DynamicImage, bool)> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok((image, orientation != Orientation::NoTransforms))
+}
+
+/// The type [`encode`] should use: PNG if the image has transparency,
+/// otherwise JPEG.
+pub fn encoded_type(image: &DynamicImage) -> &'static str {
+    if image.color().has_alpha() {
+        "image/png"
+    } else {
+        "image/jpeg"
+    }
+}
+
+/// Encodes as PNG or JPEG, with `quality` applying to JPEG.
+pub fn encode(image: &DynamicImage, content_type: &str, quality: u8) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match content_type {
+        "image/png" => image
+            .to_rgba8()
+            .write_with_encoder(PngEncoder::new(&mut out))?,
+        _ => image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality))?,
+    }
+    Ok(out)
+}
+
+/// Drops EXIF (including GPS position), XMP, IPTC and comments without
+/// touching the image data. GIFs cannot carry EXIF and are kept as they are.
+fn strip_metadata(data: Vec<u8>, content_type: &str) -> Result<Vec<u8>> {
+    let data = Bytes::from(data);
+    let stripped = match content_type {
+        "image/jpeg" => {
+            let mut jpeg = Jpeg::from_bytes(data)?;
+            jpeg.segments_mut().retain(|segment| {
+                !matches!(
+                    segment.marker(),
+                    markers::APP1 | markers::APP13 | markers::COM
+                )
+            });
+            jpeg.encoder().bytes()
+        }
+        "image/png" => {
+            let mut png = Png::from_bytes(data)?;
+            png.chunks_mut()
+                .retain(|chunk| !PNG_METADATA_CHUNKS.contains(&chunk.kind()));
+            png.encoder().bytes()
+        }
+        "image/webp" => {
+            let mut webp = WebP::from_bytes(data)?;
+            webp.remove_chunks_by_id(CHUNK_XMP);
+            // 同时更新 VP8X 头里的标志位
+            webp.set_exif(None);
+            webp.encoder().bytes()
+        }
+        _ => data,
+    };
+    Ok(stripped.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+    use img_parts::png::PngChunk;
+
+    /// Marks the text chunk, so its removal can be checked.
+    const TEXT: &[u8] = b"Comment\0taken at home";
+
+    /// A little-endian TIFF block as found in EXIF: a GPS position, and an
+    /// orientation if one is given.
+    fn exif(orientation: Option<u16>) -> Vec<u8> {
+        let entries: u16 = if orientation.is_some() { 2 } else { 1 };
+        let gps_ifd = 8 + 2 + 12 * u32::from(entries) + 4;
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend(8u32.to_le_bytes());
+        tiff.extend(entries.to_le_bytes());
+        if let Some(orientation) = orientation {
+            tiff.extend(0x0112u16.to_le_bytes());
+            tiff.extend(3u16.to_le_bytes());
+            tiff.extend(1u32.to_le_bytes());
+            tiff.extend(u32::from(orientation).to_le_bytes());
+        }
+        // GPSInfo 指向下面的 GPS IFD
+        tiff.extend(0x8825u16.to_le_bytes());
+        tiff.extend(4u16.to_le_bytes());
+        tiff.extend(1u32.to_le_bytes());
+        tiff.extend(gps_ifd.to_le_bytes());
+        tiff.extend(0u32.to_le_bytes());
+
+        let latitude = gps_ifd + 2 + 2 * 12 + 4;
+        tiff.extend(2u16.to_le_bytes());
+        tiff.extend(0x0001u16.to_le_bytes());
+        tiff.extend(2u16.to_le_bytes());
+        tiff.extend(2u32.to_le_bytes());
+        tiff.extend(*b"N\0\0\0");
+        tiff.extend(0x0002u16.to_le_bytes());
+        tiff.extend(5u16.to_le_bytes());
+        tiff.extend(3u32.to_le_bytes());
+        tiff.extend(latitude.to_le_bytes());
+        tiff.extend(0u32.to_le_bytes());
+        for (numerator, denominator) in [(51u32, 1u32), (30, 1), (1234, 100)] {
+            tiff.extend(numerator.to_le_bytes());
+            tiff.extend(denominator.to_le_bytes());
+        }
+        tiff
+    }
+
+    fn photo(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        }))
+    }
+
+    fn jpeg_with_exif(width: u32, height: u32, orientation: Option<u16>) -> Vec<u8> {
+        let data = encode(&photo(width, height), "image/jpeg", 90).unwrap();
+        let mut jpeg = Jpeg::from_bytes(Bytes::from(data)).unwrap();
+        jpeg.set_exif(Some(Bytes::from(exif(orientation))));
+        jpeg.encoder().bytes().to_vec()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn strips_exif_from_jpegs() {
+        let upload = jpeg_with_exif(64, 48, None);
+        assert!(contains(&upload, b"Exif\0\0II*\0"));
+
+        let processed = process(upload, "image/jpeg").unwrap();
+
+        assert_eq!(processed.content_type, "image/jpeg");
+        assert!(!contains(&processed.data, b"Exif"));
+        assert!(!contains(&processed.data, b"II*\0"));
+        let jpeg = Jpeg::from_bytes(Bytes::from(processed.data.clone())).unwrap();
+        assert!(jpeg.exif().is_none());
+        assert!(jpeg
+            .segments()
+            .iter()
+            .all(|segment| segment.marker() != markers::APP1));
+        let (image, _) = decode(&processed.data).unwrap();
+        assert_eq!((image.width(), image.height()), (64, 48));
+    }
+
+    #[test]
+    fn turns_and_reencodes_rotated_photos() {
+        let processed = process(jpeg_with_exif(64, 48, Some(6)), "image/jpeg").unwrap();
+
+        assert_eq!((processed.width, processed.height), (48, 64));
+        assert!(!contains(&processed.data, b"Exif"));
+        let (image, turned) = decode(&processed.data).unwrap();
+        assert!(!turned);
+        assert_eq!((image.width(), image.height()), (48, 64));
+    }
+
+    #[test]
+    fn strips_metadata_chunks_from_pngs() {
+        let data = encode(&photo(40, 30), "image/png", 90).unwrap();
+        let mut png = Png::from_bytes(Bytes::from(data)).unwrap();
+        png.set_exif(Some(Bytes::from(exif(None))));
+        let end = png.chunks().len() - 1;
+        png.chunks_mut()
+            .insert(end, PngChunk::new(*b"tEXt", Bytes::from_static(TEXT)));
+        let upload = png.encoder().bytes().to_vec();
+        assert!(contains(&upload, b"eXIf") && contains(&upload, TEXT));
+
+        let processed = process(upload, "image/png").unwrap();
+
+        assert_eq!(processed.content_type, "image/png");
+        let png = Png::from_bytes(Bytes::from(processed.data.clone())).unwrap();
+        for chunk in png.chunks() {
+            assert!(!PNG_METADATA_CHUNKS.contains(&chunk.kind()));
+        }
+        assert!(!contains(&processed.data, TEXT));
+        assert!(!contains(&processed.data, b"II*\0"));
+        let (image, _) = decode(&processed.data).unwrap();
+        assert_eq!((image.width(), image.height()), (40, 30));
+    }
+
+    #[test]
+    fn makes_thumbnails_without_upscaling() {
+        let upload = encode(&photo(1200, 600), "image/jpeg", 90).unwrap();
+        let processed = process(upload, "image/jpeg").unwrap();
+
+        assert_eq!((processed.width, processed.height), (1200, 600));
+        assert_eq!(processed.thumbnail_type, "image/jpeg");
+        let sizes: Vec<_> = processed
+            .thumbnails
+            .iter()
+            .map(|thumbnail| (thumbnail.size, thumbnail.width, thumbnail.height))
+            .collect();
+        assert_eq!(sizes, [(160, 160, 80), (480, 480, 240), (960, 960, 480)]);
+        for thumbnail in &processed.thumbnails {
+            let (image, _) = decode(&thumbnail.data).unwrap();
+            assert_eq!(
+                (image.width(), image.height()),
+                (thumbnail.width, thumbnail.height)
+            );
+        }
+        assert!(!processed.blurhash.is_empty());
+        assert!(blurhash::decode(&processed.blurhash, 8, 4, 1.0).is_ok());
+
+        let small = process(
+            encode(&photo(300, 200), "image/jpeg", 90).unwrap(),
+            "image/jpeg",
+        )
+        .unwrap();
+        assert_eq!(
+            small
+                .thumbnails
+                .iter()
+                .map(|thumbnail| thumbnail.size)
+                .collect::<Vec<_>>(),
+            [160]
+        );
+        let tiny = process(
+            encode(&photo(100, 50), "image/jpeg", 90).unwrap(),
+            "image/jpeg",
+        )
+        .unwrap();
+        assert!(tiny.thumbnails.is_empty());
+        assert_eq!((tiny.width, tiny.height), (100, 50));
+        assert!(!tiny.blurhash.is_empty());
+    }
+
+    #[test]
+    fn transparent_images_get_png_thumbnails() {
+        let image =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(400, 400, Rgba([255, 0, 0, 64])));
+        let processed = process(encode(&image, "image/png", 90).unwrap(), "image/png").unwrap();
+
+        assert_eq!(processed.thumbnail_type, "image/png");
+        assert_eq!(processed.thumbnails.len(), 1);
+        assert!(processed.thumbnails[0].data.starts_with(b"\x89PNG"));
+    }
+}
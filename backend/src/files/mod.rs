@@ -0,0 +1,557 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{
+        multipart::{Field, MultipartError},
+        Multipart, Path, State,
+    },
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub mod images;
+
+use crate::{
+    api_error,
+    auth::Caller,
+    bots::Scope,
+    db, internal_error,
+    moderation::{load_room, load_user, require_reviewer},
+    rooms::{self, Posted},
+    ApiError, AppState, StatusResponse,
+};
+
+const MAX_FILENAME_LEN: usize = 255;
+const MAX_CAPTION_BYTES: usize = 4096;
+/// Image types clients may show inline; other files are only downloaded.
+pub const INLINE_IMAGE_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+const DEFAULT_ALLOWED_TYPES: &str = "image/png,image/jpeg,image/gif,image/webp,application/pdf,\
+                                     text/plain,text/csv,text/markdown,application/zip";
+
+/// What may be uploaded.
+#[derive(Debug, Clone)]
+pub struct UploadLimits {
+    pub max_bytes: usize,
+    /// MIME types, or `type/*` for every subtype.
+    pub allowed_types: Vec<String>,
+}
+
+impl UploadLimits {
+    /// Reads `UPLOAD_MAX_BYTES` (default 10 MiB) and `UPLOAD_ALLOWED_TYPES`.
+    pub fn from_env() -> Result<Self> {
+        let max_bytes = match std::env::var("UPLOAD_MAX_BYTES") {
+            Ok(value) => value
+                .parse()
+                .with_context(|| format!("UPLOAD_MAX_BYTES has an invalid value {value:?}"))?,
+            Err(_) => 10 * 1024 * 1024,
+        };
+        let allowed_types = std::env::var("UPLOAD_ALLOWED_TYPES")
+            .unwrap_or_else(|_| DEFAULT_ALLOWED_TYPES.to_string())
+            .split(',')
+            .map(|kind| kind.trim().to_lowercase())
+            .filter(|kind| !kind.is_empty())
+            .collect();
+
+        Ok(Self {
+            max_bytes,
+            allowed_types,
+        })
+    }
+
+    fn allows(&self, content_type: &str) -> bool {
+        self.allowed_types.iter().any(|allowed| {
+            allowed == content_type
+                || allowed
+                    .strip_suffix("/*")
+                    .is_some_and(|kind| content_type.split('/').next() == Some(kind))
+        })
+    }
+}
+
+/// The type of an upload, judged by its contents rather than what the
+/// client claimed. Text is told apart by its file name.
+pub fn detect_content_type(data: &[u8], filename: &str) -> String {
+    if let Some(kind) = infer::get(data) {
+        return kind.mime_type().to_string();
+    }
+    if std::str::from_utf8(data).is_ok() {
+        let guess = mime_guess::from_path(filename).first_raw().unwrap_or("");
+        return match guess {
+            guess if guess.starts_with("text/") && guess != "text/html" => guess.to_string(),
+            _ => "text/plain".to_string(),
+        };
+    }
+    "application/octet-stream".to_string()
+}
+
+/// Keeps the last path component of a client's file name, without control
+/// characters or quotes.
+fn clean_filename(name: &str) -> String {
+    let name: String = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .collect();
+    let name = name.trim();
+    match name {
+        "" | "." | ".." => "file".to_string(),
+        name => name.chars().take(MAX_FILENAME_LEN).collect(),
+    }
+}
+
+fn storage_key(room_id: &str, file_id: &str) -> String {
+    format!("files/{room_id}/{file_id}")
+}
+
+fn thumbnail_key(storage_key: &str, size: u32) -> String {
+    format!("{storage_key}-{size}")
+}
+
+fn too_large(limits: &UploadLimits) -> ApiError {
+    api_error(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Files can be at most {} bytes", limits.max_bytes),
+    )
+}
+
+/// Maps a malformed or oversized multipart upload to an API error.
+pub fn form_error(limits: &UploadLimits, err: MultipartError) -> ApiError {
+    match err.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => too_large(limits),
+        status => api_error(status, format!("Invalid upload: {err}")),
+    }
+}
+
+/// Reads an uploaded file, refusing it once it exceeds the size limit.
+pub async fn read_field(limits: &UploadLimits, mut field: Field<'_>) -> Result<Vec<u8>, ApiError> {
+    let mut data = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(|err| form_error(limits, err))? {
+        if data.len() + chunk.len() > limits.max_bytes {
+            return Err(too_large(limits));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Deletes stored files and their records, e.g. with their room or
+/// message. Failures are logged, as the caller has already succeeded.
+pub async fn delete_stored(state: &AppState, files: &[db::File]) {
+    for file in files {
+        let mut keys = vec![file.storage_key.clone()];
+        if file.thumbnail_type.is_some() {
+            keys.extend(
+                images::THUMBNAIL_SIZES
+                    .into_iter()
+                    .map(|size| thumbnail_key(&file.storage_key, size)),
+            );
+        }
+        let mut deleted = true;
+        for key in &keys {
+            if let Err(err) = state.storage.delete(key).await {
+                eprintln!("failed to delete file {}: {err:#}", file.id);
+                deleted = false;
+            }
+        }
+        if !deleted {
+            continue;
+        }
+        if let Err(err) = state.db.delete_file(&file.id).await {
+            eprintln!("failed to delete file record {}: {err:#}", file.id);
+        }
+    }
+}
+
+/// Deletes the uploads of a message that will never be sent, such as a
+/// rejected held message.
+pub async fn discard(state: &AppState, attachments: &[db::Attachment]) {
+    let mut files = Vec::new();
+    for attachment in attachments {
+        let Some(file) = &attachment.file else {
+            continue;
+        };
+        match state.db.get_file(&file.id).await {
+            Ok(Some(file)) if file.message_id.is_none() => files.push(file),
+            Ok(_) => {}
+            Err(err) => eprintln!("failed to load file {}: {err:#}", file.id),
+        }
+    }
+    delete_stored(state, &files).await;
+}
+
+// 上传文件并作为消息发到房间：表单字段 file，可选 caption
+pub async fn upload_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path(room_id): Path<String>,
+    mut multipart: Multipart,
+) -> Result<Response, ApiError> {
+    let room = load_room(&state, &room_id).await?;
+    caller.require(&Scope::PostRoom(room.name.clone()))?;
+    let sender = state
+        .db
+        .get_user_by_id(caller.id())
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "Account not found"))?;
+    if state.require_email_verification && !sender.is_bot && !sender.email_verified {
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            "Please verify your email address before posting",
+        ));
+    }
+
+    let limits = &state.uploads;
+    let mut upload = None;
+    let mut caption = String::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| form_error(limits, err))?
+    {
+        match field.name() {
+            Some("file") if upload.is_none() => {
+                let filename = clean_filename(field.file_name().unwrap_or_default());
+                upload = Some((filename, read_field(limits, field).await?));
+            }
+            Some("caption") => {
+                caption = field
+                    .text()
+                    .await
+                    .map_err(|err| form_error(limits, err))?
+                    .trim()
+                    .to_string();
+            }
+            _ => {}
+        }
+    }
+    let Some((filename, data)) = upload.filter(|(_, data)| !data.is_empty()) else {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "A non-empty file is required",
+        ));
+    };
+    if caption.len() > MAX_CAPTION_BYTES {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Captions can be at most {MAX_CAPTION_BYTES} bytes"),
+        ));
+    }
+    let content_type = detect_content_type(&data, &filename);
+    if !limits.allows(&content_type) {
+        return Err(api_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Files of type {content_type} are not allowed"),
+        ));
+    }
+    // 图片：去掉 EXIF 等元数据，生成缩略图和 blurhash
+    let (data, mut image) = if INLINE_IMAGE_TYPES.contains(&content_type.as_str()) {
+        let kind = content_type.clone();
+        let mut image = tokio::task::spawn_blocking(move || images::process(data, &kind))
+            .await
+            .map_err(|err| internal_error(err.into()))?
+            .map_err(|err| {
+                api_error(
+                    StatusCode::BAD_REQUEST,
+                    format!("The image could not be read: {err}"),
+                )
+            })?;
+        (std::mem::take(&mut image.data), Some(image))
+    } else {
+        (data, None)
+    };
+
+    // 与 WebSocket 消息相同的发言检查：禁言、只读、慢速模式等
+    let text = if caption.is_empty() {
+        filename.clone()
+    } else {
+        caption
+    };
+    if let Some(refusal) = rooms::send_refusal(&state, &room, Some(&sender.id), &sender.id, &text)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(api_error(StatusCode::FORBIDDEN, refusal));
+    }
+
+    let file_id = Uuid::new_v4().to_string();
+    let file = db::File {
+        storage_key: storage_key(&room.id, &file_id),
+        id: file_id,
+        room_id: room.id.clone(),
+        uploader_id: sender.id.clone(),
+        message_id: None,
+        filename,
+        content_type: image
+            .as_ref()
+            .map_or(content_type, |image| image.content_type.clone()),
+        size_bytes: data.len() as i64,
+        created_at: Utc::now(),
+        width: image.as_ref().map(|image| image.width.into()),
+        height: image.as_ref().map(|image| image.height.into()),
+        blurhash: image.as_ref().map(|image| image.blurhash.clone()),
+        thumbnail_type: image
+            .as_ref()
+            .filter(|image| !image.thumbnails.is_empty())
+            .map(|image| image.thumbnail_type.clone()),
+    };
+    state
+        .storage
+        .put(&file.storage_key, &file.content_type, data)
+        .await
+        .map_err(internal_error)?;
+    let mut thumbnails = Vec::new();
+    if let Some(image) = image.as_mut() {
+        for thumbnail in &mut image.thumbnails {
+            state
+                .storage
+                .put(
+                    &thumbnail_key(&file.storage_key, thumbnail.size),
+                    &image.thumbnail_type,
+                    std::mem::take(&mut thumbnail.data),
+                )
+                .await
+                .map_err(internal_error)?;
+            thumbnails.push(db::Thumbnail {
+                width: thumbnail.width,
+                height: thumbnail.height,
+                url: format!("/api/files/{}/thumbnails/{}", file.id, thumbnail.size),
+            });
+        }
+    }
+    state.db.create_file(&file).await.map_err(internal_error)?;
+
+    let attachment = db::Attachment {
+        file: Some(db::FileAttachment {
+            id: file.id.clone(),
+            filename: file.filename.clone(),
+            content_type: file.content_type.clone(),
+            size_bytes: file.size_bytes,
+            url: format!("/api/files/{}", file.id),
+            width: image.as_ref().map(|image| image.width),
+            height: image.as_ref().map(|image| image.height),
+            blurhash: file.blurhash.clone(),
+            thumbnails,
+        }),
+        ..Default::default()
+    };
+    let new = rooms::NewMessage {
+        sender_id: Some(sender.id.clone()),
+        username: sender.username.clone(),
+        content: text,
+        message_type: Some(
+            if INLINE_IMAGE_TYPES.contains(&file.content_type.as_str()) {
+                "image"
+            } else {
+                "file"
+            }
+            .to_string(),
+        ),
+        is_bot: sender.is_bot,
+        integration_id: None,
+        attachments: vec![attachment],
+    };
+    match rooms::post_message(&state, &room.name, Some(&room), new, &sender.id)
+        .await
+        .map_err(internal_error)?
+    {
+        Posted::Sent(message) => {
+            let message =
+                message.ok_or_else(|| internal_error(anyhow::anyhow!("message was not stored")))?;
+            Ok(Json(*message).into_response())
+        }
+        Posted::Held(reason) => Ok((
+            StatusCode::ACCEPTED,
+            Json(StatusResponse::new(format!(
+                "Message is held for review: {reason}"
+            ))),
+        )
+            .into_response()),
+        Posted::Rejected(reason) => {
+            delete_stored(&state, std::slice::from_ref(&file)).await;
+            Err(api_error(StatusCode::BAD_REQUEST, reason))
+        }
+    }
+}
+
+/// Loads a file the caller may download: they must be a member of its
+/// room, and only the uploader and reviewers see files of held messages.
+async fn readable_file(
+    state: &AppState,
+    caller: &Caller,
+    file_id: &str,
+) -> Result<db::File, ApiError> {
+    caller.require(&Scope::ReadRooms)?;
+    let not_found = || api_error(StatusCode::NOT_FOUND, "File not found");
+    let file = state
+        .db
+        .get_file(file_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    // 非成员与不存在的文件一样返回 404
+    state
+        .db
+        .get_room_member(&file.room_id, caller.id())
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    // 尚未发出（待审核）的文件只有上传者和审核人能看
+    if file.message_id.is_none() && file.uploader_id != caller.id() {
+        let user = load_user(state, caller.id()).await?;
+        require_reviewer(state, &file.room_id, &user)
+            .await
+            .map_err(|_| not_found())?;
+    }
+    Ok(file)
+}
+
+async fn file_response(
+    state: &AppState,
+    file: &db::File,
+    key: &str,
+    content_type: &str,
+) -> Result<Response, ApiError> {
+    let data = state
+        .storage
+        .get(key)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "File not found"))?;
+
+    let inline = INLINE_IMAGE_TYPES.contains(&content_type);
+    let disposition = format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        if inline { "inline" } else { "attachment" },
+        file.filename
+            .chars()
+            .map(|c| if c.is_ascii() { c } else { '_' })
+            .collect::<String>(),
+        urlencoding::encode(&file.filename)
+    );
+    let header = |value: &str| {
+        HeaderValue::from_str(value).unwrap_or_else(|_| HeaderValue::from_static("attachment"))
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, header(content_type)),
+            (header::CONTENT_DISPOSITION, header(&disposition)),
+            (
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("private, max-age=86400"),
+            ),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static("sandbox"),
+            ),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+// 下载文件：仅限房间成员
+pub async fn download_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path(file_id): Path<String>,
+) -> Result<Response, ApiError> {
+    let file = readable_file(&state, &caller, &file_id).await?;
+    file_response(&state, &file, &file.storage_key, &file.content_type).await
+}
+
+// 下载图片缩略图，size 为缩略图的最长边
+pub async fn thumbnail_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path((file_id, size)): Path<(String, u32)>,
+) -> Result<Response, ApiError> {
+    let file = readable_file(&state, &caller, &file_id).await?;
+    let content_type = file
+        .thumbnail_type
+        .clone()
+        .filter(|_| images::THUMBNAIL_SIZES.contains(&size))
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Thumbnail not found"))?;
+    file_response(
+        &state,
+        &file,
+        &thumbnail_key(&file.storage_key, size),
+        &content_type,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(allowed_types: &[&str]) -> UploadLimits {
+        UploadLimits {
+            max_bytes: 1024,
+            allowed_types: allowed_types.iter().map(|kind| kind.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn detects_types_from_contents() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(detect_content_type(png, "notes.txt"), "image/png");
+        assert_eq!(
+            detect_content_type(b"%PDF-1.7\n", "a.png"),
+            "application/pdf"
+        );
+        assert_eq!(detect_content_type(b"a,b\n1,2\n", "data.csv"), "text/csv");
+        assert_eq!(detect_content_type(b"plain", "README"), "text/plain");
+        // 文件名不能把文本变成 HTML
+        assert_eq!(
+            detect_content_type(b"just words", "page.html"),
+            "text/plain"
+        );
+        // 内容像 HTML 的按 HTML 处理，和脚本一样默认不允许上传
+        let html = detect_content_type(b"<html><script>alert(1)</script>", "notes.txt");
+        assert_eq!(html, "text/html");
+        let script = detect_content_type(b"alert(1)", "app.js");
+        assert_eq!(script, "text/javascript");
+        let defaults = limits(&DEFAULT_ALLOWED_TYPES.split(',').collect::<Vec<_>>());
+        assert!(!defaults.allows(&html) && !defaults.allows(&script));
+        assert_eq!(
+            detect_content_type(&[0xff, 0xfe, 0x00, 0x81], "blob.png"),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn cleans_filenames() {
+        assert_eq!(clean_filename("report.pdf"), "report.pdf");
+        assert_eq!(clean_filename("../../etc/passwd"), "passwd");
+        assert_eq!(clean_filename("C:\\Users\\bob\\photo.jpg"), "photo.jpg");
+        assert_eq!(clean_filename("a\"b\r\nc.txt"), "abc.txt");
+        assert_eq!(clean_filename("  spaced.txt  "), "spaced.txt");
+        for name in ["", "..", ".", "dir/", " "] {
+            assert_eq!(clean_filename(name), "file", "{name:?}");
+        }
+        assert_eq!(
+            clean_filename(&"é".repeat(300)).chars().count(),
+            MAX_FILENAME_LEN
+        );
+    }
+
+    #[test]
+    fn allows_listed_types_and_wildcards() {
+        let limits = limits(&["image/*", "application/pdf"]);
+        assert!(limits.allows("image/png"));
+        assert!(limits.allows("application/pdf"));
+        assert!(!limits.allows("application/zip"));
+        assert!(!limits.allows("imagex/png"));
+        assert!(!limits.allows("text/plain"));
+    }
+}
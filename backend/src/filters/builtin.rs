@@ -0,0 +1,476 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use std::{collections::HashSet, sync::Arc};
+
+use super::{ContentFilter, FilterChain, FilterContext, Verdict};
+
+pub fn register(chain: &mut FilterChain) -> Result<()> {
+    let max_chars = env_number("FILTER_MAX_MESSAGE_CHARS", 2000)?;
+    if max_chars > 0 {
+        chain.push(Arc::new(MaxLength { max_chars }));
+    }
+    let max_run = env_number("FILTER_MAX_REPEATED_CHARS", 10)?;
+    if max_run > 0 {
+        chain.push(Arc::new(RepeatedChars { max_run }));
+    }
+    chain.push(Arc::new(BannedWords {
+        words: env_list("FILTER_BANNED_WORDS").into_iter().collect(),
+        action: match std::env::var("FILTER_BANNED_WORDS_ACTION").as_deref() {
+            Err(_) | Ok("mask") => WordAction::Mask,
+            Ok("hold") => WordAction::Hold,
+            Ok("reject") => WordAction::Reject,
+            Ok(other) => bail!("FILTER_BANNED_WORDS_ACTION has an invalid value {other:?}"),
+        },
+    }));
+    let links = Links {
+        allow: domain_list("FILTER_LINK_ALLOWLIST"),
+        deny: domain_list("FILTER_LINK_DENYLIST"),
+    };
+    if !links.allow.is_empty() || !links.deny.is_empty() {
+        chain.push(Arc::new(links));
+    }
+    Ok(())
+}
+
+fn env_number(name: &str, default: usize) -> Result<usize> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("{name} has an invalid value {value:?}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// A comma-separated, case-insensitive list.
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Domains match themselves and their subdomains, so `*.example.com` and
+/// `example.com` mean the same.
+fn domain_list(name: &str) -> Vec<String> {
+    env_list(name)
+        .into_iter()
+        .map(|domain| {
+            domain
+                .trim_start_matches("*.")
+                .trim_matches('.')
+                .to_string()
+        })
+        .collect()
+}
+
+/// Refuses messages over a number of characters.
+struct MaxLength {
+    max_chars: usize,
+}
+
+#[async_trait]
+impl ContentFilter for MaxLength {
+    fn name(&self) -> &str {
+        "max_length"
+    }
+
+    async fn check(&self, _ctx: &FilterContext<'_>, text: &str) -> Result<Verdict> {
+        Ok(if text.chars().count() > self.max_chars {
+            Verdict::Reject(format!(
+                "Message is too long (max {} characters)",
+                self.max_chars
+            ))
+        } else {
+            Verdict::Pass
+        })
+    }
+}
+
+/// Shortens runs of the same character, e.g. `noooooooooooooo`.
+struct RepeatedChars {
+    max_run: usize,
+}
+
+#[async_trait]
+impl ContentFilter for RepeatedChars {
+    fn name(&self) -> &str {
+        "repeated_chars"
+    }
+
+    async fn check(&self, _ctx: &FilterContext<'_>, text: &str) -> Result<Verdict> {
+        let mut squeezed = String::with_capacity(text.len());
+        let mut last = None;
+        let mut run = 0;
+        for c in text.chars() {
+            if last == Some(c) {
+                run += 1;
+            } else {
+                last = Some(c);
+                run = 1;
+            }
+            if run <= self.max_run {
+                squeezed.push(c);
+            }
+        }
+
+        Ok(if squeezed.len() == text.len() {
+            Verdict::Pass
+        } else {
+            Verdict::Rewrite(squeezed)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum WordAction {
+    /// Replaces each letter of the word with `*`.
+    Mask,
+    Hold,
+    Reject,
+}
+
+/// The server's banned words, plus each room's own additions and
+/// exemptions. Words match whole and regardless of case.
+struct BannedWords {
+    words: HashSet<String>,
+    action: WordAction,
+}
+
+#[async_trait]
+impl ContentFilter for BannedWords {
+    fn name(&self) -> &str {
+        "banned_words"
+    }
+
+    async fn check(&self, ctx: &FilterContext<'_>, text: &str) -> Result<Verdict> {
+        let mut words = self.words.clone();
+        if let Some(room) = ctx.room {
+            for filter in ctx.state.db.get_room_word_filters(&room.id).await? {
+                if filter.mode == "allow" {
+                    words.remove(&filter.word);
+                } else {
+                    words.insert(filter.word);
+                }
+            }
+        }
+        if words.is_empty() {
+            return Ok(Verdict::Pass);
+        }
+
+        let mut found = false;
+        let mut masked = String::with_capacity(text.len());
+        for (is_word, run) in runs(text) {
+            if is_word && words.contains(&run.to_lowercase()) {
+                found = true;
+                masked.extend(run.chars().map(|_| '*'));
+            } else {
+                masked.push_str(run);
+            }
+        }
+
+        Ok(match (found, self.action) {
+            (false, _) => Verdict::Pass,
+            (true, WordAction::Mask) => Verdict::Rewrite(masked),
+            (true, WordAction::Hold) => Verdict::Hold("it contains a filtered word".to_string()),
+            (true, WordAction::Reject) => {
+                Verdict::Reject("Your message contains a filtered word".to_string())
+            }
+        })
+    }
+}
+
+/// Splits text into alternating runs of letters and digits and of
+/// everything else, flagging the former.
+fn runs(text: &str) -> Vec<(bool, &str)> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    let mut current = None;
+    for (index, c) in text.char_indices() {
+        let is_word = c.is_alphanumeric();
+        match current {
+            Some(previous) if previous != is_word => {
+                runs.push((previous, &text[start..index]));
+                start = index;
+            }
+            _ => {}
+        }
+        current = Some(is_word);
+    }
+    if let Some(is_word) = current {
+        runs.push((is_word, &text[start..]));
+    }
+    runs
+}
+
+/// Refuses links to denied domains and, when there is an allowlist, holds
+/// links anywhere else for review.
+struct Links {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl Links {
+    fn matches(list: &[String], host: &str) -> bool {
+        list.iter().any(|domain| {
+            host == domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|sub| sub.ends_with('.'))
+        })
+    }
+}
+
+#[async_trait]
+impl ContentFilter for Links {
+    fn name(&self) -> &str {
+        "links"
+    }
+
+    async fn check(&self, _ctx: &FilterContext<'_>, text: &str) -> Result<Verdict> {
+        for host in link_hosts(text) {
+            if Self::matches(&self.deny, &host) {
+                return Ok(Verdict::Reject(format!("Links to {host} are not allowed")));
+            }
+            if !self.allow.is_empty() && !Self::matches(&self.allow, &host) {
+                return Ok(Verdict::Hold(format!(
+                    "links to {host} need a moderator's approval"
+                )));
+            }
+        }
+        Ok(Verdict::Pass)
+    }
+}
+
+/// The `http(s)://` and `www.` links in a message, in order.
+pub fn links(text: &str) -> Vec<reqwest::Url> {
+    text.split_whitespace()
+        .filter_map(|word| {
+            let word = word.trim_matches(|c: char| "<>()[]{}\"',.;:!?".contains(c));
+            let lower = word.to_lowercase();
+            let url = if lower.starts_with("http://") || lower.starts_with("https://") {
+                word.to_string()
+            } else if lower.starts_with("www.") {
+                format!("http://{word}")
+            } else {
+                return None;
+            };
+            reqwest::Url::parse(&url).ok()
+        })
+        .collect()
+}
+
+/// The hosts of the links in a message, lowercased.
+fn link_hosts(text: &str) -> Vec<String> {
+    links(text)
+        .iter()
+        .filter_map(|url| {
+            url.host_str()
+                .map(|host| host.trim_end_matches('.').to_lowercase())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, filters::Decision, mail::MemoryMailer, testing, AppState};
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    /// What a verdict did, in a form tests can compare.
+    fn outcome(verdict: Verdict) -> String {
+        match verdict {
+            Verdict::Pass => "pass".to_string(),
+            Verdict::Rewrite(text) => format!("rewrite: {text}"),
+            Verdict::Hold(reason) => format!("hold: {reason}"),
+            Verdict::Reject(reason) => format!("reject: {reason}"),
+        }
+    }
+
+    async fn check(state: &AppState, filter: &dyn ContentFilter, text: &str) -> String {
+        let ctx = FilterContext { state, room: None };
+        outcome(filter.check(&ctx, text).await.unwrap())
+    }
+
+    fn banned(words: &[&str], action: WordAction) -> BannedWords {
+        BannedWords {
+            words: words.iter().map(|word| word.to_string()).collect(),
+            action,
+        }
+    }
+
+    fn domains(list: &[&str]) -> Vec<String> {
+        list.iter().map(|domain| domain.to_string()).collect()
+    }
+
+    #[test]
+    fn splits_words_from_the_rest() {
+        assert_eq!(
+            runs("Hi, émile 42!"),
+            [
+                (true, "Hi"),
+                (false, ", "),
+                (true, "émile"),
+                (false, " "),
+                (true, "42"),
+                (false, "!"),
+            ]
+        );
+        assert!(runs("").is_empty());
+    }
+
+    #[test]
+    fn finds_links() {
+        let links: Vec<String> =
+            links("see <https://Example.com/a>, www.rust-lang.org. or ftp://x.y and example.org")
+                .iter()
+                .map(|url| url.to_string())
+                .collect();
+        assert_eq!(
+            links,
+            ["https://example.com/a", "http://www.rust-lang.org/"]
+        );
+        assert_eq!(link_hosts("HTTP://CDN.Example.COM./x"), ["cdn.example.com"]);
+    }
+
+    #[test]
+    fn matches_domains_and_subdomains() {
+        let list = domains(&["example.com"]);
+        assert!(Links::matches(&list, "example.com"));
+        assert!(Links::matches(&list, "cdn.example.com"));
+        assert!(!Links::matches(&list, "notexample.com"));
+        assert!(!Links::matches(&list, "example.com.evil.net"));
+    }
+
+    #[tokio::test]
+    async fn squeezes_long_runs() {
+        let state = testing::state(Arc::new(MemoryMailer::default())).await;
+        let filter = RepeatedChars { max_run: 3 };
+
+        assert_eq!(check(&state, &filter, "nooo!!!").await, "pass");
+        assert_eq!(
+            check(&state, &filter, "noooo!!!!").await,
+            "rewrite: nooo!!!"
+        );
+        assert_eq!(check(&state, &filter, "ééééé").await, "rewrite: ééé");
+    }
+
+    #[tokio::test]
+    async fn masks_whole_words_only() {
+        let state = testing::state(Arc::new(MemoryMailer::default())).await;
+        let filter = banned(&["ass"], WordAction::Mask);
+
+        assert_eq!(check(&state, &filter, "a class act").await, "pass");
+        assert_eq!(
+            check(&state, &filter, "Ass, ASS and assets").await,
+            "rewrite: ***, *** and assets"
+        );
+        assert!(check(&state, &banned(&["ass"], WordAction::Hold), "ass")
+            .await
+            .starts_with("hold:"));
+        assert!(check(&state, &banned(&["ass"], WordAction::Reject), "ass")
+            .await
+            .starts_with("reject:"));
+    }
+
+    #[tokio::test]
+    async fn rooms_adjust_the_word_list() {
+        let state = testing::state(Arc::new(MemoryMailer::default())).await;
+        let owner = testing::user(&state, "owner").await;
+        let room = testing::room(&state, "lobby", &owner).await;
+        for (word, mode) in [("darn", "allow"), ("heck", "ban")] {
+            state
+                .db
+                .upsert_room_word_filter(&db::RoomWordFilter {
+                    id: Uuid::new_v4().to_string(),
+                    room_id: room.id.clone(),
+                    word: word.to_string(),
+                    mode: mode.to_string(),
+                    created_by: owner.id.clone(),
+                    created_at: Utc::now(),
+                })
+                .await
+                .unwrap();
+        }
+        let filter = banned(&["darn"], WordAction::Mask);
+
+        let ctx = FilterContext {
+            state: &state,
+            room: Some(&room),
+        };
+        assert_eq!(
+            outcome(filter.check(&ctx, "darn, heck").await.unwrap()),
+            "rewrite: darn, ****"
+        );
+        assert_eq!(
+            check(&state, &filter, "darn, heck").await,
+            "rewrite: ****, heck"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_and_holds_links() {
+        let state = testing::state(Arc::new(MemoryMailer::default())).await;
+        let filter = Links {
+            allow: domains(&["example.com"]),
+            deny: domains(&["evil.net"]),
+        };
+
+        assert_eq!(
+            check(&state, &filter, "https://docs.example.com/x").await,
+            "pass"
+        );
+        assert_eq!(
+            check(&state, &filter, "https://www.evil.net").await,
+            "reject: Links to www.evil.net are not allowed"
+        );
+        assert!(check(&state, &filter, "https://notexample.com")
+            .await
+            .starts_with("hold:"));
+    }
+
+    #[tokio::test]
+    async fn chains_stop_at_the_first_hold() {
+        let state = testing::state(Arc::new(MemoryMailer::default())).await;
+        let ctx = FilterContext {
+            state: &state,
+            room: None,
+        };
+        let mut chain = FilterChain {
+            filters: Vec::new(),
+        };
+        chain.push(Arc::new(RepeatedChars { max_run: 2 }));
+        chain.push(Arc::new(banned(&["boo"], WordAction::Hold)));
+        chain.push(Arc::new(MaxLength { max_chars: 1 }));
+
+        // 被压缩后的文本才命中敏感词，且之后的过滤器不再运行
+        match chain.run(&ctx, "boooo").await.unwrap() {
+            Decision::Hold {
+                text,
+                filter,
+                reason,
+            } => {
+                assert_eq!(text, "boo");
+                assert_eq!(filter, "banned_words");
+                assert_eq!(reason, "it contains a filtered word");
+            }
+            _ => panic!("expected the message to be held"),
+        }
+        match chain.run(&ctx, "hiii").await.unwrap() {
+            Decision::Reject(reason) => assert!(reason.contains("max 1"), "{reason}"),
+            _ => panic!("expected the message to be rejected"),
+        }
+
+        let mut chain = FilterChain {
+            filters: Vec::new(),
+        };
+        chain.push(Arc::new(RepeatedChars { max_run: 2 }));
+        chain.push(Arc::new(banned(&["boo"], WordAction::Mask)));
+        match chain.run(&ctx, "boooo hiii").await.unwrap() {
+            Decision::Send(text) => assert_eq!(text, "*** hii"),
+            _ => panic!("expected the message to be sent"),
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    api_error, audit,
+    auth::{session::ClientInfo, AuthUser},
+    db, files, internal_error,
+    moderation::{load_room, load_user, require_reviewer},
+    rooms::{
+        self,
+        permissions::{self, Permission},
+    },
+    ApiError, AppState, StatusResponse,
+};
+
+pub mod builtin;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+const MAX_WORD_LEN: usize = 50;
+const MAX_WORDS_PER_ROOM: usize = 200;
+
+/// What a filter decides about a message.
+pub enum Verdict {
+    Pass,
+    /// Carries on down the chain with new text.
+    Rewrite(String),
+    /// Keeps the message back until a moderator approves it.
+    Hold(String),
+    /// Refuses the message; the reason is shown to the sender.
+    Reject(String),
+}
+
+/// Where a message is headed; guests' messages may have no room.
+pub struct FilterContext<'a> {
+    pub state: &'a AppState,
+    pub room: Option<&'a db::ChatRoom>,
+}
+
+/// Checks a chat message before it is stored or sent to anyone.
+#[async_trait]
+pub trait ContentFilter: Send + Sync {
+    /// Recorded with the messages it holds.
+    fn name(&self) -> &str;
+    async fn check(&self, ctx: &FilterContext<'_>, text: &str) -> Result<Verdict>;
+}
+
+/// What the chain as a whole decided.
+pub enum Decision {
+    /// Send the message with this, possibly rewritten, text.
+    Send(String),
+    Hold {
+        text: String,
+        filter: String,
+        reason: String,
+    },
+    Reject(String),
+}
+
+/// The filters every message passes through, in order. The first to hold
+/// or reject a message decides its fate; rewrites are seen by the filters
+/// after them.
+pub struct FilterChain {
+    filters: Vec<Arc<dyn ContentFilter>>,
+}
+
+impl FilterChain {
+    /// The built-in filters, configured from the `FILTER_*` environment
+    /// variables.
+    pub fn from_env() -> Result<Self> {
+        let mut chain = Self {
+            filters: Vec::new(),
+        };
+        builtin::register(&mut chain)?;
+        Ok(chain)
+    }
+
+    pub fn push(&mut self, filter: Arc<dyn ContentFilter>) {
+        self.filters.push(filter);
+    }
+
+    pub async fn run(&self, ctx: &FilterContext<'_>, text: &str) -> Result<Decision> {
+        let mut text = text.to_string();
+        for filter in &self.filters {
+            match filter.check(ctx, &text).await? {
+                Verdict::Pass => {}
+                Verdict::Rewrite(rewritten) => text = rewritten,
+                Verdict::Hold(reason) => {
+                    return Ok(Decision::Hold {
+                        text,
+                        filter: filter.name().to_string(),
+                        reason,
+                    })
+                }
+                Verdict::Reject(reason) => return Ok(Decision::Reject(reason)),
+            }
+        }
+        Ok(Decision::Send(text))
+    }
+}
+
+/// Stores a message a filter held, for the room's moderators to review.
+pub async fn hold(
+    state: &AppState,
+    room: &db::ChatRoom,
+    sender_id: &str,
+    new: rooms::NewMessage,
+    filter: String,
+    reason: String,
+) -> Result<()> {
+    state
+        .db
+        .create_held_message(&db::HeldMessage {
+            id: Uuid::new_v4().to_string(),
+            room_id: room.id.clone(),
+            sender_id: sender_id.to_string(),
+            username: new.username,
+            content: new.content,
+            message_type: new.message_type,
+            is_bot: new.is_bot,
+            integration_id: new.integration_id,
+            attachments: (!new.attachments.is_empty())
+                .then_some(sqlx::types::Json(new.attachments)),
+            filter,
+            reason,
+            status: "held".to_string(),
+            reviewed_by: None,
+            reviewed_at: None,
+            created_at: Utc::now(),
+        })
+        .await
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WordMode {
+    /// Filtered in this room on top of the server's list.
+    Ban,
+    /// Exempt in this room from the server's list.
+    Allow,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WordFilterRequest {
+    word: String,
+    mode: WordMode,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HeldQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+// 列出房间的敏感词设置
+pub async fn list_word_filters_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Path(room_id): Path<String>,
+) -> Result<Json<Vec<db::RoomWordFilter>>, ApiError> {
+    let room = load_room(&state, &room_id).await?;
+    permissions::authorize(&state, &room.id, &auth.id, Permission::ManageRoom).await?;
+
+    let filters = state
+        .db
+        .get_room_word_filters(&room.id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(filters))
+}
+
+// 为房间添加或修改敏感词
+pub async fn set_word_filter_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path(room_id): Path<String>,
+    Json(req): Json<WordFilterRequest>,
+) -> Result<Json<db::RoomWordFilter>, ApiError> {
+    let room = load_room(&state, &room_id).await?;
+    permissions::authorize(&state, &room.id, &auth.id, Permission::ManageRoom).await?;
+
+    let word = req.word.trim().to_lowercase();
+    if word.is_empty()
+        || word.chars().count() > MAX_WORD_LEN
+        || !word.chars().all(char::is_alphanumeric)
+    {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Words must be 1 to {MAX_WORD_LEN} letters or digits"),
+        ));
+    }
+    let existing = state
+        .db
+        .get_room_word_filters(&room.id)
+        .await
+        .map_err(internal_error)?;
+    if existing.len() >= MAX_WORDS_PER_ROOM && !existing.iter().any(|filter| filter.word == word) {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("A room can have at most {MAX_WORDS_PER_ROOM} filtered words"),
+        ));
+    }
+
+    let filter = state
+        .db
+        .upsert_room_word_filter(&db::RoomWordFilter {
+            id: Uuid::new_v4().to_string(),
+            room_id: room.id.clone(),
+            word,
+            mode: match req.mode {
+                WordMode::Ban => "ban",
+                WordMode::Allow => "allow",
+            }
+            .to_string(),
+            created_by: auth.id.clone(),
+            created_at: Utc::now(),
+        })
+        .await
+        .map_err(internal_error)?;
+    audit::record(
+        &state,
+        audit::Event {
+            action: "room.word_filter_set",
+            actor_id: Some(&auth.id),
+            target: Some(("room", &room.id)),
+            ip_address: client.ip_address.as_deref(),
+            details: json!({ "word": filter.word, "mode": filter.mode }),
+        },
+    )
+    .await;
+
+    Ok(Json(filter))
+}
+
+// 删除房间的敏感词设置
+pub async fn delete_word_filter_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path((room_id, filter_id)): Path<(String, String)>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let room = load_room(&state, &room_id).await?;
+    permissions::authorize(&state, &room.id, &auth.id, Permission::ManageRoom).await?;
+
+    if !state
+        .db
+        .delete_room_word_filter(&room.id, &filter_id)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(api_error(StatusCode::NOT_FOUND, "Word filter not found"));
+    }
+    audit::record(
+        &state,
+        audit::Event {
+            action: "room.word_filter_delete",
+            actor_id: Some(&auth.id),
+            target: Some(("room", &room.id)),
+            ip_address: client.ip_address.as_deref(),
+            details: json!({ "filter_id": filter_id }),
+        },
+    )
+    .await;
+
+    Ok(Json(StatusResponse::new("Word filter deleted")))
+}
+
+// 待审核的消息，最早的在前
+pub async fn list_held_messages_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Path(room_id): Path<String>,
+    Query(query): Query<HeldQuery>,
+) -> Result<Json<Vec<db::HeldMessage>>, ApiError> {
+    let room = load_room(&state, &room_id).await?;
+    let user = load_user(&state, &auth.id).await?;
+    require_reviewer(&state, &room.id, &user).await?;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let held = state
+        .db
+        .get_pending_held_messages(&room.id, limit, offset)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(held))
+}
+
+// 通过审核，消息照常发出
+pub async fn approve_held_message_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path(held_id): Path<String>,
+) -> Result<Json<db::HeldMessage>, ApiError> {
+    let (room, actor) = review(&state, &auth, client, &held_id).await?;
+    let held = state
+        .db
+        .review_held_message(&held_id, "approved", &actor.user.id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(already_reviewed)?;
+
+    let new = rooms::NewMessage {
+        sender_id: Some(held.sender_id.clone()),
+        username: held.username.clone(),
+        content: held.content.clone(),
+        message_type: held.message_type.clone(),
+        is_bot: held.is_bot,
+        integration_id: held.integration_id.clone(),
+        attachments: held
+            .attachments
+            .clone()
+            .map(|attachments| attachments.0)
+            .unwrap_or_default(),
+    };
+    let message = rooms::deliver(&state, &room.name, Some(&room), new)
+        .await
+        .map_err(internal_error)?;
+    actor
+        .record(
+            &state,
+            "moderation.held_approve",
+            Some(("held_message", &held.id)),
+            json!({
+                "room_id": room.id,
+                "sender_id": held.sender_id,
+                "message_id": message.map(|message| message.id),
+            }),
+        )
+        .await;
+
+    Ok(Json(held))
+}
+
+// 驳回，消息不会发出
+pub async fn reject_held_message_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path(held_id): Path<String>,
+) -> Result<Json<db::HeldMessage>, ApiError> {
+    let (room, actor) = review(&state, &auth, client, &held_id).await?;
+    let held = state
+        .db
+        .review_held_message(&held_id, "rejected", &actor.user.id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(already_reviewed)?;
+    if let Some(attachments) = &held.attachments {
+        files::discard(&state, attachments).await;
+    }
+    actor
+        .record(
+            &state,
+            "moderation.held_reject",
+            Some(("held_message", &held.id)),
+            json!({ "room_id": room.id, "sender_id": held.sender_id }),
+        )
+        .await;
+
+    Ok(Json(held))
+}
+
+/// Loads a held message's room and checks the caller may review it.
+async fn review(
+    state: &AppState,
+    auth: &AuthUser,
+    client: ClientInfo,
+    held_id: &str,
+) -> Result<(db::ChatRoom, audit::Actor), ApiError> {
+    let held = state
+        .db
+        .get_held_message(held_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Held message not found"))?;
+    let room = load_room(state, &held.room_id).await?;
+    let actor = audit::Actor {
+        user: load_user(state, &auth.id).await?,
+        ip_address: client.ip_address,
+    };
+    require_reviewer(state, &room.id, &actor.user).await?;
+
+    Ok((room, actor))
+}
+
+fn already_reviewed() -> ApiError {
+    api_error(
+        StatusCode::CONFLICT,
+        "This message has already been reviewed",
+    )
+}
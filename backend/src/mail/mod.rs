@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use uuid::Uuid;
+
+/// A plain-text email ready to be handed to a [`Mailer`].
+#[derive(Debug, Clone)]
+pub struct OutgoingMail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers account emails (password resets, verification links, ...).
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: OutgoingMail) -> Result<()>;
+}
+
+/// Sends mail through an SMTP relay.
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        port: u16,
+        credentials: Option<(String, String)>,
+        from: &str,
+    ) -> Result<Self> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .with_context(|| format!("invalid SMTP host {host}"))?
+            .port(port);
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: from
+                .parse()
+                .with_context(|| format!("invalid sender address {from}"))?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: OutgoingMail) -> Result<()> {
+        let message = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(mail
+                .to
+                .parse()
+                .with_context(|| format!("invalid recipient {}", mail.to))?)
+            .subject(mail.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(mail.body)?;
+
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Writes each mail to its own `.eml` file in a directory, for local
+/// development without a mail server.
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: OutgoingMail) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S"),
+            Uuid::new_v4()
+        ));
+        let contents = format!(
+            "To: {}\r\nSubject: {}\r\nDate: {}\r\n\r\n{}\r\n",
+            mail.to,
+            mail.subject,
+            Utc::now().to_rfc2822(),
+            mail.body
+        );
+        tokio::fs::write(&path, contents)
+            .await
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Keeps sent mail in memory so tests can inspect it.
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<OutgoingMail>>,
+}
+
+impl MemoryMailer {
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<OutgoingMail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, mail: OutgoingMail) -> Result<()> {
+        self.sent.lock().unwrap().push(mail);
+        Ok(())
+    }
+}
+
+/// Picks a transport from `MAIL_TRANSPORT` (`smtp`, `file` or `memory`).
+/// Defaults to dropping files into `MAIL_DIR` (default `mail-outbox`).
+pub fn mailer_from_env() -> Result<Arc<dyn Mailer>> {
+    let transport = std::env::var("MAIL_TRANSPORT").unwrap_or_else(|_| "file".to_string());
+    match transport.as_str() {
+        "smtp" => {
+            let host = std::env::var("SMTP_HOST").context("SMTP_HOST is required")?;
+            let port = match std::env::var("SMTP_PORT") {
+                Ok(port) => port.parse().context("SMTP_PORT must be a port number")?,
+                Err(_) => 587,
+            };
+            let credentials = std::env::var("SMTP_USERNAME")
+                .ok()
+                .zip(std::env::var("SMTP_PASSWORD").ok());
+            let from = std::env::var("MAIL_FROM")
+                .unwrap_or_else(|_| "ChatX <no-reply@localhost>".to_string());
+            Ok(Arc::new(SmtpMailer::new(&host, port, credentials, &from)?))
+        }
+        "file" => {
+            let dir = std::env::var("MAIL_DIR").unwrap_or_else(|_| "mail-outbox".to_string());
+            Ok(Arc::new(FileMailer::new(dir)))
+        }
+        "memory" => Ok(Arc::new(MemoryMailer::default())),
+        other => anyhow::bail!("unknown MAIL_TRANSPORT {other}"),
+    }
+}
@@ -1,48 +1,87 @@
+mod admin;
+mod audit;
+mod auth;
+mod bots;
+mod commands;
+// Friendship and direct-room queries are not wired to handlers yet.
+#[allow(dead_code)]
+mod db;
+mod files;
+mod filters;
+mod mail;
+mod moderation;
+mod outbound;
+mod profile;
+mod rate_limit;
+mod rooms;
+mod storage;
+#[cfg(test)]
+mod testing;
+mod unfurl;
+mod webhooks;
+
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        Path, State,
+        ConnectInfo, DefaultBodyLimit, Path, Query, State,
     },
     http::{HeaderValue, Method, StatusCode},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     Json, Router,
 };
 use bcrypt::{hash, verify, DEFAULT_COST};
-use chrono::{Duration, Utc};
+use chrono::Utc;
 use dashmap::DashMap;
 use futures_util::{sink::SinkExt, stream::StreamExt};
 use include_dir::{include_dir, Dir};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use mime_guess::from_path;
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::broadcast;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::{broadcast, mpsc};
 use tower_http::cors::CorsLayer;
 use uuid::Uuid;
 
+use crate::{
+    db::Database,
+    mail::Mailer,
+    rate_limit::{
+        flood::{FloodControl, MessageLimits},
+        Lockout, RateLimitLayer, RateLimiter,
+    },
+};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct ChatMessage {
     username: String,
     message: String,
     channel: String,
     message_type: Option<String>,
+    /// Set by the server for messages posted by bot accounts.
+    #[serde(default)]
+    is_bot: bool,
+    /// Id of the stored message; guests' messages are not stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// Set by the server for messages posted through an incoming webhook.
+    #[serde(default)]
+    is_integration: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<db::Attachment>,
+    /// With `user_list` events: the users in the channel.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    members: Vec<ChannelMember>,
+    /// With `updated` events: the previews of the message's links.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    previews: Vec<db::LinkPreview>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct User {
-    id: Uuid,
-    username: String,
-    email: String,
-    password_hash: String,
-    created_at: chrono::DateTime<Utc>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String, // user id
+struct ChannelMember {
     username: String,
-    exp: usize,
+    /// Unset for guests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -50,25 +89,87 @@ struct RegisterRequest {
     username: String,
     email: String,
     password: String,
+    /// Optional name for this device in the session list.
+    device_label: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct LoginRequest {
     username: String,
     password: String,
+    /// Optional name for this device in the session list.
+    device_label: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 struct AuthResponse {
-    token: String,
+    #[serde(flatten)]
+    tokens: auth::session::TokenResponse,
     user: UserResponse,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum LoginResponse {
+    Authenticated(AuthResponse),
+    /// The password was right but the account has 2FA; the client finishes
+    /// the login at `POST /api/auth/2fa/verify` with this challenge.
+    TwoFactorRequired {
+        two_factor_required: bool,
+        challenge_token: String,
+    },
+}
+
 #[derive(Debug, Serialize)]
 struct UserResponse {
-    id: Uuid,
+    id: String,
     username: String,
-    email: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    two_factor_enabled: Option<bool>,
+    /// Whether the account may use `/api/admin`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_admin: Option<bool>,
+    display_name: Option<String>,
+    bio: Option<String>,
+    timezone: Option<String>,
+    /// The external image the user linked, or their uploaded avatar or
+    /// identicon.
+    avatar_url: String,
+    is_bot: bool,
+}
+
+impl UserResponse {
+    /// Everything the account owner may see about themselves.
+    fn private(user: db::User) -> Self {
+        Self {
+            avatar_url: profile::avatar::avatar_url(&user),
+            id: user.id,
+            username: user.username,
+            email: Some(user.email),
+            email_verified: Some(user.email_verified),
+            two_factor_enabled: Some(user.totp_enabled),
+            is_admin: Some(user.is_admin),
+            display_name: user.display_name,
+            bio: user.bio,
+            timezone: user.timezone,
+            is_bot: user.is_bot,
+        }
+    }
+
+    /// The profile as shown to other users, without the email address.
+    fn public(user: db::User) -> Self {
+        Self {
+            email: None,
+            email_verified: None,
+            two_factor_enabled: None,
+            is_admin: None,
+            ..Self::private(user)
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -76,26 +177,86 @@ struct ErrorResponse {
     error: String,
 }
 
+#[derive(Debug, Serialize)]
+struct StatusResponse {
+    message: String,
+}
+
+impl StatusResponse {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+type ApiError = (StatusCode, Json<ErrorResponse>);
+
+fn api_error(status: StatusCode, error: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(ErrorResponse {
+            error: error.into(),
+        }),
+    )
+}
+
+fn internal_error(err: anyhow::Error) -> ApiError {
+    eprintln!("internal error: {err:#}");
+    api_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+}
+
 struct Channel {
     tx: broadcast::Sender<String>,
-    users: DashMap<String, ()>,
+    /// Usernames in the channel, with their avatar URLs.
+    users: DashMap<String, Option<String>>,
+    /// Usernames kicked from the channel; their connections close.
+    kicks: broadcast::Sender<String>,
 }
 
 impl Default for Channel {
     fn default() -> Self {
         let (tx, _rx) = broadcast::channel(100);
+        let (kicks, _rx) = broadcast::channel(16);
         Self {
             tx,
             users: DashMap::new(),
+            kicks,
         }
     }
 }
 
 struct AppState {
     channels: DashMap<String, Arc<Channel>>,
-    users: DashMap<String, User>,     // username -> User
-    users_by_id: DashMap<Uuid, User>, // id -> User
-    jwt_secret: String,
+    db: Database,
+    jwt_keys: auth::keys::JwtKeys,
+    mailer: Arc<dyn Mailer>,
+    /// Base URL of the web client, used to build links in emails.
+    public_url: String,
+    /// Only users with a confirmed email address may post messages.
+    require_email_verification: bool,
+    /// Signals open WebSocket connections when their session is revoked,
+    /// keyed by session id.
+    session_sockets: DashMap<String, broadcast::Sender<()>>,
+    /// Login attempts per account, on top of the per-IP limit on `/api/auth`.
+    login_limiter: RateLimiter,
+    /// Progressive lockout after repeated failed logins, keyed by username.
+    login_lockout: Lockout,
+    /// Message rate limits and auto-mutes on the WebSocket.
+    flood: FloodControl,
+    /// Content filters every chat message passes before it is sent.
+    filters: filters::FilterChain,
+    /// Where uploaded files are kept.
+    storage: Arc<dyn storage::Storage>,
+    uploads: files::UploadLimits,
+    /// Single sign-on provider, when `OIDC_ISSUER` is configured.
+    oidc: Option<auth::oidc::OidcProvider>,
+    /// Outgoing webhook delivery queue.
+    webhooks: webhooks::Dispatcher,
+    unfurler: unfurl::Unfurler,
+    /// Built-in slash commands.
+    commands: commands::CommandRegistry,
+    started_at: std::time::Instant,
 }
 
 // 静态文件目录（前端打包产物）
@@ -103,30 +264,334 @@ static STATIC_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/../frontend/dist");
 
 #[tokio::main]
 async fn main() {
-    let jwt_secret = std::env::var("JWT_SECRET")
-        .unwrap_or_else(|_| "your-secret-key-change-this-in-production".to_string());
+    let jwt_keys = auth::keys::JwtKeys::from_env().expect("Failed to load JWT signing keys");
+    let database_url =
+        std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:chatx.db".to_string());
+
+    let db = Database::new(&database_url)
+        .await
+        .expect("Failed to connect to database");
+    db.init().await.expect("Failed to run database migrations");
+
+    // ADMIN_USERNAMES 中列出的已有账号设为管理员
+    let admin_usernames: Vec<String> = std::env::var("ADMIN_USERNAMES")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    db.promote_admins(&admin_usernames)
+        .await
+        .expect("Failed to promote admins");
+
+    let mailer = mail::mailer_from_env().expect("Failed to configure mail transport");
+    let public_url =
+        std::env::var("PUBLIC_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let require_email_verification = std::env::var("REQUIRE_EMAIL_VERIFICATION")
+        .map(|value| matches!(value.as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+    let message_limits = MessageLimits::from_env().expect("Invalid message rate limit settings");
+    let filters = filters::FilterChain::from_env().expect("Failed to configure content filters");
+    let storage = storage::storage_from_env().expect("Failed to configure file storage");
+    let uploads = files::UploadLimits::from_env().expect("Invalid upload settings");
+    // multipart 表单的其余部分（文件名、说明文字）留出余量
+    let upload_body_limit = uploads.max_bytes.saturating_add(64 * 1024);
+    let oidc = auth::oidc::OidcConfig::from_env(&public_url)
+        .expect("Invalid OIDC settings")
+        .map(auth::oidc::OidcProvider::new);
 
     let app_state = Arc::new(AppState {
         channels: DashMap::new(),
-        users: DashMap::new(),
-        users_by_id: DashMap::new(),
-        jwt_secret,
+        db,
+        jwt_keys,
+        mailer,
+        public_url,
+        require_email_verification,
+        session_sockets: DashMap::new(),
+        login_limiter: RateLimiter::new(5, 1.0 / 12.0),
+        login_lockout: Lockout::new(
+            5,
+            Duration::from_secs(30),
+            Duration::from_secs(15 * 60),
+            Duration::from_secs(60 * 60),
+        ),
+        flood: FloodControl::new(message_limits),
+        filters,
+        storage,
+        uploads,
+        oidc,
+        webhooks: webhooks::Dispatcher::from_env().expect("Invalid webhook settings"),
+        unfurler: unfurl::Unfurler::from_env().expect("Invalid link preview settings"),
+        commands: commands::CommandRegistry::with_builtins(),
+        started_at: std::time::Instant::now(),
+    });
+
+    webhooks::spawn_worker(app_state.clone());
+    unfurl::spawn_worker(app_state.clone());
+
+    // 认证接口按 IP 限流：突发 10 次，之后每 6 秒恢复 1 次
+    let auth_ip_limiter = Arc::new(RateLimiter::new(10, 1.0 / 6.0));
+
+    // 定期清理限流器中的过期记录
+    tokio::spawn({
+        let state = app_state.clone();
+        let auth_ip_limiter = auth_ip_limiter.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                auth_ip_limiter.prune();
+                state.login_limiter.prune();
+                state.login_lockout.prune();
+                state.flood.prune();
+            }
+        }
     });
 
     let cors = CorsLayer::new()
         .allow_origin("http://localhost:3000".parse::<HeaderValue>().unwrap())
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
         .allow_headers([
             axum::http::header::CONTENT_TYPE,
             axum::http::header::AUTHORIZATION,
         ]);
 
-    let app = Router::new()
-        .route("/ws", get(websocket_handler))
-        .route("/api/channels", get(get_channels_handler))
+    let auth_routes = Router::new()
         .route("/api/auth/register", post(register_handler))
         .route("/api/auth/login", post(login_handler))
         .route("/api/auth/verify", post(verify_token_handler))
+        .route("/api/auth/refresh", post(auth::session::refresh_handler))
+        .route("/api/auth/logout", post(auth::session::logout_handler))
+        .route(
+            "/api/auth/2fa/verify",
+            post(auth::two_factor::verify_login_handler),
+        )
+        .route(
+            "/api/auth/verify-email",
+            post(auth::verification::verify_email_handler),
+        )
+        .route(
+            "/api/auth/password-reset/request",
+            post(auth::password::request_reset_handler),
+        )
+        .route(
+            "/api/auth/password-reset/confirm",
+            post(auth::password::confirm_reset_handler),
+        )
+        .route("/api/auth/oidc/login", get(auth::oidc::login_handler))
+        .route(
+            "/api/auth/oidc/callback",
+            post(auth::oidc::callback_handler),
+        )
+        .route_layer(RateLimitLayer::per_ip(auth_ip_limiter));
+
+    let app = Router::new()
+        .route("/ws", get(websocket_handler))
+        .route("/api/channels", get(get_channels_handler))
+        .route("/api/rooms", get(rooms::list_rooms_handler))
+        .route(
+            "/api/rooms/:room_id/files",
+            post(files::upload_handler).layer(DefaultBodyLimit::max(upload_body_limit)),
+        )
+        .route("/api/files/:id", get(files::download_handler))
+        .route(
+            "/api/files/:id/thumbnails/:size",
+            get(files::thumbnail_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/settings",
+            patch(rooms::update_room_settings_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/messages",
+            get(rooms::get_messages_handler),
+        )
+        .route(
+            "/api/messages/:id",
+            patch(rooms::edit_message_handler).delete(rooms::delete_message_handler),
+        )
+        .route(
+            "/api/messages/:id/report",
+            post(moderation::reports::report_message_handler),
+        )
+        .route(
+            "/api/reports/:id/resolve",
+            post(moderation::reports::resolve_report_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/webhooks",
+            get(webhooks::list_webhooks_handler).post(webhooks::create_webhook_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/webhooks/:webhook_id",
+            delete(webhooks::delete_webhook_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/roles",
+            get(rooms::permissions::list_roles_handler)
+                .post(rooms::permissions::create_role_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/roles/:role_id",
+            patch(rooms::permissions::update_role_handler)
+                .delete(rooms::permissions::delete_role_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/members",
+            get(rooms::permissions::list_members_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/members/:user_id/role",
+            put(rooms::permissions::set_member_role_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/sanctions",
+            get(moderation::list_sanctions_handler).post(moderation::create_sanction_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/sanctions/:sanction_id",
+            delete(moderation::lift_sanction_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/reports",
+            get(moderation::reports::list_room_reports_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/word-filters",
+            get(filters::list_word_filters_handler).post(filters::set_word_filter_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/word-filters/:filter_id",
+            delete(filters::delete_word_filter_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/held-messages",
+            get(filters::list_held_messages_handler),
+        )
+        .route(
+            "/api/held-messages/:id/approve",
+            post(filters::approve_held_message_handler),
+        )
+        .route(
+            "/api/held-messages/:id/reject",
+            post(filters::reject_held_message_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/incoming-webhooks",
+            get(webhooks::incoming::list_incoming_webhooks_handler)
+                .post(webhooks::incoming::create_incoming_webhook_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/incoming-webhooks/:webhook_id",
+            delete(webhooks::incoming::revoke_incoming_webhook_handler),
+        )
+        .route("/api/hooks/:token", post(webhooks::incoming::post_handler))
+        .route(
+            "/api/rooms/:room_id/webhooks/:webhook_id/deliveries",
+            get(webhooks::list_deliveries_handler),
+        )
+        .route(
+            "/api/rooms/:room_id/webhooks/:webhook_id/deliveries/:delivery_id/redeliver",
+            post(webhooks::redeliver_handler),
+        )
+        .route("/.well-known/jwks.json", get(auth::keys::jwks_handler))
+        .merge(auth_routes)
+        .route(
+            "/api/me",
+            get(profile::get_me_handler).patch(profile::update_me_handler),
+        )
+        .route(
+            "/api/me/avatar",
+            put(profile::avatar::upload_avatar_handler)
+                .delete(profile::avatar::delete_avatar_handler)
+                .layer(DefaultBodyLimit::max(upload_body_limit)),
+        )
+        .route(
+            "/api/me/password",
+            post(auth::password::change_password_handler),
+        )
+        .route(
+            "/api/me/verify-email",
+            post(auth::verification::resend_verification_handler),
+        )
+        .route("/api/me/2fa/setup", post(auth::two_factor::setup_handler))
+        .route("/api/me/2fa/enable", post(auth::two_factor::enable_handler))
+        .route(
+            "/api/me/2fa/disable",
+            post(auth::two_factor::disable_handler),
+        )
+        .route(
+            "/api/me/reports",
+            get(moderation::reports::my_reports_handler),
+        )
+        .route(
+            "/api/me/sessions",
+            get(auth::session::list_sessions_handler),
+        )
+        .route(
+            "/api/me/sessions/:id",
+            delete(auth::session::delete_session_handler),
+        )
+        .route("/api/users/:id", get(profile::get_user_handler))
+        .route(
+            "/api/users/:id/avatar",
+            get(profile::avatar::get_avatar_handler),
+        )
+        .route(
+            "/api/bots",
+            get(bots::list_bots_handler).post(bots::create_bot_handler),
+        )
+        .route("/api/bots/:id", delete(bots::delete_bot_handler))
+        .route(
+            "/api/bots/:id/tokens",
+            get(bots::list_tokens_handler).post(bots::create_token_handler),
+        )
+        .route(
+            "/api/bots/:id/tokens/:token_id",
+            delete(bots::revoke_token_handler),
+        )
+        .route(
+            "/api/bots/:id/commands",
+            get(commands::list_bot_commands_handler).post(commands::create_bot_command_handler),
+        )
+        .route(
+            "/api/bots/:id/commands/:command_id",
+            delete(commands::delete_bot_command_handler),
+        )
+        .route("/api/commands", get(commands::list_commands_handler))
+        .route("/api/admin/users", get(admin::list_users_handler))
+        .route("/api/admin/users/:id", get(admin::get_user_handler))
+        .route(
+            "/api/admin/users/:id/disable",
+            post(admin::disable_user_handler),
+        )
+        .route(
+            "/api/admin/users/:id/enable",
+            post(admin::enable_user_handler),
+        )
+        .route(
+            "/api/admin/users/:id/password-reset",
+            post(admin::reset_password_handler),
+        )
+        .route("/api/admin/users/:id/admin", put(admin::set_admin_handler))
+        .route("/api/admin/rooms/:id", delete(admin::delete_room_handler))
+        .route("/api/admin/stats", get(admin::stats_handler))
+        .route(
+            "/api/admin/reports",
+            get(moderation::reports::list_reports_handler),
+        )
+        .route("/api/admin/audit", get(audit::list_audit_log_handler))
+        .route(
+            "/api/admin/audit/export",
+            get(audit::export_audit_log_handler),
+        )
         // 静态文件服务，根路径单独处理
         .route("/", get(static_index_handler))
         .route("/*path", get(static_handler))
@@ -137,218 +602,221 @@ async fn main() {
     println!("listening on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
 // 用户注册
 async fn register_handler(
     State(state): State<Arc<AppState>>,
+    client: auth::session::ClientInfo,
     Json(req): Json<RegisterRequest>,
-) -> Result<Json<AuthResponse>, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Json<AuthResponse>, ApiError> {
     // 验证输入
-    if req.username.trim().is_empty() || req.email.trim().is_empty() || req.password.len() < 6 {
-        return Err((
+    if req.username.trim().is_empty()
+        || req.email.trim().is_empty()
+        || req.password.len() < auth::MIN_PASSWORD_LEN
+    {
+        return Err(api_error(
             StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Username and email are required, password must be at least 6 characters"
-                    .to_string(),
-            }),
+            format!(
+                "Username and email are required, password must be at least {} characters",
+                auth::MIN_PASSWORD_LEN
+            ),
         ));
     }
+    if !auth::verification::is_valid_email(req.email.trim()) {
+        return Err(api_error(StatusCode::BAD_REQUEST, "Invalid email address"));
+    }
 
-    // 检查用户名是否已存在
-    if state.users.contains_key(&req.username) {
-        return Err((
-            StatusCode::CONFLICT,
-            Json(ErrorResponse {
-                error: "Username already exists".to_string(),
-            }),
-        ));
+    // 检查用户名或邮箱是否已存在
+    if state
+        .db
+        .get_user_by_username(&req.username)
+        .await
+        .map_err(internal_error)?
+        .is_some()
+    {
+        return Err(api_error(StatusCode::CONFLICT, "Username already exists"));
+    }
+    if state
+        .db
+        .get_user_by_email(req.email.trim())
+        .await
+        .map_err(internal_error)?
+        .is_some()
+    {
+        return Err(api_error(StatusCode::CONFLICT, "Email already in use"));
     }
 
     // 哈希密码
-    let password_hash = match hash(&req.password, DEFAULT_COST) {
-        Ok(hash) => hash,
-        Err(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: "Failed to hash password".to_string(),
-                }),
-            ));
-        }
-    };
+    let password_hash = hash(&req.password, DEFAULT_COST)
+        .map_err(|_| api_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash password"))?;
 
     // 创建用户
-    let user_id = Uuid::new_v4();
-    let user = User {
-        id: user_id,
+    let user = db::User {
+        id: Uuid::new_v4().to_string(),
         username: req.username.clone(),
-        email: req.email.clone(),
+        email: req.email.trim().to_string(),
         password_hash,
         created_at: Utc::now(),
+        last_seen: None,
+        status: "offline".to_string(),
+        display_name: None,
+        bio: None,
+        timezone: None,
+        avatar_url: None,
+        email_verified: false,
+        totp_secret: None,
+        totp_enabled: false,
+        totp_last_step: None,
+        is_bot: false,
+        bot_owner_id: None,
+        is_admin: false,
+        disabled_at: None,
+        avatar_hash: None,
+        avatar_type: None,
     };
 
     // 保存用户
-    state.users.insert(req.username.clone(), user.clone());
-    state.users_by_id.insert(user_id, user.clone());
-
-    // 生成JWT token
-    let claims = Claims {
-        sub: user_id.to_string(),
-        username: user.username.clone(),
-        exp: (Utc::now() + Duration::hours(24)).timestamp() as usize,
-    };
+    state.db.create_user(&user).await.map_err(internal_error)?;
+    audit::record(
+        &state,
+        audit::Event {
+            details: serde_json::json!({ "username": user.username }),
+            ..audit::Event::account("auth.register", &user.id, &client)
+        },
+    )
+    .await;
 
-    let token = match encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(state.jwt_secret.as_ref()),
-    ) {
-        Ok(token) => token,
-        Err(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: "Failed to generate token".to_string(),
-                }),
-            ));
-        }
-    };
+    // 发送邮箱验证邮件
+    if let Err(err) = auth::verification::send_verification_email(&state, &user).await {
+        eprintln!("failed to send verification mail: {err:#}");
+    }
+
+    // 创建会话并签发token
+    let tokens = auth::session::start_session(&state, &user, client, req.device_label).await?;
 
     Ok(Json(AuthResponse {
-        token,
-        user: UserResponse {
-            id: user.id,
-            username: user.username,
-            email: user.email,
-        },
+        tokens,
+        user: UserResponse::private(user),
     }))
 }
 
 // 用户登录
 async fn login_handler(
     State(state): State<Arc<AppState>>,
+    client: auth::session::ClientInfo,
     Json(req): Json<LoginRequest>,
-) -> Result<Json<AuthResponse>, (StatusCode, Json<ErrorResponse>)> {
-    // 查找用户
-    let user = match state.users.get(&req.username) {
-        Some(user) => user.clone(),
-        None => {
-            return Err((
+) -> Response {
+    // 按账号限流，连续失败后逐步延长锁定时间
+    let account = req.username.clone();
+    if let Err(retry_after) = state
+        .login_lockout
+        .check(&account)
+        .and_then(|_| state.login_limiter.check(&account))
+    {
+        audit::record(
+            &state,
+            audit::Event {
+                action: "auth.login_failed",
+                ip_address: client.ip_address.as_deref(),
+                details: serde_json::json!({ "username": account, "reason": "rate_limited" }),
+                ..Default::default()
+            },
+        )
+        .await;
+        return rate_limit::too_many_requests(retry_after);
+    }
+
+    let result = login(&state, client, req).await;
+    match &result {
+        Ok(Json(LoginResponse::Authenticated(_))) => state.login_lockout.record_success(&account),
+        Err((StatusCode::UNAUTHORIZED, _)) => state.login_lockout.record_failure(&account),
+        _ => {}
+    }
+    result.into_response()
+}
+
+async fn login(
+    state: &AppState,
+    client: auth::session::ClientInfo,
+    req: LoginRequest,
+) -> Result<Json<LoginResponse>, ApiError> {
+    // 查找用户并验证密码
+    let user = state
+        .db
+        .get_user_by_username(&req.username)
+        .await
+        .map_err(internal_error)?
+        .filter(|user| !user.is_bot);
+    let user = match user {
+        Some(user) if verify(&req.password, &user.password_hash).unwrap_or(false) => user,
+        user => {
+            audit::record(
+                state,
+                audit::Event {
+                    action: "auth.login_failed",
+                    actor_id: user.as_ref().map(|user| user.id.as_str()),
+                    ip_address: client.ip_address.as_deref(),
+                    details: serde_json::json!({
+                        "username": req.username,
+                        "reason": if user.is_some() { "wrong_password" } else { "unknown_user" },
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await;
+            return Err(api_error(
                 StatusCode::UNAUTHORIZED,
-                Json(ErrorResponse {
-                    error: "Invalid username or password".to_string(),
-                }),
+                "Invalid username or password",
             ));
         }
     };
 
-    // 验证密码
-    if !verify(&req.password, &user.password_hash).unwrap_or(false) {
-        return Err((
-            StatusCode::UNAUTHORIZED,
-            Json(ErrorResponse {
-                error: "Invalid username or password".to_string(),
-            }),
-        ));
+    // 开启两步验证的账号需要再提交验证码
+    if user.totp_enabled {
+        let challenge_token = auth::two_factor::create_login_challenge(state, &user).await?;
+        return Ok(Json(LoginResponse::TwoFactorRequired {
+            two_factor_required: true,
+            challenge_token,
+        }));
     }
 
-    // 生成JWT token
-    let claims = Claims {
-        sub: user.id.to_string(),
-        username: user.username.clone(),
-        exp: (Utc::now() + Duration::hours(24)).timestamp() as usize,
-    };
-
-    let token = match encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(state.jwt_secret.as_ref()),
-    ) {
-        Ok(token) => token,
-        Err(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: "Failed to generate token".to_string(),
-                }),
-            ));
-        }
-    };
+    // 创建会话并签发token
+    let ip_address = client.ip_address.clone();
+    let tokens = auth::session::start_session(state, &user, client, req.device_label).await?;
+    auth::record_login(state, &user, "password", ip_address.as_deref()).await;
 
-    Ok(Json(AuthResponse {
-        token,
-        user: UserResponse {
-            id: user.id,
-            username: user.username,
-            email: user.email,
-        },
-    }))
+    Ok(Json(LoginResponse::Authenticated(AuthResponse {
+        tokens,
+        user: UserResponse::private(user),
+    })))
 }
 
 // 验证token
 async fn verify_token_handler(
     State(state): State<Arc<AppState>>,
     axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Result<Json<UserResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let token = match params.get("token") {
-        Some(token) => token,
-        None => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(ErrorResponse {
-                    error: "Token is required".to_string(),
-                }),
-            ));
-        }
-    };
+) -> Result<Json<UserResponse>, ApiError> {
+    let token = params
+        .get("token")
+        .ok_or_else(|| api_error(StatusCode::BAD_REQUEST, "Token is required"))?;
 
-    let claims = match decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(state.jwt_secret.as_ref()),
-        &Validation::default(),
-    ) {
-        Ok(claims) => claims.claims,
-        Err(_) => {
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                Json(ErrorResponse {
-                    error: "Invalid token".to_string(),
-                }),
-            ));
-        }
-    };
+    let claims = auth::authenticate(&state, token).await?;
 
-    let user_id = match Uuid::parse_str(&claims.sub) {
-        Ok(id) => id,
-        Err(_) => {
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                Json(ErrorResponse {
-                    error: "Invalid token".to_string(),
-                }),
-            ));
-        }
-    };
-
-    let user = match state.users_by_id.get(&user_id) {
-        Some(user) => user.clone(),
-        None => {
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                Json(ErrorResponse {
-                    error: "User not found".to_string(),
-                }),
-            ));
-        }
-    };
+    let user = state
+        .db
+        .get_user_by_id(&claims.sub)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "User not found"))?;
 
-    Ok(Json(UserResponse {
-        id: user.id,
-        username: user.username,
-        email: user.email,
-    }))
+    Ok(Json(UserResponse::private(user)))
 }
 
 // 新增：根路径 handler
@@ -392,15 +860,138 @@ async fn static_handler(Path(path): Path<String>) -> Response {
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct WsQuery {
+    token: Option<String>,
+}
+
+/// The account behind a WebSocket connection that presented a token.
+struct WsIdentity {
+    user_id: String,
+    /// The session, or for bots the API token, whose revocation closes the
+    /// connection.
+    session_id: String,
+    username: String,
+    email_verified: bool,
+    avatar_url: String,
+    /// The bot and token scopes, when a bot connected with an API token.
+    bot: Option<bots::BotCaller>,
+}
+
 #[axum::debug_handler]
 async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| websocket(socket, state))
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<WsQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    // 登录用户通过 ?token= 认证，未携带 token 的连接以访客身份加入
+    let identity = match query.token {
+        Some(token) if token.starts_with(bots::API_TOKEN_PREFIX) => {
+            let bot = bots::authenticate_token(&state, &token).await?;
+            let user = state
+                .db
+                .get_user_by_id(&bot.id)
+                .await
+                .map_err(internal_error)?
+                .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "User not found"))?;
+            Some(WsIdentity {
+                user_id: bot.id.clone(),
+                session_id: bot.token_id.clone(),
+                username: bot.username.clone(),
+                email_verified: true,
+                avatar_url: profile::avatar::avatar_url(&user),
+                bot: Some(bot),
+            })
+        }
+        Some(token) => {
+            let claims = auth::authenticate(&state, &token).await?;
+            let user = state
+                .db
+                .get_user_by_id(&claims.sub)
+                .await
+                .map_err(internal_error)?
+                .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "User not found"))?;
+            Some(WsIdentity {
+                avatar_url: profile::avatar::avatar_url(&user),
+                user_id: user.id,
+                session_id: claims.sid,
+                username: user.username,
+                email_verified: user.email_verified,
+                bot: None,
+            })
+        }
+        None => None,
+    };
+
+    // 登录用户按账号限流，访客按 IP 限流
+    let sender_key = match &identity {
+        Some(identity) => format!("user:{}", identity.username),
+        None => format!("ip:{}", addr.ip()),
+    };
+
+    // 超长帧直接断开，略长的消息在应用层拒绝并提示
+    let max_frame = state.flood.limits().max_message_bytes.saturating_mul(4);
+    let ip_address = addr.ip().to_string();
+    Ok(ws
+        .max_message_size(max_frame)
+        .on_upgrade(|socket| websocket(socket, state, identity, sender_key, ip_address)))
+}
+
+/// The users in a channel, as usernames in `message` and with their
+/// avatars in `members`.
+fn user_list_event(channel_name: &str, channel: &Channel) -> Option<String> {
+    let members: Vec<ChannelMember> = channel
+        .users
+        .iter()
+        .map(|entry| ChannelMember {
+            username: entry.key().clone(),
+            avatar_url: entry.value().clone(),
+        })
+        .collect();
+    let usernames: Vec<&str> = members
+        .iter()
+        .map(|member| member.username.as_str())
+        .collect();
+    serde_json::to_string(&ChatMessage {
+        username: "System".to_string(),
+        message: serde_json::to_string(&usernames).unwrap_or_default(),
+        channel: channel_name.to_string(),
+        message_type: Some("user_list".to_string()),
+        is_bot: false,
+        id: None,
+        is_integration: false,
+        attachments: Vec::new(),
+        members,
+        previews: Vec::new(),
+    })
+    .ok()
 }
 
-async fn websocket(stream: WebSocket, state: Arc<AppState>) {
+/// A message addressed to a single connection, e.g. a rejected send.
+fn error_event(channel: &str, message: &str) -> Option<String> {
+    serde_json::to_string(&ChatMessage {
+        username: "System".to_string(),
+        message: message.to_string(),
+        channel: channel.to_string(),
+        message_type: Some("error".to_string()),
+        is_bot: false,
+        id: None,
+        is_integration: false,
+        attachments: Vec::new(),
+        members: Vec::new(),
+        previews: Vec::new(),
+    })
+    .ok()
+}
+
+async fn websocket(
+    stream: WebSocket,
+    state: Arc<AppState>,
+    identity: Option<WsIdentity>,
+    sender_key: String,
+    ip_address: String,
+) {
     let (mut sender, mut receiver) = stream.split();
     let mut channel_name = String::new();
     let mut username = String::new();
@@ -418,6 +1009,91 @@ async fn websocket(stream: WebSocket, state: Arc<AppState>) {
         }
     }
 
+    // 已认证的连接始终使用账号的用户名
+    let authenticated = identity.is_some();
+    if let Some(identity) = &identity {
+        username = identity.username.clone();
+    }
+    let bot = identity.as_ref().and_then(|identity| identity.bot.as_ref());
+    let is_bot = bot.is_some();
+
+    // 访客不能冒用注册用户的名字，否则可绕过针对该用户的封禁和禁言
+    if !authenticated {
+        let taken = state
+            .db
+            .get_user_by_username(&username)
+            .await
+            .unwrap_or_else(|err| {
+                eprintln!("failed to look up {username}: {err:#}");
+                None
+            })
+            .is_some();
+        if taken {
+            let reason = format!("{username} is a registered user, sign in to use this name");
+            if let Some(event) = error_event(&channel_name, &reason) {
+                let _ = sender.send(Message::Text(event)).await;
+            }
+            return;
+        }
+    }
+
+    // 机器人需要 rooms:read 才能加入频道
+    if bot.is_some_and(|bot| !bot.allows(&bots::Scope::ReadRooms)) {
+        if let Some(event) = error_event(&channel_name, "API token lacks the rooms:read scope") {
+            let _ = sender.send(Message::Text(event)).await;
+        }
+        return;
+    }
+
+    let post_scope = bots::Scope::PostRoom(channel_name.clone());
+    let (can_post, post_denied) = match bot {
+        Some(bot) => (
+            bot.allows(&post_scope),
+            format!("API token lacks the {post_scope} scope"),
+        ),
+        None => (
+            !state.require_email_verification
+                || identity
+                    .as_ref()
+                    .is_some_and(|identity| identity.email_verified),
+            "Please verify your email address before posting".to_string(),
+        ),
+    };
+
+    // 频道对应的房间：登录用户加入时记为成员
+    let user_id = identity.as_ref().map(|identity| identity.user_id.clone());
+    if let Some(user_id) = &user_id {
+        let refusal = moderation::join_refusal(&state, &channel_name, user_id)
+            .await
+            .unwrap_or_else(|err| {
+                eprintln!("failed to check bans in {channel_name}: {err:#}");
+                None
+            });
+        if let Some(reason) = refusal {
+            if let Some(event) = error_event(&channel_name, &reason) {
+                let _ = sender.send(Message::Text(event)).await;
+            }
+            return;
+        }
+    }
+    let room = rooms::join_channel(&state, &channel_name, user_id.as_deref())
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("failed to join room {channel_name}: {err:#}");
+            None
+        });
+    let member_data = serde_json::json!({
+        "user_id": user_id,
+        "username": username,
+        "is_bot": is_bot,
+        "guest": !authenticated,
+    });
+
+    // 会话被注销时断开连接
+    let mut session_ended = identity
+        .as_ref()
+        .map(|identity| auth::session::watch_session(&state, &identity.session_id));
+
     let channel = state
         .channels
         .entry(channel_name.clone())
@@ -425,9 +1101,15 @@ async fn websocket(stream: WebSocket, state: Arc<AppState>) {
         .clone();
 
     let mut rx = channel.tx.subscribe();
+    let mut kicks = channel.kicks.subscribe();
 
     // Add user to channel
-    channel.users.insert(username.clone(), ());
+    channel.users.insert(
+        username.clone(),
+        identity
+            .as_ref()
+            .map(|identity| identity.avatar_url.clone()),
+    );
 
     // Send user join message
     if let Ok(join_msg) = serde_json::to_string(&ChatMessage {
@@ -435,27 +1117,42 @@ async fn websocket(stream: WebSocket, state: Arc<AppState>) {
         message: format!("{} joined {}", username, channel_name),
         channel: channel_name.clone(),
         message_type: Some("system".to_string()),
+        is_bot: false,
+        id: None,
+        is_integration: false,
+        attachments: Vec::new(),
+        members: Vec::new(),
+        previews: Vec::new(),
     }) {
         let _ = channel.tx.send(join_msg);
     }
+    if let Some(room) = &room {
+        webhooks::publish(
+            &state,
+            room,
+            webhooks::WebhookEvent::MemberJoined,
+            member_data.clone(),
+        )
+        .await;
+    }
 
     // Send current user list
-    let users: Vec<String> = channel
-        .users
-        .iter()
-        .map(|entry| entry.key().clone())
-        .collect();
-    if let Ok(user_list_msg) = serde_json::to_string(&ChatMessage {
-        username: "System".to_string(),
-        message: serde_json::to_string(&users).unwrap_or_default(),
-        channel: channel_name.clone(),
-        message_type: Some("user_list".to_string()),
-    }) {
+    if let Some(user_list_msg) = user_list_event(&channel_name, &channel) {
         let _ = channel.tx.send(user_list_msg);
     }
 
+    // Events meant only for this connection
+    let (direct_tx, mut direct_rx) = mpsc::channel::<String>(16);
+
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => match msg {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+                Some(msg) = direct_rx.recv() => msg,
+            };
             if sender.send(Message::Text(msg)).await.is_err() {
                 break;
             }
@@ -463,32 +1160,108 @@ async fn websocket(stream: WebSocket, state: Arc<AppState>) {
     });
 
     let mut recv_task = {
-        let channel = channel.clone();
+        let state = state.clone();
+        let room = room.clone();
+        let user_id = user_id.clone();
         let username = username.clone();
         let channel_name = channel_name.clone();
+        let mut bucket = state.flood.connection_bucket();
+        let slow_mode_key = rooms::slow_mode_key(user_id.as_deref(), Some(&ip_address));
         tokio::spawn(async move {
             while let Some(Ok(Message::Text(text))) = receiver.next().await {
-                // Try to parse the incoming message as JSON
-                if let Ok(parsed_msg) = serde_json::from_str::<ChatMessage>(&text) {
-                    // If it's a valid ChatMessage, use the parsed data
-                    if let Ok(msg) = serde_json::to_string(&ChatMessage {
-                        username: parsed_msg.username,
-                        message: parsed_msg.message,
-                        channel: parsed_msg.channel,
-                        message_type: parsed_msg.message_type,
-                    }) {
-                        let _ = channel.tx.send(msg);
+                // 房间设置可能在连接期间改变，每条消息重新读取
+                let room = match &room {
+                    Some(room) => state
+                        .db
+                        .get_chat_room(&room.id)
+                        .await
+                        .unwrap_or_else(|err| {
+                            eprintln!("failed to load room {channel_name}: {err:#}");
+                            None
+                        })
+                        .or_else(|| Some(room.clone())),
+                    None => None,
+                };
+                let mut rejection = (!can_post).then(|| post_denied.clone());
+                if let (None, Some(room)) = (&rejection, &room) {
+                    rejection = rooms::send_refusal(
+                        &state,
+                        room,
+                        user_id.as_deref(),
+                        &slow_mode_key,
+                        &text,
+                    )
+                    .await
+                    .unwrap_or_else(|err| {
+                        eprintln!("failed to check permissions in {channel_name}: {err:#}");
+                        None
+                    });
+                }
+                if rejection.is_none() {
+                    rejection = state
+                        .flood
+                        .check(&sender_key, &mut bucket, text.len())
+                        .err();
+                }
+                if let Some(reason) = rejection {
+                    if let Some(event) = error_event(&channel_name, &reason) {
+                        let _ = direct_tx.send(event).await;
                     }
-                } else {
-                    // If it's not valid JSON, treat it as a raw message
-                    if let Ok(msg) = serde_json::to_string(&ChatMessage {
-                        username: username.clone(),
-                        message: text,
-                        channel: channel_name.clone(),
-                        message_type: None,
-                    }) {
-                        let _ = channel.tx.send(msg);
+                    continue;
+                }
+
+                // JSON frames carry a ChatMessage, anything else is plain text
+                let (sender_name, content, message_type) =
+                    match serde_json::from_str::<ChatMessage>(&text) {
+                        // 发送者始终用加入时的名字，访客也不能逐条改名
+                        Ok(parsed_msg) => (
+                            username.clone(),
+                            parsed_msg.message,
+                            rooms::client_message_type(parsed_msg.message_type),
+                        ),
+                        Err(_) => (username.clone(), text, None),
+                    };
+
+                // 以 / 开头的是斜杠命令，以 // 开头的按原文发送
+                let content = match content.strip_prefix('/') {
+                    Some(line) if !line.starts_with('/') => {
+                        let ctx = commands::CommandContext {
+                            state: state.clone(),
+                            channel: channel_name.clone(),
+                            room: room.clone(),
+                            user_id: user_id.clone(),
+                            username: sender_name,
+                            is_bot,
+                            ip_address: Some(ip_address.clone()),
+                        };
+                        tokio::spawn(commands::dispatch(ctx, line.to_string(), direct_tx.clone()));
+                        continue;
+                    }
+                    Some(line) => line.to_string(),
+                    None => content,
+                };
+
+                let new = rooms::NewMessage {
+                    sender_id: user_id.clone(),
+                    username: sender_name,
+                    content,
+                    message_type,
+                    is_bot,
+                    integration_id: None,
+                    attachments: Vec::new(),
+                };
+                match rooms::post_message(&state, &channel_name, room.as_ref(), new, &slow_mode_key)
+                    .await
+                {
+                    Ok(posted) => {
+                        if let Some(event) = posted
+                            .notice()
+                            .and_then(|notice| error_event(&channel_name, &notice))
+                        {
+                            let _ = direct_tx.send(event).await;
+                        }
                     }
+                    Err(err) => eprintln!("failed to store message: {err:#}"),
                 }
             }
         })
@@ -497,8 +1270,36 @@ async fn websocket(stream: WebSocket, state: Arc<AppState>) {
     tokio::select! {
         _ = (&mut send_task) => recv_task.abort(),
         _ = (&mut recv_task) => send_task.abort(),
+        _ = async {
+            loop {
+                match kicks.recv().await {
+                    Ok(kicked) if kicked == username => break,
+                    Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+                    _ => {}
+                }
+            }
+        } => {
+            send_task.abort();
+            recv_task.abort();
+        }
+        _ = async {
+            match session_ended.as_mut() {
+                Some(rx) => {
+                    let _ = rx.recv().await;
+                }
+                None => std::future::pending().await,
+            }
+        } => {
+            send_task.abort();
+            recv_task.abort();
+        }
     };
 
+    drop(session_ended);
+    if let Some(identity) = &identity {
+        auth::session::unwatch_session(&state, &identity.session_id);
+    }
+
     // Remove user from channel
     channel.users.remove(&username);
 
@@ -508,22 +1309,27 @@ async fn websocket(stream: WebSocket, state: Arc<AppState>) {
         message: format!("{} left {}", username, channel_name),
         channel: channel_name.clone(),
         message_type: Some("system".to_string()),
+        is_bot: false,
+        id: None,
+        is_integration: false,
+        attachments: Vec::new(),
+        members: Vec::new(),
+        previews: Vec::new(),
     }) {
         let _ = channel.tx.send(leave_msg);
     }
+    if let Some(room) = &room {
+        webhooks::publish(
+            &state,
+            room,
+            webhooks::WebhookEvent::MemberLeft,
+            member_data,
+        )
+        .await;
+    }
 
     // Send updated user list
-    let users: Vec<String> = channel
-        .users
-        .iter()
-        .map(|entry| entry.key().clone())
-        .collect();
-    if let Ok(user_list_msg) = serde_json::to_string(&ChatMessage {
-        username: "System".to_string(),
-        message: serde_json::to_string(&users).unwrap_or_default(),
-        channel: channel_name.clone(),
-        message_type: Some("user_list".to_string()),
-    }) {
+    if let Some(user_list_msg) = user_list_event(&channel_name, &channel) {
         let _ = channel.tx.send(user_list_msg);
     }
 }
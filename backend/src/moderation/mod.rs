@@ -0,0 +1,494 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    api_error, audit,
+    auth::{session::ClientInfo, AuthUser},
+    db, internal_error,
+    rooms::{
+        self,
+        permissions::{self, Permission},
+    },
+    ApiError, AppState, StatusResponse,
+};
+
+pub mod reports;
+
+const MAX_REASON_LEN: usize = 500;
+const MAX_TIMEOUT_SECONDS: i64 = 28 * 24 * 60 * 60;
+
+/// What a moderator can do to a member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SanctionKind {
+    /// Disconnect from the channel; they may rejoin straight away.
+    Kick,
+    /// Keep out of the channel, for a while or for good.
+    Ban,
+    /// Stop from posting, for a while or for good.
+    Mute,
+    /// A mute that always runs out.
+    Timeout,
+}
+
+impl SanctionKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Kick => "kick",
+            Self::Ban => "ban",
+            Self::Mute => "mute",
+            Self::Timeout => "timeout",
+        }
+    }
+
+    fn past_tense(self) -> &'static str {
+        match self {
+            Self::Kick => "kicked",
+            Self::Ban => "banned",
+            Self::Mute => "muted",
+            Self::Timeout => "timed out",
+        }
+    }
+
+    /// Mutes and timeouts both stop a member from posting, so a new one
+    /// replaces whichever is in force.
+    fn blocks_posting(kind: &str) -> bool {
+        kind == Self::Mute.as_str() || kind == Self::Timeout.as_str()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSanctionRequest {
+    user_id: String,
+    kind: SanctionKind,
+    /// Omit for a permanent ban or mute.
+    duration_seconds: Option<i64>,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SanctionResponse {
+    #[serde(flatten)]
+    sanction: db::RoomSanction,
+    username: String,
+}
+
+/// Parses a duration such as `30s`, `10m`, `2h`, `7d` or `1w`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text.len().checked_sub(1)?;
+    let (amount, unit) = text.split_at(split);
+    let amount: i64 = amount.parse().ok().filter(|amount| *amount > 0)?;
+    match unit {
+        "s" => Duration::try_seconds(amount),
+        "m" => Duration::try_minutes(amount),
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        _ => None,
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.num_seconds();
+    let (unit, size) = [("w", 604_800), ("d", 86_400), ("h", 3_600), ("m", 60)]
+        .into_iter()
+        .find(|(_, size)| seconds % size == 0)
+        .unwrap_or(("s", 1));
+    format!("{}{unit}", seconds / size)
+}
+
+fn until(expires_at: Option<DateTime<Utc>>) -> String {
+    match expires_at {
+        Some(expires_at) => format!(" until {}", expires_at.format("%Y-%m-%d %H:%M UTC")),
+        None => String::new(),
+    }
+}
+
+/// Why a user may not join a channel right now, if they are banned from it.
+pub async fn join_refusal(
+    state: &AppState,
+    channel: &str,
+    user_id: &str,
+) -> anyhow::Result<Option<String>> {
+    let Some(room) = state.db.get_channel_room(channel).await? else {
+        return Ok(None);
+    };
+
+    Ok(state
+        .db
+        .get_active_room_sanctions(&room.id, Some(user_id))
+        .await?
+        .into_iter()
+        .find(|sanction| sanction.kind == SanctionKind::Ban.as_str())
+        .map(|ban| format!("You are banned from {channel}{}", until(ban.expires_at))))
+}
+
+/// Why a user may not post in a room right now, if they are muted or timed
+/// out there.
+pub async fn send_refusal(
+    state: &AppState,
+    room: &db::ChatRoom,
+    user_id: &str,
+) -> anyhow::Result<Option<String>> {
+    Ok(state
+        .db
+        .get_active_room_sanctions(&room.id, Some(user_id))
+        .await?
+        .into_iter()
+        .find(|sanction| SanctionKind::blocks_posting(&sanction.kind))
+        .map(|mute| {
+            let state = if mute.kind == SanctionKind::Timeout.as_str() {
+                "timed out"
+            } else {
+                "muted"
+            };
+            format!("You are {state} in {}{}", room.name, until(mute.expires_at))
+        }))
+}
+
+/// Sanctions `target` in a room on behalf of `actor`, who must moderate it,
+/// and announces it in the channel. A new ban replaces any ban in force,
+/// and a new mute or timeout any mute or timeout.
+pub async fn apply(
+    state: &AppState,
+    room: &db::ChatRoom,
+    actor: &audit::Actor,
+    target: &db::User,
+    kind: SanctionKind,
+    duration: Option<Duration>,
+    reason: Option<&str>,
+) -> Result<db::RoomSanction, ApiError> {
+    require_moderator(state, room, &actor.user).await?;
+    if target.id == actor.user.id {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "You cannot sanction yourself",
+        ));
+    }
+    if permissions::has_permission(state, &room.id, &target.id, Permission::ManageMembers)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            format!("{} is a room moderator", target.username),
+        ));
+    }
+
+    let duration = match (kind, duration) {
+        (SanctionKind::Kick, _) => None,
+        (_, Some(duration)) if duration <= Duration::zero() => {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                "Duration must be positive",
+            ));
+        }
+        (SanctionKind::Timeout, None) => {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                "A timeout needs a duration",
+            ));
+        }
+        (SanctionKind::Timeout, Some(duration)) if duration.num_seconds() > MAX_TIMEOUT_SECONDS => {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                "Timeouts can last at most 28 days",
+            ));
+        }
+        (_, duration) => duration,
+    };
+    let reason = reason.map(str::trim).filter(|reason| !reason.is_empty());
+    if reason.is_some_and(|reason| reason.chars().count() > MAX_REASON_LEN) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Reason must be at most {MAX_REASON_LEN} characters"),
+        ));
+    }
+
+    // 同类处罚只保留最新的一条
+    let active = state
+        .db
+        .get_active_room_sanctions(&room.id, Some(&target.id))
+        .await
+        .map_err(internal_error)?;
+    for previous in active.iter().filter(|previous| {
+        previous.kind == kind.as_str()
+            || (SanctionKind::blocks_posting(&previous.kind)
+                && SanctionKind::blocks_posting(kind.as_str()))
+    }) {
+        state
+            .db
+            .lift_room_sanction(&room.id, &previous.id, &actor.user.id)
+            .await
+            .map_err(internal_error)?;
+    }
+
+    let now = Utc::now();
+    let sanction = db::RoomSanction {
+        id: Uuid::new_v4().to_string(),
+        room_id: room.id.clone(),
+        user_id: target.id.clone(),
+        kind: kind.as_str().to_string(),
+        reason: reason.map(str::to_string),
+        actor_id: actor.user.id.clone(),
+        created_at: now,
+        expires_at: duration.map(|duration| now + duration),
+        lifted_at: None,
+        lifted_by: None,
+    };
+    state
+        .db
+        .create_room_sanction(&sanction)
+        .await
+        .map_err(internal_error)?;
+
+    let mut announcement = format!(
+        "{} was {} by {}",
+        target.username,
+        kind.past_tense(),
+        actor.user.username
+    );
+    if let Some(duration) = duration {
+        announcement.push_str(&format!(" for {}", format_duration(duration)));
+    }
+    if let Some(reason) = reason {
+        announcement.push_str(&format!(": {reason}"));
+    }
+    rooms::announce(state, &room.name, announcement);
+
+    if matches!(kind, SanctionKind::Kick | SanctionKind::Ban) {
+        state
+            .db
+            .remove_room_member(&room.id, &target.id)
+            .await
+            .map_err(internal_error)?;
+        rooms::kick(state, &room.name, &target.username);
+    }
+    actor
+        .record(
+            state,
+            &format!("moderation.{}", kind.as_str()),
+            Some(("user", &target.id)),
+            serde_json::json!({
+                "room_id": room.id,
+                "sanction_id": sanction.id,
+                "expires_at": sanction.expires_at,
+                "reason": sanction.reason,
+            }),
+        )
+        .await;
+    Ok(sanction)
+}
+
+/// Lifts the sanctions of the given kinds that are in force against
+/// `target`, and announces it. Returns how many were lifted.
+pub async fn lift_for_user(
+    state: &AppState,
+    room: &db::ChatRoom,
+    actor: &audit::Actor,
+    target: &db::User,
+    kinds: &[SanctionKind],
+) -> Result<usize, ApiError> {
+    require_moderator(state, room, &actor.user).await?;
+    let active = state
+        .db
+        .get_active_room_sanctions(&room.id, Some(&target.id))
+        .await
+        .map_err(internal_error)?;
+
+    let mut lifted = 0;
+    for sanction in active
+        .iter()
+        .filter(|sanction| kinds.iter().any(|kind| kind.as_str() == sanction.kind))
+    {
+        lift(state, room, actor, sanction, &target.username).await?;
+        lifted += 1;
+    }
+    Ok(lifted)
+}
+
+async fn lift(
+    state: &AppState,
+    room: &db::ChatRoom,
+    actor: &audit::Actor,
+    sanction: &db::RoomSanction,
+    username: &str,
+) -> Result<(), ApiError> {
+    if !state
+        .db
+        .lift_room_sanction(&room.id, &sanction.id, &actor.user.id)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(api_error(StatusCode::NOT_FOUND, "Sanction not found"));
+    }
+
+    let lifted = match sanction.kind.as_str() {
+        "ban" => "unbanned",
+        "timeout" => "released from timeout",
+        _ => "unmuted",
+    };
+    rooms::announce(
+        state,
+        &room.name,
+        format!("{username} was {lifted} by {}", actor.user.username),
+    );
+    actor
+        .record(
+            state,
+            "moderation.lift",
+            Some(("user", &sanction.user_id)),
+            serde_json::json!({
+                "room_id": room.id,
+                "sanction_id": sanction.id,
+                "kind": sanction.kind,
+            }),
+        )
+        .await;
+    Ok(())
+}
+
+/// Server admins may moderate any room; everyone else needs the
+/// `manage_members` permission in it.
+async fn require_moderator(
+    state: &AppState,
+    room: &db::ChatRoom,
+    user: &db::User,
+) -> Result<(), ApiError> {
+    if user.is_admin {
+        return Ok(());
+    }
+    permissions::authorize(state, &room.id, &user.id, Permission::ManageMembers).await
+}
+
+/// Server admins may review any room's reported and held messages; everyone
+/// else needs the `delete_others` permission in the room.
+pub async fn require_reviewer(
+    state: &AppState,
+    room_id: &str,
+    user: &db::User,
+) -> Result<(), ApiError> {
+    if user.is_admin {
+        return Ok(());
+    }
+    permissions::authorize(state, room_id, &user.id, Permission::DeleteOthers).await
+}
+
+/// Reads an optional `duration_seconds` field.
+fn duration_from_seconds(seconds: Option<i64>) -> Result<Option<Duration>, ApiError> {
+    seconds
+        .map(|seconds| {
+            Duration::try_seconds(seconds)
+                .ok_or_else(|| api_error(StatusCode::BAD_REQUEST, "Duration is out of range"))
+        })
+        .transpose()
+}
+
+// 列出房间内生效中的处罚
+pub async fn list_sanctions_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Path(room_id): Path<String>,
+) -> Result<Json<Vec<SanctionResponse>>, ApiError> {
+    let room = load_room(&state, &room_id).await?;
+    let user = load_user(&state, &auth.id).await?;
+    require_moderator(&state, &room, &user).await?;
+
+    let sanctions = state
+        .db
+        .get_active_room_sanctions(&room.id, None)
+        .await
+        .map_err(internal_error)?;
+    let mut responses = Vec::with_capacity(sanctions.len());
+    for sanction in sanctions {
+        let username = load_user(&state, &sanction.user_id).await?.username;
+        responses.push(SanctionResponse { sanction, username });
+    }
+
+    Ok(Json(responses))
+}
+
+// 踢出、封禁、禁言或超时
+pub async fn create_sanction_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path(room_id): Path<String>,
+    Json(req): Json<CreateSanctionRequest>,
+) -> Result<Json<SanctionResponse>, ApiError> {
+    let room = load_room(&state, &room_id).await?;
+    let actor = audit::Actor {
+        user: load_user(&state, &auth.id).await?,
+        ip_address: client.ip_address,
+    };
+    let target = load_user(&state, &req.user_id).await?;
+
+    let sanction = apply(
+        &state,
+        &room,
+        &actor,
+        &target,
+        req.kind,
+        duration_from_seconds(req.duration_seconds)?,
+        req.reason.as_deref(),
+    )
+    .await?;
+
+    Ok(Json(SanctionResponse {
+        sanction,
+        username: target.username,
+    }))
+}
+
+// 解除处罚
+pub async fn lift_sanction_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path((room_id, sanction_id)): Path<(String, String)>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let room = load_room(&state, &room_id).await?;
+    let actor = audit::Actor {
+        user: load_user(&state, &auth.id).await?,
+        ip_address: client.ip_address,
+    };
+    require_moderator(&state, &room, &actor.user).await?;
+
+    let sanction = state
+        .db
+        .get_active_room_sanctions(&room.id, None)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .find(|sanction| sanction.id == sanction_id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Sanction not found"))?;
+    let target = load_user(&state, &sanction.user_id).await?;
+    lift(&state, &room, &actor, &sanction, &target.username).await?;
+
+    Ok(Json(StatusResponse::new("Sanction lifted")))
+}
+
+pub async fn load_room(state: &AppState, room_id: &str) -> Result<db::ChatRoom, ApiError> {
+    state
+        .db
+        .get_chat_room(room_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Room not found"))
+}
+
+pub async fn load_user(state: &AppState, user_id: &str) -> Result<db::User, ApiError> {
+    state
+        .db
+        .get_user_by_id(user_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "User not found"))
+}
@@ -0,0 +1,457 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::{apply, duration_from_seconds, load_room, load_user, require_reviewer, SanctionKind};
+use crate::{
+    admin::Admin,
+    api_error, audit,
+    auth::{session::ClientInfo, AuthUser},
+    db, internal_error,
+    mail::OutgoingMail,
+    rooms, ApiError, AppState,
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+const MAX_COMMENT_LEN: usize = 1000;
+
+/// Why a member reported a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportReason {
+    Spam,
+    Harassment,
+    HateSpeech,
+    Sexual,
+    Violence,
+    SelfHarm,
+    Other,
+}
+
+impl ReportReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Spam => "spam",
+            Self::Harassment => "harassment",
+            Self::HateSpeech => "hate_speech",
+            Self::Sexual => "sexual",
+            Self::Violence => "violence",
+            Self::SelfHarm => "self_harm",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// What a moderator does about a report. Every open report of the same
+/// message is resolved with it.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ReportAction {
+    /// The message is fine.
+    Dismiss,
+    DeleteMessage,
+    /// Sanctions the sender in the room; the message stays.
+    Sanction {
+        kind: SanctionKind,
+        duration_seconds: Option<i64>,
+        reason: Option<String>,
+    },
+}
+
+impl ReportAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Dismiss => "dismiss",
+            Self::DeleteMessage => "delete_message",
+            Self::Sanction { .. } => "sanction",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateReportRequest {
+    reason: ReportReason,
+    /// Anything else moderators should know.
+    comment: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveReportRequest {
+    #[serde(flatten)]
+    action: ReportAction,
+    /// Seen by moderators only.
+    note: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportsQuery {
+    /// `open` (the default), `resolved` or `all`.
+    status: Option<String>,
+    /// Narrows the server-wide queue to one room.
+    room_id: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+impl ReportsQuery {
+    fn status(&self) -> Result<Option<&str>, ApiError> {
+        match self.status.as_deref() {
+            None | Some("open") => Ok(Some("open")),
+            Some("resolved") => Ok(Some("resolved")),
+            Some("all") => Ok(None),
+            Some(_) => Err(api_error(
+                StatusCode::BAD_REQUEST,
+                "status must be open, resolved or all",
+            )),
+        }
+    }
+
+    fn page(&self) -> (i64, i64) {
+        (
+            self.limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+            self.offset.unwrap_or(0).max(0),
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportResponse {
+    #[serde(flatten)]
+    report: db::MessageReport,
+    reporter_username: String,
+    sender_username: String,
+}
+
+impl ReportResponse {
+    async fn load(state: &AppState, report: db::MessageReport) -> Result<Self, ApiError> {
+        Ok(Self {
+            reporter_username: load_user(state, &report.reporter_id).await?.username,
+            sender_username: load_user(state, &report.sender_id).await?.username,
+            report,
+        })
+    }
+}
+
+/// Emails a reporter how their report was resolved, without saying who
+/// resolved it or how the sender was sanctioned.
+async fn notify_reporter(state: &AppState, room: &db::ChatRoom, report: &db::MessageReport) {
+    let reporter = match state.db.get_user_by_id(&report.reporter_id).await {
+        Ok(Some(reporter)) => reporter,
+        Ok(None) => return,
+        Err(err) => {
+            eprintln!("failed to load reporter {}: {err:#}", report.reporter_id);
+            return;
+        }
+    };
+    let outcome = match report.resolution.as_deref() {
+        Some("delete_message") => "The message has been removed.",
+        Some("sanction") => "The moderators have taken action against its sender.",
+        _ => "The moderators reviewed it and found that it does not break the rules.",
+    };
+    let mail = OutgoingMail {
+        to: reporter.email,
+        subject: "Your ChatX report has been reviewed".to_string(),
+        body: format!(
+            "Hi {},\n\nThanks for reporting a message in {}. {outcome}\n\n\
+             You can follow all your reports in the app.\n",
+            reporter.username, room.name
+        ),
+    };
+    if let Err(err) = state.mailer.send(mail).await {
+        eprintln!("failed to send report resolution mail: {err:#}");
+    }
+}
+
+async fn report_list(
+    state: &AppState,
+    reports: Vec<db::MessageReport>,
+) -> Result<Json<Vec<ReportResponse>>, ApiError> {
+    let mut responses = Vec::with_capacity(reports.len());
+    for report in reports {
+        responses.push(ReportResponse::load(state, report).await?);
+    }
+    Ok(Json(responses))
+}
+
+// 举报消息
+pub async fn report_message_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path(message_id): Path<String>,
+    Json(req): Json<CreateReportRequest>,
+) -> Result<Json<ReportResponse>, ApiError> {
+    let (message, room) = rooms::live_message(&state, &message_id).await?;
+    // 只能举报自己所在房间的消息
+    state
+        .db
+        .get_room_member(&room.id, &auth.id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Message not found"))?;
+    if message.sender_id == auth.id {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "You cannot report your own message",
+        ));
+    }
+    let comment = req
+        .comment
+        .as_deref()
+        .map(str::trim)
+        .filter(|comment| !comment.is_empty());
+    if comment.is_some_and(|comment| comment.chars().count() > MAX_COMMENT_LEN) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Comment must be at most {MAX_COMMENT_LEN} characters"),
+        ));
+    }
+
+    let report = db::MessageReport {
+        id: Uuid::new_v4().to_string(),
+        message_id: message.id.clone(),
+        room_id: room.id.clone(),
+        reporter_id: auth.id.clone(),
+        sender_id: message.sender_id.clone(),
+        content: message.content.clone(),
+        reason: req.reason.as_str().to_string(),
+        comment: comment.map(str::to_string),
+        status: "open".to_string(),
+        resolution: None,
+        resolution_note: None,
+        sanction_id: None,
+        resolved_by: None,
+        resolved_at: None,
+        created_at: Utc::now(),
+    };
+    if !state
+        .db
+        .create_message_report(&report)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            "You have already reported this message",
+        ));
+    }
+    audit::record(
+        &state,
+        audit::Event {
+            action: "message.report",
+            actor_id: Some(&auth.id),
+            target: Some(("message", &message.id)),
+            ip_address: client.ip_address.as_deref(),
+            details: json!({
+                "report_id": report.id,
+                "room_id": room.id,
+                "reason": report.reason,
+            }),
+        },
+    )
+    .await;
+
+    Ok(Json(ReportResponse::load(&state, report).await?))
+}
+
+// 房间的举报队列，最早的在前
+pub async fn list_room_reports_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Path(room_id): Path<String>,
+    Query(query): Query<ReportsQuery>,
+) -> Result<Json<Vec<ReportResponse>>, ApiError> {
+    let room = load_room(&state, &room_id).await?;
+    let user = load_user(&state, &auth.id).await?;
+    require_reviewer(&state, &room.id, &user).await?;
+
+    let (limit, offset) = query.page();
+    let reports = state
+        .db
+        .get_message_reports(Some(&room.id), query.status()?, limit, offset)
+        .await
+        .map_err(internal_error)?;
+
+    report_list(&state, reports).await
+}
+
+// 全站的举报队列
+pub async fn list_reports_handler(
+    State(state): State<Arc<AppState>>,
+    _admin: Admin,
+    Query(query): Query<ReportsQuery>,
+) -> Result<Json<Vec<ReportResponse>>, ApiError> {
+    let (limit, offset) = query.page();
+    let reports = state
+        .db
+        .get_message_reports(query.room_id.as_deref(), query.status()?, limit, offset)
+        .await
+        .map_err(internal_error)?;
+
+    report_list(&state, reports).await
+}
+
+// 当前用户提交过的举报及处理结果
+pub async fn my_reports_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(query): Query<ReportsQuery>,
+) -> Result<Json<Vec<ReportResponse>>, ApiError> {
+    let (limit, offset) = query.page();
+    let reports = state
+        .db
+        .get_user_message_reports(&auth.id, limit, offset)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        // 处理人、备注和处罚只给管理者看
+        .map(|report| db::MessageReport {
+            resolution_note: None,
+            sanction_id: None,
+            resolved_by: None,
+            ..report
+        })
+        .collect();
+
+    report_list(&state, reports).await
+}
+
+// 处理举报：驳回、删除消息或处罚发送者
+pub async fn resolve_report_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path(report_id): Path<String>,
+    Json(req): Json<ResolveReportRequest>,
+) -> Result<Json<ReportResponse>, ApiError> {
+    let report = state
+        .db
+        .get_message_report(&report_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Report not found"))?;
+    let room = load_room(&state, &report.room_id).await?;
+    let actor = audit::Actor {
+        user: load_user(&state, &auth.id).await?,
+        ip_address: client.ip_address,
+    };
+    require_reviewer(&state, &room.id, &actor.user).await?;
+    if report.status != "open" {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            "This report has already been resolved",
+        ));
+    }
+    let note = req
+        .note
+        .as_deref()
+        .map(str::trim)
+        .filter(|note| !note.is_empty());
+    if note.is_some_and(|note| note.chars().count() > MAX_COMMENT_LEN) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Note must be at most {MAX_COMMENT_LEN} characters"),
+        ));
+    }
+
+    let mut sanction_id = None;
+    match &req.action {
+        ReportAction::Dismiss => {}
+        ReportAction::DeleteMessage => {
+            // 消息可能已被发送者或其他管理者删除
+            let message = state
+                .db
+                .get_message(&report.message_id)
+                .await
+                .map_err(internal_error)?
+                .filter(|message| message.deleted_at.is_none());
+            if let Some(message) = message {
+                rooms::delete_message(&state, &room, &message).await?;
+                actor
+                    .record(
+                        &state,
+                        "moderation.message_delete",
+                        Some(("message", &message.id)),
+                        json!({
+                            "room_id": room.id,
+                            "sender_id": message.sender_id,
+                            "report_id": report.id,
+                        }),
+                    )
+                    .await;
+            }
+        }
+        ReportAction::Sanction {
+            kind,
+            duration_seconds,
+            reason,
+        } => {
+            let target = load_user(&state, &report.sender_id).await?;
+            let sanction = apply(
+                &state,
+                &room,
+                &actor,
+                &target,
+                *kind,
+                duration_from_seconds(*duration_seconds)?,
+                reason.as_deref(),
+            )
+            .await?;
+            sanction_id = Some(sanction.id);
+        }
+    }
+
+    let resolved = state
+        .db
+        .resolve_message_reports(
+            &report.message_id,
+            req.action.as_str(),
+            note,
+            sanction_id.as_deref(),
+            &actor.user.id,
+        )
+        .await
+        .map_err(internal_error)?;
+    actor
+        .record(
+            &state,
+            "moderation.report_resolve",
+            Some(("report", &report.id)),
+            json!({
+                "room_id": room.id,
+                "message_id": report.message_id,
+                "action": req.action.as_str(),
+                "sanction_id": sanction_id,
+                "resolved_reports": resolved.iter().map(|report| &report.id).collect::<Vec<_>>(),
+            }),
+        )
+        .await;
+    for resolved in &resolved {
+        notify_reporter(&state, &room, resolved).await;
+    }
+
+    // 并发处理时可能已被别人先处理
+    let report = match resolved
+        .into_iter()
+        .find(|resolved| resolved.id == report.id)
+    {
+        Some(report) => report,
+        None => state
+            .db
+            .get_message_report(&report.id)
+            .await
+            .map_err(internal_error)?
+            .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Report not found"))?,
+    };
+    Ok(Json(ReportResponse::load(&state, report).await?))
+}
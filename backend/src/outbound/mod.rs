@@ -0,0 +1,283 @@
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use reqwest::{redirect::Policy, Url};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Keeps requests to URLs that users supply (webhooks, command callbacks,
+/// linked pages) off this host and private networks, unless allowed.
+pub struct Guard {
+    /// Private networks that may be reached anyway.
+    allowed_networks: Vec<IpNet>,
+}
+
+impl Guard {
+    pub fn new(allowed_networks: Vec<IpNet>) -> Self {
+        Self { allowed_networks }
+    }
+
+    /// Reads the comma-separated CIDR ranges in the environment variable
+    /// `name` as the allowed networks.
+    pub fn from_env(name: &str) -> Result<Self> {
+        let allowed_networks = std::env::var(name)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .map(|network| {
+                network
+                    .parse()
+                    .with_context(|| format!("{name} has an invalid range {network:?}"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self::new(allowed_networks))
+    }
+
+    pub fn may_connect(&self, ip: IpAddr) -> bool {
+        is_public(ip)
+            || self
+                .allowed_networks
+                .iter()
+                .any(|network| network.contains(&ip))
+    }
+
+    /// The addresses a URL may be fetched from: `None` if its host is an
+    /// allowed IP address, otherwise every address its host resolves to.
+    async fn resolve(&self, url: &Url) -> Result<Option<Vec<SocketAddr>>> {
+        if !matches!(url.scheme(), "http" | "https") {
+            anyhow::bail!("unsupported scheme {}", url.scheme());
+        }
+        let port = url.port_or_known_default().context("URL has no port")?;
+        let host = url.host_str().context("URL has no host")?;
+        match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(ip) if self.may_connect(ip) => Ok(None),
+            Ok(ip) => anyhow::bail!("{ip} is a blocked address"),
+            Err(_) => {
+                let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+                if addrs.is_empty() {
+                    anyhow::bail!("{host} has no addresses");
+                }
+                if let Some(addr) = addrs.iter().find(|addr| !self.may_connect(addr.ip())) {
+                    anyhow::bail!("{host} resolves to the blocked address {}", addr.ip());
+                }
+                Ok(Some(addrs))
+            }
+        }
+    }
+
+    /// Checks a URL when it is registered; requests to it are checked again.
+    pub async fn check(&self, url: &str) -> Result<Url> {
+        let url = Url::parse(url)?;
+        self.resolve(&url).await?;
+        Ok(url)
+    }
+
+    /// A client that only reaches the URL's host at addresses that passed
+    /// the check, so a second DNS lookup cannot send it elsewhere. It does
+    /// not follow redirects, which would skip the check.
+    pub async fn client_for(&self, url: &Url, timeout: Duration) -> Result<reqwest::Client> {
+        let builder = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .no_proxy()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(timeout);
+        let builder = match (self.resolve(url).await?, url.host_str()) {
+            (Some(addrs), Some(host)) => builder.resolve_to_addrs(host, &addrs),
+            _ => builder,
+        };
+        Ok(builder.build()?)
+    }
+}
+
+/// Reads at most `limit` bytes of a response body, stopping there rather
+/// than downloading the rest. Tells whether that was the whole body.
+pub async fn read_limited(
+    mut response: reqwest::Response,
+    limit: usize,
+) -> reqwest::Result<(Vec<u8>, bool)> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        let room = limit - body.len();
+        if chunk.len() > room {
+            body.extend_from_slice(&chunk[..room]);
+            return Ok((body, false));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body, true))
+}
+
+/// Whether an address is on the public internet, rather than on this host,
+/// a private network or a reserved range.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || a == 0
+                // 运营商级 NAT、基准测试和保留地址段
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(ip.into());
+            }
+            let segments = ip.segments();
+            // NAT64 地址按其中的 IPv4 地址判断
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., high, low] = segments;
+                return is_public(IpAddr::V4((u32::from(high) << 16 | u32::from(low)).into()));
+            }
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (segments[0] & 0xfe00) == 0xfc00
+                || (segments[0] & 0xffc0) == 0xfe80
+                || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::{response::Redirect, routing::get, Router};
+
+    fn guard(allowed: &[&str]) -> Guard {
+        Guard::new(allowed.iter().map(|net| net.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in [
+            "1.1.1.1",
+            "93.184.216.34",
+            "2606:4700:4700::1111",
+            "64:ff9b::101:101",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn private_and_reserved_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "198.18.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "240.0.0.1",
+            "224.0.0.1",
+            "192.0.2.1",
+            "::1",
+            "::",
+            "fc00::1",
+            "fe80::1",
+            "2001:db8::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn allowed_networks() {
+        let guard = guard(&["10.0.0.0/8"]);
+        assert!(guard.may_connect("10.9.9.9".parse().unwrap()));
+        assert!(!guard.may_connect("192.168.1.1".parse().unwrap()));
+        assert!(guard.may_connect("8.8.8.8".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn check_refuses_blocked_urls() {
+        let guard = guard(&[]);
+        for url in [
+            "ftp://example.com/",
+            "http://127.0.0.1:8080/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://localhost/",
+            "not a url",
+        ] {
+            assert!(guard.check(url).await.is_err(), "{url}");
+        }
+        assert!(guard.check("http://8.8.8.8/").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn check_allows_allowed_networks() {
+        let guard = guard(&["127.0.0.0/8", "::1/128"]);
+        assert!(guard.check("http://127.0.0.1:8080/hook").await.is_ok());
+        assert!(guard.check("http://localhost:8080/hook").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn client_does_not_follow_redirects() {
+        let addr = testing::serve(
+            Router::new()
+                .route("/", get(|| async { Redirect::to("/next") }))
+                .route("/next", get(|| async { "followed" })),
+        )
+        .await;
+        let url = Url::parse(&format!("http://{addr}/")).unwrap();
+        let guard = guard(&["127.0.0.1/32"]);
+
+        let client = guard
+            .client_for(&url, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let response = client.get(url).send().await.unwrap();
+        assert!(response.status().is_redirection());
+    }
+
+    #[tokio::test]
+    async fn client_refuses_blocked_hosts() {
+        let url = Url::parse("http://localhost:1/").unwrap();
+        assert!(guard(&[])
+            .client_for(&url, Duration::from_secs(5))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn read_limited_stops_at_the_limit() {
+        let addr =
+            testing::serve(Router::new().route("/", get(|| async { "x".repeat(100_000) }))).await;
+        let url = Url::parse(&format!("http://{addr}/")).unwrap();
+        let client = guard(&["127.0.0.1/32"])
+            .client_for(&url, Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let response = client.get(url.clone()).send().await.unwrap();
+        let (body, complete) = read_limited(response, 1000).await.unwrap();
+        assert_eq!(body.len(), 1000);
+        assert!(!complete);
+
+        let response = client.get(url).send().await.unwrap();
+        let (body, complete) = read_limited(response, 200_000).await.unwrap();
+        assert_eq!(body.len(), 100_000);
+        assert!(complete);
+    }
+}
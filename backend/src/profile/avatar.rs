@@ -0,0 +1,425 @@
+use anyhow::Result;
+use axum::{
+    extract::{Multipart, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use image::{imageops::FilterType, DynamicImage, Rgb, RgbImage};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::{
+    api_error,
+    auth::AuthUser,
+    db,
+    files::{self, images},
+    internal_error, ApiError, AppState, UserResponse,
+};
+
+/// The stored sides of an avatar, in pixels, and the only sizes served.
+pub const AVATAR_SIZES: [u32; 4] = [32, 64, 128, 256];
+const DEFAULT_SIZE: u32 = 128;
+const AVATAR_QUALITY: u8 = 85;
+/// Cells per side of an identicon.
+const IDENTICON_CELLS: u32 = 5;
+const IDENTICON_BACKGROUND: Rgb<u8> = Rgb([240, 240, 240]);
+
+#[derive(Debug, Deserialize)]
+pub struct AvatarQuery {
+    size: Option<u32>,
+}
+
+/// Where clients load a user's avatar: the external URL they set, or else
+/// the avatar served by the server, which is versioned so that caches see
+/// a new upload.
+pub fn avatar_url(user: &db::User) -> String {
+    if let Some(url) = &user.avatar_url {
+        return url.clone();
+    }
+    match &user.avatar_hash {
+        Some(hash) => format!("/api/users/{}/avatar?v={hash}", user.id),
+        None => format!("/api/users/{}/avatar", user.id),
+    }
+}
+
+fn storage_key(user_id: &str, size: u32) -> String {
+    format!("avatars/{user_id}/{size}")
+}
+
+/// Crops an uploaded image to its centred square and scales it to each of
+/// [`AVATAR_SIZES`], in order. Re-encoding drops any metadata.
+fn render(data: &[u8]) -> Result<(&'static str, Vec<Vec<u8>>)> {
+    let (image, _) = images::decode(data)?;
+    let side = image.width().min(image.height());
+    let image = image.crop_imm(
+        (image.width() - side) / 2,
+        (image.height() - side) / 2,
+        side,
+        side,
+    );
+    let content_type = images::encoded_type(&image);
+    let sizes = AVATAR_SIZES
+        .into_iter()
+        .map(|size| {
+            let resized = image.resize_exact(size, size, FilterType::Lanczos3);
+            images::encode(&resized, content_type, AVATAR_QUALITY)
+        })
+        .collect::<Result<_>>()?;
+    Ok((content_type, sizes))
+}
+
+/// A symmetric pattern of cells in one colour, both picked from a hash of
+/// the user's id, as a PNG.
+fn identicon(user_id: &str, size: u32) -> Result<Vec<u8>> {
+    let hash = Sha256::digest(user_id.as_bytes());
+    // 取中间亮度的颜色，浅色背景上也看得清
+    let color = Rgb([64 + hash[0] / 2, 64 + hash[1] / 2, 64 + hash[2] / 2]);
+    let filled = |row: u32, column: u32| {
+        // 左右对称：只有左半边三列由哈希决定
+        let column = column.min(IDENTICON_CELLS - 1 - column);
+        let bit = row * IDENTICON_CELLS.div_ceil(2) + column;
+        (hash[3 + (bit / 8) as usize] >> (bit % 8)) & 1 == 1
+    };
+    // 格子取整数像素、两侧留白相等，左右两半才能逐像素对称；格数为奇数，
+    // 少一列格子即可让留白变为偶数
+    let mut inner = (size - 2 * (size / 10)) / IDENTICON_CELLS * IDENTICON_CELLS;
+    if (size - inner) % 2 == 1 {
+        inner -= IDENTICON_CELLS;
+    }
+    let margin = (size - inner) / 2;
+    let image = RgbImage::from_fn(size, size, |x, y| {
+        let inside = (margin..margin + inner).contains(&x) && (margin..margin + inner).contains(&y);
+        if inside
+            && filled(
+                (y - margin) * IDENTICON_CELLS / inner,
+                (x - margin) * IDENTICON_CELLS / inner,
+            )
+        {
+            color
+        } else {
+            IDENTICON_BACKGROUND
+        }
+    });
+    images::encode(&DynamicImage::ImageRgb8(image), "image/png", AVATAR_QUALITY)
+}
+
+async fn delete_stored(state: &AppState, user_id: &str) {
+    for size in AVATAR_SIZES {
+        if let Err(err) = state.storage.delete(&storage_key(user_id, size)).await {
+            eprintln!("failed to delete avatar of {user_id}: {err:#}");
+        }
+    }
+}
+
+// 上传头像：表单字段 file，裁成正方形并缩放到固定尺寸
+pub async fn upload_avatar_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    mut multipart: Multipart,
+) -> Result<Json<UserResponse>, ApiError> {
+    let limits = &state.uploads;
+    let mut upload = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| files::form_error(limits, err))?
+    {
+        if field.name() == Some("file") && upload.is_none() {
+            upload = Some(files::read_field(limits, field).await?);
+        }
+    }
+    let Some(data) = upload.filter(|data| !data.is_empty()) else {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "A non-empty file is required",
+        ));
+    };
+    let content_type = files::detect_content_type(&data, "");
+    if !files::INLINE_IMAGE_TYPES.contains(&content_type.as_str()) {
+        return Err(api_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Avatars must be PNG, JPEG, GIF or WebP images",
+        ));
+    }
+
+    let hash = hex::encode(&Sha256::digest(&data)[..8]);
+    let (content_type, sizes) = tokio::task::spawn_blocking(move || render(&data))
+        .await
+        .map_err(|err| internal_error(err.into()))?
+        .map_err(|err| {
+            api_error(
+                StatusCode::BAD_REQUEST,
+                format!("The image could not be read: {err}"),
+            )
+        })?;
+    for (size, data) in AVATAR_SIZES.into_iter().zip(sizes) {
+        state
+            .storage
+            .put(&storage_key(&auth.id, size), content_type, data)
+            .await
+            .map_err(internal_error)?;
+    }
+    state
+        .db
+        .set_user_avatar(&auth.id, Some((&hash, content_type)))
+        .await
+        .map_err(internal_error)?;
+
+    let user = state
+        .db
+        .get_user_by_id(&auth.id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "User not found"))?;
+    Ok(Json(UserResponse::private(user)))
+}
+
+// 删除头像，恢复为默认图标
+pub async fn delete_avatar_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<UserResponse>, ApiError> {
+    state
+        .db
+        .set_user_avatar(&auth.id, None)
+        .await
+        .map_err(internal_error)?;
+    delete_stored(&state, &auth.id).await;
+
+    let user = state
+        .db
+        .get_user_by_id(&auth.id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "User not found"))?;
+    Ok(Json(UserResponse::private(user)))
+}
+
+// 获取头像，无需登录以便 <img> 直接引用；没有上传头像时返回 identicon
+pub async fn get_avatar_handler(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+    Query(query): Query<AvatarQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let user = state
+        .db
+        .get_user_by_id(&user_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "User not found"))?;
+    let size = query.size.unwrap_or(DEFAULT_SIZE);
+    if !AVATAR_SIZES.contains(&size) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Avatar sizes are {}",
+                AVATAR_SIZES.map(|size| size.to_string()).join(", ")
+            ),
+        ));
+    }
+
+    let etag = match &user.avatar_hash {
+        Some(hash) => format!("\"{hash}-{size}\""),
+        None => format!("\"identicon-{size}\""),
+    };
+    let cache_headers = [
+        (
+            header::ETAG,
+            HeaderValue::from_str(&etag).map_err(|err| internal_error(err.into()))?,
+        ),
+        (
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=3600"),
+        ),
+    ];
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag))
+    {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    let stored = match (&user.avatar_hash, &user.avatar_type) {
+        (Some(_), Some(content_type)) => state
+            .storage
+            .get(&storage_key(&user.id, size))
+            .await
+            .map_err(internal_error)?
+            .map(|data| (content_type.clone(), data)),
+        _ => None,
+    };
+    let (content_type, data) = match stored {
+        Some(stored) => stored,
+        None => (
+            "image/png".to_string(),
+            identicon(&user.id, size).map_err(internal_error)?,
+        ),
+    };
+    Ok((
+        cache_headers,
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_str(&content_type).map_err(|err| internal_error(err.into()))?,
+            ),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mail::MemoryMailer, testing};
+    use axum::body::to_bytes;
+    use image::{GenericImageView, Rgba, RgbaImage};
+
+    async fn get(
+        state: &Arc<AppState>,
+        user_id: &str,
+        size: Option<u32>,
+        if_none_match: Option<&str>,
+    ) -> Result<Response, ApiError> {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = if_none_match {
+            headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(etag).unwrap());
+        }
+        get_avatar_handler(
+            State(state.clone()),
+            Path(user_id.to_string()),
+            Query(AvatarQuery { size }),
+            headers,
+        )
+        .await
+    }
+
+    #[test]
+    fn identicons_are_per_user() {
+        let first = identicon("user-1", 64).unwrap();
+        assert_eq!(first, identicon("user-1", 64).unwrap());
+        assert_ne!(first, identicon("user-2", 64).unwrap());
+
+        // 每个尺寸的图案都左右对称
+        for size in AVATAR_SIZES {
+            let image = image::load_from_memory(&identicon("user-1", size).unwrap())
+                .unwrap()
+                .to_rgb8();
+            assert_eq!(image.dimensions(), (size, size));
+            assert_eq!(image[(0, 0)], IDENTICON_BACKGROUND);
+            for y in 0..size {
+                for x in 0..size / 2 {
+                    assert_eq!(image[(x, y)], image[(size - 1 - x, y)], "{size} ({x}, {y})");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn crops_to_the_centre_at_every_size() {
+        // 左中右三块颜色，裁剪后只剩中间的绿色
+        let wide = RgbaImage::from_fn(300, 100, |x, _| match x {
+            0..=99 => Rgba([255, 0, 0, 255]),
+            100..=199 => Rgba([0, 255, 0, 255]),
+            _ => Rgba([0, 0, 255, 255]),
+        });
+        let data = images::encode(&DynamicImage::ImageRgba8(wide), "image/png", 100).unwrap();
+
+        let (content_type, sizes) = render(&data).unwrap();
+        assert_eq!(content_type, "image/png");
+        assert_eq!(sizes.len(), AVATAR_SIZES.len());
+        for (size, data) in AVATAR_SIZES.into_iter().zip(sizes) {
+            let image = image::load_from_memory(&data).unwrap();
+            assert_eq!(image.dimensions(), (size, size));
+            for (x, y) in [(0, 0), (size - 1, size - 1), (size / 2, size / 2)] {
+                assert_eq!(image.get_pixel(x, y), Rgba([0, 255, 0, 255]), "{size}");
+            }
+        }
+        assert!(render(b"not an image").is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_identicons_with_etags() {
+        let state = Arc::new(testing::state(Arc::new(MemoryMailer::default())).await);
+        let user = testing::user(&state, "alice").await;
+
+        let response = get(&state, &user.id, Some(32), None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(etag, "\"identicon-32\"");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, identicon(&user.id, 32).unwrap());
+
+        let response = get(&state, &user.id, Some(32), Some(&format!("\"x\", {etag}")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        // 尺寸不同，ETag 也不同
+        let response = get(&state, &user.id, None, Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], "\"identicon-128\"");
+
+        for size in [0, 100, 512] {
+            let err = get(&state, &user.id, Some(size), None).await.unwrap_err();
+            assert_eq!(err.0, StatusCode::BAD_REQUEST, "{size}");
+        }
+        let err = get(&state, "nobody", None, None).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn serves_uploaded_avatars() {
+        let state = Arc::new(testing::state(Arc::new(MemoryMailer::default())).await);
+        let user = testing::user(&state, "alice").await;
+        let square = RgbaImage::from_pixel(40, 40, Rgba([10, 20, 30, 128]));
+        let data = images::encode(&DynamicImage::ImageRgba8(square), "image/png", 100).unwrap();
+        let (content_type, sizes) = render(&data).unwrap();
+        for (size, data) in AVATAR_SIZES.into_iter().zip(sizes) {
+            state
+                .storage
+                .put(&storage_key(&user.id, size), content_type, data)
+                .await
+                .unwrap();
+        }
+        state
+            .db
+            .set_user_avatar(&user.id, Some(("0123abcd", content_type)))
+            .await
+            .unwrap();
+
+        let response = get(&state, &user.id, Some(64), None).await.unwrap();
+        assert_eq!(response.headers()[header::ETAG], "\"0123abcd-64\"");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            image::load_from_memory(&body).unwrap().dimensions(),
+            (64, 64)
+        );
+        let response = get(&state, &user.id, Some(64), Some("\"0123abcd-64\""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = get(&state, &user.id, Some(64), Some("\"identicon-64\""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let user = state.db.get_user_by_id(&user.id).await.unwrap().unwrap();
+        assert_eq!(
+            avatar_url(&user),
+            format!("/api/users/{}/avatar?v=0123abcd", user.id)
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use bcrypt::verify;
+use serde::Deserialize;
+use std::sync::Arc;
+
+pub mod avatar;
+
+use crate::{
+    api_error,
+    auth::{
+        verification::{is_valid_email, send_verification_email},
+        AuthUser, Caller,
+    },
+    internal_error, ApiError, AppState, UserResponse,
+};
+
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+const MAX_BIO_LEN: usize = 500;
+/// Longer than any IANA zone name.
+const MAX_TIMEZONE_LEN: usize = 64;
+const MAX_AVATAR_URL_LEN: usize = 2048;
+
+/// Body of `PATCH /api/me`. Omitted fields are left alone; an empty string
+/// clears an optional field.
+#[derive(Debug, Deserialize)]
+pub struct UpdateProfileRequest {
+    display_name: Option<String>,
+    bio: Option<String>,
+    timezone: Option<String>,
+    avatar_url: Option<String>,
+    email: Option<String>,
+    /// Required when `email` changes.
+    current_password: Option<String>,
+}
+
+// 获取当前用户资料
+pub async fn get_me_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+) -> Result<Json<UserResponse>, ApiError> {
+    let user = state
+        .db
+        .get_user_by_id(caller.id())
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "User not found"))?;
+
+    // 机器人的邮箱只是占位，不返回
+    if user.is_bot {
+        return Ok(Json(UserResponse::public(user)));
+    }
+    Ok(Json(UserResponse::private(user)))
+}
+
+// 更新当前用户资料
+pub async fn update_me_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Json(req): Json<UpdateProfileRequest>,
+) -> Result<Json<UserResponse>, ApiError> {
+    let mut user = state
+        .db
+        .get_user_by_id(&auth.id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "User not found"))?;
+
+    if let Some(display_name) = req.display_name {
+        user.display_name = optional_field(display_name, "Display name", MAX_DISPLAY_NAME_LEN)?;
+    }
+
+    if let Some(bio) = req.bio {
+        user.bio = optional_field(bio, "Bio", MAX_BIO_LEN)?;
+    }
+
+    if let Some(timezone) = req.timezone {
+        user.timezone = optional_field(timezone, "Timezone", MAX_TIMEZONE_LEN)?;
+        if let Some(timezone) = &user.timezone {
+            if timezone.parse::<chrono_tz::Tz>().is_err() {
+                return Err(api_error(StatusCode::BAD_REQUEST, "Unknown timezone"));
+            }
+        }
+    }
+
+    if let Some(avatar_url) = req.avatar_url {
+        user.avatar_url = optional_field(avatar_url, "Avatar URL", MAX_AVATAR_URL_LEN)?;
+        if let Some(avatar_url) = &user.avatar_url {
+            if !(avatar_url.starts_with("https://") || avatar_url.starts_with("http://")) {
+                return Err(api_error(
+                    StatusCode::BAD_REQUEST,
+                    "Avatar URL must be an http(s) URL",
+                ));
+            }
+        }
+    }
+
+    let mut email_changed = false;
+    if let Some(email) = req.email {
+        let email = email.trim().to_string();
+        if !email.eq_ignore_ascii_case(&user.email) {
+            // 修改邮箱需要重新确认密码
+            let password = req.current_password.unwrap_or_default();
+            if !verify(&password, &user.password_hash).unwrap_or(false) {
+                return Err(api_error(
+                    StatusCode::FORBIDDEN,
+                    "Current password is required to change email",
+                ));
+            }
+
+            if !is_valid_email(&email) {
+                return Err(api_error(StatusCode::BAD_REQUEST, "Invalid email address"));
+            }
+
+            if state
+                .db
+                .get_user_by_email(&email)
+                .await
+                .map_err(internal_error)?
+                .is_some()
+            {
+                return Err(api_error(StatusCode::CONFLICT, "Email already in use"));
+            }
+
+            // 新邮箱需要重新验证
+            user.email_verified = false;
+            email_changed = true;
+        }
+        user.email = email;
+    }
+
+    state
+        .db
+        .update_user_profile(&user)
+        .await
+        .map_err(internal_error)?;
+
+    if email_changed {
+        if let Err(err) = send_verification_email(&state, &user).await {
+            eprintln!("failed to send verification mail: {err:#}");
+        }
+    }
+
+    Ok(Json(UserResponse::private(user)))
+}
+
+// 查看其他用户的公开资料
+pub async fn get_user_handler(
+    State(state): State<Arc<AppState>>,
+    _caller: Caller,
+    Path(user_id): Path<String>,
+) -> Result<Json<UserResponse>, ApiError> {
+    let user = state
+        .db
+        .get_user_by_id(&user_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "User not found"))?;
+
+    Ok(Json(UserResponse::public(user)))
+}
+
+/// Trims a free-text profile field, mapping an empty value to `None` and
+/// rejecting anything longer than `max_len` characters.
+fn optional_field(value: String, name: &str, max_len: usize) -> Result<Option<String>, ApiError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    if value.chars().count() > max_len {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("{name} must be at most {max_len} characters"),
+        ));
+    }
+    Ok(Some(value.to_string()))
+}
@@ -0,0 +1,271 @@
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use super::{Lockout, RateLimiter, TokenBucket};
+
+/// How long a sender's violations are remembered when deciding on a mute.
+const VIOLATION_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Repeat mutes double in length, up to this many times the base mute.
+const MAX_MUTE_FACTOR: u32 = 8;
+/// The longest slow mode a room can set.
+pub const MAX_SLOW_MODE: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Limits on chat messages sent over the WebSocket.
+#[derive(Debug, Clone)]
+pub struct MessageLimits {
+    pub max_message_bytes: usize,
+    pub connection_burst: u32,
+    pub connection_per_second: f64,
+    pub user_burst: u32,
+    pub user_per_second: f64,
+    /// Violations within the window before a sender is muted.
+    pub mute_after: u32,
+    pub mute_duration: Duration,
+}
+
+impl MessageLimits {
+    /// Reads the `WS_*` environment variables, falling back to defaults.
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            max_message_bytes: env_or("WS_MAX_MESSAGE_BYTES", 4096)?,
+            connection_burst: env_or("WS_CONNECTION_BURST", 10)?,
+            connection_per_second: rate_or("WS_CONNECTION_PER_SECOND", 2.0)?,
+            user_burst: env_or("WS_USER_BURST", 20)?,
+            user_per_second: rate_or("WS_USER_PER_SECOND", 3.0)?,
+            mute_after: env_or("WS_MUTE_AFTER", 5)?,
+            mute_duration: Duration::from_secs(env_or("WS_MUTE_SECONDS", 60)?),
+        })
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("{name} has an invalid value {value:?}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Like [`env_or`], for a refill rate, which must be positive.
+fn rate_or(name: &str, default: f64) -> Result<f64> {
+    let rate = env_or(name, default)?;
+    if !(rate.is_finite() && rate > 0.0) {
+        anyhow::bail!("{name} must be a positive number, not {rate}");
+    }
+    Ok(rate)
+}
+
+/// Flood control for chat messages: a rate per connection, a shared rate per
+/// sender across all their connections, and a temporary mute for senders
+/// who keep hitting the limits.
+pub struct FloodControl {
+    limits: MessageLimits,
+    per_user: RateLimiter,
+    mutes: Lockout,
+    /// When each sender last posted in a room in slow mode, keyed by
+    /// `room_id:sender`.
+    slow_mode: DashMap<String, Instant>,
+    /// Each incoming webhook gets the rate of one connection.
+    webhooks: DashMap<String, TokenBucket>,
+}
+
+impl FloodControl {
+    pub fn new(limits: MessageLimits) -> Self {
+        Self {
+            per_user: RateLimiter::new(limits.user_burst, limits.user_per_second),
+            mutes: Lockout::new(
+                limits.mute_after,
+                limits.mute_duration,
+                limits.mute_duration.saturating_mul(MAX_MUTE_FACTOR),
+                VIOLATION_WINDOW,
+            ),
+            limits,
+            slow_mode: DashMap::new(),
+            webhooks: DashMap::new(),
+        }
+    }
+
+    pub fn limits(&self) -> &MessageLimits {
+        &self.limits
+    }
+
+    /// A fresh bucket for one WebSocket connection.
+    pub fn connection_bucket(&self) -> TokenBucket {
+        TokenBucket::new(
+            self.limits.connection_burst,
+            self.limits.connection_per_second,
+        )
+    }
+
+    /// Decides whether `sender` may post a message of `len` bytes now. On
+    /// refusal returns the reason to show them.
+    pub fn check(
+        &self,
+        sender: &str,
+        connection: &mut TokenBucket,
+        len: usize,
+    ) -> Result<(), String> {
+        if let Err(remaining) = self.mutes.check(sender) {
+            return Err(muted_message(remaining));
+        }
+
+        let violation = if len > self.limits.max_message_bytes {
+            format!(
+                "Message is too long (max {} bytes)",
+                self.limits.max_message_bytes
+            )
+        } else if connection.take().is_err() || self.per_user.check(sender).is_err() {
+            "You are sending messages too fast".to_string()
+        } else {
+            return Ok(());
+        };
+
+        self.mutes.record_failure(sender);
+        match self.mutes.check(sender) {
+            Err(remaining) => Err(muted_message(remaining)),
+            Ok(()) => Err(violation),
+        }
+    }
+
+    /// Like [`Self::check`], for a message posted through an incoming
+    /// webhook.
+    pub fn check_webhook(&self, webhook_id: &str, len: usize) -> Result<(), String> {
+        let mut bucket = self
+            .webhooks
+            .entry(webhook_id.to_string())
+            .or_insert_with(|| self.connection_bucket());
+        self.check(&format!("hook:{webhook_id}"), &mut bucket, len)
+    }
+
+    /// Whether `sender` may post in a room in slow mode, at most once per
+    /// `interval`; otherwise returns how long until they may post again.
+    pub fn slow_mode(
+        &self,
+        room_id: &str,
+        sender: &str,
+        interval: Duration,
+    ) -> Result<(), Duration> {
+        match self.slow_mode.get(&format!("{room_id}:{sender}")) {
+            Some(last) if last.elapsed() < interval => Err(interval - last.elapsed()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Starts `sender`'s wait in a room in slow mode, once a message of
+    /// theirs has been sent.
+    pub fn record_post(&self, room_id: &str, sender: &str) {
+        self.slow_mode
+            .insert(format!("{room_id}:{sender}"), Instant::now());
+    }
+
+    pub fn prune(&self) {
+        self.per_user.prune();
+        self.mutes.prune();
+        self.slow_mode
+            .retain(|_, last| last.elapsed() < MAX_SLOW_MODE);
+        self.webhooks.retain(|_, bucket| !bucket.is_full());
+    }
+}
+
+fn muted_message(remaining: Duration) -> String {
+    format!(
+        "You are muted for flooding, try again in {} seconds",
+        remaining.as_secs_f64().ceil().max(1.0) as u64
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> MessageLimits {
+        MessageLimits {
+            max_message_bytes: 10,
+            connection_burst: 2,
+            connection_per_second: 0.1,
+            user_burst: 3,
+            user_per_second: 0.1,
+            mute_after: 3,
+            mute_duration: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn limits_each_connection_and_sender() {
+        let flood = FloodControl::new(limits());
+        let mut first = flood.connection_bucket();
+        let mut second = flood.connection_bucket();
+
+        assert!(flood.check("bob", &mut first, 5).is_ok());
+        assert!(flood.check("bob", &mut first, 5).is_ok());
+        assert_eq!(
+            flood.check("bob", &mut first, 5).unwrap_err(),
+            "You are sending messages too fast"
+        );
+        // 同一发送者的所有连接共用一个速率
+        assert!(flood.check("bob", &mut second, 5).is_ok());
+        assert!(flood.check("bob", &mut second, 5).is_err());
+        assert!(flood.check("carol", &mut second, 5).is_err());
+        assert!(flood
+            .check("carol", &mut flood.connection_bucket(), 5)
+            .is_ok());
+    }
+
+    #[test]
+    fn mutes_repeat_offenders() {
+        let flood = FloodControl::new(limits());
+        let mut connection = flood.connection_bucket();
+
+        for _ in 0..2 {
+            assert_eq!(
+                flood.check("bob", &mut connection, 11).unwrap_err(),
+                "Message is too long (max 10 bytes)"
+            );
+        }
+        assert_eq!(
+            flood.check("bob", &mut connection, 11).unwrap_err(),
+            "You are muted for flooding, try again in 60 seconds"
+        );
+        assert!(flood
+            .check("bob", &mut connection, 1)
+            .unwrap_err()
+            .starts_with("You are muted"));
+    }
+
+    #[test]
+    fn slow_mode_waits_from_the_last_sent_message() {
+        let flood = FloodControl::new(limits());
+        let interval = Duration::from_secs(30);
+
+        // 只检查不记录，被拒绝的消息不开始计时
+        assert!(flood.slow_mode("room", "bob", interval).is_ok());
+        assert!(flood.slow_mode("room", "bob", interval).is_ok());
+
+        flood.record_post("room", "bob");
+        let wait = flood.slow_mode("room", "bob", interval).unwrap_err();
+        assert!(wait > Duration::from_secs(29) && wait <= interval);
+        assert!(flood.slow_mode("room", "carol", interval).is_ok());
+        assert!(flood.slow_mode("other", "bob", interval).is_ok());
+        assert!(flood.slow_mode("room", "bob", Duration::ZERO).is_ok());
+    }
+
+    #[test]
+    fn rates_must_be_positive() {
+        for value in ["0", "-1", "NaN", "inf", "fast"] {
+            std::env::set_var("CHATX_TEST_RATE", value);
+            assert!(rate_or("CHATX_TEST_RATE", 1.0).is_err(), "{value}");
+        }
+        std::env::set_var("CHATX_TEST_RATE", "0.5");
+        assert_eq!(rate_or("CHATX_TEST_RATE", 1.0).unwrap(), 0.5);
+        std::env::remove_var("CHATX_TEST_RATE");
+        assert_eq!(rate_or("CHATX_TEST_RATE", 1.0).unwrap(), 1.0);
+    }
+}
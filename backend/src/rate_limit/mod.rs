@@ -0,0 +1,322 @@
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use dashmap::DashMap;
+use futures_util::future::BoxFuture;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+
+use crate::api_error;
+
+pub mod flood;
+
+/// A single token bucket: up to `burst` actions at once, refilling at
+/// `per_second` tokens per second.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    burst: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self {
+            burst: burst as f64,
+            per_second,
+            tokens: burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes one token, or returns how long until one is available.
+    pub fn take(&mut self) -> Result<(), Duration> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.per_second,
+            ))
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.updated = now;
+    }
+
+    fn is_full(&self) -> bool {
+        let elapsed = self.updated.elapsed().as_secs_f64();
+        self.tokens + elapsed * self.per_second >= self.burst
+    }
+}
+
+/// Token-bucket rate limiter keyed by an arbitrary string (an IP, a user id,
+/// ...). Each key may spend `burst` requests at once and regains
+/// `per_second` of them every second.
+pub struct RateLimiter {
+    burst: u32,
+    per_second: f64,
+    buckets: DashMap<String, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self {
+            burst,
+            per_second,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Takes one token for `key`, or returns how long until one is available.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.buckets
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket::new(self.burst, self.per_second))
+            .take()
+    }
+
+    /// Forgets keys whose bucket has refilled, since they behave like new ones.
+    pub fn prune(&self) {
+        self.buckets.retain(|_, bucket| !bucket.is_full());
+    }
+}
+
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Locks a key out after repeated failures, doubling the lockout with each
+/// further failure. A success, or `reset_after` without failures, clears it.
+pub struct Lockout {
+    free_attempts: u32,
+    base: Duration,
+    max: Duration,
+    reset_after: Duration,
+    entries: DashMap<String, Failures>,
+}
+
+impl Lockout {
+    pub fn new(free_attempts: u32, base: Duration, max: Duration, reset_after: Duration) -> Self {
+        Self {
+            free_attempts,
+            base,
+            max,
+            reset_after,
+            entries: DashMap::new(),
+        }
+    }
+
+    /// Returns how long `key` stays locked, if it is.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        match self.entries.get(key).and_then(|entry| entry.locked_until) {
+            Some(until) if until > now => Err(until - now),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_failure(&self, key: &str) {
+        let now = Instant::now();
+        let mut entry = self.entries.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        if now.duration_since(entry.last_failure) > self.reset_after {
+            entry.count = 0;
+        }
+
+        entry.count += 1;
+        entry.last_failure = now;
+        if entry.count >= self.free_attempts {
+            let doublings = (entry.count - self.free_attempts).min(16);
+            let lockout = self.base.saturating_mul(1 << doublings).min(self.max);
+            entry.locked_until = Some(now + lockout);
+        }
+    }
+
+    pub fn record_success(&self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.entries.retain(|_, entry| {
+            now.duration_since(entry.last_failure) <= self.reset_after
+                || entry.locked_until.is_some_and(|until| until > now)
+        });
+    }
+}
+
+/// A `429 Too Many Requests` response telling the client when to retry.
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let mut response = api_error(
+        StatusCode::TOO_MANY_REQUESTS,
+        format!("Too many requests, try again in {seconds} seconds"),
+    )
+    .into_response();
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+/// The peer address of a request, when the server was started with
+/// connect info.
+pub fn client_ip(req: &Request) -> Option<String> {
+    req.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+}
+
+/// Tower layer that answers `429` once a request's key has run out of tokens.
+/// Requests the key function can't place are let through.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+    key: fn(&Request) -> Option<String>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: Arc<RateLimiter>, key: fn(&Request) -> Option<String>) -> Self {
+        Self { limiter, key }
+    }
+
+    /// Limits each client IP separately.
+    pub fn per_ip(limiter: Arc<RateLimiter>) -> Self {
+        Self::new(limiter, client_ip)
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        if let Some(key) = (self.layer.key)(&req) {
+            if let Err(retry_after) = self.layer.limiter.check(&key) {
+                return Box::pin(async move { Ok(too_many_requests(retry_after)) });
+            }
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_then_waits() {
+        let mut bucket = TokenBucket::new(3, 0.5);
+        for _ in 0..3 {
+            assert!(bucket.take().is_ok());
+        }
+        let wait = bucket.take().unwrap_err();
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+        assert!(!bucket.is_full());
+    }
+
+    #[test]
+    fn bucket_refills_up_to_its_burst() {
+        let mut bucket = TokenBucket::new(2, 100.0);
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_err());
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(bucket.is_full());
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_err());
+    }
+
+    #[test]
+    fn limiter_keeps_keys_apart() {
+        let limiter = RateLimiter::new(1, 0.1);
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_err());
+        assert!(limiter.check("b").is_ok());
+
+        limiter.prune();
+        assert_eq!(limiter.buckets.len(), 2);
+    }
+
+    #[test]
+    fn lockout_doubles_after_free_attempts() {
+        let lockout = Lockout::new(
+            2,
+            Duration::from_secs(30),
+            Duration::from_secs(100),
+            Duration::from_secs(3600),
+        );
+        lockout.record_failure("bob");
+        assert!(lockout.check("bob").is_ok());
+
+        lockout.record_failure("bob");
+        let first = lockout.check("bob").unwrap_err();
+        assert!(first > Duration::from_secs(29) && first <= Duration::from_secs(30));
+        lockout.record_failure("bob");
+        assert!(lockout.check("bob").unwrap_err() > Duration::from_secs(59));
+        lockout.record_failure("bob");
+        assert!(lockout.check("bob").unwrap_err() <= Duration::from_secs(100));
+        assert!(lockout.check("carol").is_ok());
+
+        lockout.record_success("bob");
+        assert!(lockout.check("bob").is_ok());
+    }
+
+    #[test]
+    fn too_many_requests_says_when_to_retry() {
+        let response = too_many_requests(Duration::from_millis(2500));
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "3");
+        assert_eq!(
+            too_many_requests(Duration::ZERO).headers()[RETRY_AFTER],
+            "1"
+        );
+    }
+}
@@ -0,0 +1,726 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use uuid::Uuid;
+
+use crate::{
+    api_error, audit,
+    auth::{session::ClientInfo, AuthUser, Caller},
+    bots::Scope,
+    db, files, filters, internal_error, moderation,
+    rate_limit::flood::MAX_SLOW_MODE,
+    webhooks::{self, WebhookEvent},
+    ApiError, AppState, ChatMessage, StatusResponse,
+};
+
+pub mod permissions;
+
+use permissions::Permission;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct MessagesQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditMessageRequest {
+    content: String,
+}
+
+/// Changes to a room's posting restrictions; omitted fields stay as they
+/// are.
+#[derive(Debug, Deserialize)]
+pub struct RoomSettingsRequest {
+    slow_mode_seconds: Option<i64>,
+    read_only: Option<bool>,
+    members_only: Option<bool>,
+}
+
+/// A chat message on its way into a channel.
+pub struct NewMessage {
+    /// Messages without a sender are guests' and are only broadcast.
+    pub sender_id: Option<String>,
+    pub username: String,
+    pub content: String,
+    pub message_type: Option<String>,
+    pub is_bot: bool,
+    /// Set when an incoming webhook posts the message.
+    pub integration_id: Option<String>,
+    pub attachments: Vec<db::Attachment>,
+}
+
+/// Finds the room backing a WebSocket channel and records a signed-in user
+/// as its member, unless the room is members-only. The first signed-in user to join a channel creates its
+/// room and becomes the owner; guests never create rooms.
+pub async fn join_channel(
+    state: &AppState,
+    name: &str,
+    user_id: Option<&str>,
+) -> anyhow::Result<Option<db::ChatRoom>> {
+    let room = match state.db.get_channel_room(name).await? {
+        Some(room) => room,
+        None => {
+            let Some(user_id) = user_id else {
+                return Ok(None);
+            };
+            let room = db::ChatRoom {
+                id: Uuid::new_v4().to_string(),
+                name: name.to_string(),
+                room_type: "channel".to_string(),
+                created_by: user_id.to_string(),
+                created_at: Utc::now(),
+                description: None,
+                slow_mode_seconds: 0,
+                read_only: false,
+                members_only: false,
+            };
+            match state.db.create_chat_room(&room).await {
+                Ok(()) => {
+                    add_member(state, &room.id, user_id, "owner").await?;
+                    room
+                }
+                // 并发创建时以先写入的为准
+                Err(err) => state.db.get_channel_room(name).await?.ok_or(err)?,
+            }
+        }
+    };
+
+    // 仅限成员的房间需要邀请才能成为成员
+    if let (Some(user_id), false) = (user_id, room.members_only) {
+        add_member(state, &room.id, user_id, "member").await?;
+    }
+    Ok(Some(room))
+}
+
+/// Records a user as a room member; members keep their existing role.
+pub async fn add_member(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    role: &str,
+) -> anyhow::Result<()> {
+    state
+        .db
+        .add_room_member(&db::RoomMember {
+            id: Uuid::new_v4().to_string(),
+            room_id: room_id.to_string(),
+            user_id: user_id.to_string(),
+            joined_at: Utc::now(),
+            role: role.to_string(),
+        })
+        .await
+}
+
+/// Why a sender may not post `text` in a room right now: the room is
+/// members-only or read-only, they are muted, their role does not let them
+/// send, they lack the permission to ping everyone, or slow mode wants
+/// them to wait. Guests have no `user_id`; `sender` is the key slow mode
+/// knows them by, usually from [`slow_mode_key`].
+pub async fn send_refusal(
+    state: &AppState,
+    room: &db::ChatRoom,
+    user_id: Option<&str>,
+    sender: &str,
+    text: &str,
+) -> anyhow::Result<Option<String>> {
+    let Some(user_id) = user_id else {
+        return Ok(if room.members_only {
+            Some(format!("Only members can post in {}", room.name))
+        } else if room.read_only {
+            Some(read_only_refusal(room))
+        } else {
+            slow_mode_refusal(state, room, sender)
+        });
+    };
+    if room.members_only && state.db.get_room_member(&room.id, user_id).await?.is_none() {
+        return Ok(Some(format!("Only members can post in {}", room.name)));
+    }
+    if let Some(refusal) = moderation::send_refusal(state, room, user_id).await? {
+        return Ok(Some(refusal));
+    }
+    let granted = permissions::member_permissions(state, &room.id, user_id).await?;
+    if !granted.contains(&Permission::Send) {
+        return Ok(Some(format!("You cannot post in {}", room.name)));
+    }
+    if room.read_only && !granted.contains(&Permission::ManageRoom) {
+        return Ok(Some(read_only_refusal(room)));
+    }
+    if permissions::mentions_everyone(text) && !granted.contains(&Permission::MentionEveryone) {
+        return Ok(Some(format!(
+            "You cannot mention everyone in {}",
+            room.name
+        )));
+    }
+    // 管理成员的人不受慢速模式限制
+    if granted.contains(&Permission::ManageMembers) {
+        return Ok(None);
+    }
+    Ok(slow_mode_refusal(state, room, sender))
+}
+
+fn read_only_refusal(room: &db::ChatRoom) -> String {
+    format!("{} is read-only, only its admins can post", room.name)
+}
+
+/// Who slow mode counts a message against: the sender's account, or a
+/// guest's IP address.
+pub fn slow_mode_key(user_id: Option<&str>, ip_address: Option<&str>) -> String {
+    match (user_id, ip_address) {
+        (Some(user_id), _) => user_id.to_string(),
+        (None, Some(ip_address)) => format!("ip:{ip_address}"),
+        (None, None) => "guest".to_string(),
+    }
+}
+
+/// The type a client may give a message it sends over the WebSocket: plain
+/// text or an action. Anything else, such as `system` or `image`, is
+/// dropped so nobody can pass their text off as a notice or an upload.
+pub fn client_message_type(message_type: Option<String>) -> Option<String> {
+    message_type.filter(|kind| kind == "text" || kind == "action")
+}
+
+fn slow_mode_refusal(state: &AppState, room: &db::ChatRoom, sender: &str) -> Option<String> {
+    let interval = u64::try_from(room.slow_mode_seconds)
+        .ok()
+        .filter(|seconds| *seconds > 0)?;
+    let wait = state
+        .flood
+        .slow_mode(&room.id, sender, Duration::from_secs(interval))
+        .err()?;
+    Some(format!(
+        "{} is in slow mode, you can post again in {} seconds",
+        room.name,
+        wait.as_secs_f64().ceil().max(1.0) as u64
+    ))
+}
+
+/// What became of a posted message.
+pub enum Posted {
+    /// Sent to the channel; only signed-in users' messages are stored.
+    Sent(Option<Box<db::Message>>),
+    /// Kept back by a content filter until a moderator reviews it.
+    Held(String),
+    /// Refused by a content filter.
+    Rejected(String),
+}
+
+impl Posted {
+    /// What to tell the sender when their message did not go out.
+    pub fn notice(&self) -> Option<String> {
+        match self {
+            Posted::Sent(_) => None,
+            Posted::Held(reason) => Some(format!("Your message is held for review: {reason}")),
+            Posted::Rejected(reason) => Some(reason.clone()),
+        }
+    }
+}
+
+/// Runs a message through the content filters and delivers what they let
+/// through. This is the one path every new chat message takes, whether it
+/// arrives over the WebSocket or an integration. A sent message starts the
+/// wait of `slow_mode_key` in a room in slow mode.
+pub async fn post_message(
+    state: &AppState,
+    channel: &str,
+    room: Option<&db::ChatRoom>,
+    mut new: NewMessage,
+    slow_mode_key: &str,
+) -> anyhow::Result<Posted> {
+    let ctx = filters::FilterContext { state, room };
+    match state.filters.run(&ctx, &new.content).await? {
+        filters::Decision::Send(text) => {
+            new.content = text;
+            let message = deliver(state, channel, room, new).await?;
+            if let Some(room) = room.filter(|room| room.slow_mode_seconds > 0) {
+                state.flood.record_post(&room.id, slow_mode_key);
+            }
+            Ok(Posted::Sent(message.map(Box::new)))
+        }
+        filters::Decision::Reject(reason) => Ok(Posted::Rejected(reason)),
+        filters::Decision::Hold {
+            text,
+            filter,
+            reason,
+        } => match (room, new.sender_id.clone()) {
+            (Some(room), Some(sender_id)) => {
+                new.content = text;
+                filters::hold(state, room, &sender_id, new, filter, reason.clone()).await?;
+                Ok(Posted::Held(reason))
+            }
+            // 访客消息没有地方保存，无法审核
+            _ => Ok(Posted::Rejected(format!(
+                "Your message cannot be sent: {reason}"
+            ))),
+        },
+    }
+}
+
+/// Stores a message in the channel's room, sends it to everyone connected
+/// and publishes `message.created`, skipping the content filters.
+pub async fn deliver(
+    state: &AppState,
+    channel: &str,
+    room: Option<&db::ChatRoom>,
+    new: NewMessage,
+) -> anyhow::Result<Option<db::Message>> {
+    let files: Vec<_> = new
+        .attachments
+        .iter()
+        .filter_map(|attachment| attachment.file.as_ref())
+        .collect();
+    // 登录用户的消息写入数据库，访客消息只广播
+    let stored = match (room, &new.sender_id) {
+        (Some(room), Some(sender_id)) => {
+            let message = db::Message {
+                id: Uuid::new_v4().to_string(),
+                room_id: room.id.clone(),
+                sender_id: sender_id.clone(),
+                content: new.content.clone(),
+                // 客户端给的其他类型只用于展示，不入库
+                message_type: match new.message_type.as_deref() {
+                    Some("action") => "action",
+                    Some(kind @ ("file" | "image")) if !files.is_empty() => kind,
+                    _ => "text",
+                }
+                .to_string(),
+                created_at: Utc::now(),
+                edited_at: None,
+                reply_to: None,
+                deleted_at: None,
+                sender_name: new.integration_id.as_ref().map(|_| new.username.clone()),
+                integration_id: new.integration_id.clone(),
+                attachments: (!new.attachments.is_empty())
+                    .then(|| sqlx::types::Json(new.attachments.clone())),
+                link_previews: None,
+            };
+            state.db.create_message(&message).await?;
+            for file in &files {
+                state.db.link_file(&file.id, &room.id, &message.id).await?;
+            }
+            state.unfurler.request(&message);
+            Some(message)
+        }
+        _ => None,
+    };
+
+    broadcast(
+        state,
+        channel,
+        &ChatMessage {
+            username: new.username.clone(),
+            message: new.content,
+            channel: channel.to_string(),
+            message_type: new.message_type,
+            is_bot: new.is_bot,
+            id: stored.as_ref().map(|message| message.id.clone()),
+            is_integration: new.integration_id.is_some(),
+            attachments: new.attachments,
+            members: Vec::new(),
+            previews: Vec::new(),
+        },
+    );
+
+    if let (Some(room), Some(message)) = (room, &stored) {
+        webhooks::publish(
+            state,
+            room,
+            WebhookEvent::MessageCreated,
+            webhooks::message_data(message, &new.username, new.is_bot),
+        )
+        .await;
+    }
+    Ok(stored)
+}
+
+/// Sends an event to everyone connected to a channel.
+pub fn broadcast(state: &AppState, channel: &str, message: &ChatMessage) {
+    if let Some(channel) = state.channels.get(channel) {
+        if let Ok(text) = serde_json::to_string(message) {
+            let _ = channel.tx.send(text);
+        }
+    }
+}
+
+/// Sends a system message to everyone connected to a channel.
+pub fn announce(state: &AppState, channel: &str, message: String) {
+    broadcast(
+        state,
+        channel,
+        &ChatMessage {
+            username: "System".to_string(),
+            message,
+            channel: channel.to_string(),
+            message_type: Some("system".to_string()),
+            is_bot: false,
+            id: None,
+            is_integration: false,
+            attachments: Vec::new(),
+            members: Vec::new(),
+            previews: Vec::new(),
+        },
+    );
+}
+
+/// Disconnects every connection in the channel joined under `username`.
+pub fn kick(state: &AppState, channel: &str, username: &str) {
+    if let Some(channel) = state.channels.get(channel) {
+        let _ = channel.kicks.send(username.to_string());
+    }
+}
+
+/// Disconnects everyone in the channel.
+pub fn disconnect_all(state: &AppState, channel: &str) {
+    if let Some(channel) = state.channels.get(channel) {
+        for user in channel.users.iter() {
+            let _ = channel.kicks.send(user.key().clone());
+        }
+    }
+}
+
+// 获取当前用户加入的房间
+pub async fn list_rooms_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+) -> Result<Json<Vec<db::ChatRoom>>, ApiError> {
+    caller.require(&Scope::ReadRooms)?;
+    let rooms = state
+        .db
+        .get_user_chat_rooms(caller.id())
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(rooms))
+}
+
+// 修改房间的发言限制（慢速模式、只读、仅限成员）
+pub async fn update_room_settings_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path(room_id): Path<String>,
+    Json(req): Json<RoomSettingsRequest>,
+) -> Result<Json<db::ChatRoom>, ApiError> {
+    let mut room = moderation::load_room(&state, &room_id).await?;
+    permissions::authorize(&state, &room.id, &auth.id, Permission::ManageRoom).await?;
+    let user = moderation::load_user(&state, &auth.id).await?;
+
+    let max_seconds = MAX_SLOW_MODE.as_secs() as i64;
+    let mut changes = Vec::new();
+    if let Some(seconds) = req.slow_mode_seconds {
+        if !(0..=max_seconds).contains(&seconds) {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                format!("Slow mode must be 0 to {max_seconds} seconds"),
+            ));
+        }
+        if seconds != room.slow_mode_seconds {
+            room.slow_mode_seconds = seconds;
+            changes.push(match seconds {
+                0 => "turned off slow mode".to_string(),
+                seconds => format!("set slow mode to one message every {seconds} seconds"),
+            });
+        }
+    }
+    if let Some(read_only) = req.read_only.filter(|value| *value != room.read_only) {
+        room.read_only = read_only;
+        changes.push(match read_only {
+            true => "made the room read-only".to_string(),
+            false => "let everyone post again".to_string(),
+        });
+    }
+    if let Some(members_only) = req.members_only.filter(|value| *value != room.members_only) {
+        room.members_only = members_only;
+        changes.push(match members_only {
+            true => "limited posting to members".to_string(),
+            false => "opened posting to everyone".to_string(),
+        });
+    }
+    if changes.is_empty() {
+        return Ok(Json(room));
+    }
+
+    state
+        .db
+        .update_room_settings(&room)
+        .await
+        .map_err(internal_error)?;
+    audit::record(
+        &state,
+        audit::Event {
+            action: "room.settings_update",
+            actor_id: Some(&auth.id),
+            target: Some(("room", &room.id)),
+            ip_address: client.ip_address.as_deref(),
+            details: serde_json::json!({
+                "slow_mode_seconds": room.slow_mode_seconds,
+                "read_only": room.read_only,
+                "members_only": room.members_only,
+            }),
+        },
+    )
+    .await;
+    announce(
+        &state,
+        &room.name,
+        format!("{} {}", user.username, changes.join(", ")),
+    );
+
+    Ok(Json(room))
+}
+
+// 获取房间历史消息
+pub async fn get_messages_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path(room_id): Path<String>,
+    Query(query): Query<MessagesQuery>,
+) -> Result<Json<Vec<db::Message>>, ApiError> {
+    caller.require(&Scope::ReadRooms)?;
+    state
+        .db
+        .get_room_member(&room_id, caller.id())
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Room not found"))?;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let messages = state
+        .db
+        .get_messages(&room_id, limit, offset)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(messages))
+}
+
+// 编辑消息：发送者本人或有 edit_others 权限的成员
+pub async fn edit_message_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path(message_id): Path<String>,
+    Json(req): Json<EditMessageRequest>,
+) -> Result<Json<db::Message>, ApiError> {
+    let (mut message, room) = live_message(&state, &message_id).await?;
+    caller.require(&Scope::PostRoom(room.name.clone()))?;
+    if message.sender_id != caller.id() {
+        permissions::authorize(&state, &room.id, caller.id(), Permission::EditOthers).await?;
+    }
+
+    let content = req.content.trim();
+    let max_len = state.flood.limits().max_message_bytes;
+    if content.is_empty() || content.len() > max_len {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Message must be 1 to {max_len} bytes"),
+        ));
+    }
+    if permissions::mentions_everyone(content) && !permissions::mentions_everyone(&message.content)
+    {
+        permissions::authorize(&state, &room.id, caller.id(), Permission::MentionEveryone).await?;
+    }
+    let ctx = filters::FilterContext {
+        state: &state,
+        room: Some(&room),
+    };
+    let content = match state
+        .filters
+        .run(&ctx, content)
+        .await
+        .map_err(internal_error)?
+    {
+        filters::Decision::Send(text) => text,
+        // 编辑后的消息不进审核队列
+        filters::Decision::Hold { reason, .. } | filters::Decision::Reject(reason) => {
+            return Err(api_error(StatusCode::BAD_REQUEST, reason));
+        }
+    };
+    let content = content.as_str();
+
+    state
+        .db
+        .update_message_content(&message.id, content)
+        .await
+        .map_err(internal_error)?;
+    message.content = content.to_string();
+    message.edited_at = Some(Utc::now());
+    state.unfurler.request(&message);
+
+    let (username, is_bot) = message_sender(&state, &message).await?;
+    broadcast(
+        &state,
+        &room.name,
+        &ChatMessage {
+            username: username.clone(),
+            message: message.content.clone(),
+            channel: room.name.clone(),
+            message_type: Some("edited".to_string()),
+            is_bot,
+            id: Some(message.id.clone()),
+            is_integration: message.integration_id.is_some(),
+            attachments: Vec::new(),
+            members: Vec::new(),
+            previews: Vec::new(),
+        },
+    );
+    webhooks::publish(
+        &state,
+        &room,
+        WebhookEvent::MessageEdited,
+        webhooks::message_data(&message, &username, is_bot),
+    )
+    .await;
+
+    Ok(Json(message))
+}
+
+// 删除消息：发送者本人或有 delete_others 权限的成员
+pub async fn delete_message_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    client: ClientInfo,
+    Path(message_id): Path<String>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let (message, room) = live_message(&state, &message_id).await?;
+    caller.require(&Scope::PostRoom(room.name.clone()))?;
+    let moderated = message.sender_id != caller.id();
+    if moderated {
+        permissions::authorize(&state, &room.id, caller.id(), Permission::DeleteOthers).await?;
+    }
+
+    delete_message(&state, &room, &message).await?;
+    // 删除他人的消息属于管理操作，记入审计日志
+    if moderated {
+        audit::record(
+            &state,
+            audit::Event {
+                action: "moderation.message_delete",
+                actor_id: Some(caller.id()),
+                target: Some(("message", &message.id)),
+                ip_address: client.ip_address.as_deref(),
+                details: serde_json::json!({
+                    "room_id": room.id,
+                    "sender_id": message.sender_id,
+                }),
+            },
+        )
+        .await;
+    }
+
+    Ok(Json(StatusResponse::new("Message deleted")))
+}
+
+/// Deletes a message and its files, tells everyone connected to its
+/// channel and publishes `message.deleted`.
+pub async fn delete_message(
+    state: &AppState,
+    room: &db::ChatRoom,
+    message: &db::Message,
+) -> Result<(), ApiError> {
+    state
+        .db
+        .delete_message(&message.id)
+        .await
+        .map_err(internal_error)?;
+    let message_files = state
+        .db
+        .get_message_files(&message.id)
+        .await
+        .map_err(internal_error)?;
+    files::delete_stored(state, &message_files).await;
+
+    let (username, is_bot) = message_sender(state, message).await?;
+    broadcast(
+        state,
+        &room.name,
+        &ChatMessage {
+            username: username.clone(),
+            message: String::new(),
+            channel: room.name.clone(),
+            message_type: Some("deleted".to_string()),
+            is_bot,
+            id: Some(message.id.clone()),
+            is_integration: message.integration_id.is_some(),
+            attachments: Vec::new(),
+            members: Vec::new(),
+            previews: Vec::new(),
+        },
+    );
+    webhooks::publish(
+        state,
+        room,
+        WebhookEvent::MessageDeleted,
+        webhooks::message_data(message, &username, is_bot),
+    )
+    .await;
+    Ok(())
+}
+
+/// Loads a message that has not been deleted, with its room.
+pub async fn live_message(
+    state: &AppState,
+    message_id: &str,
+) -> Result<(db::Message, db::ChatRoom), ApiError> {
+    let not_found = || api_error(StatusCode::NOT_FOUND, "Message not found");
+    let message = state
+        .db
+        .get_message(message_id)
+        .await
+        .map_err(internal_error)?
+        .filter(|message| message.deleted_at.is_none())
+        .ok_or_else(not_found)?;
+    let room = state
+        .db
+        .get_chat_room(&message.room_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+
+    Ok((message, room))
+}
+
+/// The name a message was shown under and whether a bot sent it.
+pub async fn message_sender(
+    state: &AppState,
+    message: &db::Message,
+) -> Result<(String, bool), ApiError> {
+    let sender = state
+        .db
+        .get_user_by_id(&message.sender_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Message not found"))?;
+
+    Ok((
+        message.sender_name.clone().unwrap_or(sender.username),
+        sender.is_bot,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_can_only_send_text_and_actions() {
+        let kind = |kind: &str| client_message_type(Some(kind.to_string()));
+        assert_eq!(kind("text").as_deref(), Some("text"));
+        assert_eq!(kind("action").as_deref(), Some("action"));
+        for spoofed in ["system", "image", "file", "edited", "deleted", "ACTION", ""] {
+            assert_eq!(kind(spoofed), None, "{spoofed}");
+        }
+        assert_eq!(client_message_type(None), None);
+    }
+}
@@ -0,0 +1,788 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{fmt, sync::Arc};
+use uuid::Uuid;
+
+use crate::{
+    api_error, audit,
+    auth::{session::ClientInfo, AuthUser},
+    db, internal_error, ApiError, AppState, StatusResponse,
+};
+
+const MAX_ROLE_NAME_LEN: usize = 32;
+const MAX_ROLES_PER_ROOM: usize = 20;
+/// Roles every room has; custom roles cannot reuse these names.
+const BUILTIN_ROLES: [&str; 5] = ["owner", "admin", "moderator", "member", "viewer"];
+
+/// Something a room member may be allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Send,
+    EditOthers,
+    DeleteOthers,
+    /// Invite, remove and sanction members, and change their roles.
+    ManageMembers,
+    /// Change the topic, webhooks and custom roles.
+    ManageRoom,
+    /// Post `@everyone` or `@here`.
+    MentionEveryone,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 6] = [
+        Self::Send,
+        Self::EditOthers,
+        Self::DeleteOthers,
+        Self::ManageMembers,
+        Self::ManageRoom,
+        Self::MentionEveryone,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Send => "send",
+            Self::EditOthers => "edit_others",
+            Self::DeleteOthers => "delete_others",
+            Self::ManageMembers => "manage_members",
+            Self::ManageRoom => "manage_room",
+            Self::MentionEveryone => "mention_everyone",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRoleRequest {
+    name: String,
+    permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateRoleRequest {
+    permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetMemberRoleRequest {
+    role: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoleResponse {
+    /// `None` for built-in roles.
+    id: Option<String>,
+    name: String,
+    permissions: Vec<Permission>,
+    builtin: bool,
+    created_at: Option<DateTime<Utc>>,
+}
+
+impl RoleResponse {
+    fn from_row(role: db::RoomRole) -> Self {
+        Self {
+            permissions: parse_permissions(&role.permissions),
+            id: Some(role.id),
+            name: role.name,
+            builtin: false,
+            created_at: Some(role.created_at),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemberResponse {
+    user_id: String,
+    username: String,
+    role: String,
+    permissions: Vec<Permission>,
+    joined_at: DateTime<Utc>,
+}
+
+/// The permissions of a built-in role.
+fn builtin_permissions(role: &str) -> Option<Vec<Permission>> {
+    use Permission::*;
+    Some(match role {
+        "owner" | "admin" => Permission::ALL.to_vec(),
+        "moderator" => vec![Send, DeleteOthers, ManageMembers, MentionEveryone],
+        "member" => vec![Send],
+        "viewer" => Vec::new(),
+        _ => return None,
+    })
+}
+
+/// Reads a custom role's stored permissions, skipping names this version
+/// does not know.
+fn parse_permissions(json: &str) -> Vec<Permission> {
+    serde_json::from_str::<Vec<serde_json::Value>>(json)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|value| serde_json::from_value(value).ok())
+        .collect()
+}
+
+/// The permissions a role grants in a room; unknown roles grant none.
+pub async fn role_permissions(
+    state: &AppState,
+    room_id: &str,
+    role: &str,
+) -> anyhow::Result<Vec<Permission>> {
+    if let Some(permissions) = builtin_permissions(role) {
+        return Ok(permissions);
+    }
+    Ok(state
+        .db
+        .get_room_role_by_name(room_id, role)
+        .await?
+        .map(|role| parse_permissions(&role.permissions))
+        .unwrap_or_default())
+}
+
+/// The permissions a user has in a room; non-members have none.
+pub async fn member_permissions(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+) -> anyhow::Result<Vec<Permission>> {
+    match state.db.get_room_member(room_id, user_id).await? {
+        Some(member) => role_permissions(state, room_id, &member.role).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+pub async fn has_permission(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    permission: Permission,
+) -> anyhow::Result<bool> {
+    Ok(member_permissions(state, room_id, user_id)
+        .await?
+        .contains(&permission))
+}
+
+/// The one check REST handlers and chat commands use before acting in a
+/// room on a user's behalf.
+pub async fn authorize(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    permission: Permission,
+) -> Result<(), ApiError> {
+    if !has_permission(state, room_id, user_id, permission)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            format!("You need the {permission} permission in this room"),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether a message pings the whole room.
+pub fn mentions_everyone(text: &str) -> bool {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '@' || c == '_'))
+        .any(|word| word == "@everyone" || word == "@here")
+}
+
+/// Nobody can hand out permissions they do not have themselves.
+fn require_subset(granted: &[Permission], own: &[Permission]) -> Result<(), ApiError> {
+    match granted.iter().find(|permission| !own.contains(permission)) {
+        Some(permission) => Err(api_error(
+            StatusCode::FORBIDDEN,
+            format!("You cannot grant the {permission} permission"),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn dedup(mut permissions: Vec<Permission>) -> Vec<Permission> {
+    permissions.sort_by_key(|permission| Permission::ALL.iter().position(|p| p == permission));
+    permissions.dedup();
+    permissions
+}
+
+async fn load_room(state: &AppState, room_id: &str) -> Result<db::ChatRoom, ApiError> {
+    state
+        .db
+        .get_chat_room(room_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Room not found"))
+}
+
+/// Loads a room the user belongs to, with their permissions there.
+async fn member_room(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+) -> Result<(db::ChatRoom, Vec<Permission>), ApiError> {
+    let room = load_room(state, room_id).await?;
+    let member = state
+        .db
+        .get_room_member(&room.id, user_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Room not found"))?;
+    let permissions = role_permissions(state, &room.id, &member.role)
+        .await
+        .map_err(internal_error)?;
+
+    Ok((room, permissions))
+}
+
+async fn custom_role(
+    state: &AppState,
+    room: &db::ChatRoom,
+    role_id: &str,
+) -> Result<db::RoomRole, ApiError> {
+    state
+        .db
+        .get_room_roles(&room.id)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .find(|role| role.id == role_id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Role not found"))
+}
+
+// 列出房间的内置角色和自定义角色
+pub async fn list_roles_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Path(room_id): Path<String>,
+) -> Result<Json<Vec<RoleResponse>>, ApiError> {
+    let (room, _) = member_room(&state, &room_id, &auth.id).await?;
+
+    let mut roles: Vec<RoleResponse> = BUILTIN_ROLES
+        .iter()
+        .map(|name| RoleResponse {
+            id: None,
+            name: name.to_string(),
+            permissions: builtin_permissions(name).unwrap_or_default(),
+            builtin: true,
+            created_at: None,
+        })
+        .collect();
+    roles.extend(
+        state
+            .db
+            .get_room_roles(&room.id)
+            .await
+            .map_err(internal_error)?
+            .into_iter()
+            .map(RoleResponse::from_row),
+    );
+
+    Ok(Json(roles))
+}
+
+// 创建自定义角色
+pub async fn create_role_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path(room_id): Path<String>,
+    Json(req): Json<CreateRoleRequest>,
+) -> Result<Json<RoleResponse>, ApiError> {
+    let (room, own) = member_room(&state, &room_id, &auth.id).await?;
+    authorize(&state, &room.id, &auth.id, Permission::ManageRoom).await?;
+
+    let name = req.name.trim().to_lowercase();
+    if name.is_empty()
+        || name.len() > MAX_ROLE_NAME_LEN
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Role names must be 1 to {MAX_ROLE_NAME_LEN} letters, digits, '_' or '-'"),
+        ));
+    }
+    if BUILTIN_ROLES.contains(&name.as_str()) {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("{name} is a built-in role"),
+        ));
+    }
+    let permissions = dedup(req.permissions);
+    require_subset(&permissions, &own)?;
+
+    let existing = state
+        .db
+        .get_room_roles(&room.id)
+        .await
+        .map_err(internal_error)?;
+    if existing.iter().any(|role| role.name == name) {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("{name} already exists"),
+        ));
+    }
+    if existing.len() >= MAX_ROLES_PER_ROOM {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("A room can have at most {MAX_ROLES_PER_ROOM} custom roles"),
+        ));
+    }
+
+    let role = db::RoomRole {
+        id: Uuid::new_v4().to_string(),
+        room_id: room.id,
+        name,
+        permissions: serde_json::to_string(&permissions).map_err(|e| internal_error(e.into()))?,
+        created_at: Utc::now(),
+    };
+    state
+        .db
+        .create_room_role(&role)
+        .await
+        .map_err(internal_error)?;
+    record_role_change(&state, &auth, &client, "room.role_create", &role).await;
+
+    Ok(Json(RoleResponse::from_row(role)))
+}
+
+// 修改自定义角色的权限
+pub async fn update_role_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path((room_id, role_id)): Path<(String, String)>,
+    Json(req): Json<UpdateRoleRequest>,
+) -> Result<Json<RoleResponse>, ApiError> {
+    let (room, own) = member_room(&state, &room_id, &auth.id).await?;
+    authorize(&state, &room.id, &auth.id, Permission::ManageRoom).await?;
+    let mut role = custom_role(&state, &room, &role_id).await?;
+
+    // 新旧权限都不能超出操作者自己的权限
+    let permissions = dedup(req.permissions);
+    require_subset(&permissions, &own)?;
+    require_subset(&parse_permissions(&role.permissions), &own)?;
+
+    role.permissions = serde_json::to_string(&permissions).map_err(|e| internal_error(e.into()))?;
+    state
+        .db
+        .update_room_role_permissions(&role.id, &role.permissions)
+        .await
+        .map_err(internal_error)?;
+    record_role_change(&state, &auth, &client, "room.role_update", &role).await;
+
+    Ok(Json(RoleResponse::from_row(role)))
+}
+
+// 删除自定义角色，持有者降为普通成员
+pub async fn delete_role_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path((room_id, role_id)): Path<(String, String)>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let (room, own) = member_room(&state, &room_id, &auth.id).await?;
+    authorize(&state, &room.id, &auth.id, Permission::ManageRoom).await?;
+    let role = custom_role(&state, &room, &role_id).await?;
+    require_subset(&parse_permissions(&role.permissions), &own)?;
+
+    state
+        .db
+        .delete_room_role(&role)
+        .await
+        .map_err(internal_error)?;
+    record_role_change(&state, &auth, &client, "room.role_delete", &role).await;
+
+    Ok(Json(StatusResponse::new("Role deleted")))
+}
+
+// 列出房间成员及其角色
+pub async fn list_members_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Path(room_id): Path<String>,
+) -> Result<Json<Vec<MemberResponse>>, ApiError> {
+    let (room, _) = member_room(&state, &room_id, &auth.id).await?;
+
+    let members = state
+        .db
+        .get_room_member_entries(&room.id)
+        .await
+        .map_err(internal_error)?;
+    let mut responses = Vec::with_capacity(members.len());
+    for member in members {
+        let Some(user) = state
+            .db
+            .get_user_by_id(&member.user_id)
+            .await
+            .map_err(internal_error)?
+        else {
+            continue;
+        };
+        let permissions = role_permissions(&state, &room.id, &member.role)
+            .await
+            .map_err(internal_error)?;
+        responses.push(MemberResponse {
+            user_id: member.user_id,
+            username: user.username,
+            role: member.role,
+            permissions,
+            joined_at: member.joined_at,
+        });
+    }
+
+    Ok(Json(responses))
+}
+
+// 修改成员角色
+pub async fn set_member_role_handler(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Path((room_id, user_id)): Path<(String, String)>,
+    Json(req): Json<SetMemberRoleRequest>,
+) -> Result<Json<MemberResponse>, ApiError> {
+    let (room, own) = member_room(&state, &room_id, &auth.id).await?;
+    authorize(&state, &room.id, &auth.id, Permission::ManageMembers).await?;
+    if user_id == auth.id {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "You cannot change your own role",
+        ));
+    }
+
+    let role = req.role.trim().to_lowercase();
+    if role == "owner" {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Rooms have exactly one owner",
+        ));
+    }
+    let member = state
+        .db
+        .get_room_member(&room.id, &user_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Member not found"))?;
+    if member.role == "owner" {
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            "The room owner's role cannot be changed",
+        ));
+    }
+
+    let permissions = match builtin_permissions(&role) {
+        Some(permissions) => permissions,
+        None => state
+            .db
+            .get_room_role_by_name(&room.id, &role)
+            .await
+            .map_err(internal_error)?
+            .map(|role| parse_permissions(&role.permissions))
+            .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Role not found"))?,
+    };
+    // 不能授予、也不能收回超出自己权限的角色
+    require_subset(&permissions, &own)?;
+    let current = role_permissions(&state, &room.id, &member.role)
+        .await
+        .map_err(internal_error)?;
+    require_subset(&current, &own)?;
+
+    state
+        .db
+        .update_member_role(&room.id, &user_id, &role)
+        .await
+        .map_err(internal_error)?;
+    let user = state
+        .db
+        .get_user_by_id(&user_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Member not found"))?;
+    super::announce(
+        &state,
+        &room.name,
+        format!("{} is now {role} of {}", user.username, room.name),
+    );
+    audit::record(
+        &state,
+        audit::Event {
+            action: "room.member_role",
+            actor_id: Some(&auth.id),
+            target: Some(("user", &user_id)),
+            ip_address: client.ip_address.as_deref(),
+            details: serde_json::json!({
+                "room_id": room.id,
+                "previous_role": member.role,
+                "role": role,
+            }),
+        },
+    )
+    .await;
+
+    Ok(Json(MemberResponse {
+        user_id,
+        username: user.username,
+        role,
+        permissions,
+        joined_at: member.joined_at,
+    }))
+}
+
+async fn record_role_change(
+    state: &AppState,
+    auth: &AuthUser,
+    client: &ClientInfo,
+    action: &str,
+    role: &db::RoomRole,
+) {
+    audit::record(
+        state,
+        audit::Event {
+            action,
+            actor_id: Some(&auth.id),
+            target: Some(("room_role", &role.id)),
+            ip_address: client.ip_address.as_deref(),
+            details: serde_json::json!({
+                "room_id": role.room_id,
+                "name": role.name,
+                "permissions": parse_permissions(&role.permissions),
+            }),
+        },
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mail::MemoryMailer, testing};
+    use Permission::*;
+
+    async fn join(state: &AppState, room: &db::ChatRoom, user: &db::User, role: &str) -> AuthUser {
+        state
+            .db
+            .add_room_member(&db::RoomMember {
+                id: Uuid::new_v4().to_string(),
+                room_id: room.id.clone(),
+                user_id: user.id.clone(),
+                joined_at: Utc::now(),
+                role: role.to_string(),
+            })
+            .await
+            .unwrap();
+        AuthUser {
+            id: user.id.clone(),
+            session_id: Uuid::new_v4().to_string(),
+        }
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            ip_address: None,
+            user_agent: None,
+        }
+    }
+
+    #[test]
+    fn builtin_roles_have_fixed_permissions() {
+        assert_eq!(builtin_permissions("owner").unwrap(), Permission::ALL);
+        assert_eq!(builtin_permissions("admin").unwrap(), Permission::ALL);
+        assert_eq!(
+            builtin_permissions("moderator").unwrap(),
+            [Send, DeleteOthers, ManageMembers, MentionEveryone]
+        );
+        assert_eq!(builtin_permissions("member").unwrap(), [Send]);
+        assert!(builtin_permissions("viewer").unwrap().is_empty());
+        assert!(builtin_permissions("helper").is_none());
+        for role in BUILTIN_ROLES {
+            assert!(builtin_permissions(role).is_some(), "{role}");
+        }
+    }
+
+    #[test]
+    fn parses_stored_permissions() {
+        assert_eq!(
+            parse_permissions(r#"["send","manage_room","fly",3,"edit_others"]"#),
+            [Send, ManageRoom, EditOthers]
+        );
+        assert!(parse_permissions("not json").is_empty());
+        assert_eq!(
+            dedup(vec![ManageRoom, Send, ManageRoom]),
+            [Send, ManageRoom]
+        );
+    }
+
+    #[test]
+    fn finds_everyone_mentions() {
+        assert!(mentions_everyone("hey @everyone!"));
+        assert!(mentions_everyone("@here"));
+        assert!(!mentions_everyone("mail me@everyone.com"));
+        assert!(!mentions_everyone("@everyone_else"));
+    }
+
+    #[tokio::test]
+    async fn custom_roles_grant_their_permissions() {
+        let state = Arc::new(testing::state(Arc::new(MemoryMailer::default())).await);
+        let owner = testing::user(&state, "owner").await;
+        let helper = testing::user(&state, "helper").await;
+        let stranger = testing::user(&state, "stranger").await;
+        let room = testing::room(&state, "lobby", &owner).await;
+        let auth = AuthUser {
+            id: owner.id.clone(),
+            session_id: Uuid::new_v4().to_string(),
+        };
+        join(&state, &room, &helper, "member").await;
+
+        let Json(role) = create_role_handler(
+            State(state.clone()),
+            auth.clone(),
+            client(),
+            Path(room.id.clone()),
+            Json(CreateRoleRequest {
+                name: " Editor ".to_string(),
+                permissions: vec![EditOthers, Send, EditOthers],
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(role.name, "editor");
+        assert_eq!(role.permissions, [Send, EditOthers]);
+
+        let Json(response) = set_member_role_handler(
+            State(state.clone()),
+            auth.clone(),
+            client(),
+            Path((room.id.clone(), helper.id.clone())),
+            Json(SetMemberRoleRequest {
+                role: "editor".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.permissions, [Send, EditOthers]);
+        assert_eq!(
+            member_permissions(&state, &room.id, &helper.id)
+                .await
+                .unwrap(),
+            [Send, EditOthers]
+        );
+
+        // 新版本不认识的权限名在读取时被跳过
+        state
+            .db
+            .update_room_role_permissions(role.id.as_ref().unwrap(), r#"["send","pin"]"#)
+            .await
+            .unwrap();
+        assert_eq!(
+            member_permissions(&state, &room.id, &helper.id)
+                .await
+                .unwrap(),
+            [Send]
+        );
+
+        // 非成员没有任何权限
+        assert!(member_permissions(&state, &room.id, &stranger.id)
+            .await
+            .unwrap()
+            .is_empty());
+        let err = authorize(&state, &room.id, &stranger.id, Send)
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn moderators_cannot_grant_manage_room() {
+        let state = Arc::new(testing::state(Arc::new(MemoryMailer::default())).await);
+        let owner = testing::user(&state, "owner").await;
+        let moderator = testing::user(&state, "moderator").await;
+        let member = testing::user(&state, "member").await;
+        let room = testing::room(&state, "lobby", &owner).await;
+        let owner_auth = AuthUser {
+            id: owner.id.clone(),
+            session_id: Uuid::new_v4().to_string(),
+        };
+        let auth = join(&state, &room, &moderator, "moderator").await;
+        join(&state, &room, &member, "member").await;
+
+        let err = create_role_handler(
+            State(state.clone()),
+            auth.clone(),
+            client(),
+            Path(room.id.clone()),
+            Json(CreateRoleRequest {
+                name: "manager".to_string(),
+                permissions: vec![ManageRoom],
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::FORBIDDEN);
+        assert!(err.1.error.contains("manage_room"), "{}", err.1.error);
+
+        // 房主创建的角色超出了版主的权限，版主不能把它分配出去
+        let Json(manager) = create_role_handler(
+            State(state.clone()),
+            owner_auth,
+            client(),
+            Path(room.id.clone()),
+            Json(CreateRoleRequest {
+                name: "manager".to_string(),
+                permissions: vec![Send, ManageRoom],
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(manager.permissions, [Send, ManageRoom]);
+        for role in ["manager", "admin"] {
+            let err = set_member_role_handler(
+                State(state.clone()),
+                auth.clone(),
+                client(),
+                Path((room.id.clone(), member.id.clone())),
+                Json(SetMemberRoleRequest {
+                    role: role.to_string(),
+                }),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.0, StatusCode::FORBIDDEN, "{role}");
+        }
+        assert_eq!(
+            state
+                .db
+                .get_room_member(&room.id, &member.id)
+                .await
+                .unwrap()
+                .unwrap()
+                .role,
+            "member"
+        );
+
+        // 版主可以分配自己权限范围内的角色
+        let Json(response) = set_member_role_handler(
+            State(state.clone()),
+            auth,
+            client(),
+            Path((room.id.clone(), member.id.clone())),
+            Json(SetMemberRoleRequest {
+                role: "viewer".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.role, "viewer");
+        assert!(response.permissions.is_empty());
+    }
+}
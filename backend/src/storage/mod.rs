@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+pub mod s3;
+
+/// Where uploaded files are kept. Keys are `/`-separated paths chosen by
+/// the server, e.g. `files/<room id>/<file id>`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, content_type: &str, data: Vec<u8>) -> Result<()>;
+    /// `None` if nothing is stored under the key.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    /// Deleting a missing key is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Keeps files in a directory on the local disk.
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            anyhow::bail!("invalid storage key {key:?}");
+        }
+        Ok(self.dir.join(relative))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, _content_type: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&path, data)
+            .await
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(key)?;
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("failed to delete {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Picks a backend from `STORAGE_BACKEND` (`local` or `s3`). Defaults to
+/// files under `STORAGE_DIR` (default `uploads`).
+pub fn storage_from_env() -> Result<Arc<dyn Storage>> {
+    let backend = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".to_string());
+    match backend.as_str() {
+        "local" => {
+            let dir = std::env::var("STORAGE_DIR").unwrap_or_else(|_| "uploads".to_string());
+            Ok(Arc::new(LocalStorage::new(dir)))
+        }
+        "s3" => {
+            let var =
+                |name: &str| std::env::var(name).with_context(|| format!("{name} is required"));
+            Ok(Arc::new(s3::S3Storage::new(
+                &var("S3_ENDPOINT")?,
+                &var("S3_BUCKET")?,
+                &std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+                &var("S3_ACCESS_KEY_ID")?,
+                &var("S3_SECRET_ACCESS_KEY")?,
+            )?))
+        }
+        other => anyhow::bail!("unknown STORAGE_BACKEND {other}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_storage_stays_in_its_directory() {
+        let dir = std::env::temp_dir().join(format!("chatx-test-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::new(&dir);
+
+        storage
+            .put("files/room/file", "text/plain", b"hello".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get("files/room/file").await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+        storage.delete("files/room/file").await.unwrap();
+        assert_eq!(storage.get("files/room/file").await.unwrap(), None);
+
+        for key in ["../escape", "/etc/passwd", "files/../../escape"] {
+            assert!(
+                storage.put(key, "text/plain", Vec::new()).await.is_err(),
+                "{key}"
+            );
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+use super::Storage;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Keeps files in a bucket of an S3-compatible service (AWS S3, MinIO,
+/// ...), addressed path-style as `<endpoint>/<bucket>/<key>` and signed
+/// with AWS Signature Version 4.
+pub struct S3Storage {
+    client: reqwest::Client,
+    endpoint: Url,
+    bucket: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+impl S3Storage {
+    pub fn new(
+        endpoint: &str,
+        bucket: &str,
+        region: &str,
+        access_key_id: &str,
+        secret_access_key: &str,
+    ) -> Result<Self> {
+        let endpoint =
+            Url::parse(endpoint).with_context(|| format!("invalid S3 endpoint {endpoint}"))?;
+        if !matches!(endpoint.scheme(), "http" | "https") || endpoint.host_str().is_none() {
+            anyhow::bail!("S3 endpoint must be an http or https URL");
+        }
+
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            endpoint,
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+        })
+    }
+
+    fn object_url(&self, key: &str) -> Url {
+        let mut url = self.endpoint.clone();
+        let base = url.path().trim_end_matches('/').to_string();
+        let key: Vec<_> = key.split('/').map(uri_encode).collect();
+        url.set_path(&format!(
+            "{base}/{}/{}",
+            uri_encode(&self.bucket),
+            key.join("/")
+        ));
+        url
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        content_type: Option<&str>,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        let url = self.object_url(key);
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", now.format("%Y%m%d"), self.region);
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{method}\n{}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\n{signed_headers}\n{payload_hash}",
+            url.path(),
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key = format!("AWS4{}", self.secret_access_key).into_bytes();
+        for part in scope.split('/') {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                    self.access_key_id
+                ),
+            );
+        if let Some(content_type) = content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+        Ok(request.body(body).send().await?)
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, content_type: &str, data: Vec<u8>) -> Result<()> {
+        let response = self
+            .send(Method::PUT, key, Some(content_type), data)
+            .await?;
+        if !response.status().is_success() {
+            anyhow::bail!("S3 PUT {key} answered {}", response.status());
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.send(Method::GET, key, None, Vec::new()).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.bytes().await?.to_vec())),
+            status => anyhow::bail!("S3 GET {key} answered {status}"),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let response = self.send(Method::DELETE, key, None, Vec::new()).await?;
+        if !response.status().is_success() && response.status() != StatusCode::NOT_FOUND {
+            anyhow::bail!("S3 DELETE {key} answered {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters, as
+/// Signature Version 4 expects of each path segment.
+fn uri_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::{
+        body::Bytes,
+        extract::State,
+        http::{HeaderMap, Method as HttpMethod, StatusCode as HttpStatus, Uri},
+        Router,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    const KEY_ID: &str = "test-key";
+    const SECRET: &str = "test-secret";
+
+    type Objects = Arc<Mutex<HashMap<String, (String, Vec<u8>)>>>;
+
+    /// Recomputes a request's Signature Version 4 and checks it matches.
+    fn signed(method: &HttpMethod, uri: &Uri, headers: &HeaderMap, body: &[u8]) -> bool {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+        };
+        let payload_hash = hex::encode(Sha256::digest(body));
+        if header("x-amz-content-sha256") != payload_hash {
+            return false;
+        }
+        let amz_date = header("x-amz-date");
+        let scope = format!("{}/us-east-1/s3/aws4_request", &amz_date[..8]);
+        let canonical_request = format!(
+            "{method}\n{}\n\nhost:{}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}",
+            uri.path(),
+            header("host"),
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key = format!("AWS4{SECRET}").into_bytes();
+        for part in scope.split('/') {
+            key = hmac(&key, part.as_bytes());
+        }
+        let expected = format!(
+            "AWS4-HMAC-SHA256 Credential={KEY_ID}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+            hex::encode(hmac(&key, string_to_sign.as_bytes()))
+        );
+        header("authorization") == expected
+    }
+
+    /// Stands in for an S3 bucket, keeping objects in memory.
+    async fn s3(
+        State(objects): State<Objects>,
+        method: HttpMethod,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<(HttpStatus, Vec<u8>), HttpStatus> {
+        if !signed(&method, &uri, &headers, &body) {
+            return Err(HttpStatus::FORBIDDEN);
+        }
+        let mut objects = objects.lock().unwrap();
+        let path = uri.path().to_string();
+        match method {
+            HttpMethod::PUT => {
+                let content_type = headers[CONTENT_TYPE].to_str().unwrap().to_string();
+                objects.insert(path, (content_type, body.to_vec()));
+                Ok((HttpStatus::OK, Vec::new()))
+            }
+            HttpMethod::GET => objects
+                .get(&path)
+                .map(|(_, data)| (HttpStatus::OK, data.clone()))
+                .ok_or(HttpStatus::NOT_FOUND),
+            HttpMethod::DELETE => {
+                objects.remove(&path);
+                Ok((HttpStatus::NO_CONTENT, Vec::new()))
+            }
+            _ => Err(HttpStatus::METHOD_NOT_ALLOWED),
+        }
+    }
+
+    async fn bucket() -> (String, Objects) {
+        let objects = Objects::default();
+        let addr = testing::serve(Router::new().fallback(s3).with_state(objects.clone())).await;
+        (format!("http://{addr}/storage"), objects)
+    }
+
+    #[tokio::test]
+    async fn stores_and_deletes_objects() {
+        let (endpoint, objects) = bucket().await;
+        let storage = S3Storage::new(&endpoint, "chatx", "us-east-1", KEY_ID, SECRET).unwrap();
+        let key = "files/room/some file+ü.txt";
+
+        storage
+            .put(key, "text/plain", b"hello".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            objects.lock().unwrap()["/storage/chatx/files/room/some%20file%2B%C3%BC.txt"],
+            ("text/plain".to_string(), b"hello".to_vec())
+        );
+        assert_eq!(storage.get(key).await.unwrap(), Some(b"hello".to_vec()));
+
+        storage.delete(key).await.unwrap();
+        assert_eq!(storage.get(key).await.unwrap(), None);
+        storage.delete(key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_refused_requests() {
+        let (endpoint, objects) = bucket().await;
+        let storage = S3Storage::new(&endpoint, "chatx", "us-east-1", KEY_ID, "wrong").unwrap();
+
+        assert!(storage
+            .put("files/a", "text/plain", b"x".to_vec())
+            .await
+            .is_err());
+        assert!(storage.get("files/a").await.is_err());
+        assert!(objects.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn endpoint_must_be_http() {
+        assert!(S3Storage::new("ftp://s3.example.com", "b", "r", "k", "s").is_err());
+        assert!(S3Storage::new("not a url", "b", "r", "k", "s").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Helpers shared by the tests.
+
+use axum::Router;
+use chrono::Utc;
+use dashmap::DashMap;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use uuid::Uuid;
+
+use crate::{
+    auth::keys::JwtKeys,
+    commands, db,
+    db::Database,
+    files, filters,
+    mail::MemoryMailer,
+    rate_limit::{
+        flood::{FloodControl, MessageLimits},
+        Lockout, RateLimiter,
+    },
+    storage::LocalStorage,
+    unfurl, webhooks, AppState,
+};
+
+/// Serves `router` on a free local port, for standing in for the servers
+/// the backend talks to.
+pub async fn serve(router: Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    addr
+}
+
+/// A state with default settings, a fresh database and files in a temporary
+/// directory. Mail is kept in memory; pass the same mailer to read it.
+pub async fn state(mailer: Arc<MemoryMailer>) -> AppState {
+    let dir = std::env::temp_dir().join(format!("chatx-test-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let db = Database::new(&format!("sqlite:{}", dir.join("chatx.db").display()))
+        .await
+        .unwrap();
+    db.init().await.unwrap();
+
+    AppState {
+        channels: DashMap::new(),
+        db,
+        jwt_keys: JwtKeys::hmac("test".to_string(), "test-secret"),
+        mailer,
+        public_url: "http://chatx.test".to_string(),
+        require_email_verification: false,
+        session_sockets: DashMap::new(),
+        login_limiter: RateLimiter::new(5, 1.0 / 12.0),
+        login_lockout: Lockout::new(
+            5,
+            Duration::from_secs(30),
+            Duration::from_secs(15 * 60),
+            Duration::from_secs(60 * 60),
+        ),
+        flood: FloodControl::new(MessageLimits::from_env().unwrap()),
+        filters: filters::FilterChain::from_env().unwrap(),
+        storage: Arc::new(LocalStorage::new(dir.join("uploads"))),
+        uploads: files::UploadLimits::from_env().unwrap(),
+        oidc: None,
+        webhooks: webhooks::Dispatcher::from_env().unwrap(),
+        unfurler: unfurl::Unfurler::from_env().unwrap(),
+        commands: commands::CommandRegistry::with_builtins(),
+        started_at: std::time::Instant::now(),
+    }
+}
+
+/// Creates a user whose password is `secret123`.
+pub async fn user(state: &AppState, username: &str) -> db::User {
+    let user = db::User {
+        id: Uuid::new_v4().to_string(),
+        username: username.to_string(),
+        email: format!("{username}@example.com"),
+        password_hash: bcrypt::hash("secret123", 4).unwrap(),
+        created_at: Utc::now(),
+        last_seen: None,
+        status: "offline".to_string(),
+        display_name: None,
+        bio: None,
+        timezone: None,
+        avatar_url: None,
+        email_verified: true,
+        totp_secret: None,
+        totp_enabled: false,
+        totp_last_step: None,
+        is_bot: false,
+        bot_owner_id: None,
+        is_admin: false,
+        disabled_at: None,
+        avatar_hash: None,
+        avatar_type: None,
+    };
+    state.db.create_user(&user).await.unwrap();
+    user
+}
+
+/// Creates a public room owned by `owner`.
+pub async fn room(state: &AppState, name: &str, owner: &db::User) -> db::ChatRoom {
+    let room = db::ChatRoom {
+        id: Uuid::new_v4().to_string(),
+        name: name.to_string(),
+        room_type: "public".to_string(),
+        created_by: owner.id.clone(),
+        created_at: Utc::now(),
+        description: None,
+        slow_mode_seconds: 0,
+        read_only: false,
+        members_only: false,
+    };
+    state.db.create_chat_room(&room).await.unwrap();
+    state
+        .db
+        .add_room_member(&db::RoomMember {
+            id: Uuid::new_v4().to_string(),
+            room_id: room.id.clone(),
+            user_id: owner.id.clone(),
+            joined_at: Utc::now(),
+            role: "owner".to_string(),
+        })
+        .await
+        .unwrap();
+    room
+}
@@ -0,0 +1,525 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use futures_util::future::join_all;
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE, LOCATION, USER_AGENT},
+    Url,
+};
+use scraper::{Html, Selector};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+use crate::{db, filters::builtin::links, outbound, rooms, AppState, ChatMessage};
+
+/// Links previewed per message; later ones are ignored.
+const MAX_LINKS: usize = 3;
+/// Messages waiting for previews; more are dropped.
+const MAX_PENDING: usize = 1000;
+/// Messages unfurled at the same time.
+const WORKER_BATCH: usize = 4;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Limit on fetching one link, redirects included.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REDIRECTS: usize = 3;
+/// Only the start of a page is read; the metadata is in its head.
+const MAX_BODY_BYTES: usize = 512 * 1024;
+const MAX_URL_LEN: usize = 2048;
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 500;
+const MAX_SITE_NAME_CHARS: usize = 100;
+/// How long a fetched preview is reused for other messages.
+const CACHE_HOURS: i64 = 24;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const USER_AGENT_VALUE: &str = "Mozilla/5.0 (compatible; ChatX link preview)";
+
+/// Fetches previews of the links in sent messages in the background.
+pub struct Unfurler {
+    enabled: bool,
+    guard: outbound::Guard,
+    /// Ids of messages waiting for previews.
+    pending: Mutex<VecDeque<String>>,
+    wake: Notify,
+}
+
+impl Unfurler {
+    /// Reads `UNFURL_ENABLED` (default true) and `UNFURL_ALLOWED_NETWORKS`,
+    /// a comma-separated list of CIDR ranges.
+    pub fn from_env() -> Result<Self> {
+        let enabled = std::env::var("UNFURL_ENABLED")
+            .map(|value| matches!(value.as_str(), "1" | "true" | "yes"))
+            .unwrap_or(true);
+
+        Ok(Self {
+            enabled,
+            guard: outbound::Guard::from_env("UNFURL_ALLOWED_NETWORKS")?,
+            pending: Mutex::new(VecDeque::new()),
+            wake: Notify::new(),
+        })
+    }
+
+    /// Queues a stored message to have the previews of its links made, or
+    /// cleared if an edit removed them.
+    pub fn request(&self, message: &db::Message) {
+        if !self.enabled || (links(&message.content).is_empty() && message.link_previews.is_none())
+        {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING {
+            eprintln!("link preview queue is full; skipping {}", message.id);
+            return;
+        }
+        pending.push_back(message.id.clone());
+        drop(pending);
+        self.wake.notify_one();
+    }
+
+    /// Fetches a linked page, following redirects, and reads its preview.
+    /// Pages that are not HTML or say nothing about themselves have none.
+    async fn fetch(&self, link: &Url) -> Result<Option<db::LinkPreview>> {
+        let mut url = link.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let response = self
+                .guard
+                .client_for(&url, REQUEST_TIMEOUT)
+                .await?
+                .get(url.clone())
+                .header(USER_AGENT, USER_AGENT_VALUE)
+                .header(ACCEPT, "text/html,application/xhtml+xml")
+                .send()
+                .await?;
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|value| value.to_str().ok())
+                    .context("redirect without a location")?;
+                url = url.join(location)?;
+                continue;
+            }
+            if !response.status().is_success() {
+                anyhow::bail!("{url} answered {}", response.status());
+            }
+            let is_html = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_ascii_lowercase)
+                .is_some_and(|kind| {
+                    kind.starts_with("text/html") || kind.starts_with("application/xhtml+xml")
+                });
+            if !is_html {
+                return Ok(None);
+            }
+
+            let (body, _) = outbound::read_limited(response, MAX_BODY_BYTES).await?;
+            return Ok(parse(link, &url, &String::from_utf8_lossy(&body)));
+        }
+        anyhow::bail!("{link} redirects too often")
+    }
+}
+
+/// Reads the Open Graph and Twitter card tags of a page fetched from
+/// `url`, falling back to its `<title>` and description.
+fn parse(link: &Url, url: &Url, html: &str) -> Option<db::LinkPreview> {
+    let document = Html::parse_document(html);
+    let meta_selector = Selector::parse("meta").expect("valid selector");
+    let title_selector = Selector::parse("title").expect("valid selector");
+
+    let tags: Vec<(String, &str)> = document
+        .select(&meta_selector)
+        .filter_map(|meta| {
+            let meta = meta.value();
+            let key = meta.attr("property").or_else(|| meta.attr("name"))?;
+            Some((key.to_ascii_lowercase(), meta.attr("content")?))
+        })
+        .collect();
+    let tag = |keys: &[&str], max_chars: usize| {
+        keys.iter().find_map(|key| {
+            tags.iter()
+                .find(|(name, _)| name == key)
+                .and_then(|(_, content)| clean(content, max_chars))
+        })
+    };
+
+    let title = tag(&["og:title", "twitter:title"], MAX_TITLE_CHARS).or_else(|| {
+        document
+            .select(&title_selector)
+            .next()
+            .and_then(|title| clean(&title.text().collect::<String>(), MAX_TITLE_CHARS))
+    });
+    let description = tag(
+        &["og:description", "twitter:description", "description"],
+        MAX_DESCRIPTION_CHARS,
+    );
+    if title.is_none() && description.is_none() {
+        return None;
+    }
+    let image_url = tag(
+        &[
+            "og:image:secure_url",
+            "og:image",
+            "og:image:url",
+            "twitter:image",
+            "twitter:image:src",
+        ],
+        MAX_URL_LEN,
+    )
+    .and_then(|image| url.join(&image).ok())
+    .filter(|image| matches!(image.scheme(), "http" | "https"))
+    .map(String::from);
+
+    Some(db::LinkPreview {
+        url: link.to_string(),
+        title,
+        description,
+        image_url,
+        site_name: tag(&["og:site_name"], MAX_SITE_NAME_CHARS),
+    })
+}
+
+/// Collapses whitespace and shortens to `max_chars`; `None` if empty.
+fn clean(text: &str, max_chars: usize) -> Option<String> {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.chars().count() {
+        0 => None,
+        count if count > max_chars => Some(
+            text.chars()
+                .take(max_chars - 1)
+                .chain(std::iter::once('…'))
+                .collect(),
+        ),
+        _ => Some(text),
+    }
+}
+
+/// The preview of a link, from the cache or freshly fetched.
+async fn preview(state: &AppState, link: &Url) -> Result<Option<db::LinkPreview>> {
+    let cutoff = Utc::now() - chrono::Duration::hours(CACHE_HOURS);
+    if let Some(preview) = state.db.get_link_preview(link.as_str(), cutoff).await? {
+        return Ok(Some(preview));
+    }
+    let preview = tokio::time::timeout(FETCH_TIMEOUT, state.unfurler.fetch(link))
+        .await
+        .with_context(|| format!("{link} timed out"))??;
+    if let Some(preview) = &preview {
+        state.db.save_link_preview(preview).await?;
+    }
+    Ok(preview)
+}
+
+/// Makes the previews of a message's links and, if they changed, stores
+/// them and sends an `updated` event to its channel.
+async fn unfurl_message(state: &AppState, message_id: &str) -> Result<()> {
+    let Some(message) = state
+        .db
+        .get_message(message_id)
+        .await?
+        .filter(|message| message.deleted_at.is_none())
+    else {
+        return Ok(());
+    };
+
+    let mut previews = Vec::new();
+    let content_links = links(&message.content);
+    for link in content_links
+        .iter()
+        .filter(|link| link.as_str().len() <= MAX_URL_LEN)
+        .take(MAX_LINKS)
+    {
+        match preview(state, link).await {
+            Ok(Some(preview)) => previews.push(preview),
+            Ok(None) => {}
+            Err(err) => eprintln!("failed to preview {link}: {err:#}"),
+        }
+    }
+    let old = message
+        .link_previews
+        .as_ref()
+        .map(|previews| previews.0.as_slice())
+        .unwrap_or_default();
+    // 消息在抓取期间被编辑过时放弃，编辑会重新排队
+    if previews == old
+        || !state
+            .db
+            .set_message_previews(&message.id, &message.content, &previews)
+            .await?
+    {
+        return Ok(());
+    }
+
+    let Some(room) = state.db.get_chat_room(&message.room_id).await? else {
+        return Ok(());
+    };
+    let (username, is_bot) = rooms::message_sender(state, &message)
+        .await
+        .map_err(|_| anyhow::anyhow!("sender of {} not found", message.id))?;
+    rooms::broadcast(
+        state,
+        &room.name,
+        &ChatMessage {
+            username,
+            message: message.content.clone(),
+            channel: room.name.clone(),
+            message_type: Some("updated".to_string()),
+            is_bot,
+            id: Some(message.id.clone()),
+            is_integration: message.integration_id.is_some(),
+            attachments: Vec::new(),
+            members: Vec::new(),
+            previews,
+        },
+    );
+    Ok(())
+}
+
+/// Starts the background task that makes link previews. The queue is kept
+/// in memory, so messages still waiting at shutdown go without.
+pub fn spawn_worker(state: Arc<AppState>) {
+    if !state.unfurler.enabled {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            let cutoff = Utc::now() - chrono::Duration::hours(CACHE_HOURS);
+            if let Err(err) = state.db.prune_link_previews(cutoff).await {
+                eprintln!("failed to prune link previews: {err:#}");
+            }
+
+            loop {
+                let batch: Vec<String> = {
+                    let mut pending = state.unfurler.pending.lock().unwrap();
+                    let count = pending.len().min(WORKER_BATCH);
+                    pending.drain(..count).collect()
+                };
+                if batch.is_empty() {
+                    break;
+                }
+                let results = join_all(
+                    batch
+                        .iter()
+                        .map(|message_id| unfurl_message(&state, message_id)),
+                )
+                .await;
+                for (message_id, result) in batch.iter().zip(results) {
+                    if let Err(err) = result {
+                        eprintln!("failed to unfurl message {message_id}: {err:#}");
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = state.unfurler.wake.notified() => {}
+                _ = tokio::time::sleep(PRUNE_INTERVAL) => {}
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::{
+        http::header,
+        response::{IntoResponse, Redirect},
+        routing::get,
+        Router,
+    };
+
+    const PAGE: &str = r#"<!doctype html>
+<html><head>
+  <title>Fallback title</title>
+  <meta property="og:title" content="  The   Title ">
+  <meta property="og:description" content="What it is about">
+  <meta property="og:image" content="/images/cover.png">
+  <meta property="og:site_name" content="Example">
+</head><body>Hello</body></html>"#;
+
+    fn html(body: String) -> impl IntoResponse {
+        ([(header::CONTENT_TYPE, "text/html; charset=utf-8")], body)
+    }
+
+    async fn fixtures() -> Url {
+        let addr = testing::serve(
+            Router::new()
+                .route("/page", get(|| async { html(PAGE.to_string()) }))
+                .route(
+                    "/title-only",
+                    get(|| async { html("<title>Just a title</title>".to_string()) }),
+                )
+                .route(
+                    "/blank",
+                    get(|| async { html("<p>nothing</p>".to_string()) }),
+                )
+                .route(
+                    "/large",
+                    get(|| async {
+                        html(format!(
+                            "<title>Large</title>{}",
+                            "x".repeat(2 * MAX_BODY_BYTES)
+                        ))
+                    }),
+                )
+                .route(
+                    "/image",
+                    get(|| async { ([(header::CONTENT_TYPE, "image/png")], vec![0u8; 16]) }),
+                )
+                .route("/moved", get(|| async { Redirect::to("/page") }))
+                .route("/loop", get(|| async { Redirect::to("/loop") }))
+                .route(
+                    "/to-private",
+                    get(|| async { Redirect::to("http://10.0.0.1/admin") }),
+                ),
+        )
+        .await;
+        Url::parse(&format!("http://{addr}/")).unwrap()
+    }
+
+    fn unfurler(allowed: &[&str]) -> Unfurler {
+        Unfurler {
+            enabled: true,
+            guard: outbound::Guard::new(allowed.iter().map(|net| net.parse().unwrap()).collect()),
+            pending: Mutex::new(VecDeque::new()),
+            wake: Notify::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_open_graph_tags() {
+        let base = fixtures().await;
+        let link = base.join("page").unwrap();
+
+        let preview = unfurler(&["127.0.0.1/32"])
+            .fetch(&link)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            preview,
+            db::LinkPreview {
+                url: link.to_string(),
+                title: Some("The Title".to_string()),
+                description: Some("What it is about".to_string()),
+                image_url: Some(base.join("images/cover.png").unwrap().to_string()),
+                site_name: Some("Example".to_string()),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn follows_redirects_but_keeps_the_link() {
+        let base = fixtures().await;
+        let link = base.join("moved").unwrap();
+
+        let preview = unfurler(&["127.0.0.1/32"])
+            .fetch(&link)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(preview.url, link.to_string());
+        assert_eq!(preview.title.as_deref(), Some("The Title"));
+    }
+
+    #[tokio::test]
+    async fn skips_pages_without_metadata() {
+        let base = fixtures().await;
+        let unfurler = unfurler(&["127.0.0.1/32"]);
+
+        let title_only = unfurler
+            .fetch(&base.join("title-only").unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(title_only.title.as_deref(), Some("Just a title"));
+        assert_eq!(title_only.description, None);
+
+        assert_eq!(
+            unfurler.fetch(&base.join("blank").unwrap()).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            unfurler.fetch(&base.join("image").unwrap()).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_only_the_start_of_large_pages() {
+        let base = fixtures().await;
+
+        let preview = unfurler(&["127.0.0.1/32"])
+            .fetch(&base.join("large").unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(preview.title.as_deref(), Some("Large"));
+    }
+
+    #[tokio::test]
+    async fn refuses_private_addresses() {
+        let base = fixtures().await;
+
+        assert!(unfurler(&[])
+            .fetch(&base.join("page").unwrap())
+            .await
+            .is_err());
+        let err = unfurler(&["127.0.0.1/32"])
+            .fetch(&base.join("to-private").unwrap())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("10.0.0.1 is a blocked address"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_redirect_loops_and_errors() {
+        let base = fixtures().await;
+        let unfurler = unfurler(&["127.0.0.1/32"]);
+
+        let err = unfurler
+            .fetch(&base.join("loop").unwrap())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("redirects too often"), "{err}");
+        assert!(unfurler
+            .fetch(&base.join("missing").unwrap())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn parse_falls_back_to_twitter_and_plain_tags() {
+        let url = Url::parse("https://example.com/post").unwrap();
+        let preview = parse(
+            &url,
+            &url,
+            r#"<meta name="twitter:title" content="Tweet title">
+               <meta name="description" content="Plain description">
+               <meta name="twitter:image" content="javascript:alert(1)">"#,
+        )
+        .unwrap();
+
+        assert_eq!(preview.title.as_deref(), Some("Tweet title"));
+        assert_eq!(preview.description.as_deref(), Some("Plain description"));
+        assert_eq!(preview.image_url, None);
+        assert_eq!(preview.site_name, None);
+    }
+
+    #[test]
+    fn clean_collapses_whitespace_and_shortens() {
+        assert_eq!(clean("  a \n\t b  ", 10).as_deref(), Some("a b"));
+        assert_eq!(clean(" \n ", 10), None);
+        assert_eq!(clean("abcdef", 4).as_deref(), Some("abc…"));
+        assert_eq!(clean("äöüß", 4).as_deref(), Some("äöüß"));
+    }
+}
@@ -0,0 +1,320 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::managed_room;
+use crate::{
+    api_error,
+    auth::{generate_secret, hash_secret, Caller},
+    db, internal_error,
+    rooms::{
+        self,
+        permissions::{self, Permission},
+    },
+    ApiError, AppState, StatusResponse,
+};
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_INCOMING_WEBHOOKS_PER_ROOM: usize = 20;
+const MAX_ATTACHMENTS: usize = 10;
+/// How stale a webhook's `last_used_at` may get before a post refreshes it.
+const TOUCH_INTERVAL_MINUTES: i64 = 1;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateIncomingWebhookRequest {
+    name: String,
+}
+
+/// What an integration posts to its webhook URL.
+#[derive(Debug, Deserialize)]
+pub struct IncomingMessage {
+    #[serde(default)]
+    text: String,
+    /// Shown instead of the webhook's name.
+    username: Option<String>,
+    #[serde(default)]
+    attachments: Vec<db::Attachment>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IncomingWebhookResponse {
+    id: String,
+    room_id: String,
+    name: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    /// The URL to post to; it contains the token, so it is only returned
+    /// on creation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+impl IncomingWebhookResponse {
+    fn from_row(webhook: db::IncomingWebhook) -> Self {
+        Self {
+            id: webhook.id,
+            room_id: webhook.room_id,
+            name: webhook.name,
+            created_at: webhook.created_at,
+            last_used_at: webhook.last_used_at,
+            url: None,
+        }
+    }
+}
+
+// 列出房间的 incoming webhook
+pub async fn list_incoming_webhooks_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path(room_id): Path<String>,
+) -> Result<Json<Vec<IncomingWebhookResponse>>, ApiError> {
+    let room = managed_room(&state, &caller, &room_id).await?;
+    let webhooks = state
+        .db
+        .get_room_incoming_webhooks(&room.id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(
+        webhooks
+            .into_iter()
+            .map(IncomingWebhookResponse::from_row)
+            .collect(),
+    ))
+}
+
+// 创建 incoming webhook
+pub async fn create_incoming_webhook_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path(room_id): Path<String>,
+    Json(req): Json<CreateIncomingWebhookRequest>,
+) -> Result<Json<IncomingWebhookResponse>, ApiError> {
+    let room = managed_room(&state, &caller, &room_id).await?;
+
+    let name = req.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Name must be 1 to {MAX_NAME_LEN} characters"),
+        ));
+    }
+    if state
+        .db
+        .get_room_incoming_webhooks(&room.id)
+        .await
+        .map_err(internal_error)?
+        .len()
+        >= MAX_INCOMING_WEBHOOKS_PER_ROOM
+    {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("A room can have at most {MAX_INCOMING_WEBHOOKS_PER_ROOM} incoming webhooks"),
+        ));
+    }
+
+    let token = generate_secret();
+    let webhook = db::IncomingWebhook {
+        id: Uuid::new_v4().to_string(),
+        room_id: room.id,
+        name: name.to_string(),
+        token_hash: hash_secret(&token),
+        created_by: caller.acting_user_id().to_string(),
+        created_at: Utc::now(),
+        last_used_at: None,
+        revoked_at: None,
+    };
+    state
+        .db
+        .create_incoming_webhook(&webhook)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(IncomingWebhookResponse {
+        url: Some(format!("{}/api/hooks/{token}", state.public_url)),
+        ..IncomingWebhookResponse::from_row(webhook)
+    }))
+}
+
+// 吊销 incoming webhook
+pub async fn revoke_incoming_webhook_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path((room_id, webhook_id)): Path<(String, String)>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let room = managed_room(&state, &caller, &room_id).await?;
+    if !state
+        .db
+        .revoke_incoming_webhook(&room.id, &webhook_id)
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(api_error(StatusCode::NOT_FOUND, "Webhook not found"));
+    }
+
+    Ok(Json(StatusResponse::new("Webhook revoked")))
+}
+
+// 集成通过 webhook URL 发消息，token 即凭证
+pub async fn post_handler(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+    Json(req): Json<IncomingMessage>,
+) -> Result<Response, ApiError> {
+    let not_found = || api_error(StatusCode::NOT_FOUND, "Webhook not found");
+    let webhook = state
+        .db
+        .get_incoming_webhook_by_hash(&hash_secret(&token))
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+    let room = state
+        .db
+        .get_chat_room(&webhook.room_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(not_found)?;
+
+    let text = req.text.trim();
+    let max_len = state.flood.limits().max_message_bytes;
+    if text.is_empty() && req.attachments.is_empty() {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "A message needs text or attachments",
+        ));
+    }
+    if text.len() > max_len {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Message is too long (max {max_len} bytes)"),
+        ));
+    }
+    let username = match req.username.as_deref().map(str::trim) {
+        Some(name) if name.chars().count() > MAX_NAME_LEN => {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                format!("Username must be at most {MAX_NAME_LEN} characters"),
+            ));
+        }
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => webhook.name.clone(),
+    };
+    validate_attachments(&req.attachments, max_len)?;
+
+    // 消息以创建者的名义发出，创建者须仍能管理并在房间发言
+    let sender_key = format!("hook:{}", webhook.id);
+    let creator_can_manage = match state
+        .db
+        .get_user_by_id(&webhook.created_by)
+        .await
+        .map_err(internal_error)?
+    {
+        Some(creator) if creator.disabled_at.is_none() => {
+            permissions::member_permissions(&state, &room.id, &creator.id)
+                .await
+                .map_err(internal_error)?
+                .contains(&Permission::ManageRoom)
+        }
+        _ => false,
+    };
+    if !creator_can_manage {
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            "The webhook's creator can no longer manage this room",
+        ));
+    }
+    if let Some(refusal) =
+        rooms::send_refusal(&state, &room, Some(&webhook.created_by), &sender_key, text)
+            .await
+            .map_err(internal_error)?
+    {
+        return Err(api_error(StatusCode::FORBIDDEN, refusal));
+    }
+
+    // 与 WebSocket 消息共用限流，按 webhook 计
+    state
+        .flood
+        .check_webhook(&webhook.id, text.len())
+        .map_err(|reason| api_error(StatusCode::TOO_MANY_REQUESTS, reason))?;
+
+    if webhook
+        .last_used_at
+        .is_none_or(|last_used| Utc::now() - last_used > Duration::minutes(TOUCH_INTERVAL_MINUTES))
+    {
+        state
+            .db
+            .touch_incoming_webhook(&webhook.id)
+            .await
+            .map_err(internal_error)?;
+    }
+
+    let new = rooms::NewMessage {
+        sender_id: Some(webhook.created_by.clone()),
+        username,
+        content: text.to_string(),
+        message_type: None,
+        is_bot: false,
+        integration_id: Some(webhook.id.clone()),
+        attachments: req.attachments,
+    };
+    match rooms::post_message(&state, &room.name, Some(&room), new, &sender_key)
+        .await
+        .map_err(internal_error)?
+    {
+        rooms::Posted::Sent(message) => {
+            let message =
+                message.ok_or_else(|| internal_error(anyhow::anyhow!("message was not stored")))?;
+            Ok(Json(*message).into_response())
+        }
+        // 被过滤器扣下，等待审核
+        rooms::Posted::Held(reason) => Ok((
+            StatusCode::ACCEPTED,
+            Json(StatusResponse::new(format!(
+                "Message is held for review: {reason}"
+            ))),
+        )
+            .into_response()),
+        rooms::Posted::Rejected(reason) => Err(api_error(StatusCode::BAD_REQUEST, reason)),
+    }
+}
+
+fn validate_attachments(attachments: &[db::Attachment], max_len: usize) -> Result<(), ApiError> {
+    if attachments.len() > MAX_ATTACHMENTS {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("A message can have at most {MAX_ATTACHMENTS} attachments"),
+        ));
+    }
+    for attachment in attachments {
+        if attachment.file.is_some() {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                "Upload files to /api/rooms/:room_id/files instead",
+            ));
+        }
+        let texts = [&attachment.title, &attachment.text];
+        if texts.into_iter().flatten().any(|text| text.len() > max_len) {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                format!("Attachment text is too long (max {max_len} bytes)"),
+            ));
+        }
+        let links = [&attachment.url, &attachment.image_url];
+        if links.into_iter().flatten().any(|link| {
+            !reqwest::Url::parse(link).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+        }) {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                "Attachment links must be http or https URLs",
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,693 @@
+use axum::{
+    extract::{Path, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+pub mod incoming;
+
+use crate::{
+    api_error,
+    auth::{generate_secret, Caller},
+    bots::Scope,
+    db, internal_error, outbound,
+    rooms::permissions::{self, Permission},
+    ApiError, AppState, StatusResponse,
+};
+
+/// Give up on a delivery after this many attempts.
+const MAX_ATTEMPTS: i64 = 8;
+/// The first retry waits this long; each further one waits twice as long.
+const RETRY_BASE_SECONDS: i64 = 10;
+const RETRY_MAX_SECONDS: i64 = 60 * 60;
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// How often the queue is checked for retries that have come due.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+const DELIVERY_BATCH: i64 = 20;
+const DELIVERY_LOG_LIMIT: i64 = 50;
+const MAX_WEBHOOKS_PER_ROOM: usize = 20;
+
+/// Room events a webhook can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WebhookEvent {
+    #[serde(rename = "message.created")]
+    MessageCreated,
+    #[serde(rename = "message.edited")]
+    MessageEdited,
+    #[serde(rename = "message.deleted")]
+    MessageDeleted,
+    #[serde(rename = "member.joined")]
+    MemberJoined,
+    #[serde(rename = "member.left")]
+    MemberLeft,
+}
+
+impl WebhookEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::MessageCreated => "message.created",
+            Self::MessageEdited => "message.edited",
+            Self::MessageDeleted => "message.deleted",
+            Self::MemberJoined => "member.joined",
+            Self::MemberLeft => "member.left",
+        }
+    }
+}
+
+/// Sends queued webhook deliveries; the worker is woken whenever new ones
+/// are queued.
+pub struct Dispatcher {
+    /// Keeps webhooks and command callbacks off private networks.
+    guard: outbound::Guard,
+    wake: Notify,
+}
+
+impl Dispatcher {
+    pub fn new(guard: outbound::Guard) -> Self {
+        Self {
+            guard,
+            wake: Notify::new(),
+        }
+    }
+
+    /// Reads `WEBHOOK_ALLOWED_NETWORKS`, the comma-separated CIDR ranges
+    /// integrations may be reached at despite being private.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self::new(outbound::Guard::from_env(
+            "WEBHOOK_ALLOWED_NETWORKS",
+        )?))
+    }
+
+    /// Checks the URL of a webhook or command callback being registered.
+    pub async fn check_url(&self, url: &str) -> Result<String, ApiError> {
+        self.guard
+            .check(url)
+            .await
+            .map(String::from)
+            .map_err(|err| api_error(StatusCode::BAD_REQUEST, format!("Invalid URL: {err}")))
+    }
+
+    /// POSTs a JSON `body` signed with `secret`, the way every request to
+    /// an integration is sent. Fails if the URL leads to a blocked address.
+    pub async fn send_signed(
+        &self,
+        url: &str,
+        secret: &str,
+        event: &str,
+        delivery_id: &str,
+        body: String,
+    ) -> anyhow::Result<reqwest::Response> {
+        let url = reqwest::Url::parse(url)?;
+        let client = self.guard.client_for(&url, REQUEST_TIMEOUT).await?;
+        let timestamp = Utc::now().timestamp();
+        Ok(client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Chatx-Event", event)
+            .header("X-Chatx-Delivery", delivery_id)
+            .header("X-Chatx-Timestamp", timestamp)
+            .header(
+                "X-Chatx-Signature",
+                format!("sha256={}", sign(secret, timestamp, &body)),
+            )
+            .body(body)
+            .send()
+            .await?)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhookRequest {
+    url: String,
+    events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookResponse {
+    id: String,
+    room_id: String,
+    url: String,
+    events: Vec<WebhookEvent>,
+    created_at: DateTime<Utc>,
+    /// Key for verifying `X-Chatx-Signature`; only returned on creation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+impl WebhookResponse {
+    fn from_row(webhook: db::Webhook) -> Self {
+        Self {
+            events: parse_events(&webhook.events),
+            id: webhook.id,
+            room_id: webhook.room_id,
+            url: webhook.url,
+            created_at: webhook.created_at,
+            secret: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeliveryResponse {
+    id: String,
+    event: String,
+    status: String,
+    attempts: i64,
+    next_attempt_at: Option<DateTime<Utc>>,
+    response_status: Option<i64>,
+    last_error: Option<String>,
+    created_at: DateTime<Utc>,
+    delivered_at: Option<DateTime<Utc>>,
+    payload: Value,
+}
+
+impl DeliveryResponse {
+    fn from_row(delivery: db::WebhookDelivery) -> Self {
+        Self {
+            payload: serde_json::from_str(&delivery.payload).unwrap_or(Value::Null),
+            id: delivery.id,
+            event: delivery.event,
+            status: delivery.status,
+            attempts: delivery.attempts,
+            next_attempt_at: delivery.next_attempt_at,
+            response_status: delivery.response_status,
+            last_error: delivery.last_error,
+            created_at: delivery.created_at,
+            delivered_at: delivery.delivered_at,
+        }
+    }
+}
+
+fn parse_events(events: &str) -> Vec<WebhookEvent> {
+    serde_json::from_str(events).unwrap_or_default()
+}
+
+/// The `data` of a `message.*` event.
+pub fn message_data(message: &db::Message, username: &str, is_bot: bool) -> Value {
+    json!({
+        "id": message.id,
+        "sender_id": message.sender_id,
+        "username": username,
+        "is_bot": is_bot,
+        "integration_id": message.integration_id,
+        "content": message.content,
+        "attachments": message.attachments,
+        "created_at": message.created_at,
+        "edited_at": message.edited_at,
+    })
+}
+
+/// Queues a delivery of `event` to every webhook of the room subscribed to
+/// it. Failures are logged rather than returned, so they never hold up the
+/// chat itself.
+pub async fn publish(state: &AppState, room: &db::ChatRoom, event: WebhookEvent, data: Value) {
+    if let Err(err) = enqueue(state, room, event, data).await {
+        eprintln!("failed to queue webhook deliveries: {err:#}");
+    }
+}
+
+async fn enqueue(
+    state: &AppState,
+    room: &db::ChatRoom,
+    event: WebhookEvent,
+    data: Value,
+) -> anyhow::Result<()> {
+    let webhooks: Vec<db::Webhook> = state
+        .db
+        .get_room_webhooks(&room.id)
+        .await?
+        .into_iter()
+        .filter(|webhook| parse_events(&webhook.events).contains(&event))
+        .collect();
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let payload = serde_json::to_string(&json!({
+        "event": event,
+        "room": { "id": room.id, "name": room.name },
+        "created_at": Utc::now(),
+        "data": data,
+    }))?;
+    for webhook in webhooks {
+        queue_delivery(state, &webhook.id, event.as_str(), &payload).await?;
+    }
+    state.webhooks.wake.notify_one();
+    Ok(())
+}
+
+async fn queue_delivery(
+    state: &AppState,
+    webhook_id: &str,
+    event: &str,
+    payload: &str,
+) -> anyhow::Result<db::WebhookDelivery> {
+    let now = Utc::now();
+    let delivery = db::WebhookDelivery {
+        id: Uuid::new_v4().to_string(),
+        webhook_id: webhook_id.to_string(),
+        event: event.to_string(),
+        payload: payload.to_string(),
+        status: "pending".to_string(),
+        attempts: 0,
+        next_attempt_at: Some(now),
+        response_status: None,
+        last_error: None,
+        created_at: now,
+        delivered_at: None,
+    };
+    state.db.create_webhook_delivery(&delivery).await?;
+    Ok(delivery)
+}
+
+/// Starts the background task that drains the delivery queue. Deliveries
+/// live in the database, so anything still pending is picked up again
+/// after a restart.
+pub fn spawn_worker(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        loop {
+            match state.db.get_due_webhook_deliveries(DELIVERY_BATCH).await {
+                Ok(due) => {
+                    let full = due.len() as i64 == DELIVERY_BATCH;
+                    join_all(due.into_iter().map(|delivery| deliver(&state, delivery))).await;
+                    if full {
+                        continue;
+                    }
+                }
+                Err(err) => eprintln!("failed to load webhook deliveries: {err:#}"),
+            }
+
+            tokio::select! {
+                _ = state.webhooks.wake.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    });
+}
+
+/// Makes one attempt at a delivery and schedules a retry if it fails.
+async fn deliver(state: &AppState, mut delivery: db::WebhookDelivery) {
+    let webhook = match state.db.get_webhook(&delivery.webhook_id).await {
+        Ok(Some(webhook)) => webhook,
+        Ok(None) => return,
+        Err(err) => {
+            eprintln!("failed to load webhook: {err:#}");
+            return;
+        }
+    };
+
+    let result = state
+        .webhooks
+        .send_signed(
+            &webhook.url,
+            &webhook.secret,
+            &delivery.event,
+            &delivery.id,
+            delivery.payload.clone(),
+        )
+        .await;
+
+    delivery.attempts += 1;
+    let error = match result {
+        Ok(response) => {
+            delivery.response_status = Some(response.status().as_u16() as i64);
+            if response.status().is_success() {
+                None
+            } else {
+                Some(format!("Receiver answered {}", response.status()))
+            }
+        }
+        Err(err) => {
+            delivery.response_status = None;
+            Some(format!("{err:#}"))
+        }
+    };
+
+    match error {
+        None => {
+            delivery.status = "delivered".to_string();
+            delivery.delivered_at = Some(Utc::now());
+            delivery.next_attempt_at = None;
+            delivery.last_error = None;
+        }
+        Some(error) => {
+            delivery.last_error = Some(error);
+            if delivery.attempts >= MAX_ATTEMPTS {
+                delivery.status = "failed".to_string();
+                delivery.next_attempt_at = None;
+            } else {
+                delivery.next_attempt_at = Some(Utc::now() + retry_delay(delivery.attempts));
+            }
+        }
+    }
+
+    if let Err(err) = state.db.record_webhook_attempt(&delivery).await {
+        eprintln!("failed to record webhook delivery: {err:#}");
+    }
+}
+
+fn retry_delay(attempts: i64) -> Duration {
+    let doublings = (attempts - 1).clamp(0, 16) as u32;
+    Duration::seconds((RETRY_BASE_SECONDS << doublings).min(RETRY_MAX_SECONDS))
+}
+
+/// Hex HMAC-SHA256 of `"<timestamp>.<body>"`, sent as `X-Chatx-Signature`.
+fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// 列出房间的 webhook
+pub async fn list_webhooks_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path(room_id): Path<String>,
+) -> Result<Json<Vec<WebhookResponse>>, ApiError> {
+    let room = managed_room(&state, &caller, &room_id).await?;
+    let webhooks = state
+        .db
+        .get_room_webhooks(&room.id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(
+        webhooks
+            .into_iter()
+            .map(WebhookResponse::from_row)
+            .collect(),
+    ))
+}
+
+// 注册 webhook
+pub async fn create_webhook_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path(room_id): Path<String>,
+    Json(req): Json<CreateWebhookRequest>,
+) -> Result<Json<WebhookResponse>, ApiError> {
+    let room = managed_room(&state, &caller, &room_id).await?;
+
+    let url = state.webhooks.check_url(req.url.trim()).await?;
+    if req.events.is_empty() {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "At least one event is required",
+        ));
+    }
+    if state
+        .db
+        .get_room_webhooks(&room.id)
+        .await
+        .map_err(internal_error)?
+        .len()
+        >= MAX_WEBHOOKS_PER_ROOM
+    {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("A room can have at most {MAX_WEBHOOKS_PER_ROOM} webhooks"),
+        ));
+    }
+
+    let mut events: Vec<WebhookEvent> = Vec::new();
+    for event in req.events {
+        if !events.contains(&event) {
+            events.push(event);
+        }
+    }
+    let webhook = db::Webhook {
+        id: Uuid::new_v4().to_string(),
+        room_id: room.id,
+        url,
+        secret: generate_secret(),
+        events: serde_json::to_string(&events).map_err(|err| internal_error(err.into()))?,
+        created_by: caller.acting_user_id().to_string(),
+        created_at: Utc::now(),
+    };
+    state
+        .db
+        .create_webhook(&webhook)
+        .await
+        .map_err(internal_error)?;
+
+    let secret = webhook.secret.clone();
+    Ok(Json(WebhookResponse {
+        secret: Some(secret),
+        ..WebhookResponse::from_row(webhook)
+    }))
+}
+
+// 删除 webhook
+pub async fn delete_webhook_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path((room_id, webhook_id)): Path<(String, String)>,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let room = managed_room(&state, &caller, &room_id).await?;
+    let webhook = room_webhook(&state, &room, &webhook_id).await?;
+    state
+        .db
+        .delete_webhook(&webhook.id)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(StatusResponse::new("Webhook deleted")))
+}
+
+// 查看投递记录
+pub async fn list_deliveries_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path((room_id, webhook_id)): Path<(String, String)>,
+) -> Result<Json<Vec<DeliveryResponse>>, ApiError> {
+    let room = managed_room(&state, &caller, &room_id).await?;
+    let webhook = room_webhook(&state, &room, &webhook_id).await?;
+    let deliveries = state
+        .db
+        .get_webhook_deliveries(&webhook.id, DELIVERY_LOG_LIMIT)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(
+        deliveries
+            .into_iter()
+            .map(DeliveryResponse::from_row)
+            .collect(),
+    ))
+}
+
+// 重新投递
+pub async fn redeliver_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path((room_id, webhook_id, delivery_id)): Path<(String, String, String)>,
+) -> Result<Json<DeliveryResponse>, ApiError> {
+    let room = managed_room(&state, &caller, &room_id).await?;
+    let webhook = room_webhook(&state, &room, &webhook_id).await?;
+    let original = state
+        .db
+        .get_webhook_delivery(&delivery_id)
+        .await
+        .map_err(internal_error)?
+        .filter(|delivery| delivery.webhook_id == webhook.id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Delivery not found"))?;
+
+    let delivery = queue_delivery(&state, &webhook.id, &original.event, &original.payload)
+        .await
+        .map_err(internal_error)?;
+    state.webhooks.wake.notify_one();
+
+    Ok(Json(DeliveryResponse::from_row(delivery)))
+}
+
+/// Loads a room the caller may manage. Bots need the `webhooks:manage`
+/// scope and act with their owner's role in the room.
+async fn managed_room(
+    state: &AppState,
+    caller: &Caller,
+    room_id: &str,
+) -> Result<db::ChatRoom, ApiError> {
+    caller.require(&Scope::ManageWebhooks)?;
+    let room = state
+        .db
+        .get_chat_room(room_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Room not found"))?;
+
+    permissions::authorize(
+        state,
+        &room.id,
+        caller.acting_user_id(),
+        Permission::ManageRoom,
+    )
+    .await?;
+    Ok(room)
+}
+
+async fn room_webhook(
+    state: &AppState,
+    room: &db::ChatRoom,
+    webhook_id: &str,
+) -> Result<db::Webhook, ApiError> {
+    state
+        .db
+        .get_webhook(webhook_id)
+        .await
+        .map_err(internal_error)?
+        .filter(|webhook| webhook.room_id == room.id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Webhook not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mail::MemoryMailer, testing};
+    use axum::{http::HeaderMap, routing::post, Router};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        assert_eq!(
+            sign("topsecret", 1_700_000_000, r#"{"event":"ping"}"#),
+            "49dbf5542544194f41a09374d23028d79a5a64b07761bf746c46154c7c5f264a"
+        );
+    }
+
+    #[test]
+    fn retries_back_off() {
+        assert_eq!(retry_delay(1), Duration::seconds(RETRY_BASE_SECONDS));
+        assert_eq!(retry_delay(2), Duration::seconds(RETRY_BASE_SECONDS * 2));
+        assert_eq!(retry_delay(40), Duration::seconds(RETRY_MAX_SECONDS));
+    }
+
+    /// A receiver that passes on the headers and body of each request.
+    async fn receiver() -> (String, mpsc::UnboundedReceiver<(HeaderMap, String)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let addr = testing::serve(Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: String| async move {
+                tx.send((headers, body)).unwrap();
+                "ok"
+            }),
+        ))
+        .await;
+        (format!("http://{addr}/hook"), rx)
+    }
+
+    async fn state_with_webhook(
+        url: &str,
+        allowed: &[&str],
+    ) -> (AppState, db::ChatRoom, db::Webhook) {
+        let mut state = testing::state(Arc::new(MemoryMailer::default())).await;
+        state.webhooks = Dispatcher::new(outbound::Guard::new(
+            allowed.iter().map(|net| net.parse().unwrap()).collect(),
+        ));
+        let owner = testing::user(&state, "owner").await;
+        let room = testing::room(&state, "lobby", &owner).await;
+        let webhook = db::Webhook {
+            id: Uuid::new_v4().to_string(),
+            room_id: room.id.clone(),
+            url: url.to_string(),
+            secret: "topsecret".to_string(),
+            events: r#"["message.created"]"#.to_string(),
+            created_by: owner.id.clone(),
+            created_at: Utc::now(),
+        };
+        state.db.create_webhook(&webhook).await.unwrap();
+        (state, room, webhook)
+    }
+
+    /// Publishes an event and makes one attempt at each due delivery.
+    async fn publish_and_deliver(state: &AppState, room: &db::ChatRoom, event: WebhookEvent) {
+        publish(state, room, event, json!({ "content": "hello" })).await;
+        for delivery in state
+            .db
+            .get_due_webhook_deliveries(DELIVERY_BATCH)
+            .await
+            .unwrap()
+        {
+            deliver(state, delivery).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_events() {
+        let (url, mut requests) = receiver().await;
+        let (state, room, webhook) = state_with_webhook(&url, &["127.0.0.1/32"]).await;
+
+        publish_and_deliver(&state, &room, WebhookEvent::MessageCreated).await;
+
+        let (headers, body) = requests.recv().await.unwrap();
+        let header = |name: &str| headers[name].to_str().unwrap().to_string();
+        assert_eq!(header("x-chatx-event"), "message.created");
+        let timestamp: i64 = header("x-chatx-timestamp").parse().unwrap();
+        assert_eq!(
+            header("x-chatx-signature"),
+            format!("sha256={}", sign("topsecret", timestamp, &body))
+        );
+        let payload: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["event"], "message.created");
+        assert_eq!(payload["room"]["name"], "lobby");
+        assert_eq!(payload["data"]["content"], "hello");
+
+        let deliveries = state
+            .db
+            .get_webhook_deliveries(&webhook.id, 10)
+            .await
+            .unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].id, header("x-chatx-delivery"));
+        assert_eq!(deliveries[0].status, "delivered");
+        assert_eq!(deliveries[0].response_status, Some(200));
+    }
+
+    #[tokio::test]
+    async fn skips_unsubscribed_events() {
+        let (url, mut requests) = receiver().await;
+        let (state, room, webhook) = state_with_webhook(&url, &["127.0.0.1/32"]).await;
+
+        publish_and_deliver(&state, &room, WebhookEvent::MemberJoined).await;
+
+        assert!(requests.try_recv().is_err());
+        assert!(state
+            .db
+            .get_webhook_deliveries(&webhook.id, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_private_receivers() {
+        let (url, mut requests) = receiver().await;
+        let (state, room, webhook) = state_with_webhook(&url, &[]).await;
+
+        assert!(state.webhooks.check_url(&url).await.is_err());
+        publish_and_deliver(&state, &room, WebhookEvent::MessageCreated).await;
+
+        assert!(requests.try_recv().is_err());
+        let delivery = &state
+            .db
+            .get_webhook_deliveries(&webhook.id, 10)
+            .await
+            .unwrap()[0];
+        assert_eq!(delivery.status, "pending");
+        assert_eq!(delivery.attempts, 1);
+        assert!(delivery
+            .last_error
+            .as_deref()
+            .unwrap()
+            .contains("127.0.0.1 is a blocked address"));
+    }
+}
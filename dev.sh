@@ -175,6 +175,9 @@ if [ "$FRONTEND_ONLY" != true ]; then
         export DATABASE_URL="sqlite::memory:"
     fi
 
+    # Allow the built-in development JWT secret
+    export CHATX_DEV=1
+
     # Start backend in background
     cargo run &
     BACKEND_PID=$!